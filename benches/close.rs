@@ -0,0 +1,59 @@
+// Copyright (c) 2021 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Measures the cost of closing many connections at once, as done when
+//! shedding load, with `Sender::close` and `Sender::close_fast`.
+
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use soketto::{Mode, base::CloseCode, connection::{Builder, Sender}, data::ControlPayload};
+use tokio::io::DuplexStream;
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+const CONNECTIONS: usize = 10_000;
+
+type Connections = Vec<(Sender<Compat<DuplexStream>>, DuplexStream)>;
+
+fn connections(mode: Mode) -> Connections {
+    (0 .. CONNECTIONS)
+        .map(|_| {
+            let (a, b) = tokio::io::duplex(64);
+            let (sender, _) = Builder::new(a.compat(), mode).finish();
+            (sender, b)
+        })
+        .collect()
+}
+
+fn shed(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread().build().expect("runtime");
+    let mut group = c.benchmark_group("shed");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(CONNECTIONS as u64));
+    for &(name, mode) in &[("client", Mode::Client), ("server", Mode::Server)] {
+        group.bench_function(BenchmarkId::new("close", name), |b| {
+            b.iter_batched(|| connections(mode), |mut conns: Connections| rt.block_on(async {
+                for (sender, _) in &mut conns {
+                    let payload = ControlPayload::close(CloseCode::TRY_AGAIN_LATER, "").expect("payload");
+                    sender.send_control(payload).await.expect("close")
+                }
+                conns
+            }), BatchSize::PerIteration)
+        });
+        group.bench_function(BenchmarkId::new("close_fast", name), |b| {
+            b.iter_batched(|| connections(mode), |mut conns: Connections| rt.block_on(async {
+                for (sender, _) in &mut conns {
+                    sender.close_fast(CloseCode::TRY_AGAIN_LATER).await.expect("close")
+                }
+                conns
+            }), BatchSize::PerIteration)
+        });
+    }
+    group.finish()
+}
+
+criterion_group!(benches, shed);
+criterion_main!(benches);
@@ -0,0 +1,88 @@
+// Copyright (c) 2021 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Measures the overhead of the extension chain by sending and receiving
+//! messages with no extensions and with three no-op extensions.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use soketto::{BoxedError, Mode, Storage, base::Header, connection::Builder};
+use soketto::extension::{Extension, Param};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+/// An extension which does nothing.
+#[derive(Debug)]
+struct Noop;
+
+impl Extension for Noop {
+    fn is_enabled(&self) -> bool {
+        true
+    }
+
+    fn name(&self) -> &str {
+        "noop"
+    }
+
+    fn params(&self) -> &[Param<'_>] {
+        &[]
+    }
+
+    fn configure(&mut self, _: &[Param]) -> Result<(), BoxedError> {
+        Ok(())
+    }
+
+    fn encode(&mut self, _: &mut Header, _: &mut Storage) -> Result<(), BoxedError> {
+        Ok(())
+    }
+
+    fn decode(&mut self, _: &mut Header, _: &mut Vec<u8>) -> Result<(), BoxedError> {
+        Ok(())
+    }
+}
+
+fn noops(n: usize) -> impl Iterator<Item = Box<dyn Extension + Send>> {
+    (0 .. n).map(|_| Box::new(Noop) as Box<dyn Extension + Send>)
+}
+
+fn send_receive(c: &mut Criterion) {
+    const MESSAGES: usize = 100;
+    let rt = tokio::runtime::Builder::new_current_thread().build().expect("runtime");
+    let payload = vec![0x55; 1024];
+    let mut group = c.benchmark_group("send_receive");
+    group.throughput(Throughput::Bytes((MESSAGES * payload.len()) as u64));
+    for n in &[0, 3] {
+        group.bench_with_input(BenchmarkId::new("extensions", n), n, |b, &n| {
+            b.iter(|| rt.block_on(async {
+                let (a, b) = tokio::io::duplex(64 * 1024);
+                let mut client = Builder::new(a.compat(), Mode::Client);
+                client.add_extensions(noops(n));
+                let mut server = Builder::new(b.compat(), Mode::Server);
+                server.add_extensions(noops(n));
+                let (mut sender, _) = client.finish();
+                let (_, mut receiver) = server.finish();
+                let send = async {
+                    for _ in 0 .. MESSAGES {
+                        sender.send_binary_mut(&mut payload.clone()).await.expect("send")
+                    }
+                    sender.flush().await.expect("flush")
+                };
+                let receive = async {
+                    let mut data = Vec::new();
+                    for _ in 0 .. MESSAGES {
+                        data.clear();
+                        receiver.receive_data(&mut data).await.expect("receive");
+                    }
+                };
+                tokio::join!(send, receive)
+            }))
+        });
+    }
+    group.finish()
+}
+
+criterion_group!(benches, send_receive);
+criterion_main!(benches);
@@ -0,0 +1,83 @@
+// Copyright (c) 2021 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Measures the throughput of the sans-IO framing layer: decoding and
+//! encoding a 64 KiB masked binary frame, and masking payloads of various
+//! sizes with `Codec::apply_mask`.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use soketto::base::{Codec, FrameDecoder, Header, OpCode};
+
+const LEN: usize = 64 * 1024;
+
+/// A masked binary frame header as a client sends it.
+fn header(len: usize) -> Header {
+    let mut header = Header::new(OpCode::Binary);
+    header.set_masked(true).set_mask(0x3779_4C2D).set_payload_len(len);
+    header
+}
+
+/// Header and payload of a masked binary frame, as one chunk.
+fn masked_frame(payload: &[u8]) -> Vec<u8> {
+    let header = header(payload.len());
+    let mut bytes = Codec::new().encode_header(&header).to_vec();
+    let offset = bytes.len();
+    bytes.extend_from_slice(payload);
+    Codec::apply_mask(&header, &mut bytes[offset ..]);
+    bytes
+}
+
+fn decode(c: &mut Criterion) {
+    let bytes = masked_frame(&[0x5A; LEN]);
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Bytes(LEN as u64));
+    group.bench_function(BenchmarkId::new("masked-binary", "64KiB"), |b| {
+        let mut decoder = FrameDecoder::new(Codec::new());
+        b.iter(|| {
+            decoder.push_bytes(&bytes);
+            decoder.next_frame().expect("valid frame").expect("complete frame")
+        })
+    });
+    group.finish()
+}
+
+fn encode(c: &mut Criterion) {
+    let payload = vec![0x5A; LEN];
+    let header = header(LEN);
+    let mut group = c.benchmark_group("encode");
+    group.throughput(Throughput::Bytes(LEN as u64));
+    group.bench_function(BenchmarkId::new("masked-binary", "64KiB"), |b| {
+        let mut codec = Codec::new();
+        let mut bytes = Vec::with_capacity(LEN + 14);
+        b.iter(|| {
+            bytes.clear();
+            bytes.extend_from_slice(codec.encode_header(&header));
+            let offset = bytes.len();
+            bytes.extend_from_slice(&payload);
+            Codec::apply_mask(&header, &mut bytes[offset ..]);
+            bytes.len()
+        })
+    });
+    group.finish()
+}
+
+fn mask(c: &mut Criterion) {
+    let mut group = c.benchmark_group("apply_mask");
+    for &(name, len) in &[("16B", 16), ("125B", 125), ("4KiB", 4096), ("64KiB", LEN), ("1MiB", 1024 * 1024)] {
+        let header = header(len);
+        let mut data = vec![0x5A; len];
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_function(BenchmarkId::from_parameter(name), |b| b.iter(|| {
+            Codec::apply_mask(&header, &mut data)
+        }));
+    }
+    group.finish()
+}
+
+criterion_group!(benches, decode, encode, mask);
+criterion_main!(benches);
@@ -0,0 +1,59 @@
+// Copyright (c) 2021 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Measures encoding and sending client handshake requests, as done by load
+//! generators opening many connections. The socket discards the requests
+//! and ends the handshake by reporting end of file.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use futures::io::{AsyncRead, AsyncWrite};
+use soketto::handshake::Client;
+use std::{io, pin::Pin, task::{Context, Poll}};
+
+const REQUESTS: usize = 100_000;
+
+/// A socket which discards what is written and has nothing to read.
+struct Discard;
+
+impl AsyncRead for Discard {
+    fn poll_read(self: Pin<&mut Self>, _: &mut Context, _: &mut [u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(0))
+    }
+}
+
+impl AsyncWrite for Discard {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn requests(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread().build().expect("runtime");
+    let mut group = c.benchmark_group("handshake");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(REQUESTS as u64));
+    group.bench_function(BenchmarkId::new("client", "100k"), |b| b.iter(|| rt.block_on(async {
+        for _ in 0 .. REQUESTS {
+            let mut client = Client::new(Discard, "example.com:8080", "/chat?room=1");
+            client.set_origin("https://example.com").add_protocol("graphql-transport-ws").add_protocol("graphql-ws");
+            assert!(client.handshake().await.is_err())
+        }
+    })));
+    group.finish()
+}
+
+criterion_group!(benches, requests);
+criterion_main!(benches);
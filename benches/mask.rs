@@ -0,0 +1,41 @@
+// Copyright (c) 2021 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Measures masking the payload of a 64 MiB frame with `Codec::apply_mask`
+//! against a plain single-threaded loop. Run with `--features parallel` to
+//! compare the parallel implementation.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use soketto::base::{Codec, Header, OpCode};
+
+const LEN: usize = 64 * 1024 * 1024;
+
+fn mask(c: &mut Criterion) {
+    let mut header = Header::new(OpCode::Binary);
+    header.set_masked(true);
+    header.set_mask(0x3779_4C2D);
+    let mut data = vec![0x5A; LEN];
+
+    let mut group = c.benchmark_group("mask");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(LEN as u64));
+    group.bench_function(BenchmarkId::new("scalar", "64MiB"), |b| b.iter(|| {
+        let mask = header.mask().to_be_bytes();
+        for (byte, &key) in data.iter_mut().zip(mask.iter().cycle()) {
+            *byte ^= key
+        }
+    }));
+    let name = if cfg!(feature = "parallel") { "parallel" } else { "apply_mask" };
+    group.bench_function(BenchmarkId::new(name, "64MiB"), |b| b.iter(|| {
+        Codec::apply_mask(&header, &mut data)
+    }));
+    group.finish()
+}
+
+criterion_group!(benches, mask);
+criterion_main!(benches);
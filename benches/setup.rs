@@ -0,0 +1,38 @@
+// Copyright (c) 2021 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Measures the cost of setting up a connection after the handshake, with
+//! every setting applied per connection and with a codec configuration
+//! created once and shared by all connections.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use futures::io::Cursor;
+use soketto::{Mode, base::CodecConfig, connection::Builder};
+
+const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+fn setup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("setup");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function(BenchmarkId::new("builder", "new"), |b| {
+        b.iter(|| {
+            let mut builder = Builder::new(Cursor::new(Vec::new()), Mode::Server);
+            builder.set_max_frame_size(MAX_FRAME_SIZE);
+            builder.finish()
+        })
+    });
+    group.bench_function(BenchmarkId::new("builder", "with_codec_config"), |b| {
+        let mut config = CodecConfig::new();
+        config.set_max_data_size(MAX_FRAME_SIZE);
+        b.iter(|| Builder::with_codec_config(Cursor::new(Vec::new()), Mode::Server, config).finish())
+    });
+    group.finish()
+}
+
+criterion_group!(benches, setup);
+criterion_main!(benches);
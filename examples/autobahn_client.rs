@@ -68,8 +68,23 @@ async fn run_case(n: usize) -> Result<(), BoxedError> {
                 sender.send_text(std::str::from_utf8(&message)?).await?;
                 sender.flush().await?
             }
-            Err(connection::Error::Closed) => return Ok(()),
-            Err(e) => return Err(e.into())
+            Err(connection::Error::Closed(reason)) => {
+                log::debug!("connection closed: {}", reason);
+                return Ok(())
+            }
+            Err(e @ connection::Error::Protocol(_))
+            | Err(e @ connection::Error::MessageTooLarge {..})
+            | Err(e @ connection::Error::Extension(_)) => {
+                log::debug!("closing connection with {}: {}", e.close_code(), e);
+                let _ = sender.close_on_error(&e).await;
+                return Ok(())
+            }
+            Err(e) => {
+                if e.is_fatal() {
+                    let _ = sender.close_on_error(&e).await;
+                }
+                return Err(e.into())
+            }
         }
     }
 }
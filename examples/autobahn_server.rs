@@ -50,7 +50,20 @@ async fn main() -> Result<(), BoxedError> {
                         break
                     }
                 }
-                Err(connection::Error::Closed) => break,
+                Err(connection::Error::Closed(reason)) => {
+                    log::debug!("connection closed: {}", reason);
+                    break
+                }
+                Err(e @ connection::Error::Protocol(_))
+                | Err(e @ connection::Error::MessageTooLarge {..})
+                | Err(e @ connection::Error::Extension(_))
+                | Err(e @ connection::Error::Timeout(_)) => {
+                    log::debug!("closing connection with {}: {}", e.close_code(), e);
+                    if let Err(e) = sender.close_on_error(&e).await {
+                        log::debug!("failed to close connection: {}", e)
+                    }
+                    break
+                }
                 Err(e) => {
                     log::error!("connection error: {}", e);
                     break
@@ -17,12 +17,17 @@
 //!
 //! [base]: https://tools.ietf.org/html/rfc6455#section-5.2
 
+use bytes::{Buf, BytesMut};
 use crate::{as_u64, Parsing};
 use std::{convert::TryFrom, fmt, io};
 
 /// Max. size of a frame header.
 pub(crate) const MAX_HEADER_SIZE: usize = 14;
 
+/// Max. size of an encoded control frame: two header bytes, the mask and
+/// a payload of at most 125 bytes.
+pub const MAX_CONTROL_FRAME_SIZE: usize = 2 + 4 + 125;
+
 /// Max. size of a control frame payload.
 pub(crate) const MAX_CTRL_BODY_SIZE: u64 = 125;
 
@@ -43,53 +48,29 @@ pub enum OpCode {
     Ping,
     /// A pong control frame.
     Pong,
-    /// A reserved op code.
-    Reserved3,
-    /// A reserved op code.
-    Reserved4,
-    /// A reserved op code.
-    Reserved5,
-    /// A reserved op code.
-    Reserved6,
-    /// A reserved op code.
-    Reserved7,
-    /// A reserved op code.
-    Reserved11,
-    /// A reserved op code.
-    Reserved12,
-    /// A reserved op code.
-    Reserved13,
-    /// A reserved op code.
-    Reserved14,
-    /// A reserved op code.
-    Reserved15
+    /// A reserved data op code (3 - 7).
+    ///
+    /// The op code value is preserved. Values outside of the reserved
+    /// range must not be used.
+    ReservedData(u8),
+    /// A reserved control op code (11 - 15).
+    ///
+    /// The op code value is preserved. Values outside of the reserved
+    /// range must not be used.
+    ReservedControl(u8)
 }
 
 impl OpCode {
     /// Is this a control opcode?
+    ///
+    /// This includes the reserved control opcodes.
     pub fn is_control(self) -> bool {
-        if let OpCode::Close | OpCode::Ping | OpCode::Pong = self {
-            true
-        } else {
-            false
-        }
+        matches!(self, OpCode::Close | OpCode::Ping | OpCode::Pong | OpCode::ReservedControl(_))
     }
 
     /// Is this opcode reserved?
     pub fn is_reserved(self) -> bool {
-        match self {
-            OpCode::Reserved3
-            | OpCode::Reserved4
-            | OpCode::Reserved5
-            | OpCode::Reserved6
-            | OpCode::Reserved7
-            | OpCode::Reserved11
-            | OpCode::Reserved12
-            | OpCode::Reserved13
-            | OpCode::Reserved14
-            | OpCode::Reserved15 => true,
-            _ => false
-        }
+        matches!(self, OpCode::ReservedData(_) | OpCode::ReservedControl(_))
     }
 }
 
@@ -102,16 +83,7 @@ impl fmt::Display for OpCode {
             OpCode::Close => f.write_str("Close"),
             OpCode::Ping => f.write_str("Ping"),
             OpCode::Pong => f.write_str("Pong"),
-            OpCode::Reserved3 => f.write_str("Reserved:3"),
-            OpCode::Reserved4 => f.write_str("Reserved:4"),
-            OpCode::Reserved5 => f.write_str("Reserved:5"),
-            OpCode::Reserved6 => f.write_str("Reserved:6"),
-            OpCode::Reserved7 => f.write_str("Reserved:7"),
-            OpCode::Reserved11 => f.write_str("Reserved:11"),
-            OpCode::Reserved12 => f.write_str("Reserved:12"),
-            OpCode::Reserved13 => f.write_str("Reserved:13"),
-            OpCode::Reserved14 => f.write_str("Reserved:14"),
-            OpCode::Reserved15 => f.write_str("Reserved:15")
+            OpCode::ReservedData(n) | OpCode::ReservedControl(n) => write!(f, "Reserved:{}", n)
         }
     }
 }
@@ -137,19 +109,11 @@ impl TryFrom<u8> for OpCode {
             0 => Ok(OpCode::Continue),
             1 => Ok(OpCode::Text),
             2 => Ok(OpCode::Binary),
-            3 => Ok(OpCode::Reserved3),
-            4 => Ok(OpCode::Reserved4),
-            5 => Ok(OpCode::Reserved5),
-            6 => Ok(OpCode::Reserved6),
-            7 => Ok(OpCode::Reserved7),
+            3 ..= 7 => Ok(OpCode::ReservedData(val)),
             8 => Ok(OpCode::Close),
             9 => Ok(OpCode::Ping),
             10 => Ok(OpCode::Pong),
-            11 => Ok(OpCode::Reserved11),
-            12 => Ok(OpCode::Reserved12),
-            13 => Ok(OpCode::Reserved13),
-            14 => Ok(OpCode::Reserved14),
-            15 => Ok(OpCode::Reserved15),
+            11 ..= 15 => Ok(OpCode::ReservedControl(val)),
             _ => Err(UnknownOpCode(()))
         }
     }
@@ -164,16 +128,111 @@ impl From<OpCode> for u8 {
             OpCode::Close => 8,
             OpCode::Ping => 9,
             OpCode::Pong => 10,
-            OpCode::Reserved3 => 3,
-            OpCode::Reserved4 => 4,
-            OpCode::Reserved5 => 5,
-            OpCode::Reserved6 => 6,
-            OpCode::Reserved7 => 7,
-            OpCode::Reserved11 => 11,
-            OpCode::Reserved12 => 12,
-            OpCode::Reserved13 => 13,
-            OpCode::Reserved14 => 14,
-            OpCode::Reserved15 => 15
+            OpCode::ReservedData(n) | OpCode::ReservedControl(n) => n
+        }
+    }
+}
+
+// CloseCode //////////////////////////////////////////////////////////////////////////////////////
+
+/// Status codes of close frames as defined in [RFC 6455](https://tools.ietf.org/html/rfc6455#section-7.4).
+#[derive(Debug, Eq, PartialEq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub struct CloseCode(u16);
+
+impl CloseCode {
+    /// Normal closure.
+    pub const NORMAL: CloseCode = CloseCode(1000);
+    /// The endpoint is going away, e.g. server shutdown or browser navigation.
+    pub const GOING_AWAY: CloseCode = CloseCode(1001);
+    /// The endpoint terminates the connection due to a protocol error.
+    pub const PROTOCOL_ERROR: CloseCode = CloseCode(1002);
+    /// The endpoint received a type of data it can not accept.
+    pub const UNSUPPORTED_DATA: CloseCode = CloseCode(1003);
+    /// No status code was present (must not be sent).
+    pub const NO_STATUS_RECEIVED: CloseCode = CloseCode(1005);
+    /// The connection was closed without a close frame (must not be sent).
+    pub const ABNORMAL_CLOSURE: CloseCode = CloseCode(1006);
+    /// The endpoint received data inconsistent with the message type, e.g. invalid UTF-8.
+    pub const INVALID_PAYLOAD: CloseCode = CloseCode(1007);
+    /// The endpoint received a message which violates its policy.
+    pub const POLICY_VIOLATION: CloseCode = CloseCode(1008);
+    /// The endpoint received a message too big to process.
+    pub const MESSAGE_TOO_BIG: CloseCode = CloseCode(1009);
+    /// The client expected the server to negotiate an extension.
+    pub const MANDATORY_EXTENSION: CloseCode = CloseCode(1010);
+    /// The server encountered an unexpected condition.
+    pub const INTERNAL_ERROR: CloseCode = CloseCode(1011);
+    /// The server is restarting.
+    pub const SERVICE_RESTART: CloseCode = CloseCode(1012);
+    /// The server is overloaded and the client should try again later.
+    pub const TRY_AGAIN_LATER: CloseCode = CloseCode(1013);
+    /// The server acting as a gateway received an invalid upstream response.
+    pub const BAD_GATEWAY: CloseCode = CloseCode(1014);
+    /// The TLS handshake failed (must not be sent).
+    pub const TLS_HANDSHAKE: CloseCode = CloseCode(1015);
+
+    /// Is this code reserved, i.e. must it never be sent in a close frame?
+    ///
+    /// This is the case for 1004, 1005, 1006 and 1015.
+    pub fn is_reserved(self) -> bool {
+        matches!(self.0, 1004 ..= 1006 | 1015)
+    }
+
+    /// Is this code within the range for libraries, frameworks and applications (3000 ..= 3999)?
+    pub fn is_application(self) -> bool {
+        (3000 ..= 3999).contains(&self.0)
+    }
+
+    /// Is this code within the range for private use (4000 ..= 4999)?
+    pub fn is_private(self) -> bool {
+        (4000 ..= 4999).contains(&self.0)
+    }
+
+    /// May this code be sent in a close frame?
+    ///
+    /// Allowed are all non-reserved codes defined by the protocol
+    /// (1000 ..= 1014) and codes in the application or private ranges.
+    pub fn is_allowed_on_wire(self) -> bool {
+        if self.is_reserved() {
+            return false
+        }
+        (1000 ..= 1014).contains(&self.0) || self.is_application() || self.is_private()
+    }
+}
+
+impl fmt::Display for CloseCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> Self {
+        CloseCode(code)
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> u16 {
+        code.0
+    }
+}
+
+/// The close code to answer a codec error with.
+///
+/// I/O errors map to [`CloseCode::ABNORMAL_CLOSURE`] which must not be sent.
+impl From<&Error> for CloseCode {
+    fn from(e: &Error) -> Self {
+        match e {
+            Error::Io(_) => CloseCode::ABNORMAL_CLOSURE,
+            Error::PayloadTooLarge {..} => CloseCode::MESSAGE_TOO_BIG,
+            Error::UnknownOpCode
+            | Error::ReservedOpCode
+            | Error::FragmentedControl {..}
+            | Error::InvalidControlFrameLen
+            | Error::InvalidReservedBit(_)
+            | Error::Incomplete {..}
+            | Error::TrailingBytes {..} => CloseCode::PROTOCOL_ERROR
         }
     }
 }
@@ -181,7 +240,7 @@ impl From<OpCode> for u8 {
 // Frame header ///////////////////////////////////////////////////////////////////////////////////
 
 /// A websocket base frame header, i.e. everything but the payload.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Header {
     fin: bool,
     rsv1: bool,
@@ -311,6 +370,167 @@ impl Header {
     }
 }
 
+// Frame //////////////////////////////////////////////////////////////////////////////////////////
+
+/// A websocket base frame, i.e. a [`Header`] and the payload data.
+///
+/// The payload length of the header always matches the payload data.
+///
+/// Frames are equal if they have the same header and payload, regardless
+/// of the mask key. Two frames carrying the same content are thus equal
+/// even if they would be masked differently on the wire. Use
+/// [`Frame::wire_eq`] to compare the mask key as well.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    header: Header,
+    payload: Vec<u8>
+}
+
+impl PartialEq for Frame {
+    fn eq(&self, other: &Self) -> bool {
+        let (a, b) = (&self.header, &other.header);
+        a.fin == b.fin
+            && a.rsv1 == b.rsv1
+            && a.rsv2 == b.rsv2
+            && a.rsv3 == b.rsv3
+            && a.masked == b.masked
+            && a.opcode == b.opcode
+            && a.payload_len == b.payload_len
+            && self.payload == other.payload
+    }
+}
+
+impl Eq for Frame {}
+
+impl std::hash::Hash for Frame {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        let h = &self.header;
+        (h.fin, h.rsv1, h.rsv2, h.rsv3, h.masked, h.opcode, h.payload_len).hash(state);
+        self.payload.hash(state)
+    }
+}
+
+impl Frame {
+    /// Create a new frame with a given [`OpCode`] and empty payload.
+    pub fn new(oc: OpCode) -> Self {
+        Frame { header: Header::new(oc), payload: Vec::new() }
+    }
+
+    /// Create a complete frame with a given [`OpCode`] and empty payload.
+    ///
+    /// The `fin` flag is set, which is required for control frames and marks
+    /// data frames as a complete message or the last fragment of one.
+    /// Reserved opcodes are rejected with [`Error::ReservedOpCode`].
+    pub fn empty(oc: OpCode) -> Result<Self, Error> {
+        if oc.is_reserved() {
+            return Err(Error::ReservedOpCode)
+        }
+        let mut frame = Frame::new(oc);
+        frame.header.set_fin(true);
+        Ok(frame)
+    }
+
+    /// Get a reference to the frame header.
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Get a mutable reference to the frame header.
+    ///
+    /// The payload length is not meant to be changed through this reference.
+    pub fn header_mut(&mut self) -> &mut Header {
+        &mut self.header
+    }
+
+    /// Get the payload data.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Get a mutable reference to the payload data.
+    ///
+    /// The payload length of the header is not updated.
+    pub fn payload_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.payload
+    }
+
+    /// The mask key if the frame is masked.
+    ///
+    /// A key of 0 is legal and returned as `Some(0)`.
+    pub fn mask_key(&self) -> Option<u32> {
+        if self.header.is_masked() {
+            Some(self.header.mask())
+        } else {
+            None
+        }
+    }
+
+    /// Compare with another frame, including the mask key.
+    pub fn wire_eq(&self, other: &Frame) -> bool {
+        self.header == other.header && self.payload == other.payload
+    }
+
+    /// Create a frame from header and payload data.
+    ///
+    /// The payload length of the header is set to the length of `payload`.
+    pub fn from_parts(mut header: Header, payload: Vec<u8>) -> Self {
+        header.set_payload_len(payload.len());
+        Frame { header, payload }
+    }
+
+    /// Consume this frame and return header and payload data.
+    pub fn into_parts(self) -> (Header, Vec<u8>) {
+        (self.header, self.payload)
+    }
+
+    /// Make this a complete text frame with the given text as payload.
+    ///
+    /// Sets the opcode to [`OpCode::Text`] and the `fin` flag. Since the
+    /// payload is taken from a `&str` it is valid UTF-8 by construction.
+    pub fn set_text(&mut self, text: &str) -> &mut Self {
+        self.set_payload(OpCode::Text, text.as_bytes())
+    }
+
+    /// Make this a complete binary frame with the given payload.
+    ///
+    /// Sets the opcode to [`OpCode::Binary`] and the `fin` flag.
+    pub fn set_binary(&mut self, data: &[u8]) -> &mut Self {
+        self.set_payload(OpCode::Binary, data)
+    }
+
+    fn set_payload(&mut self, oc: OpCode, data: &[u8]) -> &mut Self {
+        self.payload.clear();
+        self.payload.extend_from_slice(data);
+        self.header.set_opcode(oc).set_fin(true).set_payload_len(data.len());
+        self
+    }
+}
+
+/// Decode exactly one complete frame.
+///
+/// A [`Codec`] with default settings is used, i.e. no reserved bits may be
+/// set. The payload of a masked frame is unmasked as by
+/// [`FrameDecoder::next_frame`].
+impl TryFrom<&[u8]> for Frame {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let mut decoder = FrameDecoder::new(Codec::new());
+        decoder.push_bytes(bytes);
+        match decoder.next_frame()? {
+            Some(_) if decoder.buffered() > 0 => Err(Error::TrailingBytes { len: decoder.buffered() }),
+            Some(frame) => Ok(frame),
+            None => {
+                let needed = match Codec::new().decode_header(bytes)? {
+                    Parsing::NeedMore(n) => n,
+                    Parsing::Done { value, offset } => offset + value.payload_len() - bytes.len()
+                };
+                Err(Error::Incomplete { needed })
+            }
+        }
+    }
+}
+
 // Base codec ////////////////////////////////////////////////////////////////////////////////////.
 
 /// If the payload length byte is 126, the following two bytes represent the
@@ -326,31 +546,41 @@ const EIGHT_EXT: u8 = 127;
 /// [base]: https://tools.ietf.org/html/rfc6455#section-5.2
 #[derive(Debug, Clone)]
 pub struct Codec {
+    /// The codec configuration.
+    config: CodecConfig,
+    /// Scratch buffer used during header encoding.
+    header_buffer: [u8; MAX_HEADER_SIZE]
+}
+
+/// The configuration of a [`Codec`].
+///
+/// A configuration can be created once and then be used to create any
+/// number of codecs with [`Codec::with_config`]. It is `Copy`, so sharing
+/// it does not require any allocation or reference counting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodecConfig {
     /// Maximum size of payload data per frame.
     max_data_size: usize,
     /// Bits reserved by an extension.
-    reserved_bits: u8,
-    /// Scratch buffer used during header encoding.
-    header_buffer: [u8; MAX_HEADER_SIZE]
+    reserved_bits: u8
 }
 
-impl Default for Codec {
+impl Default for CodecConfig {
     fn default() -> Self {
-        Codec {
+        CodecConfig {
             max_data_size: 256 * 1024 * 1024,
-            reserved_bits: 0,
-            header_buffer: [0; MAX_HEADER_SIZE]
+            reserved_bits: 0
         }
     }
 }
 
-impl Codec {
-    /// Create a new base frame codec.
+impl CodecConfig {
+    /// Create a new default configuration.
     ///
-    /// The codec will support decoding payload lengths up to 256 MiB
-    /// (use `set_max_data_size` to change this value).
+    /// Payload lengths up to 256 MiB are supported (use `set_max_data_size`
+    /// to change this value).
     pub fn new() -> Self {
-        Codec::default()
+        CodecConfig::default()
     }
 
     /// Get the configured maximum payload length.
@@ -381,6 +611,59 @@ impl Codec {
     pub fn clear_reserved_bits(&mut self) {
         self.reserved_bits = 0
     }
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::with_config(CodecConfig::default())
+    }
+}
+
+impl Codec {
+    /// Create a new base frame codec.
+    ///
+    /// The codec will support decoding payload lengths up to 256 MiB
+    /// (use `set_max_data_size` to change this value).
+    pub fn new() -> Self {
+        Codec::default()
+    }
+
+    /// Create a new base frame codec with the given configuration.
+    pub fn with_config(config: CodecConfig) -> Self {
+        Codec { config, header_buffer: [0; MAX_HEADER_SIZE] }
+    }
+
+    /// Get the codec configuration.
+    pub fn config(&self) -> &CodecConfig {
+        &self.config
+    }
+
+    /// Get the configured maximum payload length.
+    pub fn max_data_size(&self) -> usize {
+        self.config.max_data_size()
+    }
+
+    /// Limit the maximum size of payload data to `size` bytes.
+    pub fn set_max_data_size(&mut self, size: usize) -> &mut Self {
+        self.config.set_max_data_size(size);
+        self
+    }
+
+    /// The reserved bits currently configured.
+    pub fn reserved_bits(&self) -> (bool, bool, bool) {
+        self.config.reserved_bits()
+    }
+
+    /// Add to the reserved bits in use.
+    pub fn add_reserved_bits(&mut self, bits: (bool, bool, bool)) -> &mut Self {
+        self.config.add_reserved_bits(bits);
+        self
+    }
+
+    /// Reset the reserved bits.
+    pub fn clear_reserved_bits(&mut self) {
+        self.config.clear_reserved_bits()
+    }
 
     /// Decode a websocket frame header.
     pub fn decode_header(&self, bytes: &[u8]) -> Result<Parsing<Header, usize>, Error> {
@@ -400,26 +683,26 @@ impl Codec {
         }
 
         if opcode.is_control() && !fin {
-            return Err(Error::FragmentedControl)
+            return Err(Error::FragmentedControl { opcode, first_byte: first })
         }
 
         let mut header = Header::new(opcode);
         header.set_fin(fin);
 
         let rsv1 = first & 0x40 != 0;
-        if rsv1 && (self.reserved_bits & 4 == 0) {
+        if rsv1 && (self.config.reserved_bits & 4 == 0) {
             return Err(Error::InvalidReservedBit(1))
         }
         header.set_rsv1(rsv1);
 
         let rsv2 = first & 0x20 != 0;
-        if rsv2 && (self.reserved_bits & 2 == 0) {
+        if rsv2 && (self.config.reserved_bits & 2 == 0) {
             return Err(Error::InvalidReservedBit(2))
         }
         header.set_rsv2(rsv2);
 
         let rsv3 = first & 0x10 != 0;
-        if rsv3 && (self.reserved_bits & 1 == 0) {
+        if rsv3 && (self.config.reserved_bits & 1 == 0) {
             return Err(Error::InvalidReservedBit(3))
         }
         header.set_rsv3(rsv3);
@@ -450,15 +733,15 @@ impl Codec {
             return Err(Error::InvalidControlFrameLen)
         }
 
-        let len: usize =
-            if len > as_u64(self.max_data_size) {
-                return Err(Error::PayloadTooLarge {
-                    actual: len,
-                    maximum: as_u64(self.max_data_size)
-                })
-            } else {
-                len as usize
-            };
+        // Lengths beyond `usize::MAX` (e.g. > 4 GiB on 32-bit platforms)
+        // can not be represented and are always too large.
+        let len = match usize::try_from(len) {
+            Ok(n) if n <= self.config.max_data_size => n,
+            _ => return Err(Error::PayloadTooLarge {
+                actual: len,
+                maximum: as_u64(self.config.max_data_size)
+            })
+        };
 
         header.set_payload_len(len);
 
@@ -510,7 +793,7 @@ impl Codec {
             second_byte |= len as u8;
             self.header_buffer[offset] = second_byte;
             offset += 1;
-        } else if len <= usize::from(u16::max_value()) {
+        } else if len <= usize::from(u16::MAX) {
             second_byte |= TWO_EXT;
             self.header_buffer[offset] = second_byte;
             offset += 1;
@@ -532,18 +815,131 @@ impl Codec {
         &self.header_buffer[.. offset]
     }
 
+    /// Encode a control frame with the given payload into `out`.
+    ///
+    /// Header and payload are written directly into `out`, the payload being
+    /// masked if the header is, without any intermediate buffer. The payload
+    /// length of `header` is ignored in favour of the length of `payload`,
+    /// which must not exceed 125 bytes. Returns the number of bytes written.
+    pub fn encode_control_frame
+        ( &mut self
+        , header: &Header
+        , payload: &[u8]
+        , out: &mut [u8; MAX_CONTROL_FRAME_SIZE]
+        ) -> Result<usize, Error>
+    {
+        if payload.len() > 125 {
+            return Err(Error::InvalidControlFrameLen)
+        }
+        let mut header = header.clone();
+        header.set_payload_len(payload.len());
+        let header_bytes = self.encode_header(&header);
+        let n = header_bytes.len();
+        out[.. n].copy_from_slice(header_bytes);
+        out[n .. n + payload.len()].copy_from_slice(payload);
+        Codec::apply_mask(&header, &mut out[n .. n + payload.len()]);
+        Ok(n + payload.len())
+    }
+
     /// Use the given header's mask and apply it to the data.
+    ///
+    /// With feature `parallel`, payloads of at least 1 MiB are masked in
+    /// chunks on the rayon thread pool.
     pub fn apply_mask(header: &Header, data: &mut [u8]) {
         if header.is_masked() {
             let mask = header.mask().to_be_bytes();
-            for (byte, &key) in data.iter_mut().zip(mask.iter().cycle()) {
-                *byte ^= key;
+            #[cfg(feature = "parallel")]
+            {
+                if data.len() >= PARALLEL_MASK_THRESHOLD {
+                    return apply_mask_parallel(mask, data, PARALLEL_MASK_CHUNK_SIZE)
+                }
             }
+            apply_mask_at(mask, 0, data)
+        }
+    }
+}
+
+/// Min. payload length to mask in parallel.
+#[cfg(feature = "parallel")]
+const PARALLEL_MASK_THRESHOLD: usize = 1024 * 1024;
+
+/// Length of the chunks masked in parallel.
+#[cfg(feature = "parallel")]
+const PARALLEL_MASK_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Apply `mask` to `data`, whose first byte is at `offset` in the payload.
+fn apply_mask_at(mask: [u8; 4], offset: usize, data: &mut [u8]) {
+    for (byte, &key) in data.iter_mut().zip(mask.iter().cycle().skip(offset % 4)) {
+        *byte ^= key;
+    }
+}
+
+/// Apply `mask` to `data` in chunks of `chunk_size` bytes processed in parallel.
+#[cfg(feature = "parallel")]
+fn apply_mask_parallel(mask: [u8; 4], data: &mut [u8], chunk_size: usize) {
+    use rayon::prelude::*;
+    data.par_chunks_mut(chunk_size)
+        .enumerate()
+        .for_each(|(i, chunk)| apply_mask_at(mask, i * chunk_size, chunk))
+}
+
+// Frame decoder //////////////////////////////////////////////////////////////////////////////////
+
+/// Decodes complete [`Frame`]s from bytes supplied by the caller.
+///
+/// This allows driving the decoding from any source of bytes, e.g. a
+/// completion-based I/O reactor. Bytes are added with
+/// [`FrameDecoder::push_bytes`] in chunks of any size and frames are taken
+/// out with [`FrameDecoder::next_frame`].
+#[derive(Debug, Clone)]
+pub struct FrameDecoder {
+    codec: Codec,
+    buffer: BytesMut
+}
+
+impl FrameDecoder {
+    /// Create a new decoder using the given codec.
+    pub fn new(codec: Codec) -> Self {
+        FrameDecoder { codec, buffer: BytesMut::new() }
+    }
+
+    /// Append bytes to the decoding buffer.
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes)
+    }
+
+    /// The number of bytes buffered which have not been decoded yet.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Decode the next frame if its bytes have been pushed completely.
+    ///
+    /// The payload of a masked frame is unmasked, but its header still
+    /// reports the mask (cf. [`Frame::mask_key`]). No validation beyond
+    /// what [`Codec::decode_header`] does is performed, i.e. the frames are
+    /// not checked to form valid messages.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, Error> {
+        let decoded = self.codec.decode_header(&self.buffer)?;
+        let (header, offset) = match decoded {
+            Parsing::Done { value, offset } => (value, offset),
+            Parsing::NeedMore(_) => return Ok(None)
+        };
+        if self.buffer.len() - offset < header.payload_len() {
+            return Ok(None)
         }
+        self.buffer.advance(offset);
+        let mut payload = self.buffer.split_to(header.payload_len()).to_vec();
+        Codec::apply_mask(&header, &mut payload);
+        Ok(Some(Frame { header, payload }))
     }
 }
 
-/// Error cases the base frame decoder may encounter.
+/// Violations of the websocket protocol.
+///
+/// Most are detected by the base frame decoder, others by a
+/// [`Receiver`](crate::connection::Receiver) which checks the sequence and
+/// contents of frames.
 #[non_exhaustive]
 #[derive(Debug)]
 pub enum Error {
@@ -554,13 +950,19 @@ pub enum Error {
     /// The opcode decoded is reserved.
     ReservedOpCode,
     /// A fragmented control frame (fin bit not set) has been decoded.
-    FragmentedControl,
+    ///
+    /// Carries the control frame's opcode and the first header byte.
+    FragmentedControl { opcode: OpCode, first_byte: u8 },
     /// A control frame with an invalid length code has been decoded.
     InvalidControlFrameLen,
     /// The reserved bit is invalid.
     InvalidReservedBit(u8),
     /// The payload length of a frame exceeded the configured maximum.
-    PayloadTooLarge { actual: u64, maximum: u64 }
+    PayloadTooLarge { actual: u64, maximum: u64 },
+    /// The bytes end before the frame does.
+    Incomplete { needed: usize },
+    /// More bytes follow the frame.
+    TrailingBytes { len: usize }
 }
 
 impl fmt::Display for Error {
@@ -572,14 +974,18 @@ impl fmt::Display for Error {
                 f.write_str("unknown opcode"),
             Error::ReservedOpCode =>
                 f.write_str("reserved opcode"),
-            Error::FragmentedControl =>
-                f.write_str("fragmented control frame"),
+            Error::FragmentedControl { opcode, first_byte } =>
+                write!(f, "fragmented control frame: {} (first byte: {:#04x})", opcode, first_byte),
             Error::InvalidControlFrameLen =>
                 f.write_str("invalid control frame length"),
             Error::InvalidReservedBit(n) =>
                 write!(f, "invalid reserved bit: {}", n),
             Error::PayloadTooLarge { actual, maximum } =>
-                write!(f, "payload too large: len = {}, maximum = {}", actual, maximum)
+                write!(f, "payload too large: len = {}, maximum = {}", actual, maximum),
+            Error::Incomplete { needed } =>
+                write!(f, "incomplete frame: {} more bytes needed", needed),
+            Error::TrailingBytes { len } =>
+                write!(f, "{} bytes after frame", len)
         }
     }
 }
@@ -590,10 +996,12 @@ impl std::error::Error for Error {
             Error::Io(e) => Some(e),
             Error::UnknownOpCode
             | Error::ReservedOpCode
-            | Error::FragmentedControl
+            | Error::FragmentedControl {..}
             | Error::InvalidControlFrameLen
             | Error::InvalidReservedBit(_)
             | Error::PayloadTooLarge {..}
+            | Error::Incomplete {..}
+            | Error::TrailingBytes {..}
             => None
         }
     }
@@ -618,7 +1026,9 @@ impl From<UnknownOpCode> for Error {
 mod test {
     use crate::Parsing;
     use quickcheck::QuickCheck;
-    use super::{OpCode, Codec, Error};
+    use super::{OpCode, CloseCode, Codec, CodecConfig, Error, Frame, FrameDecoder, Header};
+    use super::{EIGHT_EXT, MAX_CONTROL_FRAME_SIZE, TWO_EXT};
+    use std::convert::TryFrom;
 
     #[test]
     fn decode_partial_header() {
@@ -658,7 +1068,7 @@ mod test {
         if let Ok(Parsing::Done { value, offset }) = Codec::new().decode_header(partial_payload) {
             assert_eq!(3, value.payload_len() - (partial_payload.len() - offset))
         } else {
-            assert!(false)
+            panic!("failed to decode header")
         }
     }
 
@@ -669,7 +1079,8 @@ mod test {
         assert!(matches! {
             Codec::new().decode_header(ctrl_payload_len),
             Err(Error::InvalidControlFrameLen)
-        })
+        });
+
     }
 
     /// Checking that rsv1, rsv2, and rsv3 bit set returns error.
@@ -691,13 +1102,36 @@ mod test {
     #[test]
     fn decode_fragmented_control() {
         let second_bytes = [8, 9, 10];
-        for sb in &second_bytes {
+        let opcodes = [OpCode::Close, OpCode::Ping, OpCode::Pong];
+        for (sb, expected) in second_bytes.iter().zip(&opcodes) {
             let mut buf = [0; 2];
             buf[0] |= *sb;
-            assert!(matches! {
-                Codec::new().decode_header(&buf),
-                Err(Error::FragmentedControl)
-            })
+            match Codec::new().decode_header(&buf) {
+                Err(Error::FragmentedControl { opcode, first_byte }) => {
+                    assert_eq!(*expected, opcode);
+                    assert_eq!(*sb, first_byte)
+                }
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+    }
+
+    #[test]
+    fn reserved_opcode_values() {
+        let op = OpCode::try_from(0xB).unwrap();
+        assert_eq!(OpCode::ReservedControl(0xB), op);
+        assert!(op.is_control());
+        assert!(op.is_reserved());
+        assert_eq!(0xB, u8::from(op));
+
+        let op = OpCode::try_from(3).unwrap();
+        assert_eq!(OpCode::ReservedData(3), op);
+        assert!(!op.is_control());
+        assert!(op.is_reserved());
+        assert_eq!(3, u8::from(op));
+
+        for n in 0 .. 16 {
+            assert_eq!(n, u8::from(OpCode::try_from(n).unwrap()))
         }
     }
 
@@ -727,7 +1161,7 @@ mod test {
             assert!(header.opcode() == OpCode::Ping);
             assert!(header.payload_len() == 0)
         } else {
-            assert!(false)
+            panic!("failed to decode header")
         }
     }
 
@@ -741,5 +1175,359 @@ mod test {
         }
         QuickCheck::new().quickcheck(property as fn((bool, bool, bool)) -> bool)
     }
-}
 
+    #[test]
+    fn close_code_classification() {
+        for &c in &[0, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000, u16::MAX] {
+            assert!(!CloseCode::from(c).is_allowed_on_wire(), "{}", c)
+        }
+        for &c in &[1000, 1001, 1002, 1003, 1007, 1011, 1014, 3000, 3999, 4000, 4999] {
+            assert!(CloseCode::from(c).is_allowed_on_wire(), "{}", c)
+        }
+        for &c in &[1004, 1005, 1006, 1015] {
+            assert!(CloseCode::from(c).is_reserved(), "{}", c)
+        }
+        assert!(!CloseCode::NORMAL.is_reserved());
+        assert!(!CloseCode::from(2999).is_application());
+        assert!(CloseCode::from(3000).is_application());
+        assert!(CloseCode::from(3999).is_application());
+        assert!(!CloseCode::from(4000).is_application());
+        assert!(!CloseCode::from(3999).is_private());
+        assert!(CloseCode::from(4000).is_private());
+        assert!(CloseCode::from(4999).is_private());
+        assert!(!CloseCode::from(5000).is_private())
+    }
+
+    #[test]
+    fn close_code_from_error() {
+        let io = std::io::Error::from(std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(CloseCode::ABNORMAL_CLOSURE, CloseCode::from(&Error::Io(io)));
+        assert_eq!(CloseCode::PROTOCOL_ERROR, CloseCode::from(&Error::UnknownOpCode));
+        assert_eq!(CloseCode::PROTOCOL_ERROR, CloseCode::from(&Error::ReservedOpCode));
+        let fragmented = Error::FragmentedControl { opcode: OpCode::Ping, first_byte: 0x09 };
+        assert_eq!(CloseCode::PROTOCOL_ERROR, CloseCode::from(&fragmented));
+        assert_eq!(CloseCode::PROTOCOL_ERROR, CloseCode::from(&Error::InvalidControlFrameLen));
+        assert_eq!(CloseCode::PROTOCOL_ERROR, CloseCode::from(&Error::InvalidReservedBit(2)));
+        let e = Error::PayloadTooLarge { actual: 2, maximum: 1 };
+        assert_eq!(CloseCode::MESSAGE_TOO_BIG, CloseCode::from(&e))
+    }
+
+    /// Headers with the largest lengths are fed one byte at a time. The
+    /// number of bytes needed is computed anew from each prefix.
+    #[test]
+    fn max_length_drip_feed() {
+        for &masked in &[false, true] {
+            let mut header = vec![0x82, if masked { 0x80 | EIGHT_EXT } else { EIGHT_EXT }];
+            header.extend_from_slice(&u64::MAX.to_be_bytes());
+            if masked {
+                header.extend_from_slice(&[1, 2, 3, 4])
+            }
+            let mut unlimited = Codec::new();
+            unlimited.set_max_data_size(usize::MAX);
+
+            for i in 0 .. header.len() {
+                match unlimited.decode_header(&header[.. i]) {
+                    Ok(Parsing::NeedMore(n)) => assert!(n > 0 && i + n <= header.len(), "{}: {}", i, n),
+                    Err(Error::PayloadTooLarge { actual: u64::MAX, .. }) if usize::BITS < 64 && i >= 10 => {}
+                    other => panic!("{}: unexpected result: {:?}", i, other)
+                }
+            }
+            match unlimited.decode_header(&header) {
+                Ok(Parsing::Done { value, offset }) => {
+                    assert_eq!(header.len(), offset);
+                    assert_eq!(usize::MAX, value.payload_len())
+                }
+                Err(Error::PayloadTooLarge { actual: u64::MAX, .. }) if usize::BITS < 64 => {}
+                other => panic!("unexpected result: {:?}", other)
+            }
+
+            // With a limit, the length is rejected once it has been read.
+            let limited = Codec::new();
+            for i in 0 ..= header.len() {
+                match limited.decode_header(&header[.. i]) {
+                    Ok(Parsing::NeedMore(_)) if i < 10 => {}
+                    Err(Error::PayloadTooLarge { actual: u64::MAX, .. }) if i >= 10 => {}
+                    other => panic!("{}: unexpected result: {:?}", i, other)
+                }
+            }
+
+            if usize::BITS < 64 {
+                continue
+            }
+            let mut decoder = FrameDecoder::new(unlimited);
+            for b in header.iter().chain(&[0; 16]) {
+                decoder.push_bytes(&[*b]);
+                assert!(decoder.next_frame().unwrap().is_none())
+            }
+            assert_eq!(header.len() + 16, decoder.buffered())
+        }
+    }
+
+    #[test]
+    fn extended_length_zero() {
+        // Non-canonical but valid: extended lengths of 0, masked.
+        let two = vec![0x82, 0x80 | TWO_EXT, 0, 0, 1, 2, 3, 4];
+        let mut eight = vec![0x82, 0x80 | EIGHT_EXT];
+        eight.extend_from_slice(&[0; 8]);
+        eight.extend_from_slice(&[1, 2, 3, 4]);
+        for bytes in &[two, eight] {
+            match Codec::new().decode_header(bytes) {
+                Ok(Parsing::Done { value, offset }) => {
+                    assert_eq!(bytes.len(), offset);
+                    assert_eq!(OpCode::Binary, value.opcode());
+                    assert_eq!(0, value.payload_len());
+                    assert!(value.is_masked());
+                    assert_eq!(0x01020304, value.mask())
+                }
+                other => panic!("unexpected result: {:?}", other)
+            }
+
+            // No payload bytes are consumed, the next frame follows.
+            let mut decoder = FrameDecoder::new(Codec::new());
+            decoder.push_bytes(bytes);
+            decoder.push_bytes(&[0x81, 0x82, 0, 0, 0, 0, b'h', b'i']);
+            let empty = decoder.next_frame().unwrap().unwrap();
+            assert_eq!((OpCode::Binary, 0), (empty.header().opcode(), empty.payload().len()));
+            let text = decoder.next_frame().unwrap().unwrap();
+            assert_eq!((OpCode::Text, &b"hi"[..]), (text.header().opcode(), text.payload()));
+            assert_eq!(0, decoder.buffered())
+        }
+    }
+
+    #[test]
+    fn payload_len_boundaries() {
+        // (length, length byte, header size)
+        let cases = [
+            (125, 125, 2),
+            (126, TWO_EXT, 4),
+            (65535, TWO_EXT, 4),
+            (65536, EIGHT_EXT, 10)
+        ];
+        let mut codec = Codec::new();
+        for &(len, len_byte, header_size) in &cases {
+            let mut frame = Frame::new(OpCode::Binary);
+            frame.set_binary(&vec![7; len]);
+            let bytes = codec.encode_header(frame.header()).to_vec();
+            assert_eq!(header_size, bytes.len(), "{}", len);
+            assert_eq!(len_byte, bytes[1], "{}", len);
+            match header_size {
+                4 => assert_eq!(len as u64, u64::from(u16::from_be_bytes([bytes[2], bytes[3]]))),
+                10 => assert_eq!(len as u64, u64::from_be_bytes([
+                    bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7], bytes[8], bytes[9]
+                ])),
+                _ => {}
+            }
+
+            let mut decoder = FrameDecoder::new(Codec::new());
+            decoder.push_bytes(&bytes);
+            decoder.push_bytes(frame.payload());
+            let decoded = decoder.next_frame().unwrap().unwrap();
+            assert_eq!(len, decoded.header().payload_len());
+            assert_eq!(frame, decoded);
+            assert_eq!(0, decoder.buffered())
+        }
+    }
+
+    #[test]
+    fn payload_len_beyond_4gib() {
+        let len: u64 = 5 * 1024 * 1024 * 1024;
+        let mut bytes = vec![0x82, 0x7F];
+        bytes.extend_from_slice(&len.to_be_bytes());
+
+        let mut codec = Codec::new();
+        codec.set_max_data_size(usize::MAX);
+
+        #[cfg(target_pointer_width = "64")]
+        {
+            let header = match codec.decode_header(&bytes) {
+                Ok(Parsing::Done { value, offset: 10 }) => value,
+                other => panic!("unexpected: {:?}", other)
+            };
+            assert_eq!(len, header.payload_len() as u64);
+            assert_eq!(&bytes[..], codec.encode_header(&header))
+        }
+
+        #[cfg(not(target_pointer_width = "64"))]
+        assert!(matches! {
+            codec.decode_header(&bytes),
+            Err(Error::PayloadTooLarge { actual, .. }) if actual == len
+        })
+    }
+
+    #[test]
+    fn frame_set_text() {
+        let mut frame = Frame::new(OpCode::Binary);
+        frame.header_mut().set_fin(false);
+        frame.set_text("größer");
+        assert_eq!(OpCode::Text, frame.header().opcode());
+        assert!(frame.header().is_fin());
+        assert_eq!(frame.payload().len(), frame.header().payload_len());
+        assert_eq!(Ok("größer"), std::str::from_utf8(frame.payload()))
+    }
+
+    #[test]
+    fn frame_decoder() {
+        fn property(frames: Vec<(bool, Vec<u8>, u32)>, chunks: Vec<usize>) -> bool {
+            let mut codec = Codec::new();
+            let mut expected = Vec::new();
+            let mut bytes = Vec::new();
+            for (fin, payload, mask) in frames {
+                let mut frame = Frame::new(OpCode::Binary);
+                frame.set_binary(&payload).header_mut().set_fin(fin);
+                if mask % 2 == 0 {
+                    frame.header_mut().set_masked(true).set_mask(mask);
+                }
+                bytes.extend_from_slice(codec.encode_header(frame.header()));
+                let start = bytes.len();
+                bytes.extend_from_slice(frame.payload());
+                Codec::apply_mask(frame.header(), &mut bytes[start ..]);
+                expected.push(frame)
+            }
+
+            let mut decoder = FrameDecoder::new(Codec::new());
+            let mut decoded = Vec::new();
+            let mut chunks = chunks.into_iter().map(|n| n % 64 + 1).cycle();
+            let mut offset = 0;
+            while offset < bytes.len() {
+                let n = std::cmp::min(chunks.next().unwrap_or(1), bytes.len() - offset);
+                decoder.push_bytes(&bytes[offset .. offset + n]);
+                offset += n;
+                while let Some(frame) = decoder.next_frame().unwrap() {
+                    decoded.push(frame)
+                }
+            }
+            decoder.buffered() == 0 && decoded.len() == expected.len()
+                && decoded.iter().zip(&expected).all(|(a, b)| a.wire_eq(b))
+        }
+        QuickCheck::new().quickcheck(property as fn(Vec<(bool, Vec<u8>, u32)>, Vec<usize>) -> bool)
+    }
+
+    #[test]
+    fn frame_mask_key() {
+        let mut frame = Frame::new(OpCode::Binary);
+        assert_eq!(None, frame.mask_key());
+        frame.header_mut().set_mask(0x1234_5678);
+        assert_eq!(None, frame.mask_key());
+        frame.header_mut().set_masked(true);
+        assert_eq!(Some(0x1234_5678), frame.mask_key());
+        frame.header_mut().set_mask(0);
+        assert_eq!(Some(0), frame.mask_key())
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_mask() {
+        fn property(data: Vec<u8>, mask: u32, chunk_size: usize) -> bool {
+            let mask = mask.to_be_bytes();
+            let mut expected = data.clone();
+            for (byte, &key) in expected.iter_mut().zip(mask.iter().cycle()) {
+                *byte ^= key
+            }
+            // Every chunk size covers every alignment of the mask at chunk boundaries.
+            let mut actual = data;
+            super::apply_mask_parallel(mask, &mut actual, chunk_size % 37 + 1);
+            actual == expected
+        }
+        QuickCheck::new().quickcheck(property as fn(Vec<u8>, u32, usize) -> bool);
+
+        // Above the threshold, `apply_mask` takes the parallel path.
+        let mut header = Header::new(OpCode::Binary);
+        header.set_masked(true);
+        header.set_mask(0x0102_0304);
+        let mut data = vec![0; super::PARALLEL_MASK_THRESHOLD + 3];
+        Codec::apply_mask(&header, &mut data);
+        assert!(data.chunks(4).all(|c| c == &[1, 2, 3, 4][.. c.len()]))
+    }
+
+    #[test]
+    fn encode_control_frame() {
+        let mut codec = Codec::new();
+        let mut out = [0; MAX_CONTROL_FRAME_SIZE];
+
+        let ping = Header::new(OpCode::Ping);
+        assert_eq!(Ok(4), codec.encode_control_frame(&ping, b"hi", &mut out).map_err(|e| e.to_string()));
+        assert_eq!([0x89, 0x02, b'h', b'i'], out[.. 4]);
+
+        let mut ping = Header::new(OpCode::Ping);
+        ping.set_masked(true);
+        ping.set_mask(0x0102_0304);
+        ping.set_payload_len(1000); // ignored
+        assert_eq!(Ok(8), codec.encode_control_frame(&ping, b"hi", &mut out).map_err(|e| e.to_string()));
+        assert_eq!([0x89, 0x82, 1, 2, 3, 4, b'h' ^ 1, b'i' ^ 2], out[.. 8]);
+
+        // A maximal frame fills the buffer.
+        let n = codec.encode_control_frame(&ping, &[0; 125], &mut out).unwrap();
+        assert_eq!(MAX_CONTROL_FRAME_SIZE, n);
+        assert!(matches!(codec.encode_control_frame(&ping, &[0; 126], &mut out), Err(Error::InvalidControlFrameLen)))
+    }
+
+    #[test]
+    fn empty_frame() {
+        let close = Frame::empty(OpCode::Close).unwrap();
+        assert!(close.header().is_fin());
+        assert!(!close.header().is_masked());
+        assert_eq!(0, close.header().payload_len());
+        assert!(close.payload().is_empty());
+        assert_eq!(&[0x88, 0x00], Codec::new().encode_header(close.header()));
+
+        for oc in &[OpCode::Continue, OpCode::Text, OpCode::Binary, OpCode::Ping, OpCode::Pong] {
+            assert!(Frame::empty(*oc).unwrap().header().is_fin())
+        }
+        assert!(matches!(Frame::empty(OpCode::ReservedControl(0xB)), Err(Error::ReservedOpCode)));
+        assert!(matches!(Frame::empty(OpCode::ReservedData(3)), Err(Error::ReservedOpCode)))
+    }
+
+    #[test]
+    fn frame_equality() {
+        use std::collections::HashSet;
+
+        let mut a = Frame::new(OpCode::Binary);
+        a.set_binary(b"hello").header_mut().set_masked(true).set_mask(0x1234_5678);
+        let mut b = a.clone();
+        b.header_mut().set_mask(0x8765_4321);
+
+        assert_eq!(a, b);
+        assert!(a.wire_eq(&a.clone()));
+        assert!(!a.wire_eq(&b));
+        assert_eq!(1, [a.clone(), b.clone()].iter().collect::<HashSet<_>>().len());
+
+        b.header_mut().set_masked(false);
+        assert_ne!(a, b);
+        let mut c = a.clone();
+        c.set_text("hello");
+        assert_ne!(a, c);
+        c.set_binary(b"hellO");
+        assert_ne!(a, c)
+    }
+
+    #[test]
+    fn codec_with_config() {
+        let mut config = CodecConfig::new();
+        config.set_max_data_size(1).add_reserved_bits((true, false, false));
+
+        let codec = Codec::with_config(config);
+        assert_eq!(&config, codec.config());
+        assert_eq!((true, false, false), codec.reserved_bits());
+        assert!(matches!(codec.decode_header(&[0xC2, 0x01]), Ok(Parsing::Done {..})));
+        assert!(matches!(codec.decode_header(&[0xC2, 0x02]), Err(Error::PayloadTooLarge {..})))
+    }
+
+    #[test]
+    fn frame_try_from_bytes() {
+        const PING_NO_DATA: &[u8] = &[0x89, 0x80, 0, 0, 0, 0];
+        let frame = Frame::try_from(PING_NO_DATA).unwrap();
+        assert_eq!(OpCode::Ping, frame.header().opcode());
+        assert!(frame.header().is_fin());
+        assert_eq!(Some(0), frame.mask_key());
+        assert!(frame.payload().is_empty());
+
+        let text = Frame::try_from(&[0x81, 0x82, 1, 2, 3, 4, b'h' ^ 1, b'i' ^ 2][..]).unwrap();
+        assert_eq!(b"hi", text.payload());
+
+        assert!(matches!(Frame::try_from(&PING_NO_DATA[.. 4]), Err(Error::Incomplete { needed: 2 })));
+        assert!(matches!(Frame::try_from(&[0x81, 0x02, b'h'][..]), Err(Error::Incomplete { needed: 1 })));
+        assert!(matches!(Frame::try_from(&[][..]), Err(Error::Incomplete { needed: 2 })));
+        assert!(matches!(Frame::try_from(&[0x89, 0x00, 0x8A][..]), Err(Error::TrailingBytes { len: 1 })));
+        assert!(matches!(Frame::try_from(&[0x83, 0x00][..]).map_err(|e| e.to_string()), Err(e) if e == "reserved opcode"))
+    }
+}
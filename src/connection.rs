@@ -10,10 +10,13 @@
 //! as a [`Sender`] and [`Receiver`] pair.
 
 use bytes::{Buf, BytesMut};
-use crate::{Storage, Parsing, base::{self, Header, MAX_HEADER_SIZE, OpCode}, extension::Extension};
-use crate::data::{ByteSlice125, Data, Incoming};
-use futures::{io::{ReadHalf, WriteHalf}, lock::BiLock, prelude::*};
-use std::{fmt, io, str};
+use crate::{Storage, Parsing, base::{self, CloseCode, Frame, Header, MAX_HEADER_SIZE, OpCode}, extension::{DecodeState, Decoded, Extension}};
+use crate::data::{ByteSlice125, ControlPayload, Data, Incoming, InvalidControlPayload, Outgoing};
+use crate::{handshake, rng::Rng, split::{ReadHalf, WriteHalf}, tap::{self, Tapped}};
+use crate::utf8::{self, Validation, Validator};
+use futures::{channel::{mpsc, oneshot}, future::{self, BoxFuture}, lock::BiLock, prelude::*, stream};
+use std::{collections::VecDeque, fmt, io, mem, ops::{Deref, DerefMut}, str, task::{Context, Poll, Waker}, time::{Duration, Instant}};
+use std::sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}};
 
 /// Accumulated max. size of a complete message.
 const MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
@@ -21,6 +24,26 @@ const MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
 /// Max. size of a single message frame.
 const MAX_FRAME_SIZE: usize = MAX_MESSAGE_SIZE;
 
+/// Min. number of bytes to read from the socket when decoding a header.
+///
+/// Reading more than the header size lets frames pipelined by the remote
+/// be decoded from the read buffer without further socket reads.
+const READ_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Default max. number of PONGs pending for consecutive PINGs.
+const MAX_PENDING_CONTROL: usize = 16;
+
+/// Default time to wait for the remote's CLOSE after sending ours.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Max. number of payload bytes masked and written before yielding.
+///
+/// A multiple of 4, so that every slice starts at the beginning of the mask.
+const WRITE_SLICE_SIZE: usize = 1024 * 1024;
+
+/// Max. number of bytes of a message returned by [`Receiver::receive_part`].
+const PART_SIZE: usize = 64 * 1024;
+
 /// Is the connection used by a client or server?
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Mode {
@@ -32,11 +55,7 @@ pub enum Mode {
 
 impl Mode {
     pub fn is_client(self) -> bool {
-        if let Mode::Client = self {
-            true
-        } else {
-            false
-        }
+        matches!(self, Mode::Client)
     }
 
     pub fn is_server(self) -> bool {
@@ -44,588 +63,7739 @@ impl Mode {
     }
 }
 
-/// Connection ID.
-#[derive(Clone, Copy, Debug)]
-struct Id(u32);
-
-impl fmt::Display for Id {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:08x}", self.0)
-    }
+/// How a CLOSE frame received from the remote is answered.
+///
+/// A CLOSE with an invalid status code is always answered with
+/// [`CloseCode::PROTOCOL_ERROR`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum CloseEcho {
+    /// Answer with the remote's status code, or with [`CloseCode::NORMAL`]
+    /// if the remote did not send one.
+    #[default]
+    Echo,
+    /// Answer with the given status code.
+    Code(CloseCode),
+    /// Answer without a status code.
+    Empty
 }
 
-/// The sending half of a connection.
-#[derive(Debug)]
-pub struct Sender<T> {
-    id: Id,
-    mode: Mode,
-    codec: base::Codec,
-    writer: BiLock<WriteHalf<T>>,
-    mask_buffer: Vec<u8>,
-    extensions: BiLock<Vec<Box<dyn Extension + Send>>>,
-    has_extensions: bool
+/// What happens when a connection is dropped without having been closed.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Drop the socket without sending a CLOSE frame.
+    #[default]
+    Abort,
+    /// Try to send a CLOSE frame with the given status code.
+    ///
+    /// Dropping must not block, so the frame is only sent if the socket
+    /// accepts all of it immediately, otherwise the socket is dropped
+    /// without further ado. The frame is pre-encoded when the connection
+    /// is created and bypasses extensions and the frame interceptor.
+    /// Nothing is sent if a CLOSE frame has been sent already.
+    ///
+    /// **Note**: A socket which accepts only part of the frame leaves the
+    /// remote with an incomplete frame. The connection is gone either way.
+    BestEffortClose(CloseCode)
 }
 
-/// The receiving half of a connection.
-#[derive(Debug)]
-pub struct Receiver<T> {
-    id: Id,
-    mode: Mode,
-    codec: base::Codec,
-    reader: ReadHalf<T>,
-    writer: BiLock<WriteHalf<T>>,
-    extensions: BiLock<Vec<Box<dyn Extension + Send>>>,
-    has_extensions: bool,
-    buffer: BytesMut,
-    ctrl_buffer: BytesMut,
-    max_message_size: usize,
-    is_closed: bool
+/// Which frame, if any, [`Sender::heartbeat`] sends to keep a connection
+/// alive.
+///
+/// A heartbeat is sent if nothing has been sent for the given duration.
+/// Both kinds carry the payload set with [`Builder::set_keep_alive_payload`],
+/// which is empty by default.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum KeepAlive {
+    /// Send no heartbeats.
+    #[default]
+    Off,
+    /// Send a PING, which the remote answers with a PONG.
+    Ping(Duration),
+    /// Send an unsolicited PONG, which the remote does not answer
+    /// (cf. [RFC 6455, section 5.5.3](https://tools.ietf.org/html/rfc6455#section-5.5.3)).
+    Pong(Duration)
 }
 
-/// A connection builder.
+/// A limit on the rate of data received, see
+/// [`Builder::set_inbound_rate_limit`].
 ///
-/// Allows configuring certain parameters and extensions before
-/// creating the [`Sender`]/[`Receiver`] pair that represents the
-/// connection.
-#[derive(Debug)]
-pub struct Builder<T> {
-    id: Id,
-    mode: Mode,
-    socket: T,
-    codec: base::Codec,
-    extensions: Vec<Box<dyn Extension + Send>>,
-    buffer: BytesMut,
-    max_message_size: usize
+/// Messages and payload bytes are limited separately with token buckets,
+/// which allow bursts of up to one second's worth by default.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    messages_per_sec: Option<u64>,
+    bytes_per_sec: Option<u64>,
+    burst: Duration
 }
 
-impl<T: AsyncRead + AsyncWrite + Unpin> Builder<T> {
-    /// Create a new `Builder` from the given async I/O resource and mode.
-    ///
-    /// **Note**: Use this type only after a successful [handshake][0].
-    /// You can either use this crate's [handshake functionality][1]
-    /// or perform the handshake by some other means.
-    ///
-    /// [0]: https://tools.ietf.org/html/rfc6455#section-4
-    /// [1]: crate::handshake
-    pub fn new(socket: T, mode: Mode) -> Self {
-        let mut codec = base::Codec::default();
-        codec.set_max_data_size(MAX_FRAME_SIZE);
-        Builder {
-            id: Id(rand::random()),
-            mode,
-            socket,
-            codec,
-            extensions: Vec::new(),
-            buffer: BytesMut::new(),
-            max_message_size: MAX_MESSAGE_SIZE
-        }
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit::new()
     }
+}
 
-    /// Set a custom buffer to use.
-    pub fn set_buffer(&mut self, b: BytesMut) {
-        self.buffer = b
+impl RateLimit {
+    /// Create a limit which allows everything until set otherwise.
+    pub fn new() -> Self {
+        RateLimit { messages_per_sec: None, bytes_per_sec: None, burst: Duration::from_secs(1) }
     }
 
-    /// Add extensions to use with this connection.
+    /// Set the max. number of messages per second.
     ///
-    /// Only enabled extensions will be considered.
-    pub fn add_extensions<I>(&mut self, extensions: I)
-    where
-        I: IntoIterator<Item = Box<dyn Extension + Send>>
-    {
-        for e in extensions.into_iter().filter(|e| e.is_enabled()) {
-            log::debug!("{}: using extension: {}", self.id, e.name());
-            self.codec.add_reserved_bits(e.reserved_bits());
-            self.extensions.push(e)
-        }
+    /// The minimum is 1.
+    pub fn set_messages_per_sec(&mut self, max: u64) -> &mut Self {
+        self.messages_per_sec = Some(std::cmp::max(1, max));
+        self
     }
 
-    /// Set the maximum size of a complete message.
+    /// Set the max. number of payload bytes per second.
     ///
-    /// Message fragments will be buffered and concatenated up to this value,
-    /// i.e. the sum of all message frames payload lengths will not be greater
-    /// than this maximum. However, extensions may increase the total message
-    /// size further, e.g. by decompressing the payload data.
-    pub fn set_max_message_size(&mut self, max: usize) {
-        self.max_message_size = max
+    /// The minimum is 1.
+    pub fn set_bytes_per_sec(&mut self, max: u64) -> &mut Self {
+        self.bytes_per_sec = Some(std::cmp::max(1, max));
+        self
     }
 
-    /// Set the maximum size of a single websocket frame payload.
-    pub fn set_max_frame_size(&mut self, max: usize) {
-        self.codec.set_max_data_size(max);
+    /// Set for how long the rates may be exceeded at once, e.g. after an
+    /// idle period.
+    ///
+    /// At least one message or byte may be received at once. Default is one
+    /// second.
+    pub fn set_burst(&mut self, burst: Duration) -> &mut Self {
+        self.burst = burst;
+        self
     }
+}
 
-    /// Create a configured [`Sender`]/[`Receiver`] pair.
-    pub fn finish(self) -> (Sender<T>, Receiver<T>) {
-        let (rhlf, whlf) = self.socket.split();
-        let (wrt1, wrt2) = BiLock::new(whlf);
-        let has_extensions = !self.extensions.is_empty();
-        let (ext1, ext2) = BiLock::new(self.extensions);
+/// What happens when the rate of data received exceeds a [`RateLimit`].
+pub enum RateLimitPolicy {
+    /// Stop reading until the rate is within the limit again.
+    ///
+    /// This crate does not depend on a timer, so the function is called
+    /// with the time to wait to create one, e.g.
+    /// `|d| Box::pin(tokio::time::sleep(d))`. PINGs which have been received
+    /// completely already are answered before waiting.
+    Throttle(Box<dyn Fn(Duration) -> BoxFuture<'static, ()> + Send>),
+    /// Close the connection with [`CloseCode::POLICY_VIOLATION`] and fail
+    /// with [`Error::RateLimited`].
+    Fail
+}
 
-        let recv = Receiver {
-            id: self.id,
-            mode: self.mode,
-            reader: rhlf,
-            writer: wrt1,
-            codec: self.codec.clone(),
-            extensions: ext1,
-            has_extensions,
-            buffer: self.buffer,
-            ctrl_buffer: BytesMut::new(),
-            max_message_size: self.max_message_size,
-            is_closed: false
-        };
+impl fmt::Debug for RateLimitPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RateLimitPolicy::Throttle(_) => f.write_str("Throttle"),
+            RateLimitPolicy::Fail => f.write_str("Fail")
+        }
+    }
+}
 
-        let send = Sender {
-            id: self.id,
-            mode: self.mode,
-            writer: wrt2,
-            mask_buffer: Vec::new(),
-            codec: self.codec,
-            extensions: ext2,
-            has_extensions
-        };
+/// Deviations from [RFC 6455][rfc6455] a connection makes or tolerates,
+/// see [`Builder::set_deviations`].
+///
+/// **Warning**: Each of them violates RFC 6455. They are meant for links
+/// between endpoints under your control or for interoperating with peers
+/// which violate it themselves. By default, the only deviation is that
+/// data received after the remote's CLOSE frame is ignored.
+///
+/// [rfc6455]: https://tools.ietf.org/html/rfc6455
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Deviations {
+    unmasked: bool,
+    accept_unmasked: bool,
+    accept_masked: bool,
+    ignore_data_after_close: bool,
+    accept_single_byte_close: bool
+}
 
-        (send, recv)
+impl Default for Deviations {
+    fn default() -> Self {
+        Deviations::new()
     }
 }
 
-impl<T: AsyncRead + AsyncWrite + Unpin> Receiver<T> {
-    /// Receive the next websocket message.
+impl Deviations {
+    /// Create the default deviations, i.e. only data received after the
+    /// remote's CLOSE frame is ignored.
+    pub fn new() -> Self {
+        Deviations {
+            unmasked: false,
+            accept_unmasked: false,
+            accept_masked: false,
+            ignore_data_after_close: true,
+            accept_single_byte_close: false
+        }
+    }
+
+    /// Send and expect unmasked frames.
     ///
-    /// The received frames forming the complete message will be appended to
-    /// the given `message` argument. The returned [`Incoming`] value describes
-    /// the type of data that was received, e.g. binary or textual data.
+    /// Masking protects intermediaries (e.g. HTTP proxies) from being
+    /// confused by payload data which looks like HTTP. Only consider
+    /// disabling it for trusted links between two endpoints under your
+    /// control which both use this crate, e.g. server-to-server connections
+    /// within a private network.
     ///
-    /// Interleaved PONG frames are returned immediately as `Data::Pong`
-    /// values. If PONGs are not expected or uninteresting,
-    /// [`Receiver::receive_data`] may be used instead which skips over PONGs
-    /// and considers only application payload data.
-    pub async fn receive(&mut self, message: &mut Vec<u8>) -> Result<Incoming<'_>, Error> {
-        let mut first_fragment_opcode = None;
-        let mut length: usize = 0;
-        let message_len = message.len();
-        loop {
-            if self.is_closed {
-                log::debug!("{}: can not receive, connection is closed", self.id);
-                return Err(Error::Closed)
-            }
+    /// Clients send unmasked frames and servers expect them. Both ends have
+    /// to agree, which the handshake negotiates (see
+    /// [`handshake::Client::dangerous_disable_masking`] and
+    /// [`handshake::Server::dangerous_accept_unmasked_frames`]), whose
+    /// `into_builder` sets this accordingly. A server which receives a
+    /// masked frame nevertheless fails with [`Error::MaskingOptOutMismatch`]
+    /// (cf. [RFC 6455, section 5.3](https://tools.ietf.org/html/rfc6455#section-5.3)).
+    /// Default is `false`.
+    pub fn set_unmasked(&mut self, unmasked: bool) -> &mut Self {
+        self.unmasked = unmasked;
+        self
+    }
 
-            self.ctrl_buffer.clear();
-            let mut header = self.receive_header().await?;
-            log::trace!("{}: recv: {}", self.id, header);
+    /// Accept unmasked frames from clients.
+    ///
+    /// RFC 6455 requires a server to fail the connection upon receiving an
+    /// unmasked frame. Some clients nevertheless mask only some of their
+    /// frames. If accepted, a server takes such frames instead of failing
+    /// with [`Error::UnmaskedFrame`]. Each occurrence is logged and counted
+    /// (see [`Receiver::unmasked_frames`]).
+    ///
+    /// Has no effect for clients or if masking is disabled anyway (see
+    /// [`Deviations::set_unmasked`]). Default is `false`.
+    pub fn set_accept_unmasked_frames(&mut self, accept: bool) -> &mut Self {
+        self.accept_unmasked = accept;
+        self
+    }
 
-            // Handle control frames.
-            if header.opcode().is_control() {
-                self.read_buffer(&header).await?;
-                self.ctrl_buffer = self.buffer.split_to(header.payload_len());
-                base::Codec::apply_mask(&header, &mut self.ctrl_buffer);
-                if header.opcode() == OpCode::Pong {
-                    return Ok(Incoming::Pong(&self.ctrl_buffer[..]))
-                }
-                self.on_control(&header).await?;
-                continue
-            }
+    /// Accept and unmask masked frames where unmasked ones are expected.
+    ///
+    /// RFC 6455 requires a client to fail the connection upon receiving a
+    /// masked frame. As the mask key is part of the frame, the payload can
+    /// still be recovered, which may help a proxy or peer interoperating
+    /// with a misbehaving server. If accepted, such frames are unmasked
+    /// instead of failing with [`Error::MaskedFrame`], or with
+    /// [`Error::MaskingOptOutMismatch`] for servers which expect unmasked
+    /// frames. Each occurrence is logged. Default is `false`.
+    pub fn set_accept_masked_frames(&mut self, accept: bool) -> &mut Self {
+        self.accept_masked = accept;
+        self
+    }
 
-            length = length.saturating_add(header.payload_len());
+    /// Ignore data received after the remote's CLOSE frame.
+    ///
+    /// A remote must not send anything after its CLOSE frame. If ignored,
+    /// bytes which have been received along with the CLOSE frame are
+    /// discarded and the frames among them counted (see
+    /// [`Receiver::frames_after_close`]). Otherwise the CLOSE frame is
+    /// answered with [`CloseCode::PROTOCOL_ERROR`] and [`Receiver::receive`]
+    /// returns [`Error::DataAfterClose`]. Duplicate CLOSE frames are always
+    /// ignored. Default is `true`.
+    pub fn set_ignore_data_after_close(&mut self, ignore: bool) -> &mut Self {
+        self.ignore_data_after_close = ignore;
+        self
+    }
 
-            // Check if total message does not exceed maximum.
-            if length > self.max_message_size {
-                log::warn!("{}: accumulated message length exceeds maximum", self.id);
-                return Err(Error::MessageTooLarge { current: length, maximum: self.max_message_size })
-            }
+    /// Accept CLOSE frames with a payload of a single byte.
+    ///
+    /// A CLOSE payload is either empty or starts with a two-byte status
+    /// code, so a single byte is a protocol error, which is answered with
+    /// [`CloseCode::PROTOCOL_ERROR`] and makes [`Receiver::receive`] return
+    /// [`Error::InvalidClosePayload`]. Some legacy peers send it
+    /// nevertheless. If accepted, the byte is ignored and the CLOSE frame is
+    /// treated like one without payload, i.e. with status code
+    /// [`CloseCode::NO_STATUS_RECEIVED`]. Default is `false`.
+    pub fn set_accept_single_byte_close(&mut self, accept: bool) -> &mut Self {
+        self.accept_single_byte_close = accept;
+        self
+    }
+}
 
-            // Get the frame's payload data bytes from buffer or socket.
-            {
-                let old_msg_len = message.len();
+/// The ID of the next connection.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
 
-                let bytes_to_read = {
-                    let required = header.payload_len();
-                    let buffered = self.buffer.len();
+/// Connection ID.
+///
+/// Unique within the process unless set explicitly with [`Builder::set_id`].
+#[derive(Clone, Copy, Debug)]
+struct Id(u64);
 
-                    if buffered == 0 {
-                        required
-                    } else if required > buffered {
-                        message.extend_from_slice(&self.buffer);
-                        self.buffer.clear();
-                        required - buffered
-                    } else {
-                        message.extend_from_slice(&self.buffer.split_to(required));
-                        0
-                    }
-                };
+impl Id {
+    fn next() -> Self {
+        Id(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
 
-                if bytes_to_read > 0 {
-                    let n = message.len();
-                    message.resize(n + bytes_to_read, 0u8);
-                    self.reader.read_exact(&mut message[n ..]).await?
-                }
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ws-conn-{}", self.0)
+    }
+}
 
-                debug_assert_eq!(header.payload_len(), message.len() - old_msg_len);
+/// A function applied to every outgoing frame.
+///
+/// See [`Builder::set_frame_interceptor`] for details.
+struct Interceptor(Box<dyn FnMut(&mut Frame) + Send>);
 
-                base::Codec::apply_mask(&header, &mut message[old_msg_len ..]);
-            }
+impl fmt::Debug for Interceptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Interceptor")
+    }
+}
 
-            match (header.is_fin(), header.opcode()) {
-                (false, OpCode::Continue) => { // Intermediate message fragment.
-                    if first_fragment_opcode.is_none() {
-                        log::debug!("{}: continue frame while not processing message fragments", self.id);
-                        return Err(Error::UnexpectedOpCode(OpCode::Continue))
-                    }
-                    continue
-                }
-                (false, oc) => { // Initial message fragment.
-                    if first_fragment_opcode.is_some() {
-                        log::debug!("{}: initial fragment while processing a fragmented message", self.id);
-                        return Err(Error::UnexpectedOpCode(oc))
-                    }
-                    first_fragment_opcode = Some(oc);
-                    self.decode_with_extensions(&mut header, message).await?;
-                    continue
-                }
-                (true, OpCode::Continue) => { // Last message fragment.
-                    if let Some(oc) = first_fragment_opcode.take() {
-                        header.set_payload_len(message.len());
-                        log::trace!("{}: last fragment: total length = {} bytes", self.id, message.len());
-                        self.decode_with_extensions(&mut header, message).await?;
-                        header.set_opcode(oc);
-                    } else {
-                        log::debug!("{}: last continue frame while not processing message fragments", self.id);
-                        return Err(Error::UnexpectedOpCode(OpCode::Continue))
-                    }
-                }
-                (true, oc) => { // Regular non-fragmented message.
-                    if first_fragment_opcode.is_some() {
-                        log::debug!("{}: regular message while processing fragmented message", self.id);
-                        return Err(Error::UnexpectedOpCode(oc))
-                    }
-                    self.decode_with_extensions(&mut header, message).await?
-                }
-            }
+/// A function informed about the number of bytes written of a frame.
+///
+/// See [`Builder::set_write_progress`] for details.
+struct Progress(Box<dyn FnMut(usize, usize) + Send>);
 
-            let num_bytes = message.len() - message_len;
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Progress")
+    }
+}
 
-            if header.opcode() == OpCode::Text {
-                return Ok(Incoming::Data(Data::Text(num_bytes)))
-            } else {
-                return Ok(Incoming::Data(Data::Binary(num_bytes)))
-            }
-        }
+/// A function mapping the causes of closing a connection to the code and
+/// reason sent.
+///
+/// See [`Builder::set_close_messages`] for details.
+struct CloseMessages(Box<dyn Fn(CloseCause) -> (CloseCode, String) + Send>);
+
+impl fmt::Debug for CloseMessages {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("CloseMessages")
     }
+}
 
-    /// Receive the next websocket message, skipping over control frames.
-    pub async fn receive_data(&mut self, message: &mut Vec<u8>) -> Result<Data, Error> {
-        loop {
-            if let Incoming::Data(d) = self.receive(message).await? {
-                return Ok(d)
-            }
-        }
+/// A CLOSE frame to send when the connection is dropped.
+///
+/// See [`DropPolicy::BestEffortClose`] for details.
+struct DropClose<T> {
+    frame: Vec<u8>,
+    write: fn(&mut Tapped<WriteHalf<T>>, &[u8]) -> bool
+}
+
+impl<T> fmt::Debug for DropClose<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DropClose").field("frame", &self.frame).finish()
+    }
+}
+
+impl<T: AsyncWrite + Unpin> DropClose<T> {
+    fn new(rng: &mut Rng, masked: bool, code: CloseCode) -> Self {
+        let mask = if masked { Some(rng.gen()) } else { None };
+        let (frame, len) = close_frame(code, "", mask);
+        DropClose { frame: frame[.. len].to_vec(), write: write_now }
+    }
+}
+
+/// Encode a CLOSE frame with the given status code and reason.
+///
+/// Returns the frame bytes and their number, which depends on whether
+/// the frame is masked. Encoding such frames is trivial, so neither a
+/// [`Header`] nor a [`base::Codec`] is involved. Reasons longer than 123
+/// bytes are cut off.
+fn close_frame(code: CloseCode, reason: &str, mask: Option<u32>) -> ([u8; base::MAX_CONTROL_FRAME_SIZE], usize) {
+    let reason = &reason.as_bytes()[.. reason.len().min(123)];
+    let len = 2 + reason.len();
+    let mut frame = [0; base::MAX_CONTROL_FRAME_SIZE];
+    frame[0] = 0x88;
+    frame[1] = len as u8;
+    let mut offset = 2;
+    if let Some(mask) = mask {
+        frame[1] |= 0x80;
+        frame[2 .. 6].copy_from_slice(&mask.to_be_bytes());
+        offset = 6
+    }
+    frame[offset .. offset + 2].copy_from_slice(&u16::from(code).to_be_bytes());
+    frame[offset + 2 .. offset + len].copy_from_slice(reason);
+    if let Some(mask) = mask {
+        for (byte, key) in frame[offset .. offset + len].iter_mut().zip(mask.to_be_bytes().iter().cycle()) {
+            *byte ^= key
+        }
+    }
+    (frame, offset + len)
+}
+
+/// Write and flush all bytes without blocking.
+///
+/// Returns `false` if the socket is not ready to accept all bytes at once.
+fn write_now<T: AsyncWrite + Unpin>(socket: &mut Tapped<WriteHalf<T>>, bytes: &[u8]) -> bool {
+    let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+    match socket.write(bytes).poll_unpin(&mut cx) {
+        Poll::Ready(Ok(n)) if n == bytes.len() => {}
+        _ => return false
+    }
+    matches!(socket.flush().poll_unpin(&mut cx), Poll::Ready(Ok(())))
+}
+
+/// The write half of a connection, shared by [`Sender`] and [`Receiver`].
+#[derive(Debug)]
+struct Writer<T> {
+    /// Only `None` after [`Connection::into_inner`] took the socket.
+    socket: Option<Tapped<WriteHalf<T>>>,
+    interceptor: Option<Interceptor>,
+    progress: Option<Progress>,
+    traffic: Arc<Traffic>,
+    /// Is masking enabled (see [`Deviations::set_unmasked`])?
+    masking: bool,
+    /// Has a CLOSE frame been sent or the socket been closed for writing?
+    is_closed: bool,
+    /// The status code of the CLOSE frame we sent, if any.
+    close_code: Option<CloseCode>,
+    /// The reason of the remote's CLOSE frame, once received.
+    close_reason: Option<CloseReason>,
+    /// The CLOSE frame to send on drop (see [`Builder::set_drop_policy`]).
+    on_drop: Option<DropClose<T>>,
+    /// When was the last frame written?
+    last_write: Instant,
+    /// The source of mask keys.
+    rng: Rng,
+    /// The max. number of bytes to send (see [`Builder::set_send_quota`]).
+    send_quota: Option<u64>,
+    /// See [`Builder::set_close_messages`].
+    close_messages: Option<CloseMessages>,
+    /// PONGs which have not been sent yet.
+    pending_pongs: PendingPongs,
+    /// Called once a CLOSE frame has been sent or the socket been closed.
+    close_hooks: Arc<CloseHooks>
+}
+
+/// PONG payloads for PINGs which have not been answered yet.
+///
+/// The [`Receiver`] queues them and the [`Sender`] sends them ahead of the
+/// next data frame, so that PONGs do not wait for a fragmented message to
+/// be sent completely.
+type PendingPongs = Arc<Mutex<VecDeque<Vec<u8>>>>;
+
+/// A function to call once the connection is closed.
+type CloseHook = Box<dyn FnOnce() + Send>;
+
+/// Functions to call once the connection is closed.
+///
+/// `None` once they have been called. See [`SharedSender::on_close`].
+struct CloseHooks(Mutex<Option<Vec<CloseHook>>>);
+
+impl fmt::Debug for CloseHooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("CloseHooks")
+    }
+}
+
+impl CloseHooks {
+    fn new() -> Arc<Self> {
+        Arc::new(CloseHooks(Mutex::new(Some(Vec::new()))))
+    }
+
+    /// Call `f` once the connection is closed, or right away if it is.
+    fn add(&self, f: CloseHook) {
+        let mut hooks = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(hooks) = hooks.as_mut() {
+            hooks.push(f);
+            return
+        }
+        drop(hooks);
+        f()
+    }
+
+    /// Mark the connection as closed and call the functions added so far.
+    fn notify(&self) {
+        let hooks = self.0.lock().unwrap_or_else(|e| e.into_inner()).take();
+        for f in hooks.into_iter().flatten() {
+            f()
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).is_none()
+    }
+}
+
+/// Calls the [`CloseHooks`] when dropped, unless disarmed.
+struct NotifyOnDrop(Option<Arc<CloseHooks>>);
+
+impl Drop for NotifyOnDrop {
+    fn drop(&mut self) {
+        if let Some(hooks) = self.0.take() {
+            hooks.notify()
+        }
+    }
+}
+
+impl<T> Drop for Writer<T> {
+    fn drop(&mut self) {
+        self.close_hooks.notify();
+        if self.is_closed {
+            return
+        }
+        let socket = match &mut self.socket {
+            Some(socket) => socket,
+            None => return
+        };
+        if let Some(DropClose { frame, write }) = &self.on_drop {
+            if write(socket, frame) {
+                log::trace!("sent close frame on drop")
+            } else {
+                log::debug!("could not send close frame on drop")
+            }
+        }
+    }
+}
+
+impl<T> Writer<T> {
+    fn socket(&mut self) -> &mut Tapped<WriteHalf<T>> {
+        self.socket.as_mut().expect(SOCKET_TAKEN)
+    }
+
+    /// The error to report once the connection is closed.
+    fn closed(&self) -> Error {
+        let reason = self.close_reason.clone().unwrap_or_else(|| match self.close_code {
+            Some(code) => CloseReason { code, reason: None },
+            None => CloseReason::abnormal()
+        });
+        Error::Closed(reason)
+    }
+
+    /// The error to report for a failed write.
+    ///
+    /// Errors which mean that the remote went away are reported as
+    /// [`Error::Closed`], all others as [`Error::Io`].
+    fn write_error(&self, e: io::Error) -> Error {
+        if is_eof(&e) {
+            self.closed()
+        } else {
+            Error::Io(e)
+        }
+    }
+}
+
+const SOCKET_TAKEN: &str = "the socket is only taken from a writer about to be dropped";
+
+/// Does this error mean that the remote went away?
+fn is_eof(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::BrokenPipe | io::ErrorKind::ConnectionReset | io::ErrorKind::UnexpectedEof)
+}
+
+impl<T: AsyncWrite + Unpin> Writer<T> {
+    /// Close the socket for writing.
+    async fn close(&mut self) -> Result<(), Error> {
+        self.is_closed = true;
+        self.close_hooks.notify();
+        self.socket().close().await.map_err(|e| self.write_error(e))
+    }
+
+    /// Send a CLOSE frame with the given status code and reason as
+    /// pre-encoded bytes and close the socket for writing.
+    async fn close_fast(&mut self, masked: bool, code: CloseCode, reason: &str) -> Result<(), Error> {
+        let mask = if masked { Some(self.rng.gen()) } else { None };
+        let (frame, len) = close_frame(code, reason, mask);
+        self.is_closed = true;
+        self.close_hooks.notify();
+        self.close_code = Some(code);
+        self.traffic.outgoing.record_control(crate::as_u64(len));
+        self.last_write = Instant::now();
+        self.write_all(&frame[.. len], &mut 0, len).await?;
+        self.socket().flush().await.map_err(|e| self.write_error(e))?;
+        self.close().await
+    }
+
+    /// Check that sending a frame of `len` bytes stays within the quota.
+    ///
+    /// Otherwise the connection is closed (see [`Builder::set_send_quota`]).
+    async fn check_send_quota(&mut self, header: &Header, len: usize, masked: bool) -> Result<(), Error> {
+        if let Some(quota) = self.send_quota {
+            if header.opcode() != OpCode::Close && self.traffic.outgoing.total() + crate::as_u64(len) > quota {
+                log::debug!("send quota exceeded");
+                let (code, reason) = self.close_message(CloseCause::QuotaExceeded);
+                self.close_fast(masked, code, &reason).await?;
+                return Err(Error::QuotaExceeded)
+            }
+        }
+        Ok(())
+    }
+
+    /// The status code and reason to send when the connection closes itself
+    /// for the given cause (see [`Builder::set_close_messages`]).
+    ///
+    /// Codes which must not be sent are replaced with the default and
+    /// reasons are cut off at 123 bytes.
+    fn close_message(&self, cause: CloseCause) -> (CloseCode, String) {
+        let (mut code, mut reason) = match &self.close_messages {
+            Some(CloseMessages(f)) => f(cause),
+            None => return (cause.close_code(), String::new())
+        };
+        if !code.is_allowed_on_wire() {
+            log::debug!("close code {} must not be sent, using {}", code, cause.close_code());
+            code = cause.close_code()
+        }
+        if let Err(InvalidControlPayload::ReasonTooLong { truncate_at, .. }) = ControlPayload::close(code, &reason) {
+            reason.truncate(truncate_at)
+        }
+        (code, reason)
+    }
+
+    /// Mask the bytes in place and write them to the socket.
+    ///
+    /// Large payloads are masked in slices with other tasks getting a chance
+    /// to run in between (cf. [`WRITE_SLICE_SIZE`]).
+    async fn write_masked(&mut self, header: &Header, bytes: &mut [u8], written: &mut usize, total: usize) -> Result<(), Error> {
+        for (i, slice) in bytes.chunks_mut(WRITE_SLICE_SIZE).enumerate() {
+            if i > 0 {
+                yield_now().await
+            }
+            base::Codec::apply_mask(header, slice);
+            self.write_all(slice, written, total).await?
+        }
+        Ok(())
+    }
+
+    /// Write all bytes to the socket.
+    ///
+    /// If a [`Progress`] callback is set, it is invoked after every partial
+    /// write with `written` updated and the `total` number of frame bytes.
+    async fn write_all(&mut self, bytes: &[u8], written: &mut usize, total: usize) -> Result<(), Error> {
+        let f = match &mut self.progress {
+            Some(Progress(f)) => f,
+            None => return self.socket().write_all(bytes).await.map_err(|e| self.write_error(e))
+        };
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let socket = self.socket.as_mut().expect(SOCKET_TAKEN);
+            let n = match socket.write(&bytes[offset ..]).await {
+                Ok(0) => return Err(self.closed()),
+                Ok(n) => n,
+                Err(e) => return Err(self.write_error(e))
+            };
+            offset += n;
+            *written += n;
+            f(*written, total)
+        }
+        Ok(())
+    }
+}
+
+/// Callback invoked with the extension name and the time it took.
+type OnExceeded = Box<dyn FnMut(&str, Duration) + Send>;
+
+/// A time limit for extensions and the function informed about extensions
+/// exceeding it.
+///
+/// See [`Builder::set_extension_budget`] for details.
+struct Budget {
+    max: Duration,
+    on_exceeded: OnExceeded
+}
+
+impl fmt::Debug for Budget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Budget").field("max", &self.max).finish()
+    }
+}
+
+/// Counters of the work an extension has done on a connection.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtensionStats {
+    name: String,
+    decoded_frames: u64,
+    decoded_bytes: u64,
+    decode_time: Duration,
+    encoded_frames: u64,
+    encoded_bytes: u64,
+    encode_time: Duration
+}
+
+impl ExtensionStats {
+    /// The name of the extension.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The number of frames the extension has decoded.
+    pub fn decoded_frames(&self) -> u64 {
+        self.decoded_frames
+    }
+
+    /// The number of bytes passed to the extension for decoding.
+    ///
+    /// For fragmented messages this includes the data of previous
+    /// fragments, cf. [`Extension::decode`].
+    pub fn decoded_bytes(&self) -> u64 {
+        self.decoded_bytes
+    }
+
+    /// The total time the extension spent decoding.
+    pub fn decode_time(&self) -> Duration {
+        self.decode_time
+    }
+
+    /// The number of frames the extension has encoded.
+    pub fn encoded_frames(&self) -> u64 {
+        self.encoded_frames
+    }
+
+    /// The number of bytes passed to the extension for encoding.
+    pub fn encoded_bytes(&self) -> u64 {
+        self.encoded_bytes
+    }
+
+    /// The total time the extension spent encoding.
+    pub fn encode_time(&self) -> Duration {
+        self.encode_time
+    }
+}
+
+/// Byte and frame counters of a connection's traffic.
+///
+/// Payload bytes are the payload data of data frames. Everything else on the
+/// wire, i.e. frame headers including masking keys and control frames, is
+/// overhead. Statistics of several connections can be summed up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficStats {
+    payload_bytes_in: u64,
+    payload_bytes_out: u64,
+    overhead_bytes_in: u64,
+    overhead_bytes_out: u64,
+    control_frames_in: u64,
+    control_frames_out: u64
+}
+
+impl TrafficStats {
+    /// The number of payload bytes received.
+    pub fn payload_bytes_in(&self) -> u64 {
+        self.payload_bytes_in
+    }
+
+    /// The number of payload bytes sent.
+    pub fn payload_bytes_out(&self) -> u64 {
+        self.payload_bytes_out
+    }
+
+    /// The number of overhead bytes received.
+    pub fn overhead_bytes_in(&self) -> u64 {
+        self.overhead_bytes_in
+    }
+
+    /// The number of overhead bytes sent.
+    pub fn overhead_bytes_out(&self) -> u64 {
+        self.overhead_bytes_out
+    }
+
+    /// The number of control frames received.
+    pub fn control_frames_in(&self) -> u64 {
+        self.control_frames_in
+    }
+
+    /// The number of control frames sent.
+    pub fn control_frames_out(&self) -> u64 {
+        self.control_frames_out
+    }
+}
+
+impl std::ops::AddAssign for TrafficStats {
+    fn add_assign(&mut self, other: Self) {
+        self.payload_bytes_in += other.payload_bytes_in;
+        self.payload_bytes_out += other.payload_bytes_out;
+        self.overhead_bytes_in += other.overhead_bytes_in;
+        self.overhead_bytes_out += other.overhead_bytes_out;
+        self.control_frames_in += other.control_frames_in;
+        self.control_frames_out += other.control_frames_out
+    }
+}
+
+impl std::iter::Sum for TrafficStats {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(TrafficStats::default(), |mut total, s| {
+            total += s;
+            total
+        })
+    }
+}
+
+/// The traffic counters of a connection, shared by [`Sender`] and [`Receiver`].
+#[derive(Debug, Default)]
+struct Traffic {
+    incoming: Counters,
+    outgoing: Counters
+}
+
+/// The traffic counters of one direction.
+#[derive(Debug, Default)]
+struct Counters {
+    payload: AtomicU64,
+    overhead: AtomicU64,
+    control: AtomicU64
+}
+
+impl Counters {
+    /// Count a frame with the given header which is `header_len` bytes long.
+    fn record(&self, header: &Header, header_len: usize) {
+        let header_len = crate::as_u64(header_len);
+        let payload_len = crate::as_u64(header.payload_len());
+        if header.opcode().is_control() {
+            self.record_control(header_len + payload_len)
+        } else {
+            self.payload.fetch_add(payload_len, Ordering::Relaxed);
+            self.overhead.fetch_add(header_len, Ordering::Relaxed);
+        }
+    }
+
+    /// The total number of bytes counted.
+    fn total(&self) -> u64 {
+        self.payload.load(Ordering::Relaxed) + self.overhead.load(Ordering::Relaxed)
+    }
+
+    /// Count a control frame which is `len` bytes long.
+    fn record_control(&self, len: u64) {
+        self.control.fetch_add(1, Ordering::Relaxed);
+        self.overhead.fetch_add(len, Ordering::Relaxed);
+    }
+}
+
+impl Traffic {
+    fn stats(&self) -> TrafficStats {
+        TrafficStats {
+            payload_bytes_in: self.incoming.payload.load(Ordering::Relaxed),
+            payload_bytes_out: self.outgoing.payload.load(Ordering::Relaxed),
+            overhead_bytes_in: self.incoming.overhead.load(Ordering::Relaxed),
+            overhead_bytes_out: self.outgoing.overhead.load(Ordering::Relaxed),
+            control_frames_in: self.incoming.control.load(Ordering::Relaxed),
+            control_frames_out: self.outgoing.control.load(Ordering::Relaxed)
+        }
+    }
+}
+
+/// Connection settings which may be changed while the connection is open.
+///
+/// See [`Sender::update_config`] and [`Receiver::update_config`]. The
+/// initial values are those set with the corresponding [`Builder`] methods.
+#[derive(Debug, Clone)]
+pub struct RuntimeConfig {
+    max_message_size: usize,
+    max_frame_size: usize,
+    max_pending_control: usize,
+    keep_alive: KeepAlive,
+    keep_alive_payload: Vec<u8>,
+    close_timeout: Duration
+}
+
+impl RuntimeConfig {
+    /// The maximum size of a complete message.
+    pub fn max_message_size(&self) -> usize {
+        self.max_message_size
+    }
+
+    /// Set the maximum size of a complete message.
+    ///
+    /// See [`Builder::set_max_message_size`].
+    pub fn set_max_message_size(&mut self, max: usize) {
+        self.max_message_size = max
+    }
+
+    /// The maximum size of a single websocket frame payload.
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    /// Set the maximum size of a single websocket frame payload.
+    pub fn set_max_frame_size(&mut self, max: usize) {
+        self.max_frame_size = max
+    }
+
+    /// The max. number of PONGs pending for consecutive PINGs.
+    pub fn max_pending_control(&self) -> usize {
+        self.max_pending_control
+    }
+
+    /// Set the max. number of PONGs pending for consecutive PINGs.
+    ///
+    /// See [`Builder::set_max_pending_control`]. The minimum is 1.
+    pub fn set_max_pending_control(&mut self, max: usize) {
+        self.max_pending_control = std::cmp::max(1, max)
+    }
+
+    /// The heartbeat sent by [`Sender::heartbeat`].
+    pub fn keep_alive(&self) -> KeepAlive {
+        self.keep_alive
+    }
+
+    /// Set the heartbeat sent by [`Sender::heartbeat`].
+    pub fn set_keep_alive(&mut self, keep_alive: KeepAlive) {
+        self.keep_alive = keep_alive
+    }
+
+    /// The payload of the heartbeat sent by [`Sender::heartbeat`].
+    pub fn keep_alive_payload(&self) -> &[u8] {
+        &self.keep_alive_payload
+    }
+
+    /// Set the payload of the heartbeat sent by [`Sender::heartbeat`].
+    ///
+    /// See [`Builder::set_keep_alive_payload`].
+    pub fn set_keep_alive_payload(&mut self, payload: impl Into<Vec<u8>>) -> Result<(), InvalidControlPayload> {
+        let payload = payload.into();
+        crate::data::check_control_len(payload.len())?;
+        self.keep_alive_payload = payload;
+        Ok(())
+    }
+
+    /// How long [`Receiver::await_close`] waits for the remote's CLOSE.
+    pub fn close_timeout(&self) -> Duration {
+        self.close_timeout
+    }
+
+    /// Set how long [`Receiver::await_close`] waits for the remote's CLOSE.
+    pub fn set_close_timeout(&mut self, timeout: Duration) {
+        self.close_timeout = timeout
+    }
+}
+
+/// The [`RuntimeConfig`] of a connection, shared by [`Sender`] and [`Receiver`].
+///
+/// Each half keeps a copy of the settings it uses and refreshes it at the
+/// next frame boundary once the version has changed.
+#[derive(Debug)]
+struct SharedConfig {
+    version: AtomicU64,
+    config: Mutex<RuntimeConfig>
+}
+
+impl SharedConfig {
+    fn new(config: RuntimeConfig) -> Self {
+        SharedConfig { version: AtomicU64::new(0), config: Mutex::new(config) }
+    }
+
+    /// Apply `f` to the configuration and bump the version.
+    fn update(&self, f: impl FnOnce(&mut RuntimeConfig)) {
+        let mut config = self.config.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut config);
+        self.version.fetch_add(1, Ordering::Release);
+    }
+
+    /// Get the configuration if its version differs from `version`, which
+    /// is updated.
+    fn changed(&self, version: &mut u64) -> Option<RuntimeConfig> {
+        let current = self.version.load(Ordering::Acquire);
+        if current == *version {
+            return None
+        }
+        *version = current;
+        Some(self.config.lock().unwrap_or_else(|e| e.into_inner()).clone())
+    }
+}
+
+/// The extensions of a connection, shared by [`Sender`] and [`Receiver`].
+#[derive(Debug)]
+struct Extensions {
+    list: Vec<Box<dyn Extension + Send>>,
+    /// The statistics of each extension in `list`.
+    stats: Vec<ExtensionStats>,
+    budget: Option<Budget>
+}
+
+impl Extensions {
+    /// Apply all extensions to decode a frame.
+    fn decode(&mut self, id: Id, header: &mut Header, data: &mut Vec<u8>) -> Result<(), Error> {
+        for (e, stats) in self.list.iter_mut().zip(&mut self.stats) {
+            log::trace!("{}: decoding with extension: {}", id, e.name());
+            let len = data.len();
+            let start = Instant::now();
+            e.decode(header, data).map_err(Error::Extension)?;
+            let elapsed = start.elapsed();
+            stats.decoded_frames += 1;
+            stats.decoded_bytes += crate::as_u64(len);
+            stats.decode_time += elapsed;
+            if let Some(b) = &mut self.budget {
+                if elapsed > b.max {
+                    log::warn!("{}: extension {} took {:?} to decode {}", id, e.name(), elapsed, header);
+                    (b.on_exceeded)(e.name(), elapsed)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The extension to decode a message incrementally.
+    ///
+    /// This is the first extension using one of the reserved bits set in
+    /// the header of the initial frame.
+    fn decoder(&self, header: &Header) -> Option<usize> {
+        let rsv = (header.is_rsv1(), header.is_rsv2(), header.is_rsv3());
+        self.list.iter().position(|e| {
+            let bits = e.reserved_bits();
+            bits.0 && rsv.0 || bits.1 && rsv.1 || bits.2 && rsv.2
+        })
+    }
+
+    /// Apply the given extension to decode part of a message.
+    fn decode_chunk(&mut self, id: Id, d: &Decoding, output: &mut Vec<u8>) -> Result<Decoded, Error> {
+        let (e, stats) = match (self.list.get_mut(d.extension), self.stats.get_mut(d.extension)) {
+            (Some(e), Some(s)) => (e, s),
+            _ => return Err(Error::Closed(CloseReason::abnormal()))
+        };
+        log::trace!("{}: decoding part with extension: {}", id, e.name());
+        let start = Instant::now();
+        let decoded = e.decode_chunk(&d.header, &d.input, d.end_of_frame, output, PART_SIZE).map_err(Error::Extension)?;
+        let elapsed = start.elapsed();
+        if d.end_of_frame && decoded.consumed == d.input.len() && decoded.state != DecodeState::MoreOutput {
+            stats.decoded_frames += 1
+        }
+        stats.decoded_bytes += crate::as_u64(decoded.consumed);
+        stats.decode_time += elapsed;
+        if let Some(b) = &mut self.budget {
+            if elapsed > b.max {
+                log::warn!("{}: extension {} took {:?} to decode part of {}", id, e.name(), elapsed, d.header);
+                (b.on_exceeded)(e.name(), elapsed)
+            }
+        }
+        Ok(decoded)
+    }
+
+    /// Apply all extensions to encode a frame.
+    fn encode(&mut self, id: Id, header: &mut Header, data: &mut Storage<'_>) -> Result<(), Error> {
+        for (e, stats) in self.list.iter_mut().zip(&mut self.stats) {
+            log::trace!("{}: encoding with extension: {}", id, e.name());
+            let len = data.as_ref().len();
+            let start = Instant::now();
+            e.encode(header, data).map_err(Error::Extension)?;
+            let elapsed = start.elapsed();
+            stats.encoded_frames += 1;
+            stats.encoded_bytes += crate::as_u64(len);
+            stats.encode_time += elapsed;
+            if let Some(b) = &mut self.budget {
+                if elapsed > b.max {
+                    log::warn!("{}: extension {} took {:?} to encode {}", id, e.name(), elapsed, header);
+                    (b.on_exceeded)(e.name(), elapsed)
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Guards against a remote sending data too slowly.
+///
+/// See [`Builder::set_min_read_rate`] for details.
+#[derive(Debug)]
+struct ReadRate {
+    /// Min. number of bytes per second.
+    min: u64,
+    /// The length of the sliding window.
+    grace: Duration,
+    /// The time the first bytes of the current message have been read.
+    start: Option<Instant>,
+    /// Number of bytes read at some point in time within the sliding window.
+    samples: VecDeque<(Instant, usize)>
+}
+
+impl ReadRate {
+    fn new(min: u64, grace: Duration) -> Self {
+        ReadRate { min, grace, start: None, samples: VecDeque::new() }
+    }
+
+    /// Start measuring with the next message.
+    fn reset(&mut self) {
+        self.start = None;
+        self.samples.clear()
+    }
+
+    /// Record `n` bytes read at time `now`.
+    ///
+    /// Fails if, after the grace period has passed, the number of bytes read
+    /// during the sliding window is below the minimum rate.
+    fn update(&mut self, now: Instant, n: usize) -> Result<(), Error> {
+        let start = *self.start.get_or_insert(now);
+        self.samples.push_back((now, n));
+        while let Some((t, _)) = self.samples.front() {
+            if now.duration_since(*t) <= self.grace {
+                break
+            }
+            self.samples.pop_front();
+        }
+        if now.duration_since(start) < self.grace {
+            return Ok(())
+        }
+        let bytes: u128 = self.samples.iter().map(|(_, n)| *n as u128).sum();
+        if bytes * 1_000_000 < u128::from(self.min) * self.grace.as_micros() {
+            return Err(Error::Timeout(TimeoutKind::ReadRate))
+        }
+        Ok(())
+    }
+}
+
+/// Limits the rate of data received.
+///
+/// See [`Builder::set_inbound_rate_limit`] for details.
+#[derive(Debug)]
+struct InboundRate {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    policy: RateLimitPolicy
+}
+
+impl InboundRate {
+    fn new(limit: RateLimit, policy: RateLimitPolicy, now: Instant) -> Self {
+        InboundRate {
+            messages: limit.messages_per_sec.map(|r| TokenBucket::new(r, limit.burst, now)),
+            bytes: limit.bytes_per_sec.map(|r| TokenBucket::new(r, limit.burst, now)),
+            policy
+        }
+    }
+
+    /// Account for a data frame of `len` bytes received at time `now`.
+    ///
+    /// The final frame of a message counts as a message. If the rate has
+    /// been exceeded, returns the time until it is within the limit again.
+    fn take(&mut self, now: Instant, len: usize, fin: bool) -> Option<Duration> {
+        let messages = if fin { self.messages.as_mut().and_then(|b| b.take(now, 1)) } else { None };
+        let bytes = self.bytes.as_mut().and_then(|b| b.take(now, crate::as_u64(len)));
+        std::cmp::max(messages, bytes)
+    }
+}
+
+/// A token bucket which may go into debt.
+#[derive(Debug)]
+struct TokenBucket {
+    /// Tokens added per second.
+    rate: f64,
+    /// Max. number of tokens.
+    capacity: f64,
+    /// Tokens available, negative if in debt.
+    tokens: f64,
+    /// When were tokens last taken?
+    last: Instant
+}
+
+impl TokenBucket {
+    fn new(per_sec: u64, burst: Duration, now: Instant) -> Self {
+        let rate = per_sec as f64;
+        let capacity = f64::max(1.0, rate * burst.as_secs_f64());
+        TokenBucket { rate, capacity, tokens: capacity, last: now }
+    }
+
+    /// Take `n` tokens at time `now`.
+    ///
+    /// If not enough tokens are available, returns the time until the debt
+    /// has been paid off.
+    fn take(&mut self, now: Instant, n: u64) -> Option<Duration> {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = std::cmp::max(self.last, now);
+        self.tokens = f64::min(self.capacity, self.tokens + elapsed * self.rate) - n as f64;
+        if self.tokens >= 0.0 {
+            return None
+        }
+        Some(Duration::try_from_secs_f64(-self.tokens / self.rate).unwrap_or(Duration::MAX))
+    }
+}
+
+/// Read exactly enough bytes to fill `buf`, checking the read rate if set.
+async fn read_exact<R>(reader: &mut R, rate: &mut Option<ReadRate>, buf: &mut [u8]) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin
+{
+    let rate = match rate {
+        Some(rate) => rate,
+        None => return Ok(reader.read_exact(buf).await?)
+    };
+    let mut offset = 0;
+    while offset < buf.len() {
+        let n = reader.read(&mut buf[offset ..]).await?;
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+        }
+        offset += n;
+        rate.update(Instant::now(), n)?
+    }
+    Ok(())
+}
+
+/// The sending half of a connection.
+#[derive(Debug)]
+pub struct Sender<T> {
+    id: Id,
+    mode: Mode,
+    codec: base::Codec,
+    writer: BiLock<Writer<T>>,
+    mask_buffer: Vec<u8>,
+    extensions: BiLock<Extensions>,
+    has_extensions: bool,
+    traffic: Arc<Traffic>,
+    keep_alive: KeepAlive,
+    keep_alive_payload: Vec<u8>,
+    validate_text: bool,
+    fragment_size: Option<usize>,
+    max_send_frame_size: Option<usize>,
+    /// The opcode of a message sent in parts which is not complete yet.
+    part: Option<OpCode>,
+    config: Arc<SharedConfig>,
+    config_version: u64,
+    close_hooks: Arc<CloseHooks>
+}
+
+/// The receiving half of a connection.
+#[derive(Debug)]
+pub struct Receiver<T> {
+    id: Id,
+    mode: Mode,
+    codec: base::Codec,
+    reader: Tapped<ReadHalf<T>>,
+    writer: BiLock<Writer<T>>,
+    extensions: BiLock<Extensions>,
+    has_extensions: bool,
+    traffic: Arc<Traffic>,
+    buffer: BytesMut,
+    ctrl_buffer: BytesMut,
+    max_message_size: usize,
+    deviations: Deviations,
+    /// Number of unmasked frames accepted because of `deviations`.
+    unmasked_frames: u64,
+    close_echo: CloseEcho,
+    read_rate: Option<ReadRate>,
+    /// See [`Builder::set_inbound_rate_limit`].
+    inbound_rate: Option<InboundRate>,
+    fragmented: Option<Fragmented>,
+    /// The message being received with [`Receiver::receive_part`].
+    partial: Option<Partial>,
+    utf8: Box<dyn Validator + Send>,
+    pending_pongs: PendingPongs,
+    max_pending_control: usize,
+    close_timeout: Duration,
+    recv_quota: Option<u64>,
+    close_reason: Option<CloseReason>,
+    /// Number of frames received after the remote's CLOSE frame.
+    frames_after_close: u64,
+    /// Has anything but a duplicate CLOSE been received after the remote's
+    /// CLOSE frame?
+    data_after_close: bool,
+    /// Are data frames discarded after a protocol error?
+    draining: bool,
+    /// See [`Receiver::audit_frames`].
+    audit: Option<Audit>,
+    config: Arc<SharedConfig>,
+    config_version: u64,
+    close_hooks: Arc<CloseHooks>,
+    is_closed: bool
+}
+
+/// The sending end of an audit stream (see [`Receiver::audit_frames`]).
+#[derive(Debug)]
+struct Audit {
+    tx: mpsc::Sender<AuditedFrame>,
+    /// Number of frames not recorded since the stream was last full.
+    skipped: u64
+}
+
+/// A frame received, as recorded by [`Receiver::audit_frames`].
+#[derive(Debug, Clone)]
+pub enum AuditedFrame {
+    /// A data frame, i.e. a message or a fragment of one.
+    Data(Frame),
+    /// A PING, PONG or CLOSE frame.
+    Control(Frame),
+    /// The given number of frames have not been recorded, because the
+    /// stream was full.
+    Skipped(u64)
+}
+
+/// A piece of a message, as returned by [`Receiver::receive_part`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Part {
+    data: Data,
+    is_last: bool
+}
+
+impl Part {
+    /// The type of the message and the number of bytes of this piece.
+    pub fn data(&self) -> &Data {
+        &self.data
+    }
+
+    /// Is this the last piece of the message?
+    pub fn is_last(&self) -> bool {
+        self.is_last
+    }
+}
+
+/// The state of a message received with [`Receiver::receive_part`].
+#[derive(Debug)]
+struct Partial {
+    /// The opcode of the initial frame.
+    opcode: OpCode,
+    /// The header of the frame being read and the number of its payload
+    /// bytes read so far.
+    frame: Option<(Header, usize)>,
+    /// The number of payload bytes received so far.
+    length: usize,
+    /// The bytes of an incomplete character at the end of the text so far.
+    tail: Vec<u8>,
+    /// The state of decoding if the message is decoded by an extension.
+    decoding: Option<Decoding>
+}
+
+/// The state of a message decoded incrementally by an extension.
+#[derive(Debug)]
+struct Decoding {
+    /// The index of the extension in [`Extensions::list`].
+    extension: usize,
+    /// The header of the frame the input belongs to.
+    header: Header,
+    /// Is the end of the input the end of the frame?
+    end_of_frame: bool,
+    /// Payload data not consumed by the extension yet.
+    input: Vec<u8>,
+    /// Does the extension need to be called before reading more input?
+    pending: bool,
+    /// The number of decoded bytes so far.
+    length: usize
+}
+
+/// The state of a fragmented message interrupted by a PONG.
+#[derive(Debug)]
+struct Fragmented {
+    /// The opcode of the initial fragment.
+    opcode: OpCode,
+    /// The accumulated payload length so far.
+    length: usize,
+    /// The offset into the message buffer where the message starts.
+    offset: usize,
+    /// The offset into the message buffer up to which text has been validated.
+    validated: Option<usize>
+}
+
+/// The fragmentation state of the message being received.
+///
+/// Holds the opcode of the initial fragment while a fragmented message is
+/// in progress (RFC 6455, section 5.4).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct FragmentState(Option<OpCode>);
+
+impl FragmentState {
+    /// Process the opcode and FIN bit of a data frame.
+    ///
+    /// A CONTINUE frame is only valid within a message, all other data
+    /// frames only outside of one. Returns the opcode of the message if the
+    /// frame completes it. Control frames must not be passed in as they do
+    /// not belong to any message.
+    fn on_frame(&mut self, opcode: OpCode, fin: bool) -> Result<Option<OpCode>, Error> {
+        if opcode.is_control() {
+            return Err(Error::UnexpectedOpCode(opcode))
+        }
+        match (self.0, opcode) {
+            (Some(first), OpCode::Continue) => {
+                if fin {
+                    self.0 = None;
+                    return Ok(Some(first))
+                }
+                Ok(None)
+            }
+            (None, OpCode::Continue) | (Some(_), _) => Err(Error::UnexpectedOpCode(opcode)),
+            (None, _) => {
+                if fin {
+                    return Ok(Some(opcode))
+                }
+                self.0 = Some(opcode);
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// A connection builder.
+///
+/// Allows configuring certain parameters and extensions before
+/// creating the [`Sender`]/[`Receiver`] pair that represents the
+/// connection.
+#[derive(Debug)]
+pub struct Builder<T> {
+    id: Id,
+    mode: Mode,
+    socket: T,
+    codec: base::CodecConfig,
+    extensions: Vec<Box<dyn Extension + Send>>,
+    interceptor: Option<Interceptor>,
+    progress: Option<Progress>,
+    close_messages: Option<CloseMessages>,
+    buffer: BytesMut,
+    max_message_size: usize,
+    deviations: Deviations,
+    close_echo: CloseEcho,
+    drop_policy: DropPolicy,
+    keep_alive: KeepAlive,
+    keep_alive_payload: Vec<u8>,
+    min_read_rate: Option<(u64, Duration)>,
+    inbound_rate: Option<(RateLimit, RateLimitPolicy)>,
+    utf8: Box<dyn Validator + Send>,
+    validate_outgoing_text: bool,
+    fragment_size: Option<usize>,
+    max_send_frame_size: Option<usize>,
+    max_pending_control: usize,
+    close_timeout: Duration,
+    recv_quota: Option<u64>,
+    send_quota: Option<u64>,
+    budget: Option<Budget>,
+    #[cfg(feature = "tap")]
+    taps: Option<(crate::tap::Tap, crate::tap::Tap)>,
+    rng: Rng
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Builder<T> {
+    /// Create a new `Builder` from the given async I/O resource and mode.
+    ///
+    /// **Note**: Use this type only after a successful [handshake][0].
+    /// You can either use this crate's [handshake functionality][1]
+    /// or perform the handshake by some other means.
+    ///
+    /// [0]: https://tools.ietf.org/html/rfc6455#section-4
+    /// [1]: crate::handshake
+    pub fn new(socket: T, mode: Mode) -> Self {
+        let mut codec = base::CodecConfig::default();
+        codec.set_max_data_size(MAX_FRAME_SIZE);
+        Builder::with_codec_config(socket, mode, codec)
+    }
+
+    /// Create a new `Builder` from the given async I/O resource, mode and
+    /// frame codec configuration.
+    ///
+    /// A server can set up the configuration once, e.g. with the max. frame
+    /// size and the reserved bits of its extensions, and use it for every
+    /// connection it accepts instead of repeating these settings. See also
+    /// [`Builder::codec_config`].
+    pub fn with_codec_config(socket: T, mode: Mode, codec: base::CodecConfig) -> Self {
+        Builder {
+            id: Id::next(),
+            mode,
+            socket,
+            codec,
+            extensions: Vec::new(),
+            interceptor: None,
+            progress: None,
+            close_messages: None,
+            buffer: BytesMut::new(),
+            max_message_size: MAX_MESSAGE_SIZE,
+            deviations: Deviations::new(),
+            close_echo: CloseEcho::default(),
+            drop_policy: DropPolicy::default(),
+            keep_alive: KeepAlive::default(),
+            keep_alive_payload: Vec::new(),
+            min_read_rate: None,
+            inbound_rate: None,
+            utf8: Box::new(utf8::Std),
+            validate_outgoing_text: true,
+            fragment_size: None,
+            max_send_frame_size: None,
+            max_pending_control: MAX_PENDING_CONTROL,
+            close_timeout: CLOSE_TIMEOUT,
+            recv_quota: None,
+            send_quota: None,
+            budget: None,
+            #[cfg(feature = "tap")]
+            taps: None,
+            rng: Rng::default()
+        }
+    }
+
+    /// Create a new `Builder` whose connection generates mask keys from a
+    /// random number generator seeded with `seed`.
+    ///
+    /// Sending the same messages over connections created with the same
+    /// seed yields identical bytes, which allows comparing whole sessions
+    /// against recorded ones in tests. **Never use this in production**,
+    /// predictable mask keys defeat the purpose of masking.
+    ///
+    /// Requires the `test-util` feature.
+    #[cfg(feature = "test-util")]
+    pub fn new_deterministic(socket: T, mode: Mode, seed: u64) -> Self {
+        let mut builder = Builder::new(socket, mode);
+        builder.rng = Rng::seeded(seed);
+        builder
+    }
+
+    /// Set the source of mask keys.
+    pub(crate) fn set_rng(&mut self, rng: Rng) {
+        self.rng = rng
+    }
+
+    /// Set the ID of this connection.
+    ///
+    /// By default every connection gets a new ID which is unique within the
+    /// process. It appears in all log messages of the connection and is
+    /// available through [`Sender::id`], [`Receiver::id`] and
+    /// [`Connection::id`]. Uniqueness of IDs set here is up to the caller.
+    pub fn set_id(&mut self, id: u64) {
+        self.id = Id(id)
+    }
+
+    /// Set a custom buffer to use.
+    pub fn set_buffer(&mut self, b: BytesMut) {
+        self.buffer = b
+    }
+
+    /// Add extensions to use with this connection.
+    ///
+    /// Only enabled extensions will be considered.
+    pub fn add_extensions<I>(&mut self, extensions: I)
+    where
+        I: IntoIterator<Item = Box<dyn Extension + Send>>
+    {
+        for e in extensions.into_iter().filter(|e| e.is_enabled()) {
+            log::debug!("{}: using extension: {}", self.id, e.name());
+            self.codec.add_reserved_bits(e.reserved_bits());
+            self.extensions.push(e)
+        }
+    }
+
+    /// Set the maximum size of a complete message.
+    ///
+    /// Message fragments will be buffered and concatenated up to this value,
+    /// i.e. the sum of all message frames payload lengths will not be greater
+    /// than this maximum. However, extensions may increase the total message
+    /// size further, e.g. by decompressing the payload data.
+    pub fn set_max_message_size(&mut self, max: usize) {
+        self.max_message_size = max
+    }
+
+    /// Set the maximum size of a single websocket frame payload.
+    pub fn set_max_frame_size(&mut self, max: usize) {
+        self.codec.set_max_data_size(max);
+    }
+
+    /// The frame codec configuration of this builder.
+    ///
+    /// It reflects the max. frame size and the reserved bits of the added
+    /// extensions and can be passed to [`Builder::with_codec_config`].
+    pub fn codec_config(&self) -> base::CodecConfig {
+        self.codec
+    }
+
+    /// Set the status codes and reasons sent when the connection closes
+    /// itself.
+    ///
+    /// The function maps each [`CloseCause`] to the code and reason of the
+    /// CLOSE frame sent, e.g. to always send
+    /// [`CloseCode::POLICY_VIOLATION`] with a link to a support page. It
+    /// applies to [`Sender::close_on_error`] and to exceeded quotas. By
+    /// default, the code is [`CloseCause::close_code`] and there is no
+    /// reason. Reasons longer than 123 bytes are cut off at a character
+    /// boundary and codes which must not be sent are replaced with the
+    /// default.
+    pub fn set_close_messages<F>(&mut self, f: F)
+    where
+        F: Fn(CloseCause) -> (CloseCode, String) + Send + 'static
+    {
+        self.close_messages = Some(CloseMessages(Box::new(f)))
+    }
+
+    /// Set a function to apply to every outgoing frame.
+    ///
+    /// The interceptor runs just before the frame is encoded, i.e. after
+    /// extensions have been applied and a masking key has been assigned, but
+    /// before the payload data is masked. It sees the unmasked payload and may
+    /// change header and payload, e.g. set reserved bits, pick a different
+    /// masking key or rewrite the data. The `masked` flag is restored and the
+    /// payload length is taken from the payload afterwards, so masking as
+    /// required by the connection mode is always applied.
+    ///
+    /// The payload of each frame is copied to hand it to the interceptor.
+    pub fn set_frame_interceptor<F>(&mut self, f: F)
+    where
+        F: FnMut(&mut Frame) + Send + 'static
+    {
+        self.interceptor = Some(Interceptor(Box::new(f)))
+    }
+
+    /// Set a function which is informed about the write progress of frames.
+    ///
+    /// The function is invoked every time the underlying socket accepted some
+    /// bytes of a frame with the number of bytes written so far and the total
+    /// number of bytes of the frame (header and payload), so `total - written`
+    /// is the number of bytes still pending. This is useful to report the
+    /// progress of large binary messages.
+    ///
+    /// Note that bytes accepted by the socket may still be buffered by it
+    /// until [`Sender::flush`] is called.
+    pub fn set_write_progress<F>(&mut self, f: F)
+    where
+        F: FnMut(usize, usize) + Send + 'static
+    {
+        self.progress = Some(Progress(Box::new(f)))
+    }
+
+    /// Set the max. time an extension may take to encode or decode a frame.
+    ///
+    /// If an extension exceeds it, a warning is logged and the given function
+    /// is invoked with the name of the extension and the time it took. The
+    /// time spent by each extension is available through
+    /// [`Sender::extension_stats`] regardless of this setting.
+    pub fn set_extension_budget<F>(&mut self, max: Duration, f: F)
+    where
+        F: FnMut(&str, Duration) + Send + 'static
+    {
+        self.budget = Some(Budget { max, on_exceeded: Box::new(f) })
+    }
+
+    /// The deviations from RFC 6455 (see [`Deviations`]).
+    ///
+    /// Includes the masking opt-out negotiated by the handshake this builder
+    /// has been created from, if any.
+    pub fn deviations(&self) -> Deviations {
+        self.deviations
+    }
+
+    /// Set the deviations from RFC 6455 (see [`Deviations`]).
+    ///
+    /// Default is [`Deviations::new`]. To keep the masking opt-out negotiated
+    /// by a handshake, modify the value returned by [`Builder::deviations`].
+    pub fn set_deviations(&mut self, deviations: Deviations) {
+        if deviations.unmasked {
+            log::warn!("{}: masking is disabled", self.id)
+        }
+        self.deviations = deviations
+    }
+
+    /// Set how a CLOSE frame received from the remote is answered.
+    ///
+    /// Default is [`CloseEcho::Echo`].
+    pub fn set_close_echo(&mut self, echo: CloseEcho) {
+        self.close_echo = echo
+    }
+
+    /// Set what happens when the connection is dropped without having been
+    /// closed, i.e. when both [`Sender`] and [`Receiver`] are dropped.
+    ///
+    /// Default is [`DropPolicy::Abort`]. See [`DropPolicy::BestEffortClose`]
+    /// for its limitations.
+    pub fn set_drop_policy(&mut self, policy: DropPolicy) {
+        self.drop_policy = policy
+    }
+
+    /// Set which heartbeat [`Sender::heartbeat`] sends after a period of
+    /// send-side inactivity.
+    ///
+    /// Default is [`KeepAlive::Off`].
+    pub fn set_keep_alive(&mut self, keep_alive: KeepAlive) {
+        self.keep_alive = keep_alive
+    }
+
+    /// Set the payload of the heartbeats sent by [`Sender::heartbeat`].
+    ///
+    /// The remote echoes the payload of a PING in its PONG, so e.g. a
+    /// sequence number or timestamp allows correlating the two. The payload
+    /// of a control frame is limited to 125 bytes. Default is empty.
+    pub fn set_keep_alive_payload(&mut self, payload: impl Into<Vec<u8>>) -> Result<(), InvalidControlPayload> {
+        let payload = payload.into();
+        crate::data::check_control_len(payload.len())?;
+        self.keep_alive_payload = payload;
+        Ok(())
+    }
+
+    /// Set the validator for the payload data of text messages.
+    ///
+    /// Text messages which are not valid UTF-8 cause [`Receiver::receive`]
+    /// to fail with [`Error::InvalidText`]. By default [`utf8::Std`] is used.
+    pub fn set_utf8_validator<V>(&mut self, v: V)
+    where
+        V: Validator + Send + 'static
+    {
+        self.utf8 = Box::new(v)
+    }
+
+    /// Validate the text frames given to [`Sender::send_premasked`].
+    ///
+    /// The payload of a text frame sent as is must be valid UTF-8, otherwise
+    /// the remote fails the connection. By default, text frames are checked
+    /// and [`Error::InvalidUtf8Outgoing`] is returned instead of sending
+    /// invalid text. Callers which have already validated the text may skip
+    /// the check. Text passed to [`Sender::send_text`] is always valid.
+    pub fn set_validate_outgoing_text(&mut self, validate: bool) {
+        self.validate_outgoing_text = validate
+    }
+
+    /// Split outgoing data messages into fragments of at most `size` bytes.
+    ///
+    /// The size applies to the payload after extensions have been applied.
+    /// Control frames, e.g. answers to PINGs, are sent in between the
+    /// fragments of a message instead of waiting for all of it to be sent.
+    ///
+    /// The minimum is 1. By default, messages are sent as single frames.
+    pub fn set_fragment_size(&mut self, size: Option<usize>) {
+        self.fragment_size = size.map(|n| std::cmp::max(1, n))
+    }
+
+    /// Set the maximum payload size of frames to send, e.g. as advertised
+    /// by the remote.
+    ///
+    /// With a fragment size (see [`Builder::set_fragment_size`]), messages
+    /// are split into fragments of at most the smaller of both sizes.
+    /// Otherwise, sending a message larger than the maximum fails with
+    /// [`Error::FrameTooLarge`] and nothing is sent. Messages which
+    /// extensions make larger than the maximum are fragmented.
+    ///
+    /// The minimum is 1. By default, frame sizes are not limited.
+    pub fn set_max_send_frame_size(&mut self, max: Option<usize>) {
+        self.max_send_frame_size = max.map(|n| std::cmp::max(1, n))
+    }
+
+    /// Set the minimum rate at which message data must be received.
+    ///
+    /// Once the first bytes of a message have been received, the number of
+    /// bytes received during the last `grace` period must not fall below
+    /// `bytes_per_sec`, otherwise [`Receiver::receive`] fails with
+    /// [`Error::Timeout`]([`TimeoutKind::ReadRate`]). This protects against
+    /// remotes which tie up resources by sending data very slowly, e.g. one
+    /// byte at a time.
+    ///
+    /// The time between messages is not taken into account. The rate is
+    /// checked whenever data is received, so a remote which stops sending
+    /// altogether will not be detected; use a timeout for this.
+    ///
+    /// By default there is no minimum rate.
+    pub fn set_min_read_rate(&mut self, bytes_per_sec: u64, grace: Duration) {
+        self.min_read_rate = Some((bytes_per_sec, grace))
+    }
+
+    /// Limit the rate at which data is received.
+    ///
+    /// The rate is checked after each data frame has been read. If it
+    /// exceeds the limit, the [`RateLimitPolicy`] applies: the receiver
+    /// either waits until the rate is within the limit again before reading
+    /// on, or closes the connection. Control frames do not count and are not
+    /// limited. This keeps a remote from monopolizing the time spent on
+    /// decoding messages. By default there is no limit.
+    pub fn set_inbound_rate_limit(&mut self, limit: RateLimit, policy: RateLimitPolicy) {
+        self.inbound_rate = Some((limit, policy))
+    }
+
+    /// Set the max. number of PONGs pending for consecutive PINGs.
+    ///
+    /// PINGs which have been received along with further PINGs are answered
+    /// together once the last of them has been processed. If more than `max`
+    /// answers are pending, only the most recent PING is answered (cf.
+    /// [RFC 6455, section 5.5.3](https://tools.ietf.org/html/rfc6455#section-5.5.3)).
+    /// This bounds the work a remote can cause by flooding us with PINGs.
+    ///
+    /// The minimum is 1. Default is 16.
+    pub fn set_max_pending_control(&mut self, max: usize) {
+        self.max_pending_control = std::cmp::max(1, max)
+    }
+
+    /// Set the max. number of bytes to receive over the connection.
+    ///
+    /// Headers and payload data of all frames count. Once a frame exceeds
+    /// the quota, a CLOSE frame with [`CloseCode::POLICY_VIOLATION`] is sent,
+    /// the frame is not read and [`Receiver::receive`] fails with
+    /// [`Error::QuotaExceeded`]. By default there is no quota.
+    pub fn set_recv_quota(&mut self, quota: Option<u64>) {
+        self.recv_quota = quota
+    }
+
+    /// Set the max. number of bytes to send over the connection.
+    ///
+    /// Headers and payload data of all frames but CLOSE frames count. A
+    /// frame which would exceed the quota is not sent. Instead, a CLOSE
+    /// frame with [`CloseCode::POLICY_VIOLATION`] is sent and
+    /// [`Error::QuotaExceeded`] returned. By default there is no quota.
+    pub fn set_send_quota(&mut self, quota: Option<u64>) {
+        self.send_quota = quota
+    }
+
+    /// Set how long [`Receiver::await_close`] waits for the remote's CLOSE.
+    ///
+    /// Default is 10 seconds.
+    pub fn set_close_timeout(&mut self, timeout: Duration) {
+        self.close_timeout = timeout
+    }
+
+    /// Record all bytes read from and written to the socket.
+    ///
+    /// The bytes are written to `read` and `written` respectively as they
+    /// pass through the socket, so each of them can be decoded again, e.g.
+    /// with a [`base::FrameDecoder`], to reproduce the frames of the session.
+    /// A tap which fails to record is removed without affecting the
+    /// connection.
+    ///
+    /// Requires the `tap` feature.
+    #[cfg(feature = "tap")]
+    pub fn set_wire_tap<R, W>(&mut self, read: R, written: W)
+    where
+        R: std::io::Write + Send + 'static,
+        W: std::io::Write + Send + 'static
+    {
+        self.taps = Some((crate::tap::Tap(Box::new(read)), crate::tap::Tap(Box::new(written))))
+    }
+
+    /// Create a configured [`Sender`]/[`Receiver`] pair.
+    pub fn finish(self) -> (Sender<T>, Receiver<T>) {
+        let (rhlf, whlf) = crate::split::split(self.socket);
+        #[cfg(feature = "tap")]
+        let (rhlf, whlf) = {
+            let (read, written) = self.taps.map_or((None, None), |(r, w)| (Some(r), Some(w)));
+            (Tapped::new(rhlf, read), Tapped::new(whlf, written))
+        };
+        let traffic = Arc::new(Traffic::default());
+        let pending_pongs = PendingPongs::default();
+        let close_hooks = CloseHooks::new();
+        let mut rng = self.rng;
+        let on_drop = match self.drop_policy {
+            DropPolicy::Abort => None,
+            DropPolicy::BestEffortClose(code) => {
+                let masked = self.mode.is_client() && !self.deviations.unmasked;
+                Some(DropClose::new(&mut rng, masked, code))
+            }
+        };
+        let writer = Writer {
+            socket: Some(whlf),
+            interceptor: self.interceptor,
+            progress: self.progress,
+            traffic: traffic.clone(),
+            masking: !self.deviations.unmasked,
+            is_closed: false,
+            close_code: None,
+            close_reason: None,
+            on_drop,
+            last_write: Instant::now(),
+            rng,
+            send_quota: self.send_quota,
+            close_messages: self.close_messages,
+            pending_pongs: pending_pongs.clone(),
+            close_hooks: close_hooks.clone()
+        };
+        let (wrt1, wrt2) = BiLock::new(writer);
+        let has_extensions = !self.extensions.is_empty();
+        let extensions = Extensions {
+            stats: self.extensions.iter()
+                .map(|e| ExtensionStats { name: e.name().into(), .. ExtensionStats::default() })
+                .collect(),
+            list: self.extensions,
+            budget: self.budget
+        };
+        let (ext1, ext2) = BiLock::new(extensions);
+        let config = Arc::new(SharedConfig::new(RuntimeConfig {
+            max_message_size: self.max_message_size,
+            max_frame_size: self.codec.max_data_size(),
+            max_pending_control: self.max_pending_control,
+            keep_alive: self.keep_alive,
+            keep_alive_payload: self.keep_alive_payload.clone(),
+            close_timeout: self.close_timeout
+        }));
+
+        let recv = Receiver {
+            id: self.id,
+            mode: self.mode,
+            reader: rhlf,
+            writer: wrt1,
+            codec: base::Codec::with_config(self.codec),
+            extensions: ext1,
+            has_extensions,
+            traffic: traffic.clone(),
+            buffer: self.buffer,
+            ctrl_buffer: BytesMut::new(),
+            max_message_size: self.max_message_size,
+            deviations: self.deviations,
+            unmasked_frames: 0,
+            close_echo: self.close_echo,
+            read_rate: self.min_read_rate.map(|(min, grace)| ReadRate::new(min, grace)),
+            inbound_rate: self.inbound_rate.map(|(limit, policy)| InboundRate::new(limit, policy, Instant::now())),
+            fragmented: None,
+            partial: None,
+            utf8: self.utf8,
+            pending_pongs,
+            max_pending_control: self.max_pending_control,
+            close_timeout: self.close_timeout,
+            recv_quota: self.recv_quota,
+            close_reason: None,
+            frames_after_close: 0,
+            data_after_close: false,
+            draining: false,
+            audit: None,
+            config: config.clone(),
+            config_version: 0,
+            close_hooks: close_hooks.clone(),
+            is_closed: false
+        };
+
+        let send = Sender {
+            id: self.id,
+            mode: self.mode,
+            writer: wrt2,
+            mask_buffer: Vec::new(),
+            codec: base::Codec::with_config(self.codec),
+            extensions: ext2,
+            has_extensions,
+            traffic,
+            keep_alive: self.keep_alive,
+            keep_alive_payload: self.keep_alive_payload,
+            validate_text: self.validate_outgoing_text,
+            fragment_size: self.fragment_size,
+            max_send_frame_size: self.max_send_frame_size,
+            part: None,
+            config,
+            config_version: 0,
+            close_hooks
+        };
+
+        (send, recv)
+    }
+}
+
+/// A cloneable handle to send messages through a shared [`Sender`].
+///
+/// See [`Sender::into_shared`] for details.
+#[derive(Debug, Clone)]
+pub struct SharedSender {
+    tx: mpsc::Sender<Queued>,
+    close_hooks: Arc<CloseHooks>
+}
+
+/// A message queued by a [`SharedSender`].
+#[derive(Debug)]
+struct Queued {
+    /// More than one message if queued by [`SharedSender::send_batch`].
+    messages: Vec<QueuedMessage>,
+    /// The message is dropped if not sent before this point in time.
+    deadline: Option<Instant>,
+    /// Informs the producer about the outcome.
+    delivery: Option<oneshot::Sender<Delivery>>
+}
+
+/// A message in a [`Queued`] item.
+#[derive(Debug)]
+enum QueuedMessage {
+    Owned(Outgoing),
+    /// Queued by [`SharedSender::send_shared`], possibly for several connections.
+    Shared(Arc<Outgoing>)
+}
+
+impl QueuedMessage {
+    fn get(&self) -> &Outgoing {
+        match self {
+            QueuedMessage::Owned(m) => m,
+            QueuedMessage::Shared(m) => m
+        }
+    }
+}
+
+/// The outcome of [`SharedSender::send_with_deadline`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// The message has been written to the socket.
+    Sent,
+    /// The deadline passed before the message could be sent, so it has been
+    /// dropped.
+    Expired
+}
+
+impl SharedSender {
+    /// Queue an [`Outgoing`] message.
+    ///
+    /// Waits for room in the channel if necessary. Fails with
+    /// [`Error::Closed`] if the messages are no longer being sent.
+    pub async fn send(&mut self, message: Outgoing) -> Result<(), Error> {
+        let queued = Queued { messages: vec![QueuedMessage::Owned(message)], deadline: None, delivery: None };
+        self.tx.send(queued).await.or(Err(Error::Closed(CloseReason::abnormal())))
+    }
+
+    /// Queue an [`Outgoing`] message which is shared with other producers.
+    ///
+    /// Like [`SharedSender::send`], but the payload is not copied, so the
+    /// same message can be queued for several connections, e.g. by
+    /// [`Registry::broadcast`](crate::registry::Registry::broadcast).
+    pub async fn send_shared(&mut self, message: Arc<Outgoing>) -> Result<(), Error> {
+        let queued = Queued { messages: vec![QueuedMessage::Shared(message)], deadline: None, delivery: None };
+        self.tx.send(queued).await.or(Err(Error::Closed(CloseReason::abnormal())))
+    }
+
+    /// Queue several [`Outgoing`] messages as one unit.
+    ///
+    /// The messages are sent back to back, i.e. no message of another
+    /// producer is sent between them. Control frames, e.g. answers to PINGs,
+    /// may still be interleaved. The batch occupies a single place in the
+    /// channel, so it waits for room as a whole.
+    pub async fn send_batch(&mut self, messages: impl IntoIterator<Item = Outgoing>) -> Result<(), Error> {
+        let messages = messages.into_iter().map(QueuedMessage::Owned).collect::<Vec<_>>();
+        if messages.is_empty() {
+            return Ok(())
+        }
+        let queued = Queued { messages, deadline: None, delivery: None };
+        self.tx.send(queued).await.or(Err(Error::Closed(CloseReason::abnormal())))
+    }
+
+    /// Queue an [`Outgoing`] message which must be sent before `deadline`.
+    ///
+    /// Completes once the message has been written to the socket, or with
+    /// [`Delivery::Expired`] if the deadline passed while the message was
+    /// still queued, in which case it is not sent at all. A message whose
+    /// transmission has begun is always sent completely.
+    pub async fn send_with_deadline(&mut self, message: Outgoing, deadline: Instant) -> Result<Delivery, Error> {
+        if Instant::now() > deadline {
+            return Ok(Delivery::Expired)
+        }
+        let (tx, rx) = oneshot::channel();
+        let queued = Queued { messages: vec![QueuedMessage::Owned(message)], deadline: Some(deadline), delivery: Some(tx) };
+        self.tx.send(queued).await.or(Err(Error::Closed(CloseReason::abnormal())))?;
+        rx.await.or(Err(Error::Closed(CloseReason::abnormal())))
+    }
+
+    /// Queue some text.
+    pub async fn send_text(&mut self, data: impl Into<String>) -> Result<(), Error> {
+        self.send(Outgoing::Text(data.into())).await
+    }
+
+    /// Queue some binary data.
+    pub async fn send_binary(&mut self, data: impl Into<Vec<u8>>) -> Result<(), Error> {
+        self.send(Outgoing::Binary(data.into())).await
+    }
+
+    /// Are queued messages no longer being sent?
+    ///
+    /// This is the case once the future returned by [`Sender::into_shared`]
+    /// has completed or been dropped, e.g. after the connection failed, and
+    /// once the connection has been closed.
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed() || self.close_hooks.is_closed()
+    }
+
+    /// Call `f` once the connection is closed.
+    ///
+    /// The connection counts as closed once a CLOSE frame has been sent or
+    /// received, the socket has been closed or the connection dropped, or the
+    /// future returned by [`Sender::into_shared`] failed or was dropped. If
+    /// the connection is closed already, `f` is called right away.
+    ///
+    /// `f` runs on the task which closes the connection and should not block.
+    pub fn on_close<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static
+    {
+        self.close_hooks.add(Box::new(f))
+    }
+}
+
+impl<T> Receiver<T> {
+    /// The ID of this connection, which the [`Sender`] shares.
+    pub fn id(&self) -> u64 {
+        self.id.0
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Receiver<T> {
+    /// Put the socket back together with the write half of the `sender`.
+    ///
+    /// Returns the socket and the bytes read but not decoded yet. No CLOSE
+    /// frame is sent when the writer is dropped afterwards.
+    fn into_inner(self, sender: Sender<T>) -> Option<(T, BytesMut)> {
+        let mut writer = self.writer.reunite(sender.writer).ok()?;
+        let whlf = tap::into_inner(writer.socket.take()?);
+        let socket = tap::into_inner(self.reader).reunite(whlf)?;
+        Some((socket, self.buffer))
+    }
+
+    /// Receive the next websocket message.
+    ///
+    /// The received frames forming the complete message will be appended to
+    /// the given `message` argument. The returned [`Incoming`] value describes
+    /// the type of data that was received, e.g. binary or textual data.
+    ///
+    /// Interleaved PONG frames are returned immediately as `Data::Pong`
+    /// values. If PONGs are not expected or uninteresting,
+    /// [`Receiver::receive_data`] may be used instead which skips over PONGs
+    /// and considers only application payload data. If a PONG interrupts a
+    /// fragmented message, the next call continues with the message and must
+    /// be given the same `message` argument.
+    ///
+    /// If the remote sends a CLOSE frame, it is answered and its reason
+    /// returned as `Incoming::Closed`. Subsequent calls will return
+    /// [`Error::Closed`].
+    ///
+    /// If the remote shuts down its writing side between messages without
+    /// sending a CLOSE frame, `Incoming::PeerClosedWrites` is returned and
+    /// subsequent calls will return [`Error::Closed`]. The [`Sender`] remains
+    /// usable, e.g. to finish sending and then [`Sender::close`] the
+    /// connection.
+    pub async fn receive(&mut self, message: &mut Vec<u8>) -> Result<Incoming<'_>, Error> {
+        let (mut fragments, mut length, message_len, mut validated) =
+            if let Some(Fragmented { opcode, length, offset, validated }) = self.fragmented.take() {
+                (FragmentState(Some(opcode)), length, offset, validated)
+            } else {
+                (FragmentState(None), 0, message.len(), None)
+            };
+        if let Some(rate) = &mut self.read_rate {
+            rate.reset()
+        }
+        loop {
+            if self.is_closed {
+                log::debug!("{}: can not receive, connection is closed", self.id);
+                return Err(self.closed())
+            }
+
+            self.ctrl_buffer.clear();
+            let mut header = match self.receive_header().await? {
+                Some(header) => header,
+                None if fragments.0.is_none() => {
+                    log::debug!("{}: remote closed its writing side", self.id);
+                    self.set_closed();
+                    return Ok(Incoming::PeerClosedWrites)
+                }
+                None => {
+                    log::debug!("{}: remote closed its writing side within a message", self.id);
+                    return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()))
+                }
+            };
+            log::trace!("{}: recv: {}", self.id, header);
+
+            // Handle control frames.
+            if header.opcode().is_control() {
+                self.read_buffer(&header).await?;
+                debug_assert!(header.payload_len() <= self.buffer.len());
+                self.ctrl_buffer = self.buffer.split_to(header.payload_len());
+                base::Codec::apply_mask(&header, &mut self.ctrl_buffer);
+                self.record_control(&header);
+                if header.opcode() == OpCode::Pong {
+                    self.fragmented = fragments.0.map(|opcode| {
+                        Fragmented { opcode, length, offset: message_len, validated }
+                    });
+                    return Ok(Incoming::Pong(&self.ctrl_buffer[..]))
+                }
+                self.on_control(&header).await?;
+                if header.opcode() == OpCode::Close {
+                    return self.on_close().await.map(Incoming::Closed)
+                }
+                continue
+            }
+
+            // After a protocol error within a message, data frames are
+            // discarded until the remote's CLOSE arrives.
+            if self.draining {
+                log::trace!("{}: discarding {} frame", self.id, header.opcode());
+                self.discard_payload(&header).await?;
+                continue
+            }
+
+            length = length.saturating_add(header.payload_len());
+
+            // Check if total message does not exceed maximum.
+            if length > self.max_message_size {
+                log::warn!("{}: accumulated message length exceeds maximum", self.id);
+                return Err(Error::MessageTooLarge { limit: self.max_message_size, actual: length })
+            }
+
+            // Get the frame's payload data bytes from buffer or socket.
+            {
+                let old_msg_len = message.len();
+
+                let bytes_to_read = {
+                    let required = header.payload_len();
+                    let buffered = self.buffer.len();
+
+                    if buffered == 0 {
+                        required
+                    } else if required > buffered {
+                        message.extend_from_slice(&self.buffer);
+                        self.buffer.clear();
+                        required - buffered
+                    } else {
+                        message.extend_from_slice(&self.buffer.split_to(required));
+                        0
+                    }
+                };
+
+                if bytes_to_read > 0 {
+                    let n = message.len();
+                    message.resize(n + bytes_to_read, 0u8);
+                    read_exact(&mut self.reader, &mut self.read_rate, &mut message[n ..]).await?
+                }
+
+                debug_assert_eq!(header.payload_len(), message.len() - old_msg_len);
+
+                base::Codec::apply_mask(&header, &mut message[old_msg_len ..]);
+                self.record(AuditedFrame::Data, &header, &message[old_msg_len ..]);
+            }
+
+            self.limit_rate(&header).await?;
+
+            let complete = match fragments.on_frame(header.opcode(), header.is_fin()) {
+                Ok(complete) => complete,
+                Err(e) => {
+                    log::debug!("{}: {} frame (fin = {}) out of sequence", self.id, header.opcode(), header.is_fin());
+                    return Err(self.start_draining(e))
+                }
+            };
+
+            match (header.is_fin(), header.opcode()) {
+                (false, OpCode::Continue) => { // Intermediate message fragment.
+                    self.decode_with_extensions(&mut header, message).await?;
+                    if let Some(offset) = &mut validated {
+                        self.validate_fragment(message, offset)?
+                    }
+                    continue
+                }
+                (false, oc) => { // Initial message fragment.
+                    self.decode_with_extensions(&mut header, message).await?;
+                    // Text which has not been transformed by an extension can be
+                    // validated fragment by fragment to fail early.
+                    if oc == OpCode::Text && !(header.is_rsv1() || header.is_rsv2() || header.is_rsv3()) {
+                        let mut offset = message_len;
+                        self.validate_fragment(message, &mut offset)?;
+                        validated = Some(offset)
+                    }
+                    continue
+                }
+                (true, OpCode::Continue) => { // Last message fragment.
+                    header.set_payload_len(message.len());
+                    log::trace!("{}: last fragment: total length = {} bytes", self.id, message.len());
+                    self.decode_with_extensions(&mut header, message).await?;
+                    if let Some(oc) = complete {
+                        header.set_opcode(oc);
+                    }
+                }
+                (true, _) => { // Regular non-fragmented message.
+                    self.decode_with_extensions(&mut header, message).await?
+                }
+            }
+
+            let num_bytes = message.len() - message_len;
+
+            if header.opcode() == OpCode::Text {
+                if !self.utf8.validate(&message[validated.unwrap_or(message_len) ..]).is_valid() {
+                    log::debug!("{}: text message is not valid utf-8", self.id);
+                    return Err(self.start_draining(Error::InvalidText))
+                }
+                return Ok(Incoming::Data(Data::Text(num_bytes)))
+            } else {
+                return Ok(Incoming::Data(Data::Binary(num_bytes)))
+            }
+        }
+    }
+
+    /// Receive the next data frame without reassembling messages.
+    ///
+    /// The unmasked payload data of the frame is appended to `payload` and
+    /// its header is returned with the `masked` flag cleared. Control frames
+    /// are handled as by [`Receiver::receive`] and PONGs are skipped. No
+    /// extensions are applied and text is not validated, so extensions
+    /// negotiated end-to-end keep working, e.g. when relaying frames (see
+    /// [`relay`]). Returns `None` once the remote has closed the connection.
+    pub async fn receive_frame(&mut self, payload: &mut Vec<u8>) -> Result<Option<Header>, Error> {
+        loop {
+            let (header, data) = match self.next_frame().await? {
+                Some(frame) => frame.into_parts(),
+                None => return Ok(None)
+            };
+            match header.opcode() {
+                OpCode::Ping | OpCode::Pong => continue,
+                OpCode::Close => return Ok(None),
+                _ => {
+                    payload.extend_from_slice(&data);
+                    return Ok(Some(header))
+                }
+            }
+        }
+    }
+
+    /// A stream of copies of all frames received, in order of arrival.
+    ///
+    /// Unlike [`Receiver::frames`], this does not consume the frames. The
+    /// connection is used as before, e.g. with [`Receiver::receive`], while
+    /// the stream records data and control frames as they arrive, which
+    /// reassembling messages hides. Headers are recorded as received and
+    /// payloads unmasked, but before extensions are applied. Data frames
+    /// discarded after a protocol error are not recorded.
+    ///
+    /// The stream holds up to `capacity` frames. Frames which arrive while
+    /// it is full are not recorded and an [`AuditedFrame::Skipped`] item
+    /// says how many. Each call replaces the previous stream, which ends.
+    pub fn audit_frames(&mut self, capacity: usize) -> impl Stream<Item = AuditedFrame> {
+        let (tx, rx) = mpsc::channel(capacity);
+        self.audit = Some(Audit { tx, skipped: 0 });
+        rx
+    }
+
+    /// A stream of all frames received, without reassembling messages.
+    ///
+    /// In contrast to [`Receiver::receive_frame`] control frames are
+    /// included, after they have been handled as by [`Receiver::receive`].
+    /// The stream ends after the remote's CLOSE frame, when the remote has
+    /// closed its writing side, or after the first error.
+    pub fn frames(&mut self) -> impl Stream<Item = Result<Frame, Error>> + '_ {
+        stream::unfold(Some(self), |this| async move {
+            let this = this?;
+            match this.next_frame().await {
+                Ok(Some(frame)) => {
+                    let is_close = frame.header().opcode() == OpCode::Close;
+                    Some((Ok(frame), if is_close { None } else { Some(this) }))
+                }
+                Ok(None) => None,
+                Err(e) => Some((Err(e), None))
+            }
+        })
+    }
+
+    /// Receive and handle the next frame.
+    ///
+    /// The payload data is unmasked and the `masked` flag cleared. Returns
+    /// `None` if the remote has closed its writing side.
+    async fn next_frame(&mut self) -> Result<Option<Frame>, Error> {
+        if self.is_closed {
+            log::debug!("{}: can not receive, connection is closed", self.id);
+            return Err(self.closed())
+        }
+        self.ctrl_buffer.clear();
+        let mut header = match self.receive_header().await? {
+            Some(header) => header,
+            None => {
+                log::debug!("{}: remote closed its writing side", self.id);
+                self.set_closed();
+                return Ok(None)
+            }
+        };
+        log::trace!("{}: recv frame: {}", self.id, header);
+        self.read_buffer(&header).await?;
+        let mut data = self.buffer.split_to(header.payload_len());
+        base::Codec::apply_mask(&header, &mut data);
+        header.set_masked(false);
+        if !header.opcode().is_control() {
+            self.record(AuditedFrame::Data, &header, &data);
+            self.limit_rate(&header).await?;
+            return Ok(Some(Frame::from_parts(header, data.to_vec())))
+        }
+        self.ctrl_buffer = data;
+        self.record_control(&header);
+        if header.opcode() != OpCode::Pong {
+            self.on_control(&header).await?
+        }
+        if header.opcode() == OpCode::Close {
+            self.on_close().await?;
+        }
+        Ok(Some(Frame::from_parts(header, self.ctrl_buffer.to_vec())))
+    }
+
+    /// Record the reason of the remote's CLOSE frame in `ctrl_buffer`.
+    async fn on_close(&mut self) -> Result<CloseReason, Error> {
+        self.discard_after_close();
+        if self.is_data_after_close() {
+            return Err(Error::DataAfterClose)
+        }
+        if self.is_invalid_close() {
+            return Err(Error::InvalidClosePayload)
+        }
+        let mut reason = CloseReason::from_payload(&self.ctrl_buffer)?;
+        let mut w = self.writer.lock().await;
+        if reason.code == CloseCode::NO_STATUS_RECEIVED {
+            // Report the code we have sent, if any.
+            if let Some(code) = w.close_code {
+                reason.code = code
+            }
+        }
+        w.close_reason = Some(reason.clone());
+        self.close_reason = Some(reason.clone());
+        Ok(reason)
+    }
+
+    /// Is the received CLOSE payload a single byte, which is rejected unless
+    /// accepted (see [`Deviations::set_accept_single_byte_close`])?
+    fn is_invalid_close(&self) -> bool {
+        self.ctrl_buffer.len() == 1 && !self.deviations.accept_single_byte_close
+    }
+
+    /// Has data been received after the remote's CLOSE frame, which is
+    /// rejected unless ignored (see [`Deviations::set_ignore_data_after_close`])?
+    fn is_data_after_close(&self) -> bool {
+        self.data_after_close && !self.deviations.ignore_data_after_close
+    }
+
+    /// Discard what has been received along with the remote's CLOSE frame.
+    ///
+    /// Duplicate CLOSE frames are tolerated, anything else, including bytes
+    /// which do not decode as a frame, sets `data_after_close`.
+    fn discard_after_close(&mut self) {
+        let mut offset = 0;
+        while offset < self.buffer.len() {
+            match self.codec.decode_header(&self.buffer[offset ..]) {
+                Ok(Parsing::Done { value: header, offset: n }) => {
+                    self.frames_after_close += 1;
+                    if header.opcode() != OpCode::Close {
+                        self.data_after_close = true
+                    }
+                    offset += n + header.payload_len()
+                }
+                Ok(Parsing::NeedMore(_)) | Err(_) => {
+                    self.data_after_close = true;
+                    break
+                }
+            }
+        }
+        if !self.buffer.is_empty() {
+            log::debug!("{}: {} bytes after close frame", self.id, self.buffer.len());
+            self.buffer.clear()
+        }
+    }
+
+    /// Mark the connection as closed and call the close hooks.
+    fn set_closed(&mut self) {
+        self.is_closed = true;
+        self.close_hooks.notify()
+    }
+
+    /// The error to report once the connection is closed.
+    fn closed(&self) -> Error {
+        Error::Closed(self.close_reason.clone().unwrap_or_else(CloseReason::abnormal))
+    }
+
+    /// The number of frames received after the remote's CLOSE frame.
+    ///
+    /// These frames, including duplicate CLOSE frames, are discarded.
+    pub fn frames_after_close(&self) -> u64 {
+        self.frames_after_close
+    }
+
+    /// Receive the next websocket message, skipping over control frames.
+    pub async fn receive_data(&mut self, message: &mut Vec<u8>) -> Result<Data, Error> {
+        loop {
+            if let Incoming::Data(d) = self.receive(message).await? {
+                return Ok(d)
+            }
+        }
+    }
+
+    /// Receive the next piece of a message, skipping over control frames.
+    ///
+    /// Up to 64 KiB of payload data are appended to `part`, regardless of
+    /// how the message is split into frames. Messages of any size can thus
+    /// be received with constant memory. The returned [`Part`] tells if
+    /// this is the last piece of the message. The max. message size applies
+    /// to the total of all pieces.
+    ///
+    /// A message encoded by an extension, e.g. compressed, is decoded
+    /// incrementally with [`Extension::decode_chunk`] by the first extension
+    /// using a reserved bit set on its initial frame, and the pieces hold the
+    /// decoded data.
+    ///
+    /// Text is validated as it arrives, but a piece may end in the middle
+    /// of a character, which the next piece completes. The frames of a
+    /// message received this way are not recorded by
+    /// [`Receiver::audit_frames`]. Calls must not be mixed with other
+    /// receive methods within a message.
+    ///
+    /// Fails with [`Error::Closed`] once the remote's CLOSE frame has been
+    /// received (see [`Receiver::close_reason`]) or the remote has closed
+    /// its writing side between messages.
+    pub async fn receive_part(&mut self, part: &mut Vec<u8>) -> Result<Part, Error> {
+        loop {
+            if self.is_closed {
+                log::debug!("{}: can not receive, connection is closed", self.id);
+                return Err(self.closed())
+            }
+            if let Some(p) = self.decode_part(part).await? {
+                return Ok(p)
+            }
+            let (header, offset) = match self.partial.as_ref().and_then(|p| p.frame.clone()) {
+                Some(frame) => frame,
+                None => match self.receive_part_header().await? {
+                    Some(header) => (header, 0),
+                    None => continue
+                }
+            };
+
+            // Read the next piece of the payload from buffer or socket.
+            let remaining = header.payload_len() - offset;
+            if remaining > 0 && self.buffer.is_empty() {
+                self.buffer.resize(std::cmp::min(remaining, PART_SIZE), 0);
+                read_exact(&mut self.reader, &mut self.read_rate, &mut self.buffer[..]).await?
+            }
+            let n = std::cmp::min(std::cmp::min(remaining, PART_SIZE), self.buffer.len());
+            let mut mask = header.clone();
+            mask.set_mask(header.mask().rotate_left(8 * (offset % 4) as u32));
+            base::Codec::apply_mask(&mask, &mut self.buffer[.. n]);
+            let end_of_frame = n == remaining;
+            if let Some(d) = self.partial.as_mut().and_then(|p| p.decoding.as_mut()) {
+                d.input.extend_from_slice(&self.buffer[.. n]);
+                d.header = header.clone();
+                d.end_of_frame = end_of_frame;
+                d.pending = true;
+                self.buffer.advance(n);
+                if let Some(p) = &mut self.partial {
+                    p.frame = if end_of_frame { None } else { Some((header, offset + n)) }
+                }
+                continue
+            }
+            let start = part.len();
+            part.extend_from_slice(&self.buffer[.. n]);
+            self.buffer.advance(n);
+
+            let is_last = end_of_frame && header.is_fin();
+            let (opcode, valid) = match &mut self.partial {
+                Some(p) => {
+                    p.frame = if end_of_frame { None } else { Some((header, offset + n)) };
+                    let valid = p.opcode != OpCode::Text || validate_part(&*self.utf8, &mut p.tail, &part[start ..], is_last);
+                    (p.opcode, valid)
+                }
+                None => return Err(Error::Closed(CloseReason::abnormal()))
+            };
+            if !valid {
+                log::debug!("{}: text message is not valid utf-8", self.id);
+                self.partial = None;
+                return Err(self.start_draining(Error::InvalidText))
+            }
+            if is_last {
+                self.partial = None
+            } else if n == 0 {
+                continue
+            }
+            let data = if opcode == OpCode::Text { Data::Text(n) } else { Data::Binary(n) };
+            return Ok(Part { data, is_last })
+        }
+    }
+
+    /// Let the extension decoding the current message produce the next part.
+    ///
+    /// Returns `None` if the extension needs more input.
+    async fn decode_part(&mut self, part: &mut Vec<u8>) -> Result<Option<Part>, Error> {
+        let (opcode, d) = match &mut self.partial {
+            Some(Partial { opcode, decoding: Some(d), .. }) if d.pending => (*opcode, d),
+            _ => return Ok(None)
+        };
+        let start = part.len();
+        let decoded = match self.extensions.lock().await.decode_chunk(self.id, d, part) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                self.partial = None;
+                return Err(e)
+            }
+        };
+        d.input.drain(.. decoded.consumed);
+        d.pending = decoded.state == DecodeState::MoreOutput;
+        d.length = d.length.saturating_add(decoded.produced);
+        if d.length > self.max_message_size {
+            log::warn!("{}: decoded message length exceeds maximum", self.id);
+            let current = d.length;
+            self.partial = None;
+            return Err(Error::MessageTooLarge { limit: self.max_message_size, actual: current })
+        }
+
+        let is_last = decoded.state == DecodeState::End;
+        let valid = match &mut self.partial {
+            Some(p) if opcode == OpCode::Text => validate_part(&*self.utf8, &mut p.tail, &part[start ..], is_last),
+            _ => true
+        };
+        if !valid {
+            log::debug!("{}: text message is not valid utf-8", self.id);
+            self.partial = None;
+            return Err(self.start_draining(Error::InvalidText))
+        }
+        if is_last {
+            self.partial = None
+        } else if decoded.produced == 0 {
+            return Ok(None)
+        }
+        let n = part.len() - start;
+        let data = if opcode == OpCode::Text { Data::Text(n) } else { Data::Binary(n) };
+        Ok(Some(Part { data, is_last }))
+    }
+
+    /// Read the header of the next data frame for [`Receiver::receive_part`].
+    ///
+    /// Control frames are handled as by [`Receiver::receive`] and `None`
+    /// is returned. The message state is updated for data frames.
+    async fn receive_part_header(&mut self) -> Result<Option<Header>, Error> {
+        self.ctrl_buffer.clear();
+        let header = match self.receive_header().await? {
+            Some(header) => header,
+            None if self.partial.is_none() => {
+                log::debug!("{}: remote closed its writing side", self.id);
+                self.set_closed();
+                return Err(self.closed())
+            }
+            None => {
+                log::debug!("{}: remote closed its writing side within a message", self.id);
+                return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()))
+            }
+        };
+        log::trace!("{}: recv part: {}", self.id, header);
+
+        if header.opcode().is_control() {
+            self.read_buffer(&header).await?;
+            self.ctrl_buffer = self.buffer.split_to(header.payload_len());
+            base::Codec::apply_mask(&header, &mut self.ctrl_buffer);
+            self.record_control(&header);
+            if header.opcode() != OpCode::Pong {
+                self.on_control(&header).await?
+            }
+            if header.opcode() == OpCode::Close {
+                self.on_close().await?;
+                self.set_closed();
+                return Err(self.closed())
+            }
+            return Ok(None)
+        }
+
+        if self.draining {
+            log::trace!("{}: discarding {} frame", self.id, header.opcode());
+            self.discard_payload(&header).await?;
+            return Ok(None)
+        }
+
+        let mut fragments = FragmentState(self.partial.as_ref().map(|p| p.opcode));
+        if let Err(e) = fragments.on_frame(header.opcode(), header.is_fin()) {
+            log::debug!("{}: {} frame (fin = {}) out of sequence", self.id, header.opcode(), header.is_fin());
+            self.partial = None;
+            return Err(self.start_draining(e))
+        }
+
+        if self.partial.is_none() {
+            let extension =
+                if self.has_extensions {
+                    self.extensions.lock().await.decoder(&header)
+                } else {
+                    None
+                };
+            let decoding = extension.map(|extension| Decoding {
+                extension,
+                header: header.clone(),
+                end_of_frame: false,
+                input: Vec::new(),
+                pending: false,
+                length: 0
+            });
+            self.partial = Some(Partial {
+                opcode: header.opcode(),
+                frame: None,
+                length: 0,
+                tail: Vec::new(),
+                decoding
+            })
+        }
+        let partial = match &mut self.partial {
+            Some(p) => p,
+            None => return Err(Error::Closed(CloseReason::abnormal()))
+        };
+        partial.length = partial.length.saturating_add(header.payload_len());
+        if partial.length > self.max_message_size {
+            log::warn!("{}: accumulated message length exceeds maximum", self.id);
+            let current = partial.length;
+            self.partial = None;
+            return Err(Error::MessageTooLarge { limit: self.max_message_size, actual: current })
+        }
+        partial.frame = Some((header.clone(), 0));
+
+        self.limit_rate(&header).await?;
+        Ok(Some(header))
+    }
+
+    /// The reason given by the remote for closing the connection.
+    ///
+    /// Available once the remote's CLOSE frame has been received. If it
+    /// carried no status code but we have sent one in our own CLOSE frame,
+    /// our code is reported.
+    pub fn close_reason(&self) -> Option<&CloseReason> {
+        self.close_reason.as_ref()
+    }
+
+    /// Wait for the remote to answer our CLOSE frame.
+    ///
+    /// Intended to be used after [`Sender::close`] to complete the close
+    /// handshake. Messages the remote sends in the meantime are discarded.
+    /// If the remote's CLOSE does not arrive within the timeout set with
+    /// [`Builder::set_close_timeout`], the socket is closed and
+    /// [`Error::Timeout`]([`TimeoutKind::Close`]) is returned.
+    ///
+    /// This crate does not depend on a timer, so `sleep` is called with the
+    /// timeout to create one, e.g. `tokio::time::sleep`.
+    pub async fn await_close<S, F>(&mut self, sleep: S) -> Result<CloseReason, Error>
+    where
+        S: FnOnce(Duration) -> F,
+        F: Future<Output = ()>
+    {
+        self.sync_config();
+        {
+            let timeout = sleep(self.close_timeout);
+            let closing = async {
+                let mut unused = Vec::new();
+                loop {
+                    unused.clear();
+                    match self.receive(&mut unused).await? {
+                        Incoming::Closed(reason) => return Ok(reason),
+                        Incoming::PeerClosedWrites => return Err(Error::Closed(CloseReason::abnormal())),
+                        Incoming::Data(_) | Incoming::Pong(_) => {}
+                    }
+                }
+            };
+            futures::pin_mut!(closing, timeout);
+            if let future::Either::Left((result, _)) = future::select(closing, timeout).await {
+                return result
+            }
+        }
+        log::debug!("{}: timed out waiting for close", self.id);
+        self.set_closed();
+        let _ = self.writer.lock().await.close().await;
+        Err(Error::Timeout(TimeoutKind::Close))
+    }
+
+    /// The number of unmasked frames accepted so far.
+    ///
+    /// Always 0 unless [`Deviations::set_accept_unmasked_frames`] is enabled.
+    pub fn unmasked_frames(&self) -> u64 {
+        self.unmasked_frames
+    }
+
+    /// Read the next frame header.
+    ///
+    /// Returns `None` if the socket reached EOF at a frame boundary.
+    async fn receive_header(&mut self) -> Result<Option<Header>, Error> {
+        self.sync_config();
+        loop {
+            let decoded = self.codec.decode_header(&self.buffer).map_err(|e| {
+                match http_status_line(&self.buffer) {
+                    Some(status_line) => {
+                        log::debug!("{}: http response in stream: {}", self.id, status_line);
+                        Error::HttpInjected { status_line }
+                    }
+                    // A length which does not fit in `usize` is not a malformed
+                    // frame, it is one this platform can not handle.
+                    None => match e {
+                        base::Error::PayloadTooLarge { actual, maximum } if actual > crate::as_u64(usize::MAX) => {
+                            log::debug!("{}: frame length {} exceeds usize::MAX", self.id, actual);
+                            Error::FrameTooLarge { size: actual, maximum: maximum as usize }
+                        }
+                        e => Error::Malformed {
+                            error: e,
+                            raw_prefix: self.buffer[.. std::cmp::min(self.buffer.len(), RAW_PREFIX_LEN)].to_vec()
+                        }
+                    }
+                }
+            })?;
+            match decoded {
+                Parsing::Done { value: header, offset } => {
+                    debug_assert!(offset <= MAX_HEADER_SIZE);
+                    self.buffer.advance(offset);
+                    self.traffic.incoming.record(&header, offset);
+                    if let Some(quota) = self.recv_quota {
+                        if self.traffic.incoming.total() > quota {
+                            log::debug!("{}: receive quota exceeded", self.id);
+                            return Err(self.close_for(CloseCause::QuotaExceeded, Error::QuotaExceeded).await)
+                        }
+                    }
+                    // Clients must mask their frames, servers must not (RFC 6455, 5.1).
+                    let expect_masked = self.mode.is_server() && !self.deviations.unmasked;
+                    if header.is_masked() && !expect_masked {
+                        if !self.deviations.accept_masked {
+                            if self.mode.is_server() {
+                                log::debug!("{}: received masked frame, but the client should not mask", self.id);
+                                return Err(Error::MaskingOptOutMismatch)
+                            }
+                            log::debug!("{}: received masked frame", self.id);
+                            return Err(Error::MaskedFrame)
+                        }
+                        log::warn!("{}: accepting masked {:?} frame", self.id, header.opcode())
+                    }
+                    if !header.is_masked() && expect_masked {
+                        if !self.deviations.accept_unmasked {
+                            log::debug!("{}: received unmasked frame", self.id);
+                            return Err(Error::UnmaskedFrame)
+                        }
+                        log::warn!("{}: accepting unmasked {:?} frame", self.id, header.opcode());
+                        self.unmasked_frames += 1
+                    }
+                    return Ok(Some(header))
+                }
+                Parsing::NeedMore(n) => {
+                    let max = std::cmp::max(n, READ_BUFFER_SIZE);
+                    let len = self.buffer.len();
+                    match crate::read(&mut self.reader, &mut self.buffer, max).await {
+                        Ok(()) =>
+                            if let Some(rate) = &mut self.read_rate {
+                                rate.update(Instant::now(), self.buffer.len() - len)?
+                            }
+                        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && self.buffer.is_empty() =>
+                            return Ok(None),
+                        Err(e) => return Err(e.into())
+                    }
+                }
+            }
+        }
+    }
+
+    /// Validate the text of a fragmented message as far as possible.
+    ///
+    /// `offset` points to the end of the text validated so far and is
+    /// advanced. An incomplete character at the end is left for the next
+    /// fragment to complete.
+    fn validate_fragment(&mut self, message: &[u8], offset: &mut usize) -> Result<(), Error> {
+        match self.utf8.validate(&message[*offset ..]) {
+            Validation::Valid => *offset = message.len(),
+            Validation::Incomplete { valid_up_to } => *offset += valid_up_to,
+            Validation::Invalid { .. } => {
+                log::debug!("{}: text message fragment is not valid utf-8", self.id);
+                return Err(self.start_draining(Error::InvalidText))
+            }
+        }
+        Ok(())
+    }
+
+    /// Take over changes made with [`Receiver::update_config`] or
+    /// [`Sender::update_config`].
+    fn sync_config(&mut self) {
+        if let Some(c) = self.config.changed(&mut self.config_version) {
+            log::trace!("{}: receiver config updated: {:?}", self.id, c);
+            self.max_message_size = c.max_message_size;
+            self.codec.set_max_data_size(c.max_frame_size);
+            self.max_pending_control = c.max_pending_control;
+            self.close_timeout = c.close_timeout
+        }
+    }
+
+    /// Close the connection because a limit has been exceeded and return
+    /// the given error.
+    async fn close_for(&mut self, cause: CloseCause, e: Error) -> Error {
+        self.set_closed();
+        let mut w = self.writer.lock().await;
+        if !w.is_closed {
+            let masked = self.mode.is_client() && w.masking;
+            let (code, reason) = w.close_message(cause);
+            if let Err(e) = w.close_fast(masked, code, &reason).await {
+                log::debug!("{}: failed to send close: {}", self.id, e)
+            }
+        }
+        e
+    }
+
+    /// Check the rate of data received after a data frame.
+    ///
+    /// See [`Builder::set_inbound_rate_limit`] for details.
+    async fn limit_rate(&mut self, header: &Header) -> Result<(), Error> {
+        let limit = match &mut self.inbound_rate {
+            Some(limit) => limit,
+            None => return Ok(())
+        };
+        let wait = match limit.take(Instant::now(), header.payload_len(), header.is_fin()) {
+            Some(wait) => wait,
+            None => return Ok(())
+        };
+        let timer = match &limit.policy {
+            RateLimitPolicy::Throttle(sleep) => sleep(wait),
+            RateLimitPolicy::Fail => {
+                log::debug!("{}: inbound rate limit exceeded", self.id);
+                return Err(self.close_for(CloseCause::RateLimited, Error::RateLimited).await)
+            }
+        };
+        log::trace!("{}: inbound rate limit exceeded, waiting {:?}", self.id, wait);
+        self.answer_buffered_pings().await?;
+        timer.await;
+        Ok(())
+    }
+
+    /// Switch to draining after a protocol error within a message.
+    ///
+    /// The error leaves frame boundaries intact, so subsequent frames can
+    /// still be decoded. While draining, control frames are processed as
+    /// usual, which allows receiving the remote's answer to our CLOSE, but
+    /// data frames are discarded without reassembly or validation.
+    fn start_draining(&mut self, e: Error) -> Error {
+        log::debug!("{}: draining after error: {}", self.id, e);
+        self.draining = true;
+        e
+    }
+
+    /// Skip over the payload data of a frame.
+    async fn discard_payload(&mut self, header: &Header) -> Result<(), Error> {
+        let buffered = std::cmp::min(header.payload_len(), self.buffer.len());
+        self.buffer.advance(buffered);
+        let mut remaining = header.payload_len() - buffered;
+        let mut scratch = [0; 1024];
+        while remaining > 0 {
+            let n = std::cmp::min(remaining, scratch.len());
+            read_exact(&mut self.reader, &mut self.read_rate, &mut scratch[.. n]).await?;
+            remaining -= n
+        }
+        Ok(())
+    }
+
+    /// Answer PINGs which have been received completely already.
+    ///
+    /// Does not read from the socket and stops at the first buffered frame
+    /// which is not a complete PING.
+    async fn answer_buffered_pings(&mut self) -> Result<(), Error> {
+        while !self.is_closed && self.is_ping_buffered() {
+            self.ctrl_buffer.clear();
+            let header = match self.receive_header().await? {
+                Some(header) => header,
+                None => break
+            };
+            self.read_buffer(&header).await?;
+            self.ctrl_buffer = self.buffer.split_to(header.payload_len());
+            base::Codec::apply_mask(&header, &mut self.ctrl_buffer);
+            self.record_control(&header);
+            self.on_control(&header).await?
+        }
+        Ok(())
+    }
+
+    /// Record the control frame in `ctrl_buffer` on the audit stream.
+    fn record_control(&mut self, header: &Header) {
+        if self.audit.is_some() {
+            let payload = mem::take(&mut self.ctrl_buffer);
+            self.record(AuditedFrame::Control, header, &payload);
+            self.ctrl_buffer = payload
+        }
+    }
+
+    /// Record a frame with unmasked payload data on the audit stream.
+    fn record(&mut self, tag: fn(Frame) -> AuditedFrame, header: &Header, payload: &[u8]) {
+        let audit = match &mut self.audit {
+            Some(audit) => audit,
+            None => return
+        };
+        // Frames are only recorded after the number of skipped ones, so
+        // that the order is kept.
+        let result = if audit.skipped > 0 {
+            audit.tx.try_send(AuditedFrame::Skipped(audit.skipped))
+        } else {
+            Ok(())
+        };
+        let result = result.and_then(|()| {
+            audit.skipped = 0;
+            audit.tx.try_send(tag(Frame::from_parts(header.clone(), payload.to_vec())))
+        });
+        match result {
+            Ok(()) => {}
+            Err(e) if e.is_full() => audit.skipped += 1,
+            Err(_) => {
+                log::debug!("{}: audit stream dropped", self.id);
+                self.audit = None
+            }
+        }
+    }
+
+    /// Has a complete PING frame been received already?
+    fn is_ping_buffered(&self) -> bool {
+        match self.codec.decode_header(&self.buffer) {
+            Ok(Parsing::Done { value, offset }) =>
+                value.opcode() == OpCode::Ping && self.buffer.len() - offset >= value.payload_len(),
+            _ => false
+        }
+    }
+
+    /// Read the complete payload data into the read buffer.
+    async fn read_buffer(&mut self, header: &Header) -> Result<(), Error> {
+        if header.payload_len() <= self.buffer.len() {
+            return Ok(())
+        }
+        let i = self.buffer.len();
+        let d = header.payload_len() - i;
+        self.buffer.resize(i + d, 0u8);
+        read_exact(&mut self.reader, &mut self.read_rate, &mut self.buffer[i ..]).await
+    }
+
+    /// Answer incoming control frames.
+    ///
+    /// The writer stays locked throughout, so that a CLOSE sent concurrently
+    /// by the [`Sender`] is either answered here or prevents an answer.
+    /// PONGs are queued before the writer is locked, so that the [`Sender`]
+    /// can send them in between the fragments of a message.
+    async fn on_control(&mut self, header: &Header) -> Result<(), Error> {
+        if header.opcode() == OpCode::Close {
+            self.discard_after_close()
+        }
+        if header.opcode() == OpCode::Ping {
+            let mut pongs = self.pending_pongs.lock().unwrap_or_else(|e| e.into_inner());
+            if pongs.len() >= self.max_pending_control {
+                log::debug!("{}: too many pending pongs, answering only the latest ping", self.id);
+                pongs.clear()
+            }
+            pongs.push_back(self.ctrl_buffer.to_vec())
+        }
+        let mut w = self.writer.lock().await;
+        if w.is_closed {
+            // We have sent our CLOSE already, so there is nothing to answer.
+            if header.opcode() == OpCode::Close {
+                self.is_closed = true;
+                self.close_hooks.notify()
+            }
+            return Ok(())
+        }
+        match header.opcode() {
+            OpCode::Ping => {
+                if self.is_ping_buffered() {
+                    return Ok(())
+                }
+                write_pongs(self.id, self.mode, &mut self.codec, &mut w).await?;
+                w.socket().flush().await.map_err(|e| w.write_error(e))
+            }
+            OpCode::Pong => Ok(()),
+            OpCode::Close => {
+                self.is_closed = true;
+                self.close_hooks.notify();
+                let (mut header, mut code) = close_answer(&self.ctrl_buffer, self.close_echo)?;
+                if self.is_data_after_close() || self.is_invalid_close() {
+                    code = Some(CloseCode::PROTOCOL_ERROR)
+                }
+                let mut unused = Vec::new();
+                if let Some(c) = code {
+                    let mut data = u16::from(c).to_be_bytes();
+                    let mut data = Storage::Unique(&mut data);
+                    write(self.id, self.mode, &mut self.codec, &mut w, &mut header, &mut data, &mut unused).await?
+                } else {
+                    let mut data = Storage::Unique(&mut []);
+                    write(self.id, self.mode, &mut self.codec, &mut w, &mut header, &mut data, &mut unused).await?
+                }
+                w.socket().flush().await.map_err(|e| w.write_error(e))?;
+                w.close().await
+            }
+            OpCode::Binary
+            | OpCode::Text
+            | OpCode::Continue
+            | OpCode::ReservedData(_)
+            | OpCode::ReservedControl(_) => Err(Error::UnexpectedOpCode(header.opcode()))
+        }
+    }
+
+    /// Apply all extensions to the given header and the internal message buffer.
+    async fn decode_with_extensions(&mut self, header: &mut Header, message: &mut Vec<u8>) -> Result<(), Error> {
+        if !self.has_extensions {
+            return Ok(())
+        }
+        self.extensions.lock().await.decode(self.id, header, message)
+    }
+
+    /// Get the statistics of all extensions used by this connection.
+    pub async fn extension_stats(&self) -> Vec<ExtensionStats> {
+        self.extensions.lock().await.stats.clone()
+    }
+
+    /// Get the traffic statistics of this connection.
+    ///
+    /// The statistics cover both directions and are the same as those
+    /// returned by the corresponding [`Sender`].
+    pub fn traffic_stats(&self) -> TrafficStats {
+        self.traffic.stats()
+    }
+
+    /// Change the [`RuntimeConfig`] of this connection.
+    ///
+    /// The change affects both halves of the connection and takes effect
+    /// at the next frame boundary, e.g. a frame being received is still
+    /// checked against the previous limits.
+    pub fn update_config(&self, f: impl FnOnce(&mut RuntimeConfig)) {
+        self.config.update(f)
+    }
+}
+
+impl<T> Sender<T> {
+    /// The ID of this connection, which the [`Receiver`] shares.
+    pub fn id(&self) -> u64 {
+        self.id.0
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Sender<T> {
+    /// Send a text value over the websocket connection.
+    pub async fn send_text(&mut self, data: impl AsRef<str>) -> Result<(), Error> {
+        let mut header = Header::new(OpCode::Text);
+        self.send_frame(&mut header, &mut Storage::Shared(data.as_ref().as_bytes())).await
+    }
+
+    /// Send some binary data over the websocket connection.
+    pub async fn send_binary(&mut self, data: impl AsRef<[u8]>) -> Result<(), Error> {
+        let mut header = Header::new(OpCode::Binary);
+        self.send_frame(&mut header, &mut Storage::Shared(data.as_ref())).await
+    }
+
+    /// Send some binary data over the websocket connection.
+    ///
+    /// In contrast to [`Sender::send_binary`] the provided data is modified
+    /// in-place, e.g. if masking is necessary.
+    pub async fn send_binary_mut(&mut self, mut data: impl AsMut<[u8]>) -> Result<(), Error> {
+        let mut header = Header::new(OpCode::Binary);
+        self.send_frame(&mut header, &mut Storage::Unique(data.as_mut())).await
+    }
+
+    /// Send a piece of a text message.
+    ///
+    /// See [`Sender::send_binary_part`].
+    pub async fn send_text_part(&mut self, data: impl AsRef<str>, is_last: bool) -> Result<(), Error> {
+        self.send_part(OpCode::Text, data.as_ref().as_bytes(), is_last).await
+    }
+
+    /// Send a piece of a binary message.
+    ///
+    /// Messages which are too large to be held in memory, or whose size is
+    /// not known in advance, can be sent in pieces. Each piece is sent as a
+    /// frame of its own, the last one with `is_last` set. Other messages
+    /// can only be sent once the message is complete, until then they fail
+    /// with [`Error::UnexpectedOpCode`], as do pieces of a text message.
+    ///
+    /// Neither extensions nor the fragment size apply, so the message is
+    /// sent uncompressed. A piece larger than the max. frame size set with
+    /// [`Builder::set_max_send_frame_size`] fails with
+    /// [`Error::FrameTooLarge`].
+    pub async fn send_binary_part(&mut self, data: impl AsRef<[u8]>, is_last: bool) -> Result<(), Error> {
+        self.send_part(OpCode::Binary, data.as_ref(), is_last).await
+    }
+
+    /// Ping the remote end.
+    pub async fn send_ping(&mut self, data: ByteSlice125<'_>) -> Result<(), Error> {
+        let mut header = Header::new(OpCode::Ping);
+        self.write(&mut header, &mut Storage::Shared(data.as_ref())).await
+    }
+
+    /// Send an unsolicited Pong to the remote.
+    ///
+    /// The remote does not answer unsolicited PONGs, so they can serve as a
+    /// unidirectional heartbeat (see also [`KeepAlive::Pong`]). Like all
+    /// frames, a PONG is written as a whole and may be sent in between the
+    /// fragments of a message.
+    pub async fn send_pong(&mut self, data: ByteSlice125<'_>) -> Result<(), Error> {
+        let mut header = Header::new(OpCode::Pong);
+        self.write(&mut header, &mut Storage::Shared(data.as_ref())).await
+    }
+
+    /// Send a PING, PONG or CLOSE frame with the given payload.
+    ///
+    /// A CLOSE frame closes the connection like [`Sender::close`] does.
+    pub async fn send_control(&mut self, payload: ControlPayload) -> Result<(), Error> {
+        if payload.opcode() == OpCode::Close {
+            log::trace!("{}: closing connection", self.id);
+            return self.close_with(payload).await
+        }
+        let (opcode, mut data) = payload.into_parts();
+        let mut header = Header::new(opcode);
+        self.write(&mut header, &mut Storage::Unique(&mut data)).await
+    }
+
+    /// Send an [`Outgoing`] message.
+    pub async fn send(&mut self, message: Outgoing) -> Result<(), Error> {
+        match message {
+            Outgoing::Text(s) => self.send_text(s).await,
+            Outgoing::Binary(b) => self.send_binary(b).await
+        }
+    }
+
+    /// Send a frame whose payload has already been masked.
+    ///
+    /// Header and payload are written as they are, i.e. the payload is not
+    /// masked again and neither extensions nor a frame interceptor are
+    /// applied. This allows proxies to forward frames received from a
+    /// client without unmasking and re-masking them.
+    ///
+    /// The frame header must be masked if and only if this sender masks its
+    /// frames, otherwise [`Error::MaskedFrame`] or [`Error::UnmaskedFrame`]
+    /// is returned. It is up to the caller to ensure that the payload has
+    /// been masked with the mask given in the header and that the frame is
+    /// valid at this point of the connection, e.g. it must not be sent while
+    /// a fragmented message is in progress. Otherwise the remote receives
+    /// garbage or protocol violations.
+    ///
+    /// The payload of a text frame is checked to be valid UTF-8 unless
+    /// disabled with [`Builder::set_validate_outgoing_text`]. If it is not,
+    /// [`Error::InvalidUtf8Outgoing`] is returned and nothing is sent.
+    pub async fn send_premasked(&mut self, frame: &Frame) -> Result<(), Error> {
+        let mut w = self.writer.lock().await;
+
+        if w.is_closed {
+            log::debug!("{}: can not send, connection is closed", self.id);
+            return Err(w.closed())
+        }
+
+        let expect_masked = self.mode.is_client() && w.masking;
+        if frame.header().is_masked() && !expect_masked {
+            return Err(Error::MaskedFrame)
+        }
+        if !frame.header().is_masked() && expect_masked {
+            return Err(Error::UnmaskedFrame)
+        }
+
+        if frame.header().opcode().is_control() {
+            crate::data::check_control_len(frame.payload().len())?
+        }
+
+        if let Some(maximum) = self.max_send_frame_size {
+            if frame.payload().len() > maximum {
+                return Err(Error::FrameTooLarge { size: crate::as_u64(frame.payload().len()), maximum })
+            }
+        }
+
+        if self.validate_text && frame.header().opcode() == OpCode::Text {
+            check_text(self.id, &mut self.mask_buffer, frame)?
+        }
+
+        if !frame.header().opcode().is_control() {
+            write_pongs(self.id, self.mode, &mut self.codec, &mut w).await?
+        }
+
+        let mut header = frame.header().clone();
+        header.set_payload_len(frame.payload().len());
+
+        log::trace!("{}: send pre-masked: {}", self.id, header);
+
+        let header_bytes = self.codec.encode_header(&header);
+        w.check_send_quota(&header, header_bytes.len() + frame.payload().len(), expect_masked).await?;
+        w.traffic.outgoing.record(&header, header_bytes.len());
+        let total = header_bytes.len() + frame.payload().len();
+        let mut written = 0;
+        w.last_write = Instant::now();
+        w.write_all(header_bytes, &mut written, total).await?;
+        w.write_all(frame.payload(), &mut written, total).await
+    }
+
+    /// Send a frame masked with the given key.
+    ///
+    /// The payload is masked with `key`, which is set in the frame header,
+    /// instead of a key from the sender's random number generator. This is
+    /// meant for tests which need deterministic output and for proxies which
+    /// must preserve the keys of the frames they forward.
+    ///
+    /// Otherwise this is [`Sender::send_premasked`], i.e. neither extensions
+    /// nor a frame interceptor are applied and the same checks are made.
+    /// Only senders which mask their frames can send a frame with a mask,
+    /// others return [`Error::MaskedFrame`].
+    pub async fn send_frame_with_mask(&mut self, frame: Frame, key: u32) -> Result<(), Error> {
+        let (mut header, mut payload) = frame.into_parts();
+        header.set_masked(true).set_mask(key);
+        base::Codec::apply_mask(&header, &mut payload);
+        self.send_premasked(&Frame::from_parts(header, payload)).await
+    }
+
+    /// Share this sender between multiple producers.
+    ///
+    /// Returns a cloneable [`SharedSender`] which queues messages in a
+    /// channel of the given capacity, and a future which sends the queued
+    /// messages in order. The future needs to be polled to completion, e.g.
+    /// by spawning it as a task. It completes with this `Sender` once all
+    /// `SharedSender`s have been dropped, or with the first error.
+    ///
+    /// Producers have to wait when the channel is full, so a slow connection
+    /// applies backpressure to all of them.
+    pub fn into_shared(self, capacity: usize) -> (SharedSender, impl Future<Output = Result<Self, Error>>) {
+        let (tx, mut rx) = mpsc::channel(capacity);
+        let close_hooks = self.close_hooks.clone();
+        let mut notify = NotifyOnDrop(Some(self.close_hooks.clone()));
+        let mut sender = self;
+        let future = async move {
+            while let Some(queued) = rx.next().await {
+                sender.send_queued(queued).await?;
+                // Send what is queued already before flushing.
+                while let Some(Some(queued)) = rx.next().now_or_never() {
+                    sender.send_queued(queued).await?
+                }
+                sender.flush().await?
+            }
+            // All producers are gone, but the connection is still usable.
+            notify.0.take();
+            Ok(sender)
+        };
+        (SharedSender { tx, close_hooks }, future)
+    }
+
+    /// Send messages queued by a [`SharedSender`] unless they have expired.
+    async fn send_queued(&mut self, queued: Queued) -> Result<(), Error> {
+        if let Some(deadline) = queued.deadline {
+            if Instant::now() > deadline {
+                log::trace!("{}: dropping expired message", self.id);
+                if let Some(tx) = queued.delivery {
+                    let _ = tx.send(Delivery::Expired);
+                }
+                return Ok(())
+            }
+        }
+        for message in &queued.messages {
+            match message.get() {
+                Outgoing::Text(s) => self.send_text(s).await?,
+                Outgoing::Binary(b) => self.send_binary(b).await?
+            }
+        }
+        if let Some(tx) = queued.delivery {
+            let _ = tx.send(Delivery::Sent);
+        }
+        Ok(())
+    }
+
+    /// Send the heartbeat configured with [`Builder::set_keep_alive`] if
+    /// nothing has been sent for the configured duration.
+    ///
+    /// Returns the time until the next heartbeat is due, or `None` if
+    /// heartbeats are off. This crate does not depend on a timer, so the
+    /// caller is expected to invoke this method again after sleeping for
+    /// the returned duration.
+    pub async fn heartbeat(&mut self) -> Result<Option<Duration>, Error> {
+        if let Some(c) = self.config.changed(&mut self.config_version) {
+            self.keep_alive = c.keep_alive;
+            self.keep_alive_payload = c.keep_alive_payload
+        }
+        let (opcode, interval) = match self.keep_alive {
+            KeepAlive::Off => return Ok(None),
+            KeepAlive::Ping(d) => (OpCode::Ping, d),
+            KeepAlive::Pong(d) => (OpCode::Pong, d)
+        };
+        let idle = self.writer.lock().await.last_write.elapsed();
+        if idle < interval {
+            return Ok(Some(interval - idle))
+        }
+        log::trace!("{}: sending heartbeat after {:?}", self.id, idle);
+        let mut header = Header::new(opcode);
+        let payload = mem::take(&mut self.keep_alive_payload);
+        let result = self.write(&mut header, &mut Storage::Shared(&payload)).await;
+        self.keep_alive_payload = payload;
+        result?;
+        self.flush().await?;
+        Ok(Some(interval))
+    }
+
+    /// Flush the socket buffer.
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        log::trace!("{}: flushing connection", self.id);
+        let mut w = self.writer.lock().await;
+        w.socket().flush().await.map_err(|e| w.write_error(e))
+    }
+
+    /// Send a close message and close the connection.
+    ///
+    /// Only the writing side of the connection is closed. The [`Receiver`]
+    /// continues to receive messages until the remote answers with its own
+    /// CLOSE frame, which is returned as `Incoming::Closed`.
+    ///
+    /// At most one CLOSE frame is sent per connection. If one has been sent
+    /// already, e.g. by the [`Receiver`] in answer to the remote's CLOSE,
+    /// this does nothing.
+    pub async fn close(&mut self) -> Result<(), Error> {
+        log::trace!("{}: closing connection", self.id);
+        self.close_with(ControlPayload::close(CloseCode::NORMAL, "")?).await
+    }
+
+    /// Send a CLOSE frame with the given status code and no reason and
+    /// close the connection.
+    ///
+    /// Like [`Sender::close`], but the frame is written as pre-encoded
+    /// bytes, bypassing extensions and the frame interceptor. This keeps
+    /// the cost per connection low when many connections are closed at
+    /// once, e.g. with [`CloseCode::POLICY_VIOLATION`] or
+    /// [`CloseCode::TRY_AGAIN_LATER`] when shedding load.
+    pub async fn close_fast(&mut self, code: CloseCode) -> Result<(), Error> {
+        if !code.is_allowed_on_wire() {
+            return Err(InvalidControlPayload::CloseCode(code).into())
+        }
+        let mut w = self.writer.lock().await;
+        if w.is_closed {
+            log::trace!("{}: close frame has been sent already", self.id);
+            return Ok(())
+        }
+        log::trace!("{}: send close: {}", self.id, code);
+        let masked = self.mode.is_client() && w.masking;
+        w.close_fast(masked, code, "").await
+    }
+
+    /// Close the connection in response to an error.
+    ///
+    /// The close code sent is derived from the error (see the `From<&Error>`
+    /// impl of [`CloseCode`]), e.g. a message exceeding the maximum size is
+    /// answered with [`CloseCode::MESSAGE_TOO_BIG`], unless changed with
+    /// [`Builder::set_close_messages`]. If the error implies that the
+    /// connection is broken, no close message is sent.
+    pub async fn close_on_error(&mut self, e: &Error) -> Result<(), Error> {
+        log::trace!("{}: closing connection due to error: {}", self.id, e);
+        if let Some(cause) = e.close_cause() {
+            let (code, reason) = self.writer.lock().await.close_message(cause);
+            self.close_with(ControlPayload::close(code, &reason)?).await
+        } else {
+            self.writer.lock().await.close().await
+        }
+    }
+
+    /// Send a close message with the given payload and close the connection.
+    async fn close_with(&mut self, payload: ControlPayload) -> Result<(), Error> {
+        let (opcode, mut data) = payload.into_parts();
+        debug_assert_eq!(OpCode::Close, opcode);
+        let mut header = Header::new(OpCode::Close);
+        let mut w = self.writer.lock().await;
+        if w.is_closed {
+            log::trace!("{}: close frame has been sent already", self.id);
+            return Ok(())
+        }
+        let mut data = Storage::Unique(&mut data);
+        write(self.id, self.mode, &mut self.codec, &mut w, &mut header, &mut data, &mut self.mask_buffer).await?;
+        w.socket().flush().await.map_err(|e| w.write_error(e))?;
+        w.close().await
+    }
+
+    /// Send a piece of a message as a frame of its own.
+    async fn send_part(&mut self, opcode: OpCode, data: &[u8], is_last: bool) -> Result<(), Error> {
+        let frame_opcode = match self.part {
+            None => opcode,
+            Some(oc) if oc == opcode => OpCode::Continue,
+            Some(_) => return Err(Error::UnexpectedOpCode(opcode))
+        };
+        if let Some(maximum) = self.max_send_frame_size {
+            if data.len() > maximum {
+                return Err(Error::FrameTooLarge { size: crate::as_u64(data.len()), maximum })
+            }
+        }
+        let mut header = Header::new(frame_opcode);
+        header.set_fin(is_last);
+        self.write(&mut header, &mut Storage::Shared(data)).await?;
+        self.part = if is_last { None } else { Some(opcode) };
+        Ok(())
+    }
+
+    /// Send arbitrary websocket frames.
+    ///
+    /// Before sending, extensions will be applied to header and payload data.
+    async fn send_frame(&mut self, header: &mut Header, data: &mut Storage<'_>) -> Result<(), Error> {
+        if self.part.is_some() {
+            log::debug!("{}: can not send {}, a message is being sent in parts", self.id, header.opcode());
+            return Err(Error::UnexpectedOpCode(header.opcode()))
+        }
+        // Checked before extensions are applied, which may change their state.
+        if let (None, Some(maximum)) = (self.fragment_size, self.max_send_frame_size) {
+            let size = data.as_ref().len();
+            if size > maximum {
+                return Err(Error::FrameTooLarge { size: crate::as_u64(size), maximum })
+            }
+        }
+        if self.has_extensions {
+            self.extensions.lock().await.encode(self.id, header, data)?
+        }
+        let fragment_size = match (self.fragment_size, self.max_send_frame_size) {
+            (Some(n), Some(m)) => Some(std::cmp::min(n, m)),
+            (n, m) => n.or(m)
+        };
+        match fragment_size {
+            Some(n) if data.as_ref().len() > n => self.write_fragments(header, data, n).await,
+            _ => self.write(header, data).await
+        }
+    }
+
+    /// Write a message as fragments of at most `size` payload bytes.
+    ///
+    /// The writer is locked per fragment, so queued PONGs are sent in between.
+    async fn write_fragments(&mut self, header: &mut Header, data: &mut Storage<'_>, size: usize) -> Result<(), Error> {
+        let len = data.as_ref().len();
+        let mut start = 0;
+        while start < len {
+            let end = std::cmp::min(start + size, len);
+            header.set_fin(end == len);
+            let mut fragment = match data {
+                Storage::Shared(d) => Storage::Shared(&d[start .. end]),
+                Storage::Unique(d) => Storage::Unique(&mut d[start .. end]),
+                Storage::Owned(d) => Storage::Unique(&mut d[start .. end])
+            };
+            self.write(header, &mut fragment).await?;
+            *header = Header::new(OpCode::Continue);
+            start = end
+        }
+        Ok(())
+    }
+
+    /// Get the statistics of all extensions used by this connection.
+    pub async fn extension_stats(&self) -> Vec<ExtensionStats> {
+        self.extensions.lock().await.stats.clone()
+    }
+
+    /// Get the traffic statistics of this connection.
+    ///
+    /// The statistics cover both directions and are the same as those
+    /// returned by the corresponding [`Sender`].
+    pub fn traffic_stats(&self) -> TrafficStats {
+        self.traffic.stats()
+    }
+
+    /// Change the [`RuntimeConfig`] of this connection.
+    ///
+    /// See [`Receiver::update_config`].
+    pub fn update_config(&self, f: impl FnOnce(&mut RuntimeConfig)) {
+        self.config.update(f)
+    }
+
+    /// Write final header and payload data to socket.
+    ///
+    /// The data will be masked if necessary.
+    /// No extensions will be applied to header and payload data.
+    async fn write(&mut self, header: &mut Header, data: &mut Storage<'_>) -> Result<(), Error> {
+        let mut w = self.writer.lock().await;
+        if !header.opcode().is_control() {
+            write_pongs(self.id, self.mode, &mut self.codec, &mut w).await?
+        }
+        write(self.id, self.mode, &mut self.codec, &mut w, header, data, &mut self.mask_buffer).await
+    }
+}
+
+/// Write header and payload data to socket.
+///
+/// Once a CLOSE frame has been written, nothing else can be sent.
+async fn write<T: AsyncWrite + Unpin>
+    ( id: Id
+    , mode: Mode
+    , codec: &mut base::Codec
+    , w: &mut Writer<T>
+    , header: &mut Header
+    , data: &mut Storage<'_>
+    , mask_buffer: &mut Vec<u8>
+    ) -> Result<(), Error>
+{
+    if w.is_closed {
+        log::debug!("{}: can not send, connection is closed", id);
+        return Err(w.closed())
+    }
+
+    let masked = mode.is_client() && w.masking;
+    if masked {
+        header.set_masked(true);
+        header.set_mask(w.rng.gen());
+    }
+    header.set_payload_len(data.as_ref().len());
+
+    if let Some(Interceptor(f)) = &mut w.interceptor {
+        let mut frame = Frame::from_parts(header.clone(), data.as_ref().to_vec());
+        f(&mut frame);
+        let (h, payload) = frame.into_parts();
+        *header = h;
+        header.set_masked(masked);
+        header.set_payload_len(payload.len());
+        *data = Storage::Owned(payload)
+    }
+
+    // Control frames with larger payloads would be rejected by the remote.
+    if header.opcode().is_control() {
+        crate::data::check_control_len(data.as_ref().len())?
+    }
+
+    log::trace!("{}: send: {}", id, header);
+
+    if header.opcode() == OpCode::Close {
+        w.is_closed = true;
+        w.close_hooks.notify();
+        if let [a, b, ..] = data.as_ref() {
+            w.close_code = Some(CloseCode::from(u16::from_be_bytes([*a, *b])))
+        }
+    }
+
+    // Control frames are small enough to be encoded as a whole on the stack
+    // and written at once.
+    if header.opcode().is_control() {
+        let mut frame = [0; base::MAX_CONTROL_FRAME_SIZE];
+        let len = codec.encode_control_frame(header, data.as_ref(), &mut frame)?;
+        w.check_send_quota(header, len, masked).await?;
+        w.traffic.outgoing.record(header, len - data.as_ref().len());
+        w.last_write = Instant::now();
+        return w.write_all(&frame[.. len], &mut 0, len).await
+    }
+
+    let header_bytes = codec.encode_header(header);
+
+    w.check_send_quota(header, header_bytes.len() + data.as_ref().len(), masked).await?;
+
+    w.traffic.outgoing.record(header, header_bytes.len());
+    w.last_write = Instant::now();
+    let total = header_bytes.len() + data.as_ref().len();
+    let mut written = 0;
+    w.write_all(header_bytes, &mut written, total).await?;
+
+    // Large payloads are masked and written in slices with other tasks
+    // getting a chance to run in between. The writer stays locked, so
+    // control frames wait for the end of this frame.
+    if !header.is_masked() {
+        for (i, slice) in data.as_ref().chunks(WRITE_SLICE_SIZE).enumerate() {
+            if i > 0 {
+                yield_now().await
+            }
+            w.write_all(slice, &mut written, total).await?
+        }
+        return Ok(())
+    }
+
+    match data {
+        Storage::Shared(bytes) => {
+            for (i, slice) in bytes.chunks(WRITE_SLICE_SIZE).enumerate() {
+                if i > 0 {
+                    yield_now().await
+                }
+                mask_buffer.clear();
+                mask_buffer.extend_from_slice(slice);
+                base::Codec::apply_mask(header, mask_buffer);
+                w.write_all(mask_buffer, &mut written, total).await?
+            }
+        }
+        Storage::Unique(bytes) => w.write_masked(header, bytes, &mut written, total).await?,
+        Storage::Owned(bytes) => w.write_masked(header, bytes, &mut written, total).await?
+    }
+    Ok(())
+}
+
+/// Let other tasks run before continuing.
+async fn yield_now() {
+    let mut yielded = false;
+    future::poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(())
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await
+}
+
+/// Send the PONGs queued by the [`Receiver`].
+///
+/// Nothing is sent once a CLOSE frame has been written.
+async fn write_pongs<T: AsyncWrite + Unpin>
+    ( id: Id
+    , mode: Mode
+    , codec: &mut base::Codec
+    , w: &mut Writer<T>
+    ) -> Result<(), Error>
+{
+    let mut unused = Vec::new();
+    loop {
+        let payload = {
+            let mut pongs = w.pending_pongs.lock().unwrap_or_else(|e| e.into_inner());
+            if w.is_closed {
+                pongs.clear()
+            }
+            pongs.pop_front()
+        };
+        let mut payload = match payload {
+            Some(p) => p,
+            None => return Ok(())
+        };
+        let mut answer = Header::new(OpCode::Pong);
+        write(id, mode, codec, w, &mut answer, &mut Storage::Unique(&mut payload), &mut unused).await?
+    }
+}
+
+/// Check that the payload of a text frame is valid UTF-8.
+///
+/// The text of a fragment may end with an incomplete character.
+fn check_text(id: Id, mask_buffer: &mut Vec<u8>, frame: &Frame) -> Result<(), Error> {
+    let payload =
+        if frame.header().is_masked() {
+            mask_buffer.clear();
+            mask_buffer.extend_from_slice(frame.payload());
+            base::Codec::apply_mask(frame.header(), mask_buffer);
+            &mask_buffer[..]
+        } else {
+            frame.payload()
+        };
+    match utf8::Std.validate(payload) {
+        Validation::Valid => Ok(()),
+        Validation::Incomplete {..} if !frame.header().is_fin() => Ok(()),
+        _ => {
+            log::debug!("{}: can not send text frame, payload is not valid utf-8", id);
+            Err(Error::InvalidUtf8Outgoing)
+        }
+    }
+}
+
+/// Check that the next piece of a text message continues valid UTF-8.
+///
+/// `tail` holds the bytes of an incomplete character at the end of the
+/// previous piece and is updated for the next one.
+fn validate_part(utf8: &(dyn Validator + Send), tail: &mut Vec<u8>, text: &[u8], is_last: bool) -> bool {
+    let validation =
+        if tail.is_empty() {
+            utf8.validate(text)
+        } else {
+            tail.extend_from_slice(text);
+            utf8.validate(tail)
+        };
+    match validation {
+        Validation::Valid => {
+            tail.clear();
+            true
+        }
+        Validation::Incomplete { valid_up_to } if !is_last => {
+            if tail.is_empty() {
+                tail.extend_from_slice(&text[valid_up_to ..])
+            } else {
+                tail.drain(.. valid_up_to);
+            }
+            true
+        }
+        _ => false
+    }
+}
+
+/// Find an HTTP response status line in bytes which failed to decode.
+///
+/// Intermediaries sometimes inject an HTTP error response into a websocket
+/// stream, e.g. when the upstream connection dies. The response may start
+/// at a frame boundary or anywhere in a frame header which has been cut off.
+fn http_status_line(bytes: &[u8]) -> Option<String> {
+    const PREFIX: &[u8] = b"HTTP/1.";
+    const MAX_LEN: usize = 256;
+    let start = bytes.windows(PREFIX.len()).position(|w| w == PREFIX)?;
+    let line = &bytes[start .. std::cmp::min(bytes.len(), start + MAX_LEN)];
+    let end = line.iter().position(|&b| b == b'\r' || b == b'\n').unwrap_or(line.len());
+    Some(String::from_utf8_lossy(&line[.. end]).into_owned())
+}
+
+/// Forward data frames from `src` to `dst` until `src` is closed.
+///
+/// Intended for intermediaries like proxies which act as server towards one
+/// end and as client towards the other. Messages are not reassembled, i.e.
+/// fragmentation and reserved bits are preserved, and frames are masked as
+/// required by the mode of `dst`. Control frames are handled on each side
+/// separately (see [`Receiver::receive_frame`]). Once the remote of `src`
+/// has closed the connection, `dst` is closed with the same status code and
+/// reason, if any. To relay both directions, run two relays concurrently.
+///
+/// **Note**: Frames with reserved bits set are only accepted by `src` and
+/// the remote of `dst` if both sides have negotiated the same extensions.
+pub async fn relay<A, B>(src: &mut Receiver<A>, dst: &mut Sender<B>) -> Result<(), Error>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin
+{
+    let mut payload = Vec::new();
+    while let Some(mut header) = src.receive_frame(&mut payload).await? {
+        dst.write(&mut header, &mut Storage::Unique(&mut payload)).await?;
+        dst.flush().await?;
+        payload.clear()
+    }
+    match src.close_reason() {
+        Some(reason) if reason.code().is_allowed_on_wire() => {
+            let payload = ControlPayload::close(reason.code(), reason.raw_reason().unwrap_or(""))?;
+            dst.close_with(payload).await
+        }
+        _ => dst.close().await
+    }
+}
+
+/// Default max. number of connections [`Shutdown`] closes concurrently.
+const DEFAULT_SHUTDOWN_CONCURRENCY: usize = 64;
+
+/// The close reason sent by [`Shutdown`].
+const SHUTDOWN_REASON: &str = "server shutting down";
+
+/// Closes many connections at once, e.g. when a server shuts down.
+///
+/// Connections are registered with [`Shutdown::add`]. [`Shutdown::run`]
+/// sends a CLOSE with code 1001 (going away) on each of them and waits for
+/// the remote to answer with its own CLOSE, at most for the close timeout
+/// of the connection (see [`Builder::set_close_timeout`]). Only a limited
+/// number of connections are closed concurrently (see
+/// [`Shutdown::set_concurrency_limit`]).
+///
+/// Also available as `handshake::server::Shutdown`.
+#[derive(Debug)]
+pub struct Shutdown<T> {
+    connections: Vec<(Sender<T>, Receiver<T>)>,
+    concurrency: usize
+}
+
+impl<T> Default for Shutdown<T> {
+    fn default() -> Self {
+        Shutdown { connections: Vec::new(), concurrency: DEFAULT_SHUTDOWN_CONCURRENCY }
+    }
+}
+
+impl<T> Extend<(Sender<T>, Receiver<T>)> for Shutdown<T> {
+    fn extend<I: IntoIterator<Item = (Sender<T>, Receiver<T>)>>(&mut self, iter: I) {
+        self.connections.extend(iter)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Shutdown<T> {
+    /// Create a new, empty `Shutdown`.
+    pub fn new() -> Self {
+        Shutdown::default()
+    }
+
+    /// Set the max. number of connections which are closed concurrently.
+    ///
+    /// The minimum is 1. Default is 64.
+    pub fn set_concurrency_limit(&mut self, max: usize) {
+        self.concurrency = std::cmp::max(1, max)
+    }
+
+    /// Register a connection.
+    pub fn add(&mut self, sender: Sender<T>, receiver: Receiver<T>) {
+        self.connections.push((sender, receiver))
+    }
+
+    /// The number of registered connections.
+    pub fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// Are there no registered connections?
+    pub fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    /// Close all registered connections.
+    ///
+    /// Messages the remotes send before answering our CLOSE are discarded.
+    /// A connection whose remote does not answer within its close timeout is
+    /// dropped, as are all connections not closed yet once `deadline`
+    /// completes. The returned [`ShutdownReport`] tells how many connections
+    /// have been closed cleanly.
+    ///
+    /// As with [`Receiver::await_close`], `sleep` is called with the close
+    /// timeout to create a timer for each connection.
+    pub async fn run<S, F>(self, deadline: impl Future<Output = ()>, sleep: S) -> ShutdownReport
+    where
+        S: Fn(Duration) -> F,
+        F: Future<Output = ()>
+    {
+        let total = self.connections.len();
+        let mut clean = 0;
+        {
+            let sleep = &sleep;
+            let closing = stream::iter(self.connections)
+                .map(|(s, r)| close_gracefully(s, r, sleep))
+                .buffer_unordered(self.concurrency)
+                .for_each(|is_clean| {
+                    if is_clean {
+                        clean += 1
+                    }
+                    future::ready(())
+                });
+            futures::pin_mut!(closing, deadline);
+            future::select(closing, deadline).await;
+        }
+        log::debug!("shutdown: {} of {} connections closed cleanly", clean, total);
+        ShutdownReport { clean, forced: total - clean }
+    }
+}
+
+/// Send a CLOSE and wait for the remote's CLOSE until the close timeout.
+///
+/// Returns `true` if the close handshake has been completed.
+async fn close_gracefully<T, S, F>(mut sender: Sender<T>, mut receiver: Receiver<T>, sleep: &S) -> bool
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    S: Fn(Duration) -> F,
+    F: Future<Output = ()>
+{
+    let result = match ControlPayload::close(CloseCode::GOING_AWAY, SHUTDOWN_REASON) {
+        Ok(payload) => sender.close_with(payload).await,
+        Err(e) => Err(e.into())
+    };
+    if let Err(e) = result {
+        log::debug!("{}: failed to send close: {}", sender.id, e);
+        return false
+    }
+    match receiver.await_close(sleep).await {
+        Ok(_) => true,
+        Err(e) => {
+            log::debug!("{}: failed to receive close: {}", receiver.id, e);
+            false
+        }
+    }
+}
+
+/// The outcome of [`Shutdown::run`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    clean: usize,
+    forced: usize
+}
+
+impl ShutdownReport {
+    /// The number of connections which completed the close handshake.
+    pub fn clean(&self) -> usize {
+        self.clean
+    }
+
+    /// The number of connections which have been dropped without completing
+    /// the close handshake, because of an error or the deadline.
+    pub fn forced(&self) -> usize {
+        self.forced
+    }
+}
+
+/// A [`Sender`]/[`Receiver`] pair driven by polling.
+///
+/// Allows integrating a connection into hand-written futures which can not
+/// await the `async` methods of [`Sender`] and [`Receiver`]. An operation in
+/// progress owns its half of the connection, hence `T: Send + 'static`.
+pub struct Connection<T> {
+    id: Id,
+    mode: Mode,
+    /// Has the connection been closed or failed?
+    closed: bool,
+    traffic: Arc<Traffic>,
+    receiver: Half<Receiver<T>, Result<Option<Message>, Error>>,
+    sender: Half<Sender<T>, Result<(), Error>>,
+    /// Is the operation in progress on the sender a flush?
+    flushing: bool,
+    /// Is the operation in progress on the receiver answering buffered PINGs?
+    answering: bool,
+    /// Is reading paused (see [`Connection::pause_reading`])?
+    paused: bool,
+    /// The task to wake up when reading is resumed.
+    resume: Option<Waker>
+}
+
+/// A received message type and its payload.
+type Message = (Data, Vec<u8>);
+
+/// A half of a [`Connection`].
+enum Half<H, R> {
+    /// No operation is in progress.
+    Idle(H),
+    /// An operation is in progress and gives back the half when done.
+    Busy(BoxFuture<'static, (H, R)>),
+    /// The half has been lost, e.g. because an operation panicked.
+    Gone
+}
+
+impl<H, R> Half<H, R> {
+    fn state(&self) -> &'static str {
+        match self {
+            Half::Idle(_) => "idle",
+            Half::Busy(_) => "busy",
+            Half::Gone => "gone"
+        }
+    }
+}
+
+impl<T> fmt::Debug for Connection<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("id", &self.id.0)
+            .field("receiver", &self.receiver.state())
+            .field("sender", &self.sender.state())
+            .finish()
+    }
+}
+
+/// Shows the connection ID, whether the connection is still open and its
+/// mode, e.g. `ws-conn-42(open, client)`.
+impl<T> fmt::Display for Connection<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = if self.closed { "closed" } else { "open" };
+        let mode = if self.mode.is_client() { "client" } else { "server" };
+        write!(f, "{}({}, {})", self.id, state, mode)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Connection<T> {
+    /// Create a new `Connection` from the given [`Sender`]/[`Receiver`] pair.
+    pub fn new(sender: Sender<T>, receiver: Receiver<T>) -> Self {
+        Connection {
+            id: receiver.id,
+            mode: receiver.mode,
+            closed: false,
+            traffic: receiver.traffic.clone(),
+            receiver: Half::Idle(receiver),
+            sender: Half::Idle(sender),
+            flushing: false,
+            answering: false,
+            paused: false,
+            resume: None
+        }
+    }
+
+    /// Create a new `Connection` with default settings from a socket.
+    ///
+    /// `buffer` holds bytes which have been read from the socket already,
+    /// e.g. the beginning of a first frame received along with the
+    /// handshake. They are decoded before anything else is read. A
+    /// [`Builder`] allows for other settings.
+    pub fn from_parts(socket: T, mode: Mode, buffer: BytesMut) -> Self {
+        let mut builder = Builder::new(socket, mode);
+        builder.set_buffer(buffer);
+        let (sender, receiver) = builder.finish();
+        Connection::new(sender, receiver)
+    }
+
+    /// The ID of this connection, see [`Builder::set_id`].
+    pub fn id(&self) -> u64 {
+        self.id.0
+    }
+
+    /// Get the traffic statistics of this connection.
+    pub fn stats(&self) -> TrafficStats {
+        self.traffic.stats()
+    }
+
+    /// Get back the [`Sender`]/[`Receiver`] pair.
+    ///
+    /// Returns `None` if an operation is still in progress.
+    pub fn into_parts(self) -> Option<(Sender<T>, Receiver<T>)> {
+        match (self.sender, self.receiver) {
+            (Half::Idle(s), Half::Idle(r)) => Some((s, r)),
+            _ => None
+        }
+    }
+
+    /// Get back the socket and the bytes read from it but not decoded yet.
+    ///
+    /// The inverse of [`Connection::from_parts`]. The connection is not
+    /// closed, i.e. no CLOSE frame is sent regardless of the [`DropPolicy`],
+    /// and a message which has been received partially is lost.
+    ///
+    /// Returns `None` if an operation is still in progress.
+    pub fn into_inner(self) -> Option<(T, BytesMut)> {
+        let (sender, receiver) = self.into_parts()?;
+        receiver.into_inner(sender)
+    }
+
+    /// Borrow the socket.
+    ///
+    /// Returns `None` while a receive operation is in progress or the socket
+    /// is borrowed already.
+    pub fn get_ref(&self) -> Option<impl Deref<Target = T> + '_> {
+        match &self.receiver {
+            Half::Idle(r) => tap::inner(&r.reader).get(),
+            _ => None
+        }
+    }
+
+    /// Borrow the socket mutably, e.g. to change its settings.
+    ///
+    /// Reading from or writing to the socket directly corrupts the stream
+    /// of frames. Returns `None` while a receive operation is in progress.
+    pub fn get_mut(&mut self) -> Option<impl DerefMut<Target = T> + '_> {
+        match &self.receiver {
+            Half::Idle(r) => tap::inner(&r.reader).get(),
+            _ => None
+        }
+    }
+
+    /// Stop reading from the socket.
+    ///
+    /// While paused, [`Connection::poll_recv`] yields no messages, but PINGs
+    /// which have been received completely already are still answered.
+    /// Sending is not affected. A receive operation in progress is suspended
+    /// until reading is resumed.
+    pub fn pause_reading(&mut self) {
+        self.paused = true
+    }
+
+    /// Resume reading from the socket after [`Connection::pause_reading`].
+    pub fn resume_reading(&mut self) {
+        self.paused = false;
+        if let Some(w) = self.resume.take() {
+            w.wake()
+        }
+    }
+
+    /// Is reading paused?
+    pub fn is_reading_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Poll for the next websocket message, skipping over control frames.
+    ///
+    /// Like [`Receiver::receive_data`] but returns the message payload along
+    /// with its type. Returns `None` once the connection is closed.
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<Result<Message, Error>>> {
+        loop {
+            match mem::replace(&mut self.receiver, Half::Gone) {
+                Half::Idle(mut receiver) if self.paused => {
+                    self.receiver = Half::Busy(Box::pin(async move {
+                        let result = receiver.answer_buffered_pings().await.map(|()| None);
+                        (receiver, result)
+                    }));
+                    self.answering = true
+                }
+                Half::Busy(future) if self.paused && !self.answering => {
+                    self.receiver = Half::Busy(future);
+                    self.resume = Some(cx.waker().clone());
+                    return Poll::Pending
+                }
+                Half::Busy(mut future) if self.answering => {
+                    match future.as_mut().poll(cx) {
+                        Poll::Ready((receiver, Ok(_))) => {
+                            self.receiver = Half::Idle(receiver);
+                            self.answering = false;
+                            if self.paused {
+                                self.resume = Some(cx.waker().clone());
+                                return Poll::Pending
+                            }
+                        }
+                        Poll::Ready((receiver, Err(e))) => {
+                            self.receiver = Half::Idle(receiver);
+                            self.answering = false;
+                            self.closed |= e.is_fatal();
+                            return Poll::Ready(Some(Err(e)))
+                        }
+                        Poll::Pending => {
+                            self.receiver = Half::Busy(future);
+                            return Poll::Pending
+                        }
+                    }
+                }
+                Half::Idle(mut receiver) => {
+                    self.receiver = Half::Busy(Box::pin(async move {
+                        let mut message = Vec::new();
+                        let result = match receiver.receive_data(&mut message).await {
+                            Ok(data) => Ok(Some((data, message))),
+                            Err(Error::Closed(_)) => Ok(None),
+                            Err(e) => Err(e)
+                        };
+                        (receiver, result)
+                    }))
+                }
+                Half::Busy(mut future) => {
+                    if let Poll::Ready((receiver, result)) = future.as_mut().poll(cx) {
+                        self.receiver = Half::Idle(receiver);
+                        self.closed |= result.as_ref().map_or_else(Error::is_fatal, Option::is_none);
+                        return Poll::Ready(result.transpose())
+                    }
+                    self.receiver = Half::Busy(future);
+                    return Poll::Pending
+                }
+                Half::Gone => return Poll::Ready(None)
+            }
+        }
+    }
+
+    /// Poll until the sender is ready to accept a message.
+    ///
+    /// Completes the operation in progress, i.e. a message given to
+    /// [`Connection::start_send`] or a flush, and returns its result.
+    pub fn poll_send_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Error>> {
+        match mem::replace(&mut self.sender, Half::Gone) {
+            Half::Idle(sender) => {
+                self.sender = Half::Idle(sender);
+                Poll::Ready(Ok(()))
+            }
+            Half::Busy(mut future) => {
+                if let Poll::Ready((sender, result)) = future.as_mut().poll(cx) {
+                    self.sender = Half::Idle(sender);
+                    self.closed |= matches!(&result, Err(e) if e.is_fatal());
+                    return Poll::Ready(result)
+                }
+                self.sender = Half::Busy(future);
+                Poll::Pending
+            }
+            Half::Gone => Poll::Ready(Err(Error::Closed(CloseReason::abnormal())))
+        }
+    }
+
+    /// Begin sending a message.
+    ///
+    /// Must only be called after [`Connection::poll_send_ready`] returned
+    /// `Poll::Ready(Ok(()))`, otherwise an I/O error of kind
+    /// [`io::ErrorKind::WouldBlock`] is returned. The message is sent by
+    /// subsequent calls to [`Connection::poll_send_ready`] or
+    /// [`Connection::poll_flush`].
+    pub fn start_send(&mut self, message: Outgoing) -> Result<(), Error> {
+        match mem::replace(&mut self.sender, Half::Gone) {
+            Half::Idle(mut sender) => {
+                self.sender = Half::Busy(Box::pin(async move {
+                    let result = sender.send(message).await;
+                    (sender, result)
+                }));
+                Ok(())
+            }
+            Half::Busy(future) => {
+                self.sender = Half::Busy(future);
+                Err(Error::Io(io::ErrorKind::WouldBlock.into()))
+            }
+            Half::Gone => Err(Error::Closed(CloseReason::abnormal()))
+        }
+    }
+
+    /// Poll until all messages have been sent and the socket is flushed.
+    pub fn poll_flush(&mut self, cx: &mut Context) -> Poll<Result<(), Error>> {
+        if !self.flushing {
+            futures::ready!(self.poll_send_ready(cx))?;
+            if let Half::Idle(mut sender) = mem::replace(&mut self.sender, Half::Gone) {
+                self.sender = Half::Busy(Box::pin(async move {
+                    let result = sender.flush().await;
+                    (sender, result)
+                }));
+                self.flushing = true
+            }
+        }
+        let result = futures::ready!(self.poll_send_ready(cx));
+        self.flushing = false;
+        Poll::Ready(result)
+    }
+}
+
+/// Max. number of characters of a [`CloseReason`] shown by default.
+const CLOSE_REASON_DISPLAY_LEN: usize = 64;
+
+/// The reason the remote gave for closing the connection.
+///
+/// The reason text is controlled by the remote and should be treated as
+/// untrusted input. [`CloseReason::sanitized`] returns a version which is
+/// safe to log and the `Debug` and `Display` impls use it. The text as
+/// received is available through [`CloseReason::raw_reason`].
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct CloseReason {
+    code: CloseCode,
+    reason: Option<String>
+}
+
+impl CloseReason {
+    /// The reason of a connection closed without an exchange of CLOSE
+    /// frames, with status code 1006 (abnormal closure).
+    pub(crate) fn abnormal() -> Self {
+        CloseReason { code: CloseCode::ABNORMAL_CLOSURE, reason: None }
+    }
+
+    /// Parse the payload data of a CLOSE frame.
+    ///
+    /// An empty payload is reported with status code 1005 (no status received).
+    fn from_payload(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < 2 {
+            return Ok(CloseReason { code: CloseCode::NO_STATUS_RECEIVED, reason: None })
+        }
+        let code = CloseCode::from(u16::from_be_bytes([data[0], data[1]]));
+        let reason = str::from_utf8(&data[2 ..])?;
+        if reason.is_empty() {
+            Ok(CloseReason { code, reason: None })
+        } else {
+            Ok(CloseReason { code, reason: Some(reason.into()) })
+        }
+    }
+
+    /// The close status code.
+    pub fn code(&self) -> CloseCode {
+        self.code
+    }
+
+    /// The reason text exactly as sent by the remote.
+    ///
+    /// **Note**: The text may contain arbitrary (including control)
+    /// characters. Use [`CloseReason::sanitized`] if it is to be displayed.
+    pub fn raw_reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
+
+    /// The reason text without control characters, truncated to 64 characters.
+    pub fn sanitized(&self) -> Option<String> {
+        self.sanitized_to(CLOSE_REASON_DISPLAY_LEN)
+    }
+
+    /// The reason text without control characters, truncated to `max` characters.
+    ///
+    /// Terminal escape sequences are removed as a whole, as are invisible
+    /// format characters (Unicode category Cf) such as bidirectional
+    /// overrides and zero-width spaces. If the text had to be truncated,
+    /// "..." is appended.
+    pub fn sanitized_to(&self, max: usize) -> Option<String> {
+        let reason = self.reason.as_ref()?;
+        let mut chars = Printable(reason.chars());
+        let mut s: String = chars.by_ref().take(max).collect();
+        if chars.next().is_some() {
+            s.push_str("...")
+        }
+        Some(s)
+    }
+}
+
+/// An iterator over the characters of a text which are safe to display.
+///
+/// Skips control and format characters and whole escape sequences.
+struct Printable<I>(I);
+
+impl<I: Iterator<Item = char>> Iterator for Printable<I> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        loop {
+            let c = self.0.next()?;
+            match c {
+                '\u{1b}' => match self.0.next() {
+                    Some('[') => self.skip_csi(),
+                    Some(']' | 'P' | 'X' | '^' | '_') => self.skip_string(),
+                    _ => {} // a two-character escape sequence
+                }
+                '\u{9b}' => self.skip_csi(),
+                '\u{90}' | '\u{98}' | '\u{9d}' | '\u{9e}' | '\u{9f}' => self.skip_string(),
+                c if c.is_control() || is_format(c) => {}
+                c => return Some(c)
+            }
+        }
+    }
+}
+
+impl<I: Iterator<Item = char>> Printable<I> {
+    /// Skip a control sequence up to and including its final character.
+    fn skip_csi(&mut self) {
+        for c in self.0.by_ref() {
+            if ('\u{40}' ..= '\u{7e}').contains(&c) {
+                return
+            }
+        }
+    }
+
+    /// Skip an OSC or other control string up to its terminator.
+    fn skip_string(&mut self) {
+        let mut escaped = false;
+        for c in self.0.by_ref() {
+            match c {
+                '\u{7}' | '\u{9c}' => return,
+                '\\' if escaped => return,
+                _ => escaped = c == '\u{1b}'
+            }
+        }
+    }
+}
+
+/// Is this character of the Unicode general category Cf (format)?
+fn is_format(c: char) -> bool {
+    matches!(c,
+        '\u{ad}'
+        | '\u{600}' ..= '\u{605}'
+        | '\u{61c}'
+        | '\u{6dd}'
+        | '\u{70f}'
+        | '\u{890}' ..= '\u{891}'
+        | '\u{8e2}'
+        | '\u{180e}'
+        | '\u{200b}' ..= '\u{200f}'
+        | '\u{202a}' ..= '\u{202e}'
+        | '\u{2060}' ..= '\u{2064}'
+        | '\u{2066}' ..= '\u{206f}'
+        | '\u{feff}'
+        | '\u{fff9}' ..= '\u{fffb}'
+        | '\u{110bd}'
+        | '\u{110cd}'
+        | '\u{13430}' ..= '\u{1343f}'
+        | '\u{1bca0}' ..= '\u{1bca3}'
+        | '\u{1d173}' ..= '\u{1d17a}'
+        | '\u{e0001}'
+        | '\u{e0020}' ..= '\u{e007f}')
+}
+
+impl fmt::Debug for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CloseReason")
+            .field("code", &self.code)
+            .field("reason", &self.sanitized())
+            .finish()
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(r) = self.sanitized() {
+            write!(f, "{} ({})", self.code, r)
+        } else {
+            write!(f, "{}", self.code)
+        }
+    }
+}
+
+/// Create a close frame based on the given data.
+fn close_answer(data: &[u8], echo: CloseEcho) -> Result<(Header, Option<CloseCode>), Error> {
+    let answer = Header::new(OpCode::Close);
+    let code =
+        if data.len() < 2 {
+            None
+        } else {
+            std::str::from_utf8(&data[2 ..])?; // check reason is properly encoded
+            let code = CloseCode::from(u16::from_be_bytes([data[0], data[1]]));
+            if !code.is_allowed_on_wire() {
+                return Ok((answer, Some(CloseCode::PROTOCOL_ERROR)))
+            }
+            Some(code)
+        };
+    match echo {
+        CloseEcho::Echo => Ok((answer, Some(code.unwrap_or(CloseCode::NORMAL)))),
+        CloseEcho::Code(c) => Ok((answer, Some(c))),
+        CloseEcho::Empty => Ok((answer, None))
+    }
+}
+
+/// Errors which may occur when sending or receiving messages.
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum Error {
+    /// The connection is closed.
+    ///
+    /// Carries the remote's close reason if its CLOSE frame has been
+    /// received, or else the status code of our own CLOSE frame. If no CLOSE
+    /// frame has been exchanged, e.g. because the socket has been closed,
+    /// the code is 1006 (abnormal closure).
+    Closed(CloseReason),
+    /// The remote violated the websocket protocol.
+    Protocol(base::Error),
+    /// The opening handshake failed.
+    Handshake(handshake::Error),
+    /// An I/O error was encountered.
+    Io(io::Error),
+    /// The total message payload data size exceeds the configured maximum.
+    MessageTooLarge { limit: usize, actual: usize },
+    /// The remote did not respond or send data in time.
+    Timeout(TimeoutKind),
+    /// A frame header could not be decoded.
+    ///
+    /// Like [`Error::Protocol`], but carries the first bytes of the offending
+    /// frame (at most [`RAW_PREFIX_LEN`]) for diagnostics.
+    Malformed { error: base::Error, raw_prefix: Vec<u8> },
+    /// A frame with an unexpected opcode, e.g. a CONTINUE frame outside of
+    /// a fragmented message, was received or is to be sent.
+    UnexpectedOpCode(OpCode),
+    /// A frame was masked although masking was not expected.
+    MaskedFrame,
+    /// A frame was not masked although masking was expected.
+    UnmaskedFrame,
+    /// A client has sent a masked frame, although masking has been
+    /// disabled, see [`Deviations::set_unmasked`].
+    MaskingOptOutMismatch,
+    /// A CLOSE frame with a payload of a single byte was received.
+    InvalidClosePayload,
+    /// A close reason was not correctly UTF-8 encoded.
+    InvalidCloseReason(str::Utf8Error),
+    /// A text message was not correctly UTF-8 encoded.
+    InvalidText,
+    /// Data was received after a CLOSE frame.
+    DataAfterClose,
+    /// An HTTP response was received instead of a frame, probably injected
+    /// by an intermediary.
+    HttpInjected { status_line: String },
+    /// An extension produced an error while encoding or decoding.
+    Extension(crate::BoxedError),
+    /// The configured receive or send quota has been exceeded.
+    QuotaExceeded,
+    /// Data was received above the rate set with
+    /// [`Builder::set_inbound_rate_limit`].
+    RateLimited,
+    /// A frame to send exceeds the maximum set with
+    /// [`Builder::set_max_send_frame_size`], or a received frame is longer
+    /// than `usize::MAX` (e.g. > 4 GiB on 32-bit platforms).
+    FrameTooLarge { size: u64, maximum: usize },
+    /// A control frame payload to send is invalid.
+    InvalidControlPayload(InvalidControlPayload),
+    /// A text frame to send is not correctly UTF-8 encoded.
+    InvalidUtf8Outgoing
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Closed(reason) =>
+                write!(f, "connection closed: {}", reason),
+            Error::Protocol(e) =>
+                write!(f, "protocol error: {}", e),
+            Error::Handshake(e) =>
+                write!(f, "handshake error: {}", e),
+            Error::Io(e) =>
+                write!(f, "i/o error: {}", e),
+            Error::MessageTooLarge { limit, actual } =>
+                write!(f, "message too large: len >= {}, maximum = {}", actual, limit),
+            Error::Timeout(k) =>
+                write!(f, "timeout: {}", k),
+            Error::Malformed { error, raw_prefix } =>
+                write!(f, "protocol error: {} (raw bytes: {:02x?})", error, raw_prefix),
+            Error::UnexpectedOpCode(c) =>
+                write!(f, "unexpected opcode: {}", c),
+            Error::MaskedFrame =>
+                f.write_str("unexpected masked frame"),
+            Error::UnmaskedFrame =>
+                f.write_str("unexpected unmasked frame"),
+            Error::MaskingOptOutMismatch =>
+                f.write_str("masked frame received although masking has been disabled"),
+            Error::InvalidClosePayload =>
+                f.write_str("close payload of a single byte"),
+            Error::InvalidCloseReason(e) =>
+                write!(f, "close reason is not valid utf-8: {}", e),
+            Error::InvalidText =>
+                f.write_str("text message is not valid utf-8"),
+            Error::DataAfterClose =>
+                f.write_str("data after close frame"),
+            Error::HttpInjected { status_line } =>
+                write!(f, "http response in websocket stream: {}", status_line),
+            Error::Extension(e) =>
+                write!(f, "extension error: {}", e),
+            Error::QuotaExceeded =>
+                f.write_str("byte quota exceeded"),
+            Error::RateLimited =>
+                f.write_str("inbound rate limit exceeded"),
+            Error::FrameTooLarge { size, maximum } =>
+                write!(f, "frame too large: len = {}, maximum = {}", size, maximum),
+            Error::InvalidControlPayload(e) =>
+                write!(f, "invalid control payload: {}", e),
+            Error::InvalidUtf8Outgoing =>
+                f.write_str("text frame to send is not valid utf-8")
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Protocol(e) | Error::Malformed { error: e, .. } => Some(e),
+            Error::InvalidCloseReason(e) => Some(e),
+            Error::Handshake(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Extension(e) => Some(&**e),
+            Error::InvalidControlPayload(e) => Some(e),
+            Error::Closed(_)
+            | Error::MessageTooLarge {..}
+            | Error::Timeout(_)
+            | Error::UnexpectedOpCode(_)
+            | Error::MaskedFrame
+            | Error::UnmaskedFrame
+            | Error::MaskingOptOutMismatch
+            | Error::InvalidClosePayload
+            | Error::InvalidText
+            | Error::DataAfterClose
+            | Error::HttpInjected {..}
+            | Error::QuotaExceeded
+            | Error::RateLimited
+            | Error::FrameTooLarge {..}
+            | Error::InvalidUtf8Outgoing
+            => None
+        }
+    }
+}
+
+/// Max. number of bytes of a malformed frame kept in [`Error::Malformed`].
+pub const RAW_PREFIX_LEN: usize = 64;
+
+/// What did not happen in time, see [`Error::Timeout`].
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    /// The remote did not answer our CLOSE within the timeout set with
+    /// [`Builder::set_close_timeout`].
+    Close,
+    /// Data was received below the minimum rate set with
+    /// [`Builder::set_min_read_rate`].
+    ReadRate
+}
+
+impl fmt::Display for TimeoutKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimeoutKind::Close => f.write_str("no answer to close"),
+            TimeoutKind::ReadRate => f.write_str("data received below minimum rate")
+        }
+    }
+}
+
+/// Why a connection closes itself, see [`Builder::set_close_messages`].
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CloseCause {
+    /// A message or frame exceeded the maximum size.
+    MessageTooBig,
+    /// A text message or a close reason was not valid UTF-8.
+    InvalidPayload,
+    /// The remote violated the websocket protocol.
+    ProtocolError,
+    /// The remote sent data below the minimum rate.
+    ReadTooSlow,
+    /// The receive or send quota has been exceeded.
+    QuotaExceeded,
+    /// The remote sent data above the inbound rate limit.
+    RateLimited,
+    /// The application tried to send something invalid.
+    InternalError
+}
+
+impl CloseCause {
+    /// The status code sent by default, as recommended by RFC 6455.
+    pub fn close_code(self) -> CloseCode {
+        match self {
+            CloseCause::MessageTooBig => CloseCode::MESSAGE_TOO_BIG,
+            CloseCause::InvalidPayload => CloseCode::INVALID_PAYLOAD,
+            CloseCause::ProtocolError => CloseCode::PROTOCOL_ERROR,
+            CloseCause::ReadTooSlow
+            | CloseCause::QuotaExceeded
+            | CloseCause::RateLimited => CloseCode::POLICY_VIOLATION,
+            CloseCause::InternalError => CloseCode::INTERNAL_ERROR
+        }
+    }
+}
+
+impl Error {
+    /// Can the connection no longer be used after this error?
+    ///
+    /// Errors caused by an oversized or invalid payload passed to a send
+    /// method leave the connection intact, as does a message that is not sent because the
+    /// socket is not ready (cf. [`Connection::start_send`]). All others
+    /// end the connection, which should be closed (e.g. with
+    /// [`Sender::close_on_error`]) and dropped.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            Error::Io(e) => e.kind() != io::ErrorKind::WouldBlock,
+            Error::FrameTooLarge { size, .. } => *size > crate::as_u64(usize::MAX),
+            Error::InvalidControlPayload(_)
+            | Error::InvalidUtf8Outgoing => false,
+            _ => true
+        }
+    }
+
+    /// The close code to answer this error with.
+    ///
+    /// See the `From<&Error>` implementation of [`CloseCode`].
+    pub fn close_code(&self) -> CloseCode {
+        CloseCode::from(self)
+    }
+
+    /// The cause to close the connection for in response to this error.
+    ///
+    /// `None` if the error implies a broken or closed connection, which can
+    /// not be closed with a CLOSE frame.
+    pub fn close_cause(&self) -> Option<CloseCause> {
+        match self {
+            Error::Closed(_)
+            | Error::Handshake(_)
+            | Error::Io(_)
+            | Error::HttpInjected {..}
+            | Error::Timeout(TimeoutKind::Close) => None,
+            Error::Protocol(e) | Error::Malformed { error: e, .. } => match CloseCode::from(e) {
+                CloseCode::ABNORMAL_CLOSURE => None,
+                CloseCode::MESSAGE_TOO_BIG => Some(CloseCause::MessageTooBig),
+                CloseCode::INVALID_PAYLOAD => Some(CloseCause::InvalidPayload),
+                _ => Some(CloseCause::ProtocolError)
+            }
+            Error::InvalidCloseReason(_) | Error::InvalidText => Some(CloseCause::InvalidPayload),
+            Error::UnexpectedOpCode(_)
+            | Error::MaskedFrame
+            | Error::UnmaskedFrame
+            | Error::MaskingOptOutMismatch
+            | Error::InvalidClosePayload
+            | Error::DataAfterClose => Some(CloseCause::ProtocolError),
+            Error::MessageTooLarge {..} => Some(CloseCause::MessageTooBig),
+            Error::Timeout(TimeoutKind::ReadRate) => Some(CloseCause::ReadTooSlow),
+            Error::Extension(_) => Some(CloseCause::ProtocolError),
+            Error::QuotaExceeded => Some(CloseCause::QuotaExceeded),
+            Error::RateLimited => Some(CloseCause::RateLimited),
+            Error::FrameTooLarge { size, .. } if *size > crate::as_u64(usize::MAX) => Some(CloseCause::MessageTooBig),
+            Error::FrameTooLarge {..}
+            | Error::InvalidControlPayload(_)
+            | Error::InvalidUtf8Outgoing => Some(CloseCause::InternalError)
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        if is_eof(&e) {
+            Error::Closed(CloseReason::abnormal())
+        } else {
+            Error::Io(e)
+        }
+    }
+}
+
+/// The close code to answer a connection error with.
+///
+/// Errors which imply a broken connection map to
+/// [`CloseCode::ABNORMAL_CLOSURE`] which must not be sent.
+impl From<&Error> for CloseCode {
+    fn from(e: &Error) -> Self {
+        e.close_cause().map_or(CloseCode::ABNORMAL_CLOSURE, CloseCause::close_code)
+    }
+}
+
+impl From<InvalidControlPayload> for Error {
+    fn from(e: InvalidControlPayload) -> Self {
+        Error::InvalidControlPayload(e)
+    }
+}
+
+impl From<str::Utf8Error> for Error {
+    fn from(e: str::Utf8Error) -> Self {
+        Error::InvalidCloseReason(e)
+    }
+}
+
+impl From<base::Error> for Error {
+    fn from(e: base::Error) -> Self {
+        match e {
+            base::Error::Io(e) => Error::from(e),
+            e => Error::Protocol(e)
+        }
+    }
+}
+
+impl From<handshake::Error> for Error {
+    fn from(e: handshake::Error) -> Self {
+        Error::Handshake(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::{ControlPayload, Data, Incoming, Outgoing};
+    use crate::base::CloseCode;
+    use crate::{Parsing, base::{self, FrameDecoder, Header, OpCode}};
+    use crate::extension::{Extension, Param};
+    use quickcheck::QuickCheck;
+    use super::{Builder, CloseCause, CloseEcho, CloseReason, Connection, Deviations, DropPolicy, Delivery, KeepAlive, TrafficStats, Error, Mode, ReadRate, ShutdownReport, TimeoutKind};
+    use std::time::{Duration, Instant};
+    use std::{io, pin::Pin, task::{Context, Poll}};
+    use std::sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    #[tokio::test]
+    async fn fragments_use_own_masks() {
+        // Each frame is unmasked from the start of its own payload. Lengths
+        // which are not multiples of 4 would reveal a mask offset carried
+        // over from the previous frame, as would reusing its key.
+        let fragments = [
+            (OpCode::Binary, 0x1122_3344, &b"abc"[..]),
+            (OpCode::Continue, 0xA5A5_5A5A, b"defgh"),
+            (OpCode::Continue, 0x0F1E_2D3C, b"ijklmno")
+        ];
+        let mut input = Vec::new();
+        let mut naive = Vec::new();
+        for (i, (opcode, mask, payload)) in fragments.iter().enumerate() {
+            let mut header = Header::new(*opcode);
+            header.set_fin(i == fragments.len() - 1).set_masked(true).set_mask(*mask).set_payload_len(payload.len());
+            input.extend_from_slice(base::Codec::new().encode_header(&header));
+            let mut masked = payload.to_vec();
+            base::Codec::apply_mask(&header, &mut masked);
+            // What continuing the offset of the first frame would yield.
+            let key = 0x1122_3344_u32.to_be_bytes();
+            let offset = naive.len();
+            naive.extend(masked.iter().enumerate().map(|(j, b)| b ^ key[(offset + j) % 4]));
+            input.extend_from_slice(&masked)
+        }
+        assert_ne!(&b"abcdefghijklmno"[..], &naive[..]);
+
+        // Arriving in chunks which do not line up with frame boundaries.
+        let (a, mut b) = tokio::io::duplex(1024);
+        let (_, mut receiver) = Builder::new(a.compat(), Mode::Server).finish();
+        let writer = tokio::spawn(async move {
+            for chunk in input.chunks(3) {
+                b.write_all(chunk).await.unwrap();
+                tokio::task::yield_now().await
+            }
+            b
+        });
+        let mut message = Vec::new();
+        assert!(matches!(receiver.receive(&mut message).await, Ok(Incoming::Data(Data::Binary(15)))));
+        assert_eq!(&b"abcdefghijklmno"[..], &message[..]);
+        drop(writer.await.unwrap());
+
+        // Frame by frame, the same holds for the decoder.
+        let mut decoder = FrameDecoder::new(base::Codec::new());
+        let mut payloads = Vec::new();
+        for (opcode, mask, payload) in &fragments {
+            let mut header = Header::new(*opcode);
+            header.set_masked(true).set_mask(*mask).set_payload_len(payload.len());
+            decoder.push_bytes(base::Codec::new().encode_header(&header));
+            let mut masked = payload.to_vec();
+            base::Codec::apply_mask(&header, &mut masked);
+            for byte in masked {
+                decoder.push_bytes(&[byte])
+            }
+            let frame = decoder.next_frame().unwrap().unwrap();
+            payloads.push(frame.payload().to_vec())
+        }
+        assert_eq!(vec![b"abc".to_vec(), b"defgh".to_vec(), b"ijklmno".to_vec()], payloads)
+    }
+
+    #[tokio::test]
+    async fn audit_frames() {
+        use futures::{FutureExt, StreamExt};
+        use super::AuditedFrame;
+
+        let (a, b) = tokio::io::duplex(1024);
+        let (mut client, _) = Builder::new(a.compat(), Mode::Client).finish();
+        let (_, mut server) = Builder::new(b.compat(), Mode::Server).finish();
+        let mut audit = server.audit_frames(8);
+
+        let mut first = Header::new(OpCode::Text);
+        first.set_fin(false);
+        client.send_frame(&mut first, &mut crate::Storage::Shared(b"hel")).await.unwrap();
+        client.send_control(ControlPayload::ping(b"p").unwrap()).await.unwrap();
+        client.send_frame(&mut Header::new(OpCode::Continue), &mut crate::Storage::Shared(b"lo")).await.unwrap();
+        client.flush().await.unwrap();
+
+        // The message is reassembled as usual.
+        let mut message = Vec::new();
+        assert!(matches!(server.receive(&mut message).await, Ok(Incoming::Data(Data::Text(5)))));
+        assert_eq!(b"hello", &message[..]);
+
+        // The stream has the frames in order of arrival.
+        let mut frames = Vec::new();
+        while let Some(Some(item)) = audit.next().now_or_never() {
+            frames.push(match item {
+                AuditedFrame::Data(f) => ("data", f.header().opcode(), f.payload().to_vec()),
+                AuditedFrame::Control(f) => ("control", f.header().opcode(), f.payload().to_vec()),
+                AuditedFrame::Skipped(n) => panic!("{} frames skipped", n)
+            })
+        }
+        assert_eq!(vec![
+            ("data", OpCode::Text, b"hel".to_vec()),
+            ("control", OpCode::Ping, b"p".to_vec()),
+            ("data", OpCode::Continue, b"lo".to_vec())
+        ], frames);
+
+        // Frames arriving while the stream is full are counted.
+        let mut audit = server.audit_frames(0);
+        for _ in 0 .. 3 {
+            client.send_binary(b"x").await.unwrap();
+            client.flush().await.unwrap();
+            server.receive(&mut message).await.unwrap();
+        }
+        assert!(matches!(audit.next().await, Some(AuditedFrame::Data(_))));
+        client.send_binary(b"x").await.unwrap();
+        client.flush().await.unwrap();
+        server.receive(&mut message).await.unwrap();
+        assert!(matches!(audit.next().await, Some(AuditedFrame::Skipped(2))));
+
+        // The stream ends when auditing is replaced.
+        drop(server.audit_frames(1));
+        assert!(audit.next().await.is_none())
+    }
+
+    #[tokio::test]
+    async fn interceptor_can_not_disable_masking() {
+        let (a, mut b) = tokio::io::duplex(1024);
+
+        let mut builder = Builder::new(a.compat(), Mode::Client);
+        builder.set_frame_interceptor(|f| { f.header_mut().set_masked(false).set_mask(0x01020304); });
+        let (mut sender, _receiver) = builder.finish();
+
+        sender.send_binary(b"ab").await.unwrap();
+        sender.flush().await.unwrap();
+
+        let mut frame = [0; 8];
+        b.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame, [0x82, 0x82, 1, 2, 3, 4, b'a' ^ 1, b'b' ^ 2])
+    }
+
+    #[cfg(feature = "deflate")]
+    #[tokio::test]
+    async fn interceptor_sets_rsv1() {
+        use crate::extension::deflate::Deflate;
+
+        let (a, mut b) = tokio::io::duplex(1024);
+
+        let mut client = Deflate::new(Mode::Client);
+        client.configure(&[Param::new("server_no_context_takeover")]).unwrap();
+        let mut server = Deflate::new(Mode::Server);
+        server.configure(&[]).unwrap();
+        assert!(client.is_enabled() && server.is_enabled());
+
+        // Parts bypass extensions, so the interceptor compresses the payload
+        // itself and sets RSV1.
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let mut builder = Builder::new(a.compat(), Mode::Client);
+        builder.add_extensions(Some(Box::new(client) as Box<dyn Extension + Send>));
+        builder.set_frame_interceptor({
+            let (seen, sent) = (seen.clone(), sent.clone());
+            let mut encoder = Deflate::new(Mode::Client);
+            encoder.configure(&[Param::new("server_no_context_takeover")]).unwrap();
+            move |f| {
+                assert!(f.header().is_masked());
+                seen.lock().unwrap().push(f.payload().to_vec());
+                let mut data = crate::Storage::Shared(f.payload());
+                let mut header = f.header().clone();
+                encoder.encode(&mut header, &mut data).unwrap();
+                let compressed = data.as_ref().to_vec();
+                *f.payload_mut() = compressed.clone();
+                f.header_mut().set_rsv1(true);
+                sent.lock().unwrap().push(compressed)
+            }
+        });
+        let (mut sender, _receiver) = builder.finish();
+        sender.send_binary_part(b"hello, hello, hello", true).await.unwrap();
+        sender.flush().await.unwrap();
+
+        // The interceptor saw the data before masking and the peer unmasks
+        // exactly what it produced.
+        let mut bytes = vec![0; 1024];
+        let n = b.read(&mut bytes).await.unwrap();
+        let mut codec = base::Codec::new();
+        codec.add_reserved_bits((true, false, false));
+        let mut decoder = FrameDecoder::new(codec);
+        decoder.push_bytes(&bytes[.. n]);
+        let frame = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(vec![b"hello, hello, hello".to_vec()], *seen.lock().unwrap());
+        assert!(frame.header().is_rsv1() && frame.header().is_masked());
+        assert_eq!(sent.lock().unwrap()[0], frame.payload());
+
+        let (mut header, mut payload) = frame.into_parts();
+        server.decode(&mut header, &mut payload).unwrap();
+        assert_eq!(b"hello, hello, hello", &payload[..])
+    }
+
+    #[test]
+    fn fragment_state() {
+        use OpCode::{Binary, Close, Continue, Ping, Text};
+        let ok = |o| Ok(Some(o));
+        let more = Ok(None);
+        let err = Err(());
+        // Each sequence lists the frames with the expected outcome.
+        type Step = (OpCode, bool, Result<Option<OpCode>, ()>);
+        let table: &[&[Step]] = &[
+            // Legal sequences.
+            &[(Text, true, ok(Text))],
+            &[(Binary, true, ok(Binary))],
+            &[(Text, false, more), (Continue, true, ok(Text))],
+            &[(Binary, false, more), (Continue, false, more), (Continue, true, ok(Binary))],
+            &[(Text, false, more), (Continue, true, ok(Text)), (Binary, true, ok(Binary))],
+            &[(Binary, true, ok(Binary)), (Text, false, more), (Continue, true, ok(Text))],
+            // CONTINUE outside of a message.
+            &[(Continue, true, err)],
+            &[(Continue, false, err)],
+            &[(Text, true, ok(Text)), (Continue, true, err)],
+            &[(Text, false, more), (Continue, true, ok(Text)), (Continue, false, err)],
+            // A new message within a message.
+            &[(Text, false, more), (Text, true, err)],
+            &[(Text, false, more), (Binary, false, err)],
+            &[(Binary, false, more), (Continue, false, more), (Text, true, err)],
+            // Control frames are not part of messages.
+            &[(Ping, true, err)],
+            &[(Text, false, more), (Close, true, err), (Continue, true, ok(Text))]
+        ];
+        for frames in table {
+            let mut state = super::FragmentState::default();
+            for (i, &(opcode, fin, expected)) in frames.iter().enumerate() {
+                let actual = state.on_frame(opcode, fin).map_err(|_| ());
+                assert_eq!(expected, actual, "{:?} / {}", frames, i)
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn shared_codec_config() {
+        let mut config = base::CodecConfig::new();
+        config.set_max_data_size(4);
+
+        // The same configuration is used for every accepted connection.
+        for _ in 0 .. 2 {
+            let (a, b) = tokio::io::duplex(1024);
+            let builder = Builder::with_codec_config(a.compat(), Mode::Server, config);
+            assert_eq!(config, builder.codec_config());
+            let (_, mut receiver) = builder.finish();
+            let (mut sender, _) = Builder::new(b.compat(), Mode::Client).finish();
+            sender.send_binary(b"1234").await.unwrap();
+            sender.send_binary(b"12345").await.unwrap();
+            sender.flush().await.unwrap();
+
+            let mut data = Vec::new();
+            assert!(matches!(receiver.receive_data(&mut data).await, Ok(Data::Binary(4))));
+            match receiver.receive_data(&mut data).await {
+                Err(Error::Malformed { error, .. }) => assert!(matches!(error, base::Error::PayloadTooLarge { actual: 5, maximum: 4 })),
+                other => panic!("unexpected: {:?}", other)
+            }
+        }
+    }
+
+    #[test]
+    fn close_reason_is_sanitized() {
+        let raw = "\x1b[31mALERT\x1b[0m\r\nfake log line\n";
+        let mut payload = 1000_u16.to_be_bytes().to_vec();
+        payload.extend_from_slice(raw.as_bytes());
+        let reason = CloseReason::from_payload(&payload).unwrap();
+
+        assert_eq!(reason.raw_reason(), Some(raw));
+        assert_eq!(reason.sanitized().as_deref(), Some("ALERTfake log line"));
+        assert_eq!(reason.sanitized_to(5).as_deref(), Some("ALERT..."));
+        assert_eq!(reason.to_string(), "1000 (ALERTfake log line)");
+        assert!(!format!("{:?}", reason).chars().any(char::is_control))
+    }
+
+    #[test]
+    fn close_reason_without_escape_sequences() {
+        let reasons = [
+            // CSI with parameters and intermediate bytes, 7- and 8-bit.
+            ("a\x1b[1;31mb\x1b[?25lc\u{9b}2Jd", "abcd"),
+            // OSC terminated by BEL and by ST, e.g. a hyperlink or window title.
+            ("\x1b]8;;http://evil\x07link\x1b]8;;\x1b\\!", "link!"),
+            ("\x1b]0;title\u{9c}x", "x"),
+            // Two-character escapes and an unterminated sequence.
+            ("a\x1bcb\x1b7c\x1b[31", "abc")
+        ];
+        for (raw, expected) in &reasons {
+            let mut payload = 1000_u16.to_be_bytes().to_vec();
+            payload.extend_from_slice(raw.as_bytes());
+            let reason = CloseReason::from_payload(&payload).unwrap();
+            assert_eq!(reason.sanitized().as_deref(), Some(*expected), "{:?}", raw)
+        }
+    }
+
+    #[test]
+    fn close_reason_without_format_characters() {
+        // Bidirectional overrides and isolates, zero-width characters and a BOM.
+        let raw = "\u{202e}evil\u{202c} \u{2066}txt\u{2069}\u{200b}.\u{200d}exe\u{feff}\u{200f}";
+        let mut payload = 1000_u16.to_be_bytes().to_vec();
+        payload.extend_from_slice(raw.as_bytes());
+        let reason = CloseReason::from_payload(&payload).unwrap();
+        assert_eq!(reason.raw_reason(), Some(raw));
+        assert_eq!(reason.sanitized().as_deref(), Some("evil txt.exe"));
+        assert_eq!(reason.sanitized_to(4).as_deref(), Some("evil..."));
+        for c in ('\u{202a}' ..= '\u{202e}').chain('\u{2066}' ..= '\u{2069}').chain('\u{200b}' ..= '\u{200f}') {
+            assert!(super::is_format(c), "{:?}", c)
+        }
+        assert!(!super::is_format('a') && !super::is_format('\u{e9}'))
+    }
+
+    #[test]
+    fn close_reason_without_payload() {
+        let reason = CloseReason::from_payload(&[]).unwrap();
+        assert_eq!(reason.code(), CloseCode::NO_STATUS_RECEIVED);
+        assert_eq!(reason.raw_reason(), None);
+        assert_eq!(reason.to_string(), "1005")
+    }
+
+    #[tokio::test]
+    async fn receive_close_reason() {
+        let (a, mut b) = tokio::io::duplex(1024);
+        let (_sender, mut receiver) = Builder::new(a.compat(), Mode::Client).finish();
+
+        b.write_all(&[0x88, 0x05, 0x03, 0xE8, b'b', b'y', b'e']).await.unwrap();
+
+        let mut data = Vec::new();
+        match receiver.receive(&mut data).await {
+            Ok(Incoming::Closed(reason)) => {
+                assert_eq!(reason.code(), CloseCode::NORMAL);
+                assert_eq!(reason.raw_reason(), Some("bye"))
+            }
+            other => panic!("unexpected: {:?}", other)
+        }
+        assert!(matches!(receiver.receive(&mut data).await, Err(super::Error::Closed(_))));
+
+        // The close frame has been answered.
+        let mut answer = [0; 4];
+        b.read_exact(&mut answer).await.unwrap();
+        assert_eq!(answer, [0x88, 0x82, answer[2], answer[3]])
+    }
+
+    #[tokio::test]
+    async fn data_after_close() {
+        for &strict in &[false, true] {
+            let (a, mut b) = tokio::io::duplex(1024);
+            let mut builder = Builder::new(a.compat(), Mode::Client);
+            builder.set_deviations(*Deviations::new().set_ignore_data_after_close(!strict));
+            let (_sender, mut receiver) = builder.finish();
+
+            b.write_all(&[0x88, 0x02, 0x03, 0xE8, 0xde, 0xad, 0xbe, 0xef]).await.unwrap();
+
+            let mut data = Vec::new();
+            let result = receiver.receive(&mut data).await;
+            if strict {
+                assert!(matches!(result, Err(Error::DataAfterClose)))
+            } else {
+                assert!(matches!(result, Ok(Incoming::Closed(_))))
+            }
+            assert!(matches!(receiver.receive(&mut data).await, Err(Error::Closed(_))));
+
+            // The close frame has been answered with a masked close code.
+            let mut answer = [0; 8];
+            b.read_exact(&mut answer).await.unwrap();
+            assert_eq!(answer[.. 2], [0x88, 0x82]);
+            let code = u16::from_be_bytes([answer[6] ^ answer[2], answer[7] ^ answer[3]]);
+            let expected = if strict { CloseCode::PROTOCOL_ERROR } else { CloseCode::NORMAL };
+            assert_eq!(expected, CloseCode::from(code))
+        }
+    }
+
+    #[tokio::test]
+    async fn duplicate_close() {
+        const CLOSE: &[u8] = &[0x88, 0x02, 0x03, 0xE8];
+        const TEXT: &[u8] = &[0x81, 0x01, b'a'];
+        let cases: [(&[&[u8]], bool, u64); 3] = [
+            (&[CLOSE, CLOSE], false, 1),
+            (&[CLOSE, CLOSE, TEXT], true, 2),
+            (&[CLOSE, TEXT, CLOSE, TEXT], true, 3)
+        ];
+        for &(input, is_violation, frames) in &cases {
+            for &strict in &[false, true] {
+                let (a, mut b) = tokio::io::duplex(1024);
+                let mut builder = Builder::new(a.compat(), Mode::Client);
+                builder.set_deviations(*Deviations::new().set_ignore_data_after_close(!strict));
+                let (sender, mut receiver) = builder.finish();
+                b.write_all(&input.concat()).await.unwrap();
+
+                let mut data = Vec::new();
+                match receiver.receive(&mut data).await {
+                    Err(Error::DataAfterClose) => assert!(strict && is_violation),
+                    Ok(Incoming::Closed(reason)) => {
+                        assert!(!(strict && is_violation));
+                        assert_eq!(CloseCode::NORMAL, reason.code())
+                    }
+                    other => panic!("unexpected result: {:?}", other)
+                }
+                assert_eq!(frames, receiver.frames_after_close());
+                assert!(data.is_empty());
+                assert!(matches!(receiver.receive(&mut data).await, Err(Error::Closed(_))));
+
+                // Only one CLOSE frame is sent in answer.
+                drop((sender, receiver));
+                let mut answer = Vec::new();
+                b.read_to_end(&mut answer).await.unwrap();
+                assert_eq!(8, answer.len());
+                let code = u16::from_be_bytes([answer[6] ^ answer[2], answer[7] ^ answer[3]]);
+                let expected = if strict && is_violation { CloseCode::PROTOCOL_ERROR } else { CloseCode::NORMAL };
+                assert_eq!(expected, CloseCode::from(code))
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_control_payload() {
+        let (a, mut b) = tokio::io::duplex(1024);
+        let mut server = Builder::new(a.compat(), Mode::Server);
+        // Turn every frame into a PING.
+        server.set_frame_interceptor(|f| { f.header_mut().set_opcode(OpCode::Ping); });
+        let (mut sender, _receiver) = server.finish();
+
+        assert!(ControlPayload::ping(&[0; 200]).is_err());
+        assert!(matches!(sender.send_binary(vec![0; 200]).await, Err(Error::InvalidControlPayload(_))));
+        let mut frame = base::Frame::empty(OpCode::Ping).unwrap();
+        frame.set_binary(&[0; 200]).header_mut().set_opcode(OpCode::Ping);
+        assert!(matches!(sender.send_premasked(&frame).await, Err(Error::InvalidControlPayload(_))));
+
+        // Nothing has been written.
+        sender.send_binary(&[1]).await.unwrap();
+        let mut bytes = [0; 3];
+        b.read_exact(&mut bytes).await.unwrap();
+        assert_eq!([0x89, 0x01, 1], bytes)
+    }
+
+    #[tokio::test]
+    async fn close_fast() {
+        for &mode in &[Mode::Client, Mode::Server] {
+            let (a, mut b) = tokio::io::duplex(1024);
+            let (mut sender, receiver) = Builder::new(a.compat(), mode).finish();
+            assert!(sender.close_fast(CloseCode::ABNORMAL_CLOSURE).await.is_err());
+            sender.close_fast(CloseCode::TRY_AGAIN_LATER).await.unwrap();
+            sender.close_fast(CloseCode::TRY_AGAIN_LATER).await.unwrap();
+            assert!(matches!(sender.send_text("a").await, Err(Error::Closed(r)) if r.code() == CloseCode::TRY_AGAIN_LATER));
+            assert_eq!(1, sender.traffic_stats().control_frames_out());
+            drop((sender, receiver));
+
+            let mut bytes = Vec::new();
+            b.read_to_end(&mut bytes).await.unwrap();
+            let mut decoder = FrameDecoder::new(base::Codec::new());
+            decoder.push_bytes(&bytes);
+            let frame = decoder.next_frame().unwrap().unwrap();
+            assert_eq!(mode.is_client(), frame.header().is_masked());
+            let (header, payload) = frame.into_parts();
+            assert_eq!(OpCode::Close, header.opcode());
+            assert_eq!(u16::from(CloseCode::TRY_AGAIN_LATER).to_be_bytes(), &payload[..]);
+            assert_eq!(0, decoder.buffered())
+        }
+    }
+
+    #[cfg(feature = "tap")]
+    #[tokio::test]
+    async fn wire_tap() {
+        #[derive(Clone, Default)]
+        struct Recording(Arc<Mutex<Vec<u8>>>);
+
+        impl std::io::Write for Recording {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        fn decode(bytes: &[u8]) -> Vec<(OpCode, Vec<u8>)> {
+            let mut decoder = FrameDecoder::new(base::Codec::new());
+            decoder.push_bytes(bytes);
+            let mut frames = Vec::new();
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                frames.push((frame.header().opcode(), frame.payload().to_vec()))
+            }
+            assert_eq!(0, decoder.buffered());
+            frames
+        }
+
+        let (a, b) = tokio::io::duplex(1024);
+        let (read, written) = (Recording::default(), Recording::default());
+        let mut client = Builder::new(a.compat(), Mode::Client);
+        client.set_wire_tap(read.clone(), written.clone());
+        let (mut client_tx, mut client_rx) = client.finish();
+        let (mut server_tx, mut server_rx) = Builder::new(b.compat(), Mode::Server).finish();
+
+        client_tx.send_text("hello").await.unwrap();
+        client_tx.send_binary(&[1, 2, 3]).await.unwrap();
+        client_tx.flush().await.unwrap();
+        server_tx.send_text("world").await.unwrap();
+        server_tx.flush().await.unwrap();
+
+        let mut message = Vec::new();
+        server_rx.receive_data(&mut message).await.unwrap();
+        server_rx.receive_data(&mut message).await.unwrap();
+        client_rx.receive_data(&mut message).await.unwrap();
+
+        let expected = vec![(OpCode::Text, b"hello".to_vec()), (OpCode::Binary, vec![1, 2, 3])];
+        assert_eq!(expected, decode(&written.0.lock().unwrap()));
+        let incoming = decode(&read.0.lock().unwrap());
+        assert_eq!(vec![(OpCode::Text, b"world".to_vec())], incoming)
+    }
+
+    #[cfg(feature = "test-util")]
+    #[tokio::test]
+    async fn deterministic_masks() {
+        async fn session(seed: u64) -> Vec<u8> {
+            let (a, mut b) = tokio::io::duplex(1024);
+            let (mut sender, receiver) = Builder::new_deterministic(a.compat(), Mode::Client, seed).finish();
+            sender.send_text("hello").await.unwrap();
+            sender.send_binary(&[1, 2, 3]).await.unwrap();
+            sender.close().await.unwrap();
+            drop((sender, receiver));
+            let mut bytes = Vec::new();
+            b.read_to_end(&mut bytes).await.unwrap();
+            bytes
+        }
+        assert_eq!(session(1).await, session(1).await);
+        assert_ne!(session(1).await, session(2).await)
+    }
+
+    #[tokio::test]
+    async fn peer_closed_writes() {
+        let (a, mut b) = tokio::io::duplex(1024);
+        let (mut sender, mut receiver) = Builder::new(a.compat(), Mode::Server).finish();
+
+        b.write_all(&[0x81, 0x81, 0, 0, 0, 0, b'a']).await.unwrap();
+        b.shutdown().await.unwrap();
+
+        let mut data = Vec::new();
+        assert!(matches!(receiver.receive(&mut data).await, Ok(Incoming::Data(Data::Text(1)))));
+        assert!(matches!(receiver.receive(&mut data).await, Ok(Incoming::PeerClosedWrites)));
+        assert!(matches!(receiver.receive(&mut data).await, Err(Error::Closed(_))));
+
+        // We can still send.
+        sender.send_text("b").await.unwrap();
+        sender.flush().await.unwrap();
+        let mut frame = [0; 3];
+        b.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame, [0x81, 0x01, b'b'])
+    }
+
+    #[tokio::test]
+    async fn peer_closed_writes_within_message() {
+        let (a, mut b) = tokio::io::duplex(1024);
+        let (_sender, mut receiver) = Builder::new(a.compat(), Mode::Server).finish();
+
+        b.write_all(&[0x01, 0x81, 0, 0, 0, 0, b'a']).await.unwrap();
+        b.shutdown().await.unwrap();
+
+        let mut data = Vec::new();
+        assert!(matches!(receiver.receive(&mut data).await, Err(Error::Io(_))))
+    }
+
+    #[test]
+    fn inbound_rate() {
+        use super::{InboundRate, RateLimit, RateLimitPolicy};
+
+        let ms = Duration::from_millis(1);
+        let t0 = Instant::now();
+
+        // Two messages at once, then one every 500 ms.
+        let mut limit = RateLimit::new();
+        limit.set_messages_per_sec(2);
+        let mut rate = InboundRate::new(limit, RateLimitPolicy::Fail, t0);
+        assert_eq!(None, rate.take(t0, 10, true));
+        assert_eq!(None, rate.take(t0, 10, false));
+        assert_eq!(None, rate.take(t0, 10, true));
+        assert_eq!(Some(500 * ms), rate.take(t0, 10, true));
+        assert_eq!(None, rate.take(t0 + 1000 * ms, 10, true));
+        assert_eq!(Some(500 * ms), rate.take(t0 + 1000 * ms, 10, true));
+
+        // Bytes count for every frame, the burst allows for 200 at once.
+        let mut limit = RateLimit::new();
+        limit.set_bytes_per_sec(100).set_burst(2000 * ms);
+        let mut rate = InboundRate::new(limit, RateLimitPolicy::Fail, t0);
+        assert_eq!(None, rate.take(t0, 150, false));
+        assert_eq!(Some(500 * ms), rate.take(t0, 100, true));
+        assert_eq!(None, rate.take(t0 + 10_000 * ms, 200, true))
+    }
+
+    #[tokio::test]
+    async fn inbound_rate_limit_fail() {
+        use super::{RateLimit, RateLimitPolicy};
+
+        let (a, b) = tokio::io::duplex(1024);
+        let (mut client_tx, mut client_rx) = Builder::new(a.compat(), Mode::Client).finish();
+        let mut server = Builder::new(b.compat(), Mode::Server);
+        let mut limit = RateLimit::new();
+        limit.set_messages_per_sec(1);
+        server.set_inbound_rate_limit(limit, RateLimitPolicy::Fail);
+        let (_, mut server_rx) = server.finish();
+
+        client_tx.send_binary(b"one").await.unwrap();
+        client_tx.send_binary(b"two").await.unwrap();
+        client_tx.flush().await.unwrap();
+
+        let mut data = Vec::new();
+        server_rx.receive_data(&mut data).await.unwrap();
+        assert!(matches!(server_rx.receive_data(&mut data).await, Err(Error::RateLimited)));
+        match client_rx.receive(&mut data).await {
+            Ok(Incoming::Closed(reason)) => assert_eq!(CloseCode::POLICY_VIOLATION, reason.code()),
+            other => panic!("unexpected: {:?}", other)
+        }
+    }
+
+    #[tokio::test]
+    async fn inbound_rate_limit_throttle() {
+        use futures::channel::oneshot;
+        use super::{RateLimit, RateLimitPolicy};
+
+        let (a, b) = tokio::io::duplex(1024);
+        let (mut client_tx, mut client_rx) = Builder::new(a.compat(), Mode::Client).finish();
+        let mut server = Builder::new(b.compat(), Mode::Server);
+        let mut limit = RateLimit::new();
+        limit.set_messages_per_sec(1);
+
+        // Timers which complete when told to.
+        let timers = Arc::new(Mutex::new(Vec::new()));
+        let timers2 = timers.clone();
+        let sleep = move |d| {
+            let (tx, rx) = oneshot::channel::<()>();
+            timers2.lock().unwrap().push((d, tx));
+            Box::pin(async move { let _ = rx.await; }) as futures::future::BoxFuture<'static, ()>
+        };
+        server.set_inbound_rate_limit(limit, RateLimitPolicy::Throttle(Box::new(sleep)));
+        let (_, mut server_rx) = server.finish();
+
+        client_tx.send_binary(b"one").await.unwrap();
+        client_tx.send_binary(b"two").await.unwrap();
+        client_tx.send_control(ControlPayload::ping(b"p").unwrap()).await.unwrap();
+        client_tx.flush().await.unwrap();
+
+        let server = tokio::spawn(async move {
+            let mut messages = Vec::new();
+            for _ in 0 .. 2 {
+                let mut data = Vec::new();
+                server_rx.receive_data(&mut data).await.unwrap();
+                messages.push(data)
+            }
+            messages
+        });
+
+        // While the second message waits for the rate to go down, the PING
+        // is answered.
+        let mut data = Vec::new();
+        assert!(matches!(client_rx.receive(&mut data).await, Ok(Incoming::Pong(b"p"))));
+        assert!(!server.is_finished());
+        let (wait, timer) = timers.lock().unwrap().pop().unwrap();
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1), "{:?}", wait);
+
+        timer.send(()).unwrap();
+        assert_eq!(vec![b"one".to_vec(), b"two".to_vec()], server.await.unwrap())
+    }
+
+    #[test]
+    fn read_rate() {
+        let sec = Duration::from_secs(1);
+        let t0 = Instant::now();
+
+        // A byte per second is too slow.
+        let mut rate = ReadRate::new(100, 10 * sec);
+        for i in 0 .. 10 {
+            assert!(rate.update(t0 + i * sec, 1).is_ok())
+        }
+        assert!(matches!(rate.update(t0 + 10 * sec, 1), Err(Error::Timeout(TimeoutKind::ReadRate))));
+
+        // Fast enough.
+        rate.reset();
+        for i in 0 .. 100 {
+            assert!(rate.update(t0 + i * sec, 100).is_ok())
+        }
+
+        // The window slides, so a fast start does not cover a stall later on.
+        rate.reset();
+        assert!(rate.update(t0, 100_000).is_ok());
+        assert!(matches!(rate.update(t0 + 11 * sec, 1), Err(Error::Timeout(TimeoutKind::ReadRate))));
+
+        // Time before the first bytes of a message does not count.
+        rate.reset();
+        assert!(rate.update(t0 + 100 * sec, 1000).is_ok());
+        assert!(rate.update(t0 + 105 * sec, 1000).is_ok())
+    }
+
+    #[tokio::test]
+    async fn min_read_rate() {
+        let (a, mut b) = tokio::io::duplex(1024);
+        let mut builder = Builder::new(a.compat(), Mode::Client);
+        builder.set_min_read_rate(1000, Duration::from_millis(50));
+        let (_sender, mut receiver) = builder.finish();
+
+        let dribble = async move {
+            for byte in &[0x81, 0x01, b'a'] {
+                b.write_all(&[*byte]).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(60)).await
+            }
+            b
+        };
+        let mut data = Vec::new();
+        let (result, _b) = tokio::join!(receiver.receive(&mut data), dribble);
+        assert!(matches!(result, Err(Error::Timeout(TimeoutKind::ReadRate))))
+    }
+
+    #[tokio::test]
+    async fn ping_split_across_reads() {
+        fn masked(first: u8, payload: &[u8]) -> Vec<u8> {
+            let mask = [0x37, 0xfa, 0x21, 0x3d];
+            let mut frame = vec![first, 0x80 | payload.len() as u8];
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+            frame
+        }
+
+        let ping = (0 .. 125).collect::<Vec<u8>>();
+        let inputs = [
+            // A PING on its own.
+            ([masked(0x89, &ping), masked(0x88, &[0x03, 0xE8])].concat(), vec![]),
+            // A PING in between the fragments of a message.
+            ([masked(0x01, b"ab"), masked(0x89, &ping), masked(0x80, b"cd"), masked(0x88, &[0x03, 0xE8])].concat(),
+             vec![b"abcd".to_vec()])
+        ];
+        for (input, expected) in &inputs {
+            let (a, b) = tokio::io::duplex(4096);
+            let (mut b_rx, mut b_tx) = tokio::io::split(b);
+            let (sender, mut receiver) = Builder::new(a.compat(), Mode::Server).finish();
+            let dribble = async move {
+                for byte in input {
+                    b_tx.write_all(&[*byte]).await.unwrap();
+                    tokio::task::yield_now().await
+                }
+            };
+            let receive = async {
+                let mut data = Vec::new();
+                let mut texts = Vec::new();
+                loop {
+                    match receiver.receive(&mut data).await.unwrap() {
+                        Incoming::Data(_) => texts.push(std::mem::take(&mut data)),
+                        Incoming::Closed(_) => return texts,
+                        other => panic!("unexpected: {:?}", other)
+                    }
+                }
+            };
+            let ((), texts) = tokio::join!(dribble, receive);
+            assert_eq!(expected, &texts);
+            drop((sender, receiver));
+
+            // Exactly one complete PONG, then the CLOSE answer.
+            let mut output = Vec::new();
+            b_rx.read_to_end(&mut output).await.unwrap();
+            assert_eq!([&[0x8A, 125][..], &ping, &[0x88, 0x02, 0x03, 0xE8]].concat(), output)
+        }
+    }
+
+    #[tokio::test]
+    async fn quotas() {
+        async fn close_code(b: &mut tokio::io::DuplexStream) -> CloseCode {
+            let mut bytes = Vec::new();
+            b.read_to_end(&mut bytes).await.unwrap();
+            assert_eq!(bytes[.. 2], [0x88, 0x02]);
+            CloseCode::from(u16::from_be_bytes([bytes[2], bytes[3]]))
+        }
+
+        // Two frames of 7 bytes each are received, the third exceeds the quota.
+        let (a, mut b) = tokio::io::duplex(1024);
+        let mut server = Builder::new(a.compat(), Mode::Server);
+        server.set_recv_quota(Some(20));
+        let (sender, mut receiver) = server.finish();
+        for _ in 0 .. 3 {
+            b.write_all(&[0x81, 0x81, 0, 0, 0, 0, b'a']).await.unwrap();
+        }
+        let mut data = Vec::new();
+        receiver.receive_data(&mut data).await.unwrap();
+        receiver.receive_data(&mut data).await.unwrap();
+        assert!(matches!(receiver.receive_data(&mut data).await, Err(Error::QuotaExceeded)));
+        assert!(matches!(receiver.receive_data(&mut data).await, Err(Error::Closed(_))));
+        drop((sender, receiver));
+        assert_eq!(CloseCode::POLICY_VIOLATION, close_code(&mut b).await);
+
+        // Two frames of 3 bytes each are sent, the third would exceed the quota.
+        let (a, mut b) = tokio::io::duplex(1024);
+        let mut server = Builder::new(a.compat(), Mode::Server);
+        server.set_send_quota(Some(8));
+        let (mut sender, receiver) = server.finish();
+        sender.send_text("a").await.unwrap();
+        sender.send_text("b").await.unwrap();
+        assert!(matches!(sender.send_text("c").await, Err(Error::QuotaExceeded)));
+        assert!(matches!(sender.send_text("d").await, Err(Error::Closed(_))));
+        drop((sender, receiver));
+        let mut frames = [0; 6];
+        b.read_exact(&mut frames).await.unwrap();
+        assert_eq!([0x81, 0x01, b'a', 0x81, 0x01, b'b'], frames);
+        assert_eq!(CloseCode::POLICY_VIOLATION, close_code(&mut b).await)
+    }
+
+    /// A socket which yields bytes as if they arrived in chunks of the
+    /// given sizes.
+    struct Chunked {
+        data: futures::io::Cursor<Vec<u8>>,
+        /// The sizes of the chunks which have not arrived yet.
+        schedule: std::collections::VecDeque<usize>,
+        /// The number of bytes of the last chunk which have not been read.
+        available: usize
+    }
+
+    impl futures::AsyncRead for Chunked {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, b: &mut [u8]) -> Poll<io::Result<usize>> {
+            if self.available == 0 {
+                self.available = self.schedule.pop_front().unwrap_or(0)
+            }
+            let n = std::cmp::min(self.available, b.len());
+            let n = futures::ready!(Pin::new(&mut self.data).poll_read(cx, &mut b[.. n]))?;
+            self.available -= n;
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    impl futures::AsyncWrite for Chunked {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context, b: &[u8]) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(b.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Split `len` bytes into chunks of the given sizes, repeated as needed.
+    fn schedule(sizes: &[usize], len: usize) -> Vec<usize> {
+        let mut chunks = Vec::new();
+        let mut total = 0;
+        for &size in sizes.iter().chain(std::iter::repeat(&1)).cycle() {
+            if total == len {
+                break
+            }
+            let n = std::cmp::min(std::cmp::max(1, size % 64), len - total);
+            chunks.push(n);
+            total += n
+        }
+        chunks
+    }
+
+    /// Decode complete messages from `bytes` with the sans-IO base codec.
+    fn decode_messages(mut bytes: &[u8]) -> Vec<(OpCode, Vec<u8>)> {
+        let codec = base::Codec::new();
+        let mut messages = Vec::new();
+        let mut current: Option<(OpCode, Vec<u8>)> = None;
+        while let Ok(Parsing::Done { value: header, offset }) = codec.decode_header(bytes) {
+            let payload = &bytes[offset .. offset + header.payload_len()];
+            bytes = &bytes[offset + header.payload_len() ..];
+            if header.opcode().is_control() {
+                continue
+            }
+            let (_, data) = current.get_or_insert_with(|| (header.opcode(), Vec::new()));
+            data.extend_from_slice(payload);
+            if header.is_fin() {
+                messages.extend(current.take())
+            }
+        }
+        messages
+    }
+
+    /// What a decoder made of a stream of bytes.
+    #[derive(Debug, PartialEq)]
+    enum Decoded {
+        /// A frame's opcode, fin bit, payload and the stream offset up to
+        /// which the bytes have been consumed.
+        Frame(OpCode, bool, Vec<u8>, usize),
+        /// The kind of an error and the stream offset up to which the bytes
+        /// have been consumed.
+        Error(String, usize),
+        /// The stream ended at a frame boundary.
+        End
+    }
+
+    /// The kind of an error, independent of where it has been detected.
+    fn error_kind(e: &Error) -> String {
+        match e {
+            Error::Protocol(e) | Error::Malformed { error: e, .. } => format!("{:?}", e),
+            Error::Closed(_) => "Closed".into(),
+            Error::HttpInjected {..} => "HttpInjected".into(),
+            e => format!("{:?}", e)
+        }
+    }
+
+    /// Decode frames as a client's `Receiver` does, but with the sans-IO
+    /// `FrameDecoder`, which is given the bytes in the chunks of `schedule`.
+    ///
+    /// As the receiver discards everything after a CLOSE frame, the offset
+    /// of a CLOSE frame, or an error about one, is where it starts. The same
+    /// goes for a frame which is incomplete at the end of the stream, as the
+    /// receiver has made room for its payload already.
+    fn decode_frames(bytes: &[u8], schedule: &[usize], config: base::CodecConfig) -> Vec<Decoded> {
+        let codec = base::Codec::with_config(config);
+        let mut decoder = FrameDecoder::new(base::Codec::with_config(config));
+        let mut decoded = Vec::new();
+        let mut pushed = 0;
+        for &n in schedule {
+            decoder.push_bytes(&bytes[pushed .. pushed + n]);
+            pushed += n;
+            loop {
+                let start = pushed - decoder.buffered();
+                // The receiver rejects a masked frame as soon as its header
+                // is complete.
+                if let Ok(Parsing::Done { value, offset }) = codec.decode_header(&bytes[start .. pushed]) {
+                    if value.is_masked() {
+                        decoded.push(Decoded::Error(error_kind(&Error::MaskedFrame), start + offset));
+                        return decoded
+                    }
+                }
+                let (header, payload) = match decoder.next_frame() {
+                    Ok(Some(frame)) => frame.into_parts(),
+                    Ok(None) => break,
+                    Err(e) => {
+                        let e = match super::http_status_line(&bytes[start .. pushed]) {
+                            Some(status_line) => Error::HttpInjected { status_line },
+                            None => Error::from(e)
+                        };
+                        decoded.push(Decoded::Error(error_kind(&e), start));
+                        return decoded
+                    }
+                };
+                if header.opcode() == OpCode::Close {
+                    let invalid = if payload.len() == 1 {
+                        Some(Error::InvalidClosePayload)
+                    } else {
+                        CloseReason::from_payload(&payload).err()
+                    };
+                    decoded.push(match invalid {
+                        Some(e) => Decoded::Error(error_kind(&e), start),
+                        None => Decoded::Frame(OpCode::Close, header.is_fin(), payload, start)
+                    });
+                    return decoded
+                }
+                let end = pushed - decoder.buffered();
+                decoded.push(Decoded::Frame(header.opcode(), header.is_fin(), payload, end))
+            }
+        }
+        let start = pushed - decoder.buffered();
+        decoded.push(if start == bytes.len() {
+            Decoded::End
+        } else {
+            Decoded::Error(error_kind(&Error::Closed(CloseReason::abnormal())), start)
+        });
+        decoded
+    }
+
+    /// Decode frames with a client's `Receiver`, reading `bytes` in the
+    /// chunks of `schedule`.
+    fn receive_frames(bytes: Vec<u8>, schedule: Vec<usize>, config: base::CodecConfig) -> Vec<Decoded> {
+        let socket = Chunked { data: futures::io::Cursor::new(bytes), schedule: schedule.into(), available: 0 };
+        let (_, mut receiver) = Builder::with_codec_config(socket, Mode::Client, config).finish();
+        let consumed = |r: &super::Receiver<Chunked>| {
+            let read = crate::tap::inner(&r.reader).get().unwrap().data.position();
+            read as usize - r.buffer.len()
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let mut decoded = Vec::new();
+        runtime.block_on(async {
+            loop {
+                let start = consumed(&receiver);
+                match receiver.next_frame().await {
+                    Ok(Some(frame)) => {
+                        let (header, payload) = frame.into_parts();
+                        if header.opcode() == OpCode::Close {
+                            decoded.push(Decoded::Frame(OpCode::Close, header.is_fin(), payload, start));
+                            break
+                        }
+                        let end = consumed(&receiver);
+                        decoded.push(Decoded::Frame(header.opcode(), header.is_fin(), payload, end))
+                    }
+                    Ok(None) => {
+                        decoded.push(Decoded::End);
+                        break
+                    }
+                    Err(e) => {
+                        let offset = match e {
+                            Error::InvalidClosePayload
+                            | Error::InvalidCloseReason(_)
+                            | Error::Closed(_) => start,
+                            _ => consumed(&receiver)
+                        };
+                        decoded.push(Decoded::Error(error_kind(&e), offset));
+                        break
+                    }
+                }
+            }
+        });
+        decoded
+    }
+
+    /// Frames, valid or not, delivered in arbitrary chunks must be decoded
+    /// identically by the `Receiver` and the sans-IO `FrameDecoder`, with
+    /// the same errors after consuming the same number of bytes.
+    #[test]
+    fn chunked_frames_round_trip() {
+        fn property(frames: Vec<(u8, u8, Vec<u8>)>, mutations: Vec<(usize, u8)>, cut: Option<usize>, sizes: Vec<usize>) -> bool {
+            let mut config = base::CodecConfig::new();
+            config.set_max_data_size(96);
+            let mut codec = base::Codec::with_config(config);
+            let mut bytes = Vec::new();
+            for (opcode, flags, payload) in frames {
+                let op = match opcode % 32 {
+                    0 ..= 3 => OpCode::Continue,
+                    4 ..= 11 => OpCode::Text,
+                    12 ..= 19 => OpCode::Binary,
+                    20 ..= 24 => OpCode::Ping,
+                    25 ..= 30 => OpCode::Pong,
+                    _ => OpCode::Close
+                };
+                let mut header = Header::new(op);
+                header.set_fin(flags & 1 == 1 || op.is_control()).set_payload_len(payload.len());
+                if flags % 64 == 63 {
+                    header.set_masked(true).set_mask(0x0102_0304);
+                }
+                bytes.extend_from_slice(codec.encode_header(&header));
+                bytes.extend_from_slice(&payload)
+            }
+            // Up to 3 bytes are changed, so that streams are not always
+            // invalid from the start.
+            if !bytes.is_empty() {
+                let n = mutations.len() % 4;
+                for (i, x) in mutations.into_iter().take(n) {
+                    let len = bytes.len();
+                    bytes[i % len] ^= x
+                }
+            }
+            if let Some(cut) = cut {
+                bytes.truncate(bytes.len() - cut % (bytes.len() + 1))
+            }
+            let schedule = schedule(&sizes, bytes.len());
+            let expected = decode_frames(&bytes, &schedule, config);
+            expected == receive_frames(bytes, schedule, config)
+        }
+        QuickCheck::new()
+            .tests(1000)
+            .quickcheck(property as fn(Vec<(u8, u8, Vec<u8>)>, Vec<(usize, u8)>, Option<usize>, Vec<usize>) -> bool)
+    }
+
+    #[tokio::test]
+    async fn send_with_deadline() {
+        // The remote does not read, so the writer stalls once the pipe is full.
+        let (a, mut b) = tokio::io::duplex(64);
+        let (sender, _) = Builder::new(a.compat(), Mode::Server).finish();
+        let (mut shared, task) = sender.into_shared(4);
+        let task = tokio::spawn(task);
+
+        shared.send_binary(vec![0; 100]).await.unwrap();
+
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let stale = (0 .. 2).map(|i| {
+            let mut shared = shared.clone();
+            tokio::spawn(async move {
+                shared.send_with_deadline(Outgoing::Binary(vec![i]), deadline).await
+            })
+        }).collect::<Vec<_>>();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let fresh = {
+            let mut shared = shared.clone();
+            let deadline = Instant::now() + Duration::from_secs(60);
+            tokio::spawn(async move {
+                shared.send_with_deadline(Outgoing::Binary(vec![2]), deadline).await
+            })
+        };
+        drop(shared);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut bytes = [0; 105];
+        b.read_exact(&mut bytes).await.unwrap();
+        for s in stale {
+            assert_eq!(Delivery::Expired, s.await.unwrap().unwrap())
+        }
+        assert_eq!(Delivery::Sent, fresh.await.unwrap().unwrap());
+        assert!(task.await.unwrap().is_ok());
+
+        // The first message has been sent completely, followed by the fresh one.
+        assert_eq!(bytes[.. 2], [0x82, 0x64]);
+        assert_eq!(bytes[102 ..], [0x82, 0x01, 2])
+    }
+
+    #[tokio::test]
+    async fn shared_sender() {
+        let (a, b) = tokio::io::duplex(1024);
+        let (sender, _) = Builder::new(a.compat(), Mode::Client).finish();
+        let (_, mut receiver) = Builder::new(b.compat(), Mode::Server).finish();
+
+        let (shared, task) = sender.into_shared(1);
+        let task = tokio::spawn(task);
+
+        let producers = (0 .. 2).map(|i| {
+            let mut shared = shared.clone();
+            tokio::spawn(async move {
+                for j in 0 .. 10 {
+                    shared.send_text(format!("{}:{}", i, j)).await.unwrap()
+                }
+            })
+        }).collect::<Vec<_>>();
+        drop(shared);
+
+        let mut received = Vec::new();
+        for _ in 0 .. 20 {
+            let mut data = Vec::new();
+            receiver.receive_data(&mut data).await.unwrap();
+            received.push(String::from_utf8(data).unwrap())
+        }
+        for p in producers {
+            p.await.unwrap()
+        }
+        assert!(task.await.unwrap().is_ok());
+
+        for i in 0 .. 2 {
+            let mine = received.iter().filter(|m| m.starts_with(&format!("{}:", i)));
+            let expected = (0 .. 10).map(|j| format!("{}:{}", i, j));
+            assert!(mine.cloned().eq(expected))
+        }
+    }
+
+    #[tokio::test]
+    async fn shared_sender_batch() {
+        let (a, b) = tokio::io::duplex(64);
+        let (sender, _) = Builder::new(a.compat(), Mode::Client).finish();
+        let (_, mut receiver) = Builder::new(b.compat(), Mode::Server).finish();
+
+        let (shared, task) = sender.into_shared(1);
+        let task = tokio::spawn(task);
+
+        let producers = (0 .. 2).map(|i| {
+            let mut shared = shared.clone();
+            tokio::spawn(async move {
+                for j in 0 .. 20 {
+                    let batch = (0 .. 5).map(|k| Outgoing::Text(format!("{}:{}:{}", i, j, k)));
+                    shared.send_batch(batch).await.unwrap();
+                    tokio::task::yield_now().await
+                }
+            })
+        }).collect::<Vec<_>>();
+        drop(shared);
+
+        let mut received = Vec::new();
+        for _ in 0 .. 200 {
+            let mut data = Vec::new();
+            receiver.receive_data(&mut data).await.unwrap();
+            received.push(String::from_utf8(data).unwrap())
+        }
+        for p in producers {
+            p.await.unwrap()
+        }
+        assert!(task.await.unwrap().is_ok());
+
+        for group in received.chunks(5) {
+            let (i, j) = group[0].rsplit_once(':').unwrap().0.split_once(':').unwrap();
+            let expected = (0 .. 5).map(|k| format!("{}:{}:{}", i, j, k));
+            assert!(group.iter().cloned().eq(expected), "{:?}", group)
+        }
+    }
+
+    #[tokio::test]
+    async fn receive_after_close() {
+        let (a, mut b) = tokio::io::duplex(1024);
+        let (mut sender, mut receiver) = Builder::new(a.compat(), Mode::Client).finish();
+
+        sender.close().await.unwrap();
+        assert!(matches!(sender.send_text("x").await, Err(Error::Closed(_))));
+        let mut close = [0; 8];
+        b.read_exact(&mut close).await.unwrap();
+        assert_eq!(close[.. 2], [0x88, 0x82]);
+
+        // The remote still sends some data before answering our CLOSE.
+        b.write_all(&[0x81, 0x01, b'x', 0x89, 0x00, 0x88, 0x02, 0x03, 0xE8]).await.unwrap();
+
+        let mut data = Vec::new();
+        assert!(matches!(receiver.receive(&mut data).await, Ok(Incoming::Data(Data::Text(1)))));
+        assert!(matches!(receiver.receive(&mut data).await, Ok(Incoming::Closed(_))));
+        assert!(matches!(receiver.receive(&mut data).await, Err(Error::Closed(r)) if r.code() == CloseCode::NORMAL));
+        assert!(matches!(sender.send_text("x").await, Err(Error::Closed(r)) if r.code() == CloseCode::NORMAL));
+
+        // Neither PING nor CLOSE have been answered.
+        let mut rest = Vec::new();
+        b.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty())
+    }
+
+    /// An extension which takes some time for every frame.
+    #[derive(Debug)]
+    struct Sleepy(Duration);
+
+    impl Extension for Sleepy {
+        fn is_enabled(&self) -> bool {
+            true
+        }
+
+        fn name(&self) -> &str {
+            "sleepy"
+        }
+
+        fn params(&self) -> &[Param<'_>] {
+            &[]
+        }
+
+        fn configure(&mut self, _: &[Param]) -> Result<(), crate::BoxedError> {
+            Ok(())
+        }
+
+        fn encode(&mut self, _: &mut Header, _: &mut crate::Storage) -> Result<(), crate::BoxedError> {
+            std::thread::sleep(self.0);
+            Ok(())
+        }
+
+        fn decode(&mut self, _: &mut Header, _: &mut Vec<u8>) -> Result<(), crate::BoxedError> {
+            std::thread::sleep(self.0);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn extension_stats() {
+        let (a, b) = tokio::io::duplex(1024);
+        let exceeded = Arc::new(Mutex::new(Vec::new()));
+        let mut client = Builder::new(a.compat(), Mode::Client);
+        client.add_extensions(Some(Box::new(Sleepy(Duration::from_millis(5))) as Box<dyn Extension + Send>));
+        client.set_extension_budget(Duration::from_millis(1), {
+            let exceeded = exceeded.clone();
+            move |name, elapsed| exceeded.lock().unwrap().push((name.to_string(), elapsed))
+        });
+        let mut server = Builder::new(b.compat(), Mode::Server);
+        server.add_extensions(Some(Box::new(Sleepy(Duration::from_millis(0))) as Box<dyn Extension + Send>));
+        let (mut sender, _) = client.finish();
+        let (_, mut receiver) = server.finish();
+
+        sender.send_text("hello").await.unwrap();
+        sender.flush().await.unwrap();
+        let mut data = Vec::new();
+        assert!(matches!(receiver.receive_data(&mut data).await, Ok(Data::Text(5))));
+
+        let stats = sender.extension_stats().await;
+        assert_eq!(1, stats.len());
+        assert_eq!("sleepy", stats[0].name());
+        assert_eq!((1, 5), (stats[0].encoded_frames(), stats[0].encoded_bytes()));
+        assert!(stats[0].encode_time() >= Duration::from_millis(5));
+        assert_eq!(0, stats[0].decoded_frames());
+
+        let stats = receiver.extension_stats().await;
+        assert_eq!((1, 5), (stats[0].decoded_frames(), stats[0].decoded_bytes()));
+
+        let exceeded = exceeded.lock().unwrap();
+        assert_eq!(1, exceeded.len());
+        assert_eq!("sleepy", exceeded[0].0);
+        assert!(exceeded[0].1 >= Duration::from_millis(5))
+    }
+
+    #[tokio::test]
+    async fn control_payload() {
+        use crate::data::{ControlPayload, InvalidControlPayload};
+
+        assert!(ControlPayload::ping(&[0; 125]).is_ok());
+        assert_eq!(Err(InvalidControlPayload::TooLong { len: 126 }), ControlPayload::pong(&[0; 126]));
+        assert_eq!(Err(InvalidControlPayload::CloseCode(CloseCode::NO_STATUS_RECEIVED)),
+            ControlPayload::close(CloseCode::NO_STATUS_RECEIVED, ""));
+        assert!(ControlPayload::close(CloseCode::from(4000), &"x".repeat(123)).is_ok());
+        // The reason can be truncated only at character boundaries.
+        let reason = format!("x{}", "ö".repeat(62));
+        assert_eq!(Err(InvalidControlPayload::ReasonTooLong { len: 125, truncate_at: 123 }),
+            ControlPayload::close(CloseCode::NORMAL, &reason));
+        let reason = "ö".repeat(62);
+        assert_eq!(Err(InvalidControlPayload::ReasonTooLong { len: 124, truncate_at: 122 }),
+            ControlPayload::close(CloseCode::NORMAL, &reason));
+
+        let (a, b) = tokio::io::duplex(1024);
+        let (mut sender, _) = Builder::new(a.compat(), Mode::Client).finish();
+        let (_, mut receiver) = Builder::new(b.compat(), Mode::Server).finish();
+        sender.send_control(ControlPayload::pong(b"pong").unwrap()).await.unwrap();
+        sender.send_control(ControlPayload::close(CloseCode::GOING_AWAY, "bye").unwrap()).await.unwrap();
+        assert!(matches!(sender.send_text("x").await, Err(Error::Closed(_))));
+
+        let mut data = Vec::new();
+        assert!(matches!(receiver.receive(&mut data).await, Ok(Incoming::Pong(b"pong"))));
+        match receiver.receive(&mut data).await {
+            Ok(Incoming::Closed(reason)) => {
+                assert_eq!(CloseCode::GOING_AWAY, reason.code());
+                assert_eq!(Some("bye"), reason.raw_reason())
+            }
+            other => panic!("unexpected: {:?}", other.map(|_| ()))
+        }
+    }
+
+    #[tokio::test]
+    async fn close_echo() {
+        async fn answer(echo: Option<CloseEcho>, close: &[u8]) -> Vec<u8> {
+            let (a, mut b) = tokio::io::duplex(1024);
+            let mut server = Builder::new(a.compat(), Mode::Server);
+            if let Some(e) = echo {
+                server.set_close_echo(e)
+            }
+            let (_, mut receiver) = server.finish();
+            let mut frame = vec![0x88, 0x80 | close.len() as u8, 0, 0, 0, 0];
+            frame.extend_from_slice(close);
+            b.write_all(&frame).await.unwrap();
+            let mut data = Vec::new();
+            assert!(matches!(receiver.receive(&mut data).await, Ok(Incoming::Closed(_))));
+            let mut answer = Vec::new();
+            b.read_to_end(&mut answer).await.unwrap();
+            answer
+        }
+
+        assert_eq!(vec![0x88, 0x02, 0x03, 0xE8], answer(None, &[0x03, 0xE8]).await);
+        assert_eq!(vec![0x88, 0x02, 0x03, 0xE9], answer(None, &[0x03, 0xE9]).await);
+        assert_eq!(vec![0x88, 0x02, 0x03, 0xE8], answer(None, &[]).await);
+        assert_eq!(vec![0x88, 0x02, 0x03, 0xEA], answer(None, &[0x03, 0xED]).await);
+        assert_eq!(vec![0x88, 0x00], answer(Some(CloseEcho::Empty), &[0x03, 0xE8]).await);
+        let going_away = Some(CloseEcho::Code(CloseCode::GOING_AWAY));
+        assert_eq!(vec![0x88, 0x02, 0x03, 0xE9], answer(going_away, &[0x03, 0xE8]).await);
+        assert_eq!(vec![0x88, 0x02, 0x03, 0xEA], answer(going_away, &[0x03, 0xED]).await)
+    }
+
+    #[tokio::test]
+    async fn single_byte_close() {
+        async fn receive(lenient: bool) -> (Result<CloseCode, Error>, Vec<u8>) {
+            let (a, mut b) = tokio::io::duplex(1024);
+            let mut builder = Builder::new(a.compat(), Mode::Server);
+            builder.set_deviations(*Deviations::new().set_accept_single_byte_close(lenient));
+            let (_, mut receiver) = builder.finish();
+            b.write_all(&[0x88, 0x81, 0, 0, 0, 0, 0x03]).await.unwrap();
+            let mut data = Vec::new();
+            let result = match receiver.receive(&mut data).await {
+                Ok(Incoming::Closed(reason)) => Ok(reason.code()),
+                Ok(other) => panic!("unexpected: {:?}", other),
+                Err(e) => Err(e)
+            };
+            drop(receiver);
+            let mut answer = Vec::new();
+            b.read_to_end(&mut answer).await.unwrap();
+            (result, answer)
+        }
+
+        // By default, the CLOSE is rejected as a protocol error.
+        let (result, answer) = receive(false).await;
+        assert!(matches!(result, Err(Error::InvalidClosePayload)));
+        assert_eq!(vec![0x88, 0x02, 0x03, 0xEA], answer);
+
+        // If lenient, it is treated like a CLOSE without payload, which is
+        // answered and reported with the normal status code.
+        let (result, answer) = receive(true).await;
+        assert_eq!(CloseCode::NORMAL, result.unwrap());
+        assert_eq!(vec![0x88, 0x02, 0x03, 0xE8], answer)
+    }
+
+    #[tokio::test]
+    async fn drop_policy() {
+        // By default, nothing is sent.
+        let (a, mut b) = tokio::io::duplex(1024);
+        drop(Builder::new(a.compat(), Mode::Client).finish());
+        let mut bytes = Vec::new();
+        b.read_to_end(&mut bytes).await.unwrap();
+        assert!(bytes.is_empty());
+
+        let (a, mut b) = tokio::io::duplex(1024);
+        let mut client = Builder::new(a.compat(), Mode::Client);
+        client.set_drop_policy(DropPolicy::BestEffortClose(CloseCode::GOING_AWAY));
+        drop(client.finish());
+        b.read_to_end(&mut bytes).await.unwrap();
+        assert_eq!(bytes[.. 2], [0x88, 0x82]);
+        assert_eq!([0x03, 0xE9], [bytes[6] ^ bytes[2], bytes[7] ^ bytes[3]]);
+
+        // No second CLOSE is sent after closing.
+        let (a, mut b) = tokio::io::duplex(1024);
+        let mut server = Builder::new(a.compat(), Mode::Server);
+        server.set_drop_policy(DropPolicy::BestEffortClose(CloseCode::GOING_AWAY));
+        let (mut sender, receiver) = server.finish();
+        sender.close().await.unwrap();
+        drop((sender, receiver));
+        bytes.clear();
+        b.read_to_end(&mut bytes).await.unwrap();
+        assert_eq!(vec![0x88, 0x02, 0x03, 0xE8], bytes);
+
+        // Nothing is sent if the socket is not writable.
+        let (a, mut b) = tokio::io::duplex(4);
+        let mut server = Builder::new(a.compat(), Mode::Server);
+        server.set_drop_policy(DropPolicy::BestEffortClose(CloseCode::GOING_AWAY));
+        let (mut sender, receiver) = server.finish();
+        sender.send_binary(&[1, 2]).await.unwrap();
+        drop((sender, receiver));
+        bytes.clear();
+        b.read_to_end(&mut bytes).await.unwrap();
+        assert_eq!(vec![0x82, 0x02, 1, 2], bytes)
+    }
+
+    #[tokio::test]
+    async fn heartbeat() {
+        let (a, _b) = tokio::io::duplex(1024);
+        let (mut sender, _receiver) = Builder::new(a.compat(), Mode::Server).finish();
+        assert_eq!(None, sender.heartbeat().await.unwrap());
+
+        let (a, mut b) = tokio::io::duplex(1024);
+        let mut server = Builder::new(a.compat(), Mode::Server);
+        server.set_keep_alive(KeepAlive::Pong(Duration::from_millis(50)));
+        let (mut sender, _receiver) = server.finish();
+
+        // Not idle long enough yet.
+        assert!(sender.heartbeat().await.unwrap().unwrap() <= Duration::from_millis(50));
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(Some(Duration::from_millis(50)), sender.heartbeat().await.unwrap());
+        // Sending data postpones the next heartbeat.
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        sender.send_binary(&[1]).await.unwrap();
+        assert!(sender.heartbeat().await.unwrap().unwrap() > Duration::from_millis(30));
+        sender.send_pong(std::convert::TryFrom::try_from(&b"x"[..]).unwrap()).await.unwrap();
+
+        let mut bytes = vec![0; 8];
+        b.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(bytes, [0x8A, 0x00, 0x82, 0x01, 1, 0x8A, 0x01, b'x'])
+    }
+
+    #[tokio::test]
+    async fn keep_alive_payload() {
+        let (a, b) = tokio::io::duplex(1024);
+        let mut client = Builder::new(a.compat(), Mode::Client);
+        client.set_keep_alive(KeepAlive::Ping(Duration::ZERO));
+        assert!(client.set_keep_alive_payload(vec![0; 126]).is_err());
+        client.set_keep_alive_payload(1_u64.to_be_bytes()).unwrap();
+        let (mut sender, mut receiver) = client.finish();
+        let (_, mut server) = Builder::new(b.compat(), Mode::Server).finish();
+
+        sender.heartbeat().await.unwrap();
+        sender.update_config(|c| c.set_keep_alive_payload(2_u64.to_be_bytes()).unwrap());
+        sender.heartbeat().await.unwrap();
+        let server = tokio::spawn(async move {
+            let mut data = Vec::new();
+            server.receive_data(&mut data).await
+        });
+
+        // The remote's PONGs echo the payloads.
+        for seq in 1_u64 ..= 2 {
+            let mut data = Vec::new();
+            match receiver.receive(&mut data).await {
+                Ok(Incoming::Pong(pong)) => assert_eq!(seq.to_be_bytes(), pong),
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
+        server.abort()
+    }
+
+    #[tokio::test]
+    async fn update_config() {
+        let (a, b) = tokio::io::duplex(1024);
+        let (mut client, _) = Builder::new(a.compat(), Mode::Client).finish();
+        let (mut sender, mut receiver) = Builder::new(b.compat(), Mode::Server).finish();
+
+        // Changes made through one half apply to the other.
+        sender.update_config(|c| {
+            assert_eq!(super::MAX_MESSAGE_SIZE, c.max_message_size());
+            c.set_max_message_size(4);
+            c.set_keep_alive(KeepAlive::Ping(Duration::from_secs(60)))
+        });
+        client.send_binary(&[1, 2, 3, 4]).await.unwrap();
+        client.send_binary(&[1, 2, 3, 4, 5]).await.unwrap();
+        client.flush().await.unwrap();
+
+        let mut data = Vec::new();
+        assert!(matches!(receiver.receive_data(&mut data).await, Ok(Data::Binary(4))));
+        assert!(matches!(receiver.receive_data(&mut data).await, Err(Error::MessageTooLarge { limit: 4, .. })));
+
+        assert!(sender.heartbeat().await.unwrap().unwrap() > Duration::from_secs(59));
+        receiver.update_config(|c| c.set_keep_alive(KeepAlive::Off));
+        assert_eq!(None, sender.heartbeat().await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn close_timeout() {
+        // The remote never answers our CLOSE.
+        let (a, mut b) = tokio::io::duplex(1024);
+        let mut server = Builder::new(a.compat(), Mode::Server);
+        server.set_close_timeout(Duration::from_millis(50));
+        let (mut sender, mut receiver) = server.finish();
+        sender.close().await.unwrap();
+        b.write_all(&[0x81, 0x81, 0, 0, 0, 0, b'a']).await.unwrap();
+        let t0 = Instant::now();
+        match receiver.await_close(tokio::time::sleep).await {
+            Err(Error::Timeout(TimeoutKind::Close)) => {}
+            other => panic!("unexpected result: {:?}", other)
+        }
+        assert!(t0.elapsed() >= Duration::from_millis(50));
+        assert!(matches!(sender.send_text("a").await, Err(Error::Closed(_))));
+
+        // The remote answers in time.
+        let (a, mut b) = tokio::io::duplex(1024);
+        let (mut sender, mut receiver) = Builder::new(a.compat(), Mode::Server).finish();
+        sender.close().await.unwrap();
+        b.write_all(&[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xE8]).await.unwrap();
+        let reason = receiver.await_close(tokio::time::sleep).await.unwrap();
+        assert_eq!(CloseCode::NORMAL, reason.code())
+    }
+
+    #[tokio::test]
+    async fn http_injected() {
+        const RESPONSE: &[u8] = b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n";
+
+        // At a frame boundary.
+        let (a, mut b) = tokio::io::duplex(1024);
+        let (_sender, mut receiver) = Builder::new(a.compat(), Mode::Client).finish();
+        b.write_all(&[0x82, 0x01, 1]).await.unwrap();
+        b.write_all(RESPONSE).await.unwrap();
+        let mut message = Vec::new();
+        receiver.receive_data(&mut message).await.unwrap();
+        match receiver.receive_data(&mut message).await {
+            Err(Error::HttpInjected { status_line }) => assert_eq!("HTTP/1.1 502 Bad Gateway", status_line),
+            other => panic!("unexpected result: {:?}", other)
+        }
+
+        // Within a frame header.
+        let (a, mut b) = tokio::io::duplex(1024);
+        let (_sender, mut receiver) = Builder::new(a.compat(), Mode::Client).finish();
+        b.write_all(&[0x82, 0x7F]).await.unwrap();
+        b.write_all(RESPONSE).await.unwrap();
+        match receiver.receive_data(&mut message).await {
+            Err(Error::HttpInjected { status_line }) => assert_eq!("HTTP/1.1 502 Bad Gateway", status_line),
+            other => panic!("unexpected result: {:?}", other)
+        }
+
+        // Other decoding errors are unaffected.
+        let (a, mut b) = tokio::io::duplex(1024);
+        let (_sender, mut receiver) = Builder::new(a.compat(), Mode::Client).finish();
+        b.write_all(&[0xC2, 0x00]).await.unwrap();
+        assert!(matches!(receiver.receive_data(&mut message).await, Err(Error::Malformed {..})))
+    }
+
+    #[tokio::test]
+    async fn malformed_header_keeps_raw_prefix() {
+        use tokio::io::AsyncWriteExt;
+
+        // A PING with a payload length above 125.
+        const CTRL_PAYLOAD_LEN: &[u8] = &[0x89, 0x7E, 0x00, 0x7E];
+        let (a, mut b) = tokio::io::duplex(1024);
+        let (_sender, mut receiver) = Builder::new(a.compat(), Mode::Client).finish();
+        b.write_all(CTRL_PAYLOAD_LEN).await.unwrap();
+        match receiver.receive_data(&mut Vec::new()).await {
+            Err(e@Error::Malformed {..}) => {
+                assert_eq!(CloseCode::PROTOCOL_ERROR, e.close_code());
+                if let Error::Malformed { error, raw_prefix } = e {
+                    assert!(matches!(error, base::Error::InvalidControlFrameLen));
+                    assert_eq!(CTRL_PAYLOAD_LEN, &raw_prefix[..])
+                }
+            }
+            other => panic!("unexpected result: {:?}", other)
+        }
+
+        // At most `RAW_PREFIX_LEN` bytes are kept.
+        let long = vec![0x83; 2 * super::RAW_PREFIX_LEN];
+        let (a, mut b) = tokio::io::duplex(1024);
+        let (_sender, mut receiver) = Builder::new(a.compat(), Mode::Client).finish();
+        b.write_all(&long).await.unwrap();
+        match receiver.receive_data(&mut Vec::new()).await {
+            Err(Error::Malformed { raw_prefix, .. }) => assert_eq!(&long[.. super::RAW_PREFIX_LEN], &raw_prefix[..]),
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[tokio::test]
+    async fn frame_len_beyond_usize() {
+        use tokio::io::AsyncWriteExt;
+
+        // A BINARY frame of 2^32 bytes.
+        const HEADER: &[u8] = &[0x82, 0x7F, 0, 0, 0, 1, 0, 0, 0, 0];
+        let (a, mut b) = tokio::io::duplex(1024);
+        let mut builder = Builder::new(a.compat(), Mode::Client);
+        builder.set_max_frame_size(usize::MAX);
+        let (_sender, mut receiver) = builder.finish();
+        b.write_all(HEADER).await.unwrap();
+        let result = receiver.receive_part(&mut Vec::new()).await;
+
+        // 32-bit platforms can not represent the length.
+        #[cfg(target_pointer_width = "32")]
+        match result {
+            Err(e@Error::FrameTooLarge { size: 0x1_0000_0000, maximum: usize::MAX }) => {
+                assert!(e.is_fatal());
+                assert_eq!(CloseCode::MESSAGE_TOO_BIG, e.close_code())
+            }
+            other => panic!("unexpected result: {:?}", other)
+        }
+
+        // Otherwise the frame is only too large for the message size limit.
+        #[cfg(not(target_pointer_width = "32"))]
+        assert!(matches!(result, Err(Error::MessageTooLarge {..})), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn frames() {
+        // A message in 3 fragments with a PING in between, then a CLOSE.
+        const INPUT: &[u8] = &[
+            0x01, 0x01, b'a',
+            0x89, 0x00,
+            0x00, 0x01, b'b',
+            0x80, 0x01, b'c',
+            0x88, 0x02, 0x03, 0xE8
+        ];
+
+        let (a, mut b) = tokio::io::duplex(1024);
+        let (_sender, mut receiver) = Builder::new(a.compat(), Mode::Client).finish();
+        b.write_all(INPUT).await.unwrap();
+        let frames = futures::StreamExt::collect::<Vec<_>>(receiver.frames()).await;
+        let opcodes = frames.iter()
+            .map(|f| f.as_ref().unwrap().header().opcode())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![OpCode::Text, OpCode::Ping, OpCode::Continue, OpCode::Continue, OpCode::Close], opcodes);
+        assert_eq!(b"c", frames[3].as_ref().unwrap().payload());
+        assert!(frames[3].as_ref().unwrap().header().is_fin());
+
+        let (a, mut b) = tokio::io::duplex(1024);
+        let (_sender, mut receiver) = Builder::new(a.compat(), Mode::Client).finish();
+        b.write_all(INPUT).await.unwrap();
+        let mut message = Vec::new();
+        assert_eq!(Data::Text(3), receiver.receive_data(&mut message).await.unwrap());
+        assert_eq!(b"abc", &message[..]);
+        assert!(matches!(receiver.receive(&mut message).await, Ok(Incoming::Closed(_))))
+    }
+
+    #[tokio::test]
+    async fn draining_after_protocol_error() {
+        let (a, mut b) = tokio::io::duplex(4096);
+        let (mut sender, mut receiver) = Builder::new(a.compat(), Mode::Client).finish();
+
+        // The first fragment is invalid UTF-8, the remote keeps sending the
+        // rest of the message, a PING, a new message and finally its CLOSE.
+        b.write_all(&[0x01, 0x02, b'a', 0xFF]).await.unwrap();
+        let mut data = Vec::new();
+        let e = match receiver.receive(&mut data).await {
+            Err(e@Error::InvalidText) => e,
+            other => panic!("unexpected: {:?}", other.map(|_| ()))
+        };
+        sender.close_on_error(&e).await.unwrap();
+        b.write_all(&[0x00, 0x01, b'b', 0x89, 0x00, 0x80, 0x02, 0xFF, 0xFE]).await.unwrap();
+        b.write_all(&[0x82, 0x7E, 0x04, 0x00]).await.unwrap();
+        b.write_all(&[0; 1024]).await.unwrap();
+        b.write_all(&[0x88, 0x02, 0x03, 0xEF]).await.unwrap();
+
+        data.clear();
+        match receiver.receive(&mut data).await {
+            Ok(Incoming::Closed(reason)) => assert_eq!(CloseCode::INVALID_PAYLOAD, reason.code()),
+            other => panic!("unexpected: {:?}", other.map(|_| ()))
+        }
+        assert!(data.is_empty());
+
+        // Only our CLOSE has been sent.
+        let mut close = Vec::new();
+        b.read_to_end(&mut close).await.unwrap();
+        assert_eq!(close[.. 2], [0x88, 0x82]);
+        assert_eq!([0x03, 0xEF], [close[6] ^ close[2], close[7] ^ close[3]]);
+        assert_eq!(8, close.len())
+    }
+
+    #[tokio::test]
+    async fn relay_fragmented() {
+        // Client --> (server | relay | client) --> server
+        let (mut client, a) = tokio::io::duplex(1024);
+        let (b, mut server) = tokio::io::duplex(1024);
+        let (_, mut src) = Builder::new(a.compat(), Mode::Server).finish();
+        let (mut dst, _) = Builder::new(b.compat(), Mode::Client).finish();
+
+        // Autobahn case 5.6: a fragmented text message with a PING in between.
+        let mut input = Vec::new();
+        for (fin, opcode, payload) in &[
+            (false, OpCode::Text, &b"fragment1"[..]),
+            (true, OpCode::Ping, b"ping payload"),
+            (true, OpCode::Continue, b"fragment2"),
+            (true, OpCode::Close, &[0x03, 0xE8, b'b', b'y', b'e'][..])
+        ] {
+            let mut header = Header::new(*opcode);
+            header.set_fin(*fin).set_masked(true).set_mask(0x01020304).set_payload_len(payload.len());
+            input.extend_from_slice(base::Codec::new().encode_header(&header));
+            let mut payload = payload.to_vec();
+            base::Codec::apply_mask(&header, &mut payload);
+            input.extend_from_slice(&payload)
+        }
+        client.write_all(&input).await.unwrap();
+
+        super::relay(&mut src, &mut dst).await.unwrap();
+        drop((src, dst));
+
+        // The PING and the CLOSE have been answered on the client side.
+        let mut answers = Vec::new();
+        client.read_to_end(&mut answers).await.unwrap();
+        let mut decoder = FrameDecoder::new(base::Codec::new());
+        decoder.push_bytes(&answers);
+        let pong = decoder.next_frame().unwrap().unwrap();
+        assert_eq!((OpCode::Pong, &b"ping payload"[..]), (pong.header().opcode(), pong.payload()));
+        let close = decoder.next_frame().unwrap().unwrap();
+        assert_eq!((OpCode::Close, &[0x03, 0xE8][..]), (close.header().opcode(), close.payload()));
+
+        // The message arrives at the server unchanged but masked anew.
+        let mut output = Vec::new();
+        server.read_to_end(&mut output).await.unwrap();
+        let mut decoder = FrameDecoder::new(base::Codec::new());
+        decoder.push_bytes(&output);
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            assert!(frame.header().is_masked());
+            frames.push((frame.header().is_fin(), frame.header().opcode(), frame.payload().to_vec()))
+        }
+        assert_eq!(vec![
+            (false, OpCode::Text, b"fragment1".to_vec()),
+            (true, OpCode::Continue, b"fragment2".to_vec()),
+            (true, OpCode::Close, vec![0x03, 0xE8, b'b', b'y', b'e'])
+        ], frames)
+    }
+
+    #[tokio::test]
+    async fn simultaneous_close() {
+        // Reads the close frame sent by the client and expects EOF afterwards.
+        async fn read_close(b: &mut tokio::io::DuplexStream) -> u16 {
+            let mut close = [0; 8];
+            b.read_exact(&mut close).await.unwrap();
+            assert_eq!(close[.. 2], [0x88, 0x82]);
+            let mut rest = Vec::new();
+            b.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+            u16::from_be_bytes([close[6] ^ close[2], close[7] ^ close[3]])
+        }
+
+        // Our CLOSE is sent first, the remote's CLOSE without status code arrives later.
+        let (a, mut b) = tokio::io::duplex(1024);
+        let (mut sender, mut receiver) = Builder::new(a.compat(), Mode::Client).finish();
+        sender.close().await.unwrap();
+        b.write_all(&[0x88, 0x00]).await.unwrap();
+        let mut data = Vec::new();
+        match receiver.receive(&mut data).await {
+            Ok(Incoming::Closed(reason)) => assert_eq!(CloseCode::NORMAL, reason.code()),
+            other => panic!("unexpected: {:?}", other.map(|_| ()))
+        }
+        assert_eq!(Some(CloseCode::NORMAL), receiver.close_reason().map(CloseReason::code));
+        assert!(matches!(receiver.receive(&mut data).await, Err(Error::Closed(_))));
+        sender.close().await.unwrap();
+        assert_eq!(1000, read_close(&mut b).await);
+
+        // The remote's CLOSE arrives first and is answered, our CLOSE is not sent.
+        let (a, mut b) = tokio::io::duplex(1024);
+        let (mut sender, mut receiver) = Builder::new(a.compat(), Mode::Client).finish();
+        b.write_all(&[0x88, 0x02, 0x03, 0xE9]).await.unwrap();
+        match receiver.receive(&mut data).await {
+            Ok(Incoming::Closed(reason)) => assert_eq!(CloseCode::GOING_AWAY, reason.code()),
+            other => panic!("unexpected: {:?}", other.map(|_| ()))
+        }
+        sender.close().await.unwrap();
+        assert!(matches!(sender.send_text("x").await, Err(Error::Closed(_))));
+        assert_eq!(1001, read_close(&mut b).await);
+
+        // Both ends close at the same time.
+        let (a, b) = tokio::io::duplex(1024);
+        let (mut sender_a, mut receiver_a) = Builder::new(a.compat(), Mode::Client).finish();
+        let (mut sender_b, mut receiver_b) = Builder::new(b.compat(), Mode::Server).finish();
+        let (ra, rb) = tokio::join!(sender_a.close(), sender_b.close());
+        ra.unwrap();
+        rb.unwrap();
+        let mut data_a = Vec::new();
+        let mut data_b = Vec::new();
+        let (ra, rb) = tokio::join!(receiver_a.receive(&mut data_a), receiver_b.receive(&mut data_b));
+        assert!(matches!(ra, Ok(Incoming::Closed(_))));
+        assert!(matches!(rb, Ok(Incoming::Closed(_))));
+        assert!(matches!(receiver_a.receive(&mut data_a).await, Err(Error::Closed(_))));
+        assert!(matches!(receiver_b.receive(&mut data_b).await, Err(Error::Closed(_))))
+    }
+
+    #[tokio::test]
+    async fn invalid_text() {
+        let (a, mut b) = tokio::io::duplex(1024);
+        let (_sender, mut receiver) = Builder::new(a.compat(), Mode::Client).finish();
+
+        // A valid text message split within a character, followed by an invalid one.
+        b.write_all(&[0x01, 0x01, 0xC3, 0x80, 0x01, 0xB6, 0x81, 0x01, 0xFF]).await.unwrap();
+
+        let mut data = Vec::new();
+        assert!(matches!(receiver.receive_data(&mut data).await, Ok(Data::Text(2))));
+        assert_eq!(data, "ö".as_bytes());
+        assert!(matches!(receiver.receive_data(&mut data).await, Err(Error::InvalidText)))
+    }
+
+    #[tokio::test]
+    async fn invalid_text_fragments() {
+        for (sequence, invalid_after) in crate::utf8::tests::INVALID {
+            let mut text = b"a".to_vec();
+            text.extend_from_slice(sequence);
+            text.push(b'b');
+
+            // Within a single frame.
+            let (a, mut b) = tokio::io::duplex(1024);
+            let (_sender, mut receiver) = Builder::new(a.compat(), Mode::Client).finish();
+            b.write_all(&[0x81, text.len() as u8]).await.unwrap();
+            b.write_all(&text).await.unwrap();
+            let mut data = Vec::new();
+            assert!(matches!(receiver.receive_data(&mut data).await, Err(Error::InvalidText)));
+
+            // Split across two fragments at every offset of the sequence.
+            for i in 1 ..= sequence.len() + 1 {
+                let (a, mut b) = tokio::io::duplex(1024);
+                let (_sender, mut receiver) = Builder::new(a.compat(), Mode::Client).finish();
+                b.write_all(&[0x01, i as u8]).await.unwrap();
+                b.write_all(&text[.. i]).await.unwrap();
+                if i > *invalid_after {
+                    // The first fragment is invalid already, so the message is
+                    // rejected without waiting for the second.
+                    drop(b)
+                } else {
+                    b.write_all(&[0x80, (text.len() - i) as u8]).await.unwrap();
+                    b.write_all(&text[i ..]).await.unwrap();
+                }
+                let mut data = Vec::new();
+                assert!(matches!(receiver.receive_data(&mut data).await, Err(Error::InvalidText)), "{:?} / {}", sequence, i)
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn max_send_frame_size() {
+        let (a, mut b) = tokio::io::duplex(1024);
+        let mut builder = Builder::new(a.compat(), Mode::Server);
+        builder.set_max_send_frame_size(Some(8));
+        let (mut sender, _receiver) = builder.finish();
+
+        sender.send_binary(&[1; 8]).await.unwrap();
+        match sender.send_binary(&[2; 9]).await {
+            Err(e@Error::FrameTooLarge { size: 9, maximum: 8 }) => assert!(!e.is_fatal()),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        let frame = base::Frame::from_parts(Header::new(OpCode::Text), vec![b'x'; 9]);
+        assert!(matches!(sender.send_premasked(&frame).await, Err(Error::FrameTooLarge { .. })));
+        sender.flush().await.unwrap();
+        let mut bytes = vec![0; 10];
+        b.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(vec![(OpCode::Binary, vec![1; 8])], decode_messages(&bytes));
+
+        // With a fragment size, the smaller of both sizes applies.
+        let (a, mut b) = tokio::io::duplex(1024);
+        let mut builder = Builder::new(a.compat(), Mode::Server);
+        builder.set_max_send_frame_size(Some(8));
+        builder.set_fragment_size(Some(16));
+        let (mut sender, _receiver) = builder.finish();
+        sender.send_binary(&[3; 20]).await.unwrap();
+        sender.flush().await.unwrap();
+        let mut bytes = vec![0; 3 * 2 + 20];
+        b.read_exact(&mut bytes).await.unwrap();
+        assert_eq!([0x02, 0x08], bytes[.. 2]);
+        assert_eq!([0x00, 0x08], bytes[10 .. 12]);
+        assert_eq!([0x80, 0x04], bytes[20 .. 22]);
+        assert_eq!(vec![(OpCode::Binary, vec![3; 20])], decode_messages(&bytes))
+    }
+
+    #[tokio::test]
+    async fn pong_between_fragments() {
+        let (a, mut b) = tokio::io::duplex(32);
+        let mut builder = Builder::new(a.compat(), Mode::Server);
+        builder.set_fragment_size(Some(16));
+        let (mut sender, mut receiver) = builder.finish();
+
+        let sending = tokio::spawn(async move {
+            sender.send_binary(&[7; 200]).await.unwrap();
+            sender.flush().await.unwrap();
+            sender
+        });
+
+        // The first fragment has been sent, the sender waits for room.
+        let mut bytes = vec![0; 18];
+        b.read_exact(&mut bytes).await.unwrap();
+        b.write_all(&[0x89, 0x81, 0, 0, 0, 0, 0x2A]).await.unwrap();
+        let receiving = tokio::spawn(async move {
+            let mut data = Vec::new();
+            let _ = receiver.receive_data(&mut data).await;
+        });
+        for _ in 0 .. 10 {
+            tokio::task::yield_now().await
+        }
+
+        let mut rest = vec![0; 12 * 18 + 10 + 3 - 18];
+        b.read_exact(&mut rest).await.unwrap();
+        bytes.extend_from_slice(&rest);
+        let sender = sending.await.unwrap();
+
+        let codec = base::Codec::new();
+        let mut frames = Vec::new();
+        let mut remaining = &bytes[..];
+        while let Ok(Parsing::Done { value: header, offset }) = codec.decode_header(remaining) {
+            frames.push((header.opcode(), header.is_fin(), header.payload_len()));
+            remaining = &remaining[offset + header.payload_len() ..]
+        }
+        assert!(remaining.is_empty());
+        assert_eq!(14, frames.len());
+        let pong = frames.iter().position(|f| *f == (OpCode::Pong, true, 1)).unwrap();
+        assert!(pong < 13, "{:?}", frames);
+        let data = frames.iter().filter(|f| f.0 != OpCode::Pong).collect::<Vec<_>>();
+        assert_eq!((OpCode::Binary, false, 16), *data[0]);
+        assert!(data[1 .. 12].iter().all(|f| **f == (OpCode::Continue, false, 16)));
+        assert_eq!((OpCode::Continue, true, 8), *data[12]);
+        assert_eq!(vec![(OpCode::Binary, vec![7; 200])], decode_messages(&bytes));
+
+        drop(sender);
+        receiving.abort()
+    }
+
+    #[tokio::test]
+    async fn ping_flood() {
+        let (a, mut b) = tokio::io::duplex(64 * 1024);
+        let mut builder = Builder::new(a.compat(), Mode::Client);
+        builder.set_max_pending_control(4);
+        let (sender, mut receiver) = builder.finish();
+
+        let mut flood = Vec::new();
+        for i in 0 .. 1000_u16 {
+            flood.extend_from_slice(&[0x89, 0x02]);
+            flood.extend_from_slice(&i.to_be_bytes())
+        }
+        flood.extend_from_slice(&[0x81, 0x01, b'x']);
+        b.write_all(&flood).await.unwrap();
+
+        let mut data = Vec::new();
+        assert!(matches!(receiver.receive_data(&mut data).await, Ok(Data::Text(1))));
+        assert!(receiver.pending_pongs.lock().unwrap().is_empty());
+        drop((sender, receiver));
+
+        let mut bytes = Vec::new();
+        b.read_to_end(&mut bytes).await.unwrap();
+        let mut pongs = Vec::new();
+        let codec = base::Codec::new();
+        while !bytes.is_empty() {
+            let header = match codec.decode_header(&bytes).unwrap() {
+                Parsing::Done { value, offset } => { bytes.drain(.. offset); value }
+                Parsing::NeedMore(_) => panic!("incomplete header")
+            };
+            assert_eq!(OpCode::Pong, header.opcode());
+            let mut payload = bytes.drain(.. header.payload_len()).collect::<Vec<_>>();
+            base::Codec::apply_mask(&header, &mut payload);
+            pongs.push(u16::from_be_bytes([payload[0], payload[1]]))
+        }
+
+        // At most 4 PONGs are pending and the most recent PING is answered last.
+        assert!(!pongs.is_empty() && pongs.len() <= 4, "{} pongs", pongs.len());
+        assert_eq!(Some(&999), pongs.last())
+    }
+
+    #[tokio::test]
+    async fn empty_messages() {
+        // On the wire: masked frames with an empty payload.
+        let (a, mut b) = tokio::io::duplex(1024);
+        let (mut sender, _) = Builder::new(a.compat(), Mode::Client).finish();
+        sender.send(Outgoing::Text(String::new())).await.unwrap();
+        sender.send(Outgoing::Binary(Vec::new())).await.unwrap();
+        sender.flush().await.unwrap();
+        let mut bytes = [0; 12];
+        b.read_exact(&mut bytes).await.unwrap();
+        assert_eq!([0x81, 0x80], bytes[.. 2]);
+        assert_eq!([0x82, 0x80], bytes[6 .. 8]);
+
+        // Round trip.
+        let (a, b) = tokio::io::duplex(1024);
+        let (mut sender, _) = Builder::new(a.compat(), Mode::Client).finish();
+        let (_, mut receiver) = Builder::new(b.compat(), Mode::Server).finish();
+        sender.send_text("").await.unwrap();
+        sender.send_binary([]).await.unwrap();
+        sender.send_text("x").await.unwrap();
+        sender.flush().await.unwrap();
+
+        let mut data = Vec::new();
+        assert!(matches!(receiver.receive_data(&mut data).await, Ok(Data::Text(0))));
+        assert!(data.is_empty());
+        assert!(matches!(receiver.receive_data(&mut data).await, Ok(Data::Binary(0))));
+        assert!(data.is_empty());
+        assert!(matches!(receiver.receive_data(&mut data).await, Ok(Data::Text(1))));
+        assert_eq!(data, b"x")
+    }
+
+    fn unmasked<T: futures::AsyncRead + futures::AsyncWrite + Unpin>(mut builder: Builder<T>) -> Builder<T> {
+        builder.set_deviations(*Deviations::new().set_unmasked(true));
+        builder
+    }
+
+    #[tokio::test]
+    async fn disabled_masking() {
+        let (a, mut b) = tokio::io::duplex(1024);
+        let (mut sender, _) = unmasked(Builder::new(a.compat(), Mode::Client)).finish();
+        sender.send_text("unmasked").await.unwrap();
+        sender.send_control(ControlPayload::ping(b"ping").unwrap()).await.unwrap();
+        sender.flush().await.unwrap();
+
+        // Frames are sent as they are, without anything ahead of them.
+        let mut bytes = [0; 16];
+        b.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(b"\x81\x08unmasked\x89\x04ping", &bytes);
+
+        let (a, b) = tokio::io::duplex(1024);
+        let (mut sender, _) = unmasked(Builder::new(a.compat(), Mode::Client)).finish();
+        let (_, mut receiver) = unmasked(Builder::new(b.compat(), Mode::Server)).finish();
+        sender.send_text("unmasked").await.unwrap();
+        sender.send_text("again").await.unwrap();
+        sender.flush().await.unwrap();
+
+        let mut data = Vec::new();
+        assert!(matches!(receiver.receive(&mut data).await, Ok(Incoming::Data(Data::Text(8)))));
+        assert_eq!(data, b"unmasked");
+        data.clear();
+        assert!(matches!(receiver.receive(&mut data).await, Ok(Incoming::Data(Data::Text(5)))));
+        assert_eq!(data, b"again");
+
+        // A dropped connection sends its CLOSE frame unmasked as well.
+        let (a, b) = tokio::io::duplex(1024);
+        let mut client = unmasked(Builder::new(a.compat(), Mode::Client));
+        client.set_drop_policy(DropPolicy::BestEffortClose(CloseCode::GOING_AWAY));
+        let (_, mut receiver) = unmasked(Builder::new(b.compat(), Mode::Server)).finish();
+        drop(client.finish());
+        // The CLOSE frame can not be answered, as the socket is gone.
+        match receiver.receive(&mut data).await {
+            Err(Error::Closed(reason)) => assert_eq!(CloseCode::GOING_AWAY, reason.code()),
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[tokio::test]
+    async fn disabled_masking_requires_both_ends() {
+        let (a, b) = tokio::io::duplex(1024);
+        let (mut sender, _) = unmasked(Builder::new(a.compat(), Mode::Client)).finish();
+        let (_, mut receiver) = Builder::new(b.compat(), Mode::Server).finish();
+
+        sender.send_text("unmasked").await.unwrap();
+        sender.flush().await.unwrap();
+
+        let mut data = Vec::new();
+        assert!(matches!(receiver.receive_data(&mut data).await, Err(Error::UnmaskedFrame)));
+
+        let (a, b) = tokio::io::duplex(1024);
+        let (mut sender, _) = Builder::new(a.compat(), Mode::Client).finish();
+        let (_, mut receiver) = unmasked(Builder::new(b.compat(), Mode::Server)).finish();
+
+        sender.send_text("masked").await.unwrap();
+        sender.flush().await.unwrap();
+
+        assert!(matches!(receiver.receive_data(&mut data).await, Err(Error::MaskingOptOutMismatch)))
+    }
+
+    #[tokio::test]
+    async fn accept_unmasked_frames() {
+        // An unmasked and a masked text frame, the latter with an all-zero key.
+        const FRAMES: &[u8] = &[0x81, 0x01, b'a', 0x81, 0x81, 0, 0, 0, 0, b'b'];
+
+        let (mut a, b) = tokio::io::duplex(1024);
+        a.write_all(FRAMES).await.unwrap();
+        let (_, mut receiver) = Builder::new(b.compat(), Mode::Server).finish();
+        let mut data = Vec::new();
+        assert!(matches!(receiver.receive_data(&mut data).await, Err(Error::UnmaskedFrame)));
+
+        let (mut a, b) = tokio::io::duplex(1024);
+        a.write_all(FRAMES).await.unwrap();
+        let mut server = Builder::new(b.compat(), Mode::Server);
+        server.set_deviations(*Deviations::new().set_accept_unmasked_frames(true));
+        let (_, mut receiver) = server.finish();
+        assert!(matches!(receiver.receive_data(&mut data).await, Ok(Data::Text(1))));
+        assert!(matches!(receiver.receive_data(&mut data).await, Ok(Data::Text(1))));
+        assert_eq!(b"ab", &data[..]);
+        assert_eq!(1, receiver.unmasked_frames())
+    }
+
+    #[tokio::test]
+    async fn accept_masked_frames() {
+        // A masked text frame and a masked PING, followed by an unmasked text frame.
+        const FRAMES: &[u8] = &[
+            0x81, 0x82, 1, 2, 3, 4, b'h' ^ 1, b'i' ^ 2,
+            0x89, 0x81, 5, 6, 7, 8, b'p' ^ 5,
+            0x81, 0x01, b'!'
+        ];
+
+        let (mut a, b) = tokio::io::duplex(1024);
+        a.write_all(FRAMES).await.unwrap();
+        let (_, mut receiver) = Builder::new(b.compat(), Mode::Client).finish();
+        let mut data = Vec::new();
+        assert!(matches!(receiver.receive_data(&mut data).await, Err(Error::MaskedFrame)));
+
+        let (mut a, b) = tokio::io::duplex(1024);
+        a.write_all(FRAMES).await.unwrap();
+        let mut client = Builder::new(b.compat(), Mode::Client);
+        client.set_deviations(*Deviations::new().set_accept_masked_frames(true));
+        let (_, mut receiver) = client.finish();
+        assert!(matches!(receiver.receive_data(&mut data).await, Ok(Data::Text(2))));
+        assert!(matches!(receiver.receive_data(&mut data).await, Ok(Data::Text(1))));
+        assert_eq!(b"hi!", &data[..]);
+
+        // The PING has been unmasked and answered with a masked PONG.
+        let mut pong = [0; 7];
+        a.read_exact(&mut pong).await.unwrap();
+        assert_eq!([0x8A, 0x81], pong[.. 2]);
+        assert_eq!(b'p', pong[6] ^ pong[2])
+    }
+
+    #[tokio::test]
+    async fn traffic_stats() {
+        let (a, b) = tokio::io::duplex(1024);
+        let (mut sender, receiver) = Builder::new(a.compat(), Mode::Client).finish();
+        let (server_sender, mut server_receiver) = Builder::new(b.compat(), Mode::Server).finish();
+
+        // Masked frames: 2 header bytes + 4 mask bytes (+ 2 length bytes beyond 125).
+        sender.send_control(ControlPayload::ping(b"abc").unwrap()).await.unwrap();
+        sender.send_text("hello").await.unwrap();
+        sender.send_binary(vec![0; 200]).await.unwrap();
+        sender.flush().await.unwrap();
+
+        let mut data = Vec::new();
+        assert!(matches!(server_receiver.receive_data(&mut data).await, Ok(Data::Text(5))));
+        data.clear();
+        assert!(matches!(server_receiver.receive_data(&mut data).await, Ok(Data::Binary(200))));
+
+        let client = sender.traffic_stats();
+        assert_eq!(client, receiver.traffic_stats());
+        assert_eq!(205, client.payload_bytes_out());
+        assert_eq!(9 + 6 + 8, client.overhead_bytes_out());
+        assert_eq!(1, client.control_frames_out());
+        assert_eq!(0, client.payload_bytes_in());
+
+        // The server has answered the PING with an unmasked PONG.
+        let server = server_sender.traffic_stats();
+        assert_eq!(205, server.payload_bytes_in());
+        assert_eq!(9 + 6 + 8, server.overhead_bytes_in());
+        assert_eq!(1, server.control_frames_in());
+        assert_eq!(0, server.payload_bytes_out());
+        assert_eq!(2 + 3, server.overhead_bytes_out());
+        assert_eq!(1, server.control_frames_out());
+
+        let total: TrafficStats = vec![client, server].into_iter().sum();
+        assert_eq!(205, total.payload_bytes_in());
+        assert_eq!(205, total.payload_bytes_out());
+        assert_eq!(2, total.control_frames_out())
+    }
+
+    #[tokio::test]
+    async fn paused_reading() {
+        let (a, mut b) = tokio::io::duplex(1024);
+        let (sender, receiver) = Builder::new(a.compat(), Mode::Server).finish();
+        let mut server = Connection::new(sender, receiver);
+        let mut data = Vec::new();
+
+        // A PING received along with a message is answered although reading is paused.
+        b.write_all(&[0x81, 0x81, 0, 0, 0, 0, b'a', 0x89, 0x81, 0, 0, 0, 0, b'p']).await.unwrap();
+        assert!(matches!(futures::future::poll_fn(|cx| server.poll_recv(cx)).await, Some(Ok((Data::Text(1), _)))));
+        server.pause_reading();
+        assert!(futures::future::poll_fn(|cx| Poll::Ready(server.poll_recv(cx))).await.is_pending());
+        let mut pong = [0; 3];
+        b.read_exact(&mut pong).await.unwrap();
+        assert_eq!([0x8A, 0x01, b'p'], pong);
+
+        // Messages are not yielded while paused, even if available.
+        b.write_all(&[0x81, 0x81, 0, 0, 0, 0, b'x']).await.unwrap();
+        for _ in 0 .. 3 {
+            tokio::task::yield_now().await;
+            assert!(futures::future::poll_fn(|cx| Poll::Ready(server.poll_recv(cx))).await.is_pending())
+        }
+
+        // Sending still works.
+        futures::future::poll_fn(|cx| server.poll_send_ready(cx)).await.unwrap();
+        server.start_send(Outgoing::Text("y".into())).unwrap();
+        futures::future::poll_fn(|cx| server.poll_flush(cx)).await.unwrap();
+        let mut text = [0; 3];
+        b.read_exact(&mut text).await.unwrap();
+        assert_eq!([0x81, 0x01, b'y'], text);
+
+        server.resume_reading();
+        match futures::future::poll_fn(|cx| server.poll_recv(cx)).await {
+            Some(Ok((Data::Text(1), m))) => data.extend_from_slice(&m),
+            other => panic!("unexpected: {:?}", other.map(|r| r.map(|_| ())))
+        }
+        assert_eq!(b"x", &data[..])
+    }
+
+    #[tokio::test]
+    async fn connection_from_parts() {
+        let (a, mut b) = tokio::io::duplex(1024);
+        let buffer = bytes::BytesMut::from(&[0x81, 0x02, b'h', b'i', 0x82][..]);
+        let mut client = Connection::from_parts(a.compat(), Mode::Client, buffer);
+
+        // The buffered frame is decoded without reading from the socket.
+        let received = futures::future::poll_fn(|cx| client.poll_recv(cx)).await;
+        assert!(matches!(received, Some(Ok((Data::Text(2), ref m))) if m == b"hi"));
+
+        // The rest of the partially buffered frame follows on the socket.
+        b.write_all(&[0x01, 7]).await.unwrap();
+        let received = futures::future::poll_fn(|cx| client.poll_recv(cx)).await;
+        assert!(matches!(received, Some(Ok((Data::Binary(1), ref m))) if m == &[7]))
+    }
+
+    #[tokio::test]
+    async fn connection_into_inner() {
+        // Two frames are buffered, the socket holds a third.
+        let socket = futures::io::Cursor::new(vec![0x82, 0x01, 3]);
+        let mut builder = Builder::new(socket, Mode::Client);
+        builder.set_buffer(bytes::BytesMut::from(&[0x82, 0x01, 1, 0x82, 0x01, 2][..]));
+        builder.set_drop_policy(DropPolicy::BestEffortClose(CloseCode::NORMAL));
+        let (sender, receiver) = builder.finish();
+        let mut client = Connection::new(sender, receiver);
+        {
+            let socket = client.get_ref().unwrap();
+            assert_eq!(0, socket.position());
+            assert!(client.get_ref().is_none())
+        }
+
+        let received = futures::future::poll_fn(|cx| client.poll_recv(cx)).await;
+        assert!(matches!(received, Some(Ok((Data::Binary(1), ref m))) if m == &[1]));
+
+        // The socket comes back unchanged, without a CLOSE frame written to
+        // it, along with the frame which has not been decoded yet.
+        let (socket, buffer) = client.into_inner().unwrap();
+        assert_eq!(0, socket.position());
+        assert_eq!(&[0x82, 0x01, 3], &socket.get_ref()[..]);
+        assert_eq!(&[0x82, 0x01, 2], &buffer[..]);
+
+        // And can be used for a connection again.
+        let mut client = Connection::from_parts(socket, Mode::Client, buffer);
+        client.get_mut().unwrap().get_mut().extend_from_slice(&[0x82, 0x01, 4]);
+        for i in 2 .. 5 {
+            let received = futures::future::poll_fn(|cx| client.poll_recv(cx)).await;
+            assert!(matches!(received, Some(Ok((Data::Binary(1), ref m))) if m == &[i]))
+        }
+    }
+
+    #[tokio::test]
+    async fn connection_id() {
+        let (a, b) = tokio::io::duplex(1024);
+        let (sender, receiver) = Builder::new(a.compat(), Mode::Client).finish();
+        assert_eq!(sender.id(), receiver.id());
+        let mut builder = Builder::new(b.compat(), Mode::Server);
+        assert!(builder.id.0 > sender.id());
+        builder.set_id(42);
+        let (mut remote, _) = builder.finish();
+        assert_eq!(42, remote.id());
+
+        let mut client = Connection::new(sender, receiver);
+        let id = client.id();
+        assert_eq!(format!("ws-conn-{}(open, client)", id), client.to_string());
+        remote.close().await.unwrap();
+        let received = futures::future::poll_fn(|cx| client.poll_recv(cx)).await;
+        assert!(received.is_none());
+        assert_eq!(format!("ws-conn-{}(closed, client)", id), client.to_string())
+    }
+
+    #[tokio::test]
+    async fn poll_connection() {
+        let (a, b) = tokio::io::duplex(1024);
+        let (sender, receiver) = Builder::new(a.compat(), Mode::Client).finish();
+        let mut client = Connection::new(sender, receiver);
+        let (mut sender, mut receiver) = Builder::new(b.compat(), Mode::Server).finish();
+
+        sender.send_text("hello").await.unwrap();
+        sender.flush().await.unwrap();
+        let received = futures::future::poll_fn(|cx| client.poll_recv(cx)).await;
+        assert!(matches!(received, Some(Ok((Data::Text(5), ref m))) if m == b"hello"));
+
+        futures::future::poll_fn(|cx| client.poll_send_ready(cx)).await.unwrap();
+        client.start_send(Outgoing::Binary(vec![1, 2, 3])).unwrap();
+        assert!(matches!(client.start_send(Outgoing::Binary(Vec::new())), Err(Error::Io(_))));
+        futures::future::poll_fn(|cx| client.poll_flush(cx)).await.unwrap();
+        let mut data = Vec::new();
+        assert!(matches!(receiver.receive_data(&mut data).await, Ok(Data::Binary(3))));
+        assert_eq!(vec![1, 2, 3], data);
+
+        sender.close().await.unwrap();
+        assert!(futures::future::poll_fn(|cx| client.poll_recv(cx)).await.is_none());
+        assert!(client.into_parts().is_some())
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        // Closes connections whose remote answers, silent ones are forced.
+        async fn run(answering: &[bool], limit: usize, close_timeout: Duration, deadline: Duration) -> ShutdownReport {
+            let mut shutdown = crate::handshake::server::Shutdown::new();
+            shutdown.set_concurrency_limit(limit);
+            let mut silent = Vec::new();
+            for &answer in answering {
+                let (a, b) = tokio::io::duplex(1024);
+                let mut builder = Builder::new(a.compat(), Mode::Server);
+                builder.set_close_timeout(close_timeout);
+                let (sender, receiver) = builder.finish();
+                shutdown.add(sender, receiver);
+                if !answer {
+                    silent.push(b);
+                    continue
+                }
+                tokio::spawn(async move {
+                    let (_sender, mut receiver) = Builder::new(b.compat(), Mode::Client).finish();
+                    let mut data = Vec::new();
+                    match receiver.receive(&mut data).await {
+                        Ok(Incoming::Closed(reason)) => {
+                            assert_eq!(CloseCode::GOING_AWAY, reason.code());
+                            assert_eq!(Some("server shutting down"), reason.raw_reason())
+                        }
+                        other => panic!("unexpected: {:?}", other.map(|_| ()))
+                    }
+                });
+            }
+            assert_eq!(answering.len(), shutdown.len());
+            shutdown.run(tokio::time::sleep(deadline), tokio::time::sleep).await
+        }
+
+        let deadline = Duration::from_millis(100);
+        let report = run(&[false, true, true], 2, super::CLOSE_TIMEOUT, deadline).await;
+        assert_eq!((2, 1), (report.clean(), report.forced()));
+
+        // Until its close timeout, a silent remote keeps the others waiting.
+        let report = run(&[false, true, true], 1, super::CLOSE_TIMEOUT, deadline).await;
+        assert_eq!((0, 3), (report.clean(), report.forced()));
+
+        // More silent remotes than connections closed concurrently.
+        let answering = [false, false, false, false, true, true, true];
+        let report = run(&answering, 2, Duration::from_millis(20), Duration::from_secs(5)).await;
+        assert_eq!((3, 4), (report.clean(), report.forced()))
+    }
+
+    #[tokio::test]
+    async fn premasked_frame() {
+        // A masked binary frame as sent by some client.
+        let mut input = vec![0x82, 0x85, 0x37, 0xFA, 0x21, 0x3D];
+        input.extend(b"hello".iter().zip([0x37, 0xFA, 0x21, 0x3D].iter().cycle()).map(|(b, m)| b ^ m));
+
+        let header = match base::Codec::default().decode_header(&input).unwrap() {
+            Parsing::Done { value, offset } => { assert_eq!(6, offset); value }
+            Parsing::NeedMore(_) => panic!("incomplete header")
+        };
+        let mut frame = base::Frame::new(OpCode::Binary);
+        frame.set_binary(&input[6 ..]);
+        *frame.header_mut() = header;
+
+        let (a, mut b) = tokio::io::duplex(1024);
+        let (mut sender, _) = Builder::new(a.compat(), Mode::Client).finish();
+        sender.send_premasked(&frame).await.unwrap();
+        sender.flush().await.unwrap();
+
+        let mut output = vec![0; input.len()];
+        b.read_exact(&mut output).await.unwrap();
+        assert_eq!(input, output);
+
+        frame.header_mut().set_masked(false);
+        assert!(matches!(sender.send_premasked(&frame).await, Err(Error::UnmaskedFrame)));
+
+        let (a, _b) = tokio::io::duplex(1024);
+        let (mut sender, _) = Builder::new(a.compat(), Mode::Server).finish();
+        frame.header_mut().set_masked(true);
+        assert!(matches!(sender.send_premasked(&frame).await, Err(Error::MaskedFrame)))
+    }
+
+    #[tokio::test]
+    async fn frame_with_mask() {
+        let (a, mut b) = tokio::io::duplex(1024);
+        let (mut sender, _) = Builder::new(a.compat(), Mode::Client).finish();
+        let mut frame = base::Frame::new(OpCode::Text);
+        frame.set_text("Hello");
+        sender.send_frame_with_mask(frame.clone(), 0x37FA_213D).await.unwrap();
+        sender.flush().await.unwrap();
+
+        // The masked example frame of RFC 6455, section 5.7.
+        let mut output = [0; 11];
+        b.read_exact(&mut output).await.unwrap();
+        assert_eq!([0x81, 0x85, 0x37, 0xFA, 0x21, 0x3D, 0x7F, 0x9F, 0x4D, 0x51, 0x58], output);
+
+        let (a, _b) = tokio::io::duplex(1024);
+        let (mut sender, _) = Builder::new(a.compat(), Mode::Server).finish();
+        assert!(matches!(sender.send_frame_with_mask(frame, 0x37FA_213D).await, Err(Error::MaskedFrame)))
+    }
+
+    #[tokio::test]
+    async fn premasked_invalid_text() {
+        fn text(payload: &[u8], fin: bool, mask: Option<u32>) -> base::Frame {
+            let mut header = Header::new(OpCode::Text);
+            header.set_fin(fin);
+            if let Some(m) = mask {
+                header.set_masked(true);
+                header.set_mask(m);
+            }
+            base::Frame::from_parts(header, payload.to_vec())
+        }
+
+        let (a, _b) = tokio::io::duplex(1024);
+        let (mut sender, _) = Builder::new(a.compat(), Mode::Server).finish();
+        assert!(matches!(sender.send_premasked(&text(&[0xC3, 0x28], true, None)).await, Err(Error::InvalidUtf8Outgoing)));
+        assert!(matches!(sender.send_premasked(&text(&[0xC3], true, None)).await, Err(Error::InvalidUtf8Outgoing)));
+        // A fragment may end with an incomplete character.
+        sender.send_premasked(&text(&[b'a', 0xC3], false, None)).await.unwrap();
+
+        // "é" masked with key 0x80000000.
+        let (a, _b) = tokio::io::duplex(1024);
+        let (mut sender, _) = Builder::new(a.compat(), Mode::Client).finish();
+        sender.send_premasked(&text(&[0xC3 ^ 0x80, 0xA9], true, Some(0x8000_0000))).await.unwrap();
+        assert!(matches!(sender.send_premasked(&text(&[0xC3, 0xA9], true, Some(0x8000_0000))).await, Err(Error::InvalidUtf8Outgoing)));
+
+        let (a, _b) = tokio::io::duplex(1024);
+        let mut builder = Builder::new(a.compat(), Mode::Server);
+        builder.set_validate_outgoing_text(false);
+        let (mut sender, _) = builder.finish();
+        sender.send_premasked(&text(&[0xC3, 0x28], true, None)).await.unwrap()
+    }
+
+    /// A socket which can be read once and records all bytes written to it.
+    struct Capture {
+        read: Option<Vec<u8>>,
+        written: Arc<Mutex<Vec<u8>>>
     }
 
-    /// Read the next frame header.
-    async fn receive_header(&mut self) -> Result<Header, Error> {
-        loop {
-            match self.codec.decode_header(&self.buffer)? {
-                Parsing::Done { value: header, offset } => {
-                    debug_assert!(offset <= MAX_HEADER_SIZE);
-                    self.buffer.advance(offset);
-                    return Ok(header)
-                }
-                Parsing::NeedMore(n) => {
-                    crate::read(&mut self.reader, &mut self.buffer, n).await?
+    impl futures::AsyncRead for Capture {
+        fn poll_read(mut self: Pin<&mut Self>, _: &mut Context, b: &mut [u8]) -> Poll<io::Result<usize>> {
+            match self.read.take() {
+                Some(bytes) => {
+                    b[.. bytes.len()].copy_from_slice(&bytes);
+                    Poll::Ready(Ok(bytes.len()))
                 }
+                None => Poll::Pending
             }
         }
     }
 
-    /// Read the complete payload data into the read buffer.
-    async fn read_buffer(&mut self, header: &Header) -> Result<(), Error> {
-        if header.payload_len() <= self.buffer.len() {
-            return Ok(())
+    impl futures::AsyncWrite for Capture {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context, b: &[u8]) -> Poll<io::Result<usize>> {
+            self.written.lock().unwrap().extend_from_slice(b);
+            Poll::Ready(Ok(b.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
         }
-        let i = self.buffer.len();
-        let d = header.payload_len() - i;
-        self.buffer.resize(i + d, 0u8);
-        self.reader.read_exact(&mut self.buffer[i ..]).await?;
-        Ok(())
     }
 
-    /// Answer incoming control frames.
-    async fn on_control(&mut self, header: &Header) -> Result<(), Error> {
-        match header.opcode() {
-            OpCode::Ping => {
-                let mut answer = Header::new(OpCode::Pong);
-                let mut unused = Vec::new();
-                let mut data = Storage::Unique(&mut self.ctrl_buffer);
-                write(self.id, self.mode, &mut self.codec, &mut self.writer, &mut answer, &mut data, &mut unused).await?;
-                self.flush().await?;
-                Ok(())
-            }
-            OpCode::Pong => Ok(()),
-            OpCode::Close => {
-                self.is_closed = true;
-                let (mut header, code) = close_answer(&self.ctrl_buffer)?;
-                let mut unused = Vec::new();
-                if let Some(c) = code {
-                    let mut data = c.to_be_bytes();
-                    let mut data = Storage::Unique(&mut data);
-                    write(self.id, self.mode, &mut self.codec, &mut self.writer, &mut header, &mut data, &mut unused).await?
-                } else {
-                    let mut data = Storage::Unique(&mut []);
-                    write(self.id, self.mode, &mut self.codec, &mut self.writer, &mut header, &mut data, &mut unused).await?
+    #[tokio::test]
+    async fn large_frame_yields() {
+        const LEN: usize = 64 * 1024 * 1024;
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let socket = Capture { read: Some(vec![0x89, 0x01, 0x2A]), written: written.clone() };
+        let (mut sender, mut receiver) = Builder::new(socket, Mode::Client).finish();
+
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let probe = {
+            let ticks = ticks.clone();
+            tokio::spawn(async move {
+                loop {
+                    ticks.fetch_add(1, Ordering::SeqCst);
+                    super::yield_now().await
                 }
-                self.flush().await?;
-                self.writer.lock().await.close().await.or(Err(Error::Closed))
+            })
+        };
+        let payload = (0 .. LEN).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let sending = tokio::spawn({
+            let payload = payload.clone();
+            let ticks = ticks.clone();
+            async move {
+                // The PING is received while the frame is being sent.
+                let receiving = tokio::spawn(async move {
+                    let mut data = Vec::new();
+                    let _ = receiver.receive_data(&mut data).await;
+                });
+                let before = ticks.load(Ordering::SeqCst);
+                sender.send_binary(&payload).await.unwrap();
+                (sender, receiving, ticks.load(Ordering::SeqCst) - before)
             }
-            OpCode::Binary
-            | OpCode::Text
-            | OpCode::Continue
-            | OpCode::Reserved3
-            | OpCode::Reserved4
-            | OpCode::Reserved5
-            | OpCode::Reserved6
-            | OpCode::Reserved7
-            | OpCode::Reserved11
-            | OpCode::Reserved12
-            | OpCode::Reserved13
-            | OpCode::Reserved14
-            | OpCode::Reserved15 => Err(Error::UnexpectedOpCode(header.opcode()))
+        });
+        let (sender, receiving, ticks_during_send) = sending.await.unwrap();
+        assert!(ticks_during_send >= LEN / super::WRITE_SLICE_SIZE - 1, "{}", ticks_during_send);
+
+        // Let the receiver answer the PING.
+        for _ in 0 .. 10 {
+            tokio::task::yield_now().await
         }
+        probe.abort();
+        receiving.abort();
+        drop(sender);
+
+        // The frame is sent as a whole, followed by the PONG.
+        let mut bytes = std::mem::take(&mut *written.lock().unwrap());
+        let codec = base::Codec::new();
+        let (header, offset) = match codec.decode_header(&bytes) {
+            Ok(Parsing::Done { value, offset }) => (value, offset),
+            other => panic!("{:?}", other)
+        };
+        assert_eq!((OpCode::Binary, true, LEN), (header.opcode(), header.is_fin(), header.payload_len()));
+        base::Codec::apply_mask(&header, &mut bytes[offset .. offset + LEN]);
+        assert!(bytes[offset .. offset + LEN] == payload[..]);
+        let pong = &bytes[offset + LEN ..];
+        assert_eq!([0x8A, 0x81], pong[.. 2]);
+        assert_eq!(7, pong.len())
     }
 
-    /// Apply all extensions to the given header and the internal message buffer.
-    async fn decode_with_extensions(&mut self, header: &mut Header, message: &mut Vec<u8>) -> Result<(), Error> {
-        if !self.has_extensions {
-            return Ok(())
+    /// A socket which accepts at most 3 bytes per write.
+    struct Trickle(Vec<u8>);
+
+    impl futures::AsyncRead for Trickle {
+        fn poll_read(self: Pin<&mut Self>, _: &mut Context, _: &mut [u8]) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(0))
         }
-        for e in self.extensions.lock().await.iter_mut() {
-            log::trace!("{}: decoding with extension: {}", self.id, e.name());
-            e.decode(header, message).map_err(Error::Extension)?
+    }
+
+    impl futures::AsyncWrite for Trickle {
+        fn poll_write(mut self: Pin<&mut Self>, _: &mut Context, b: &[u8]) -> Poll<io::Result<usize>> {
+            let n = std::cmp::min(3, b.len());
+            self.0.extend_from_slice(&b[.. n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
         }
-        Ok(())
     }
 
-    /// Flush the socket buffer.
-    async fn flush(&mut self) -> Result<(), Error> {
-        log::trace!("{}: flushing connection", self.id);
-        if self.is_closed {
-            return Ok(())
+    /// A socket whose writes fail with the given error kind.
+    struct Failing(io::ErrorKind);
+
+    impl futures::AsyncRead for Failing {
+        fn poll_read(self: Pin<&mut Self>, _: &mut Context, _: &mut [u8]) -> Poll<io::Result<usize>> {
+            Poll::Pending
         }
-        self.writer.lock().await.flush().await.or(Err(Error::Closed))
     }
-}
 
-impl<T: AsyncRead + AsyncWrite + Unpin> Sender<T> {
-    /// Send a text value over the websocket connection.
-    pub async fn send_text(&mut self, data: impl AsRef<str>) -> Result<(), Error> {
-        let mut header = Header::new(OpCode::Text);
-        self.send_frame(&mut header, &mut Storage::Shared(data.as_ref().as_bytes())).await
+    impl futures::AsyncWrite for Failing {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context, _: &[u8]) -> Poll<io::Result<usize>> {
+            Poll::Ready(Err(self.0.into()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Err(self.0.into()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
     }
 
-    /// Send some binary data over the websocket connection.
-    pub async fn send_binary(&mut self, data: impl AsRef<[u8]>) -> Result<(), Error> {
-        let mut header = Header::new(OpCode::Binary);
-        self.send_frame(&mut header, &mut Storage::Shared(data.as_ref())).await
+    #[tokio::test]
+    async fn write_errors() {
+        // Errors other than a lost connection are returned as they are.
+        let (mut sender, _receiver) = Builder::new(Failing(io::ErrorKind::PermissionDenied), Mode::Client).finish();
+        match sender.send_text("hello").await {
+            Err(Error::Io(e)) => assert_eq!(io::ErrorKind::PermissionDenied, e.kind()),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        assert!(matches!(sender.flush().await, Err(Error::Io(_))));
+
+        // A lost connection is reported as closed.
+        for kind in [io::ErrorKind::BrokenPipe, io::ErrorKind::ConnectionReset, io::ErrorKind::UnexpectedEof] {
+            let (mut sender, _receiver) = Builder::new(Failing(kind), Mode::Client).finish();
+            match sender.send_text("hello").await {
+                Err(Error::Closed(r)) => assert_eq!(CloseCode::ABNORMAL_CLOSURE, r.code()),
+                other => panic!("unexpected result: {:?}", other)
+            }
+        }
     }
 
-    /// Send some binary data over the websocket connection.
-    ///
-    /// In contrast to [`Sender::send_binary`] the provided data is modified
-    /// in-place, e.g. if masking is necessary.
-    pub async fn send_binary_mut(&mut self, mut data: impl AsMut<[u8]>) -> Result<(), Error> {
-        let mut header = Header::new(OpCode::Binary);
-        self.send_frame(&mut header, &mut Storage::Unique(data.as_mut())).await
+    /// A socket which counts the number of reads.
+    struct Counting {
+        data: futures::io::Cursor<Vec<u8>>,
+        reads: Arc<AtomicUsize>
     }
 
-    /// Ping the remote end.
-    pub async fn send_ping(&mut self, data: ByteSlice125<'_>) -> Result<(), Error> {
-        let mut header = Header::new(OpCode::Ping);
-        self.write(&mut header, &mut Storage::Shared(data.as_ref())).await
+    impl futures::AsyncRead for Counting {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, b: &mut [u8]) -> Poll<io::Result<usize>> {
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            Pin::new(&mut self.data).poll_read(cx, b)
+        }
     }
 
-    /// Send an unsolicited Pong to the remote.
-    pub async fn send_pong(&mut self, data: ByteSlice125<'_>) -> Result<(), Error> {
-        let mut header = Header::new(OpCode::Pong);
-        self.write(&mut header, &mut Storage::Shared(data.as_ref())).await
+    impl futures::AsyncWrite for Counting {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context, b: &[u8]) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(b.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
     }
 
-    /// Flush the socket buffer.
-    pub async fn flush(&mut self) -> Result<(), Error> {
-        log::trace!("{}: flushing connection", self.id);
-        self.writer.lock().await.flush().await.or(Err(Error::Closed))
+    #[tokio::test]
+    async fn pipelined_frames_are_read_at_once() {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let data = vec![0x81, 0x01, b'a', 0x89, 0x00, 0x82, 0x02, 1, 2];
+        let socket = Counting { data: futures::io::Cursor::new(data), reads: reads.clone() };
+        let (_, mut receiver) = Builder::new(socket, Mode::Client).finish();
+
+        let mut data = Vec::new();
+        assert!(matches!(receiver.receive(&mut data).await, Ok(Incoming::Data(Data::Text(1)))));
+        assert!(matches!(receiver.receive(&mut data).await, Ok(Incoming::Data(Data::Binary(2)))));
+        assert_eq!(data, [b'a', 1, 2]);
+        assert_eq!(1, reads.load(Ordering::SeqCst))
     }
 
-    /// Send a close message and close the connection.
-    pub async fn close(&mut self) -> Result<(), Error> {
-        log::trace!("{}: closing connection", self.id);
-        let mut header = Header::new(OpCode::Close);
-        let code = 1000_u16.to_be_bytes(); // 1000 = normal closure
-        self.write(&mut header, &mut Storage::Shared(&code[..])).await?;
-        self.flush().await?;
-        self.writer.lock().await.close().await.or(Err(Error::Closed))
+    /// A socket which yields at most one byte every other poll.
+    struct Dribble {
+        data: futures::io::Cursor<Vec<u8>>,
+        ready: bool
     }
 
-    /// Send arbitrary websocket frames.
-    ///
-    /// Before sending, extensions will be applied to header and payload data.
-    async fn send_frame(&mut self, header: &mut Header, data: &mut Storage<'_>) -> Result<(), Error> {
-        if !self.has_extensions {
-            return self.write(header, data).await
+    impl futures::AsyncRead for Dribble {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, b: &mut [u8]) -> Poll<io::Result<usize>> {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+                return Poll::Pending
+            }
+            let n = std::cmp::min(1, b.len());
+            Pin::new(&mut self.data).poll_read(cx, &mut b[.. n])
+        }
+    }
+
+    impl futures::AsyncWrite for Dribble {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context, b: &[u8]) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(b.len()))
         }
 
-        for e in self.extensions.lock().await.iter_mut() {
-            log::trace!("{}: encoding with extension: {}", self.id, e.name());
-            e.encode(header, data).map_err(Error::Extension)?
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
         }
 
-        self.write(header, data).await
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
     }
 
-    /// Write final header and payload data to socket.
-    ///
-    /// The data will be masked if necessary.
-    /// No extensions will be applied to header and payload data.
-    async fn write(&mut self, header: &mut Header, data: &mut Storage<'_>) -> Result<(), Error> {
-        write(self.id, self.mode, &mut self.codec, &mut self.writer, header, data, &mut self.mask_buffer).await
+    // Payload accounting is checked by debug assertions while frames arrive
+    // over many polls.
+    #[tokio::test]
+    async fn payload_accounting_across_polls() {
+        let mut data = vec![0x01, 0x02, b'a', b'b', 0x89, 0x01, b'p', 0x80, 0x01, b'c'];
+        data.extend_from_slice(&[0x82, 0x7E, 0x01, 0x2C]);
+        data.extend((0 .. 300).map(|i| i as u8));
+        data.extend_from_slice(&[0x8A, 0x00]);
+        let socket = Dribble { data: futures::io::Cursor::new(data), ready: false };
+        let (_, mut receiver) = Builder::new(socket, Mode::Client).finish();
+
+        let mut message = Vec::new();
+        assert!(matches!(receiver.receive(&mut message).await, Ok(Incoming::Data(Data::Text(3)))));
+        assert_eq!(message, b"abc");
+
+        message.clear();
+        assert!(matches!(receiver.receive(&mut message).await, Ok(Incoming::Data(Data::Binary(300)))));
+        assert!(message.iter().enumerate().all(|(i, b)| *b == i as u8));
+
+        assert!(matches!(receiver.receive(&mut message).await, Ok(Incoming::Pong(&[]))))
     }
-}
 
-/// Write header and payload data to socket.
-async fn write<T: AsyncWrite + Unpin>
-    ( id: Id
-    , mode: Mode
-    , codec: &mut base::Codec
-    , writer: &mut BiLock<WriteHalf<T>>
-    , header: &mut Header
-    , data: &mut Storage<'_>
-    , mask_buffer: &mut Vec<u8>
-    ) -> Result<(), Error>
-{
-    if mode.is_client() {
-        header.set_masked(true);
-        header.set_mask(rand::random());
+    #[tokio::test]
+    async fn payload_shorter_than_declared() {
+        let data = vec![0x82, 0x05, 1, 2];
+        let socket = Dribble { data: futures::io::Cursor::new(data), ready: false };
+        let (_, mut receiver) = Builder::new(socket, Mode::Client).finish();
+        let mut message = Vec::new();
+        assert!(matches!(receiver.receive(&mut message).await, Err(Error::Closed(_))))
     }
-    header.set_payload_len(data.as_ref().len());
 
-    log::trace!("{}: send: {}", id, header);
+    #[tokio::test]
+    async fn write_progress() {
+        let pending = Arc::new(Mutex::new(Vec::new()));
+        let mut builder = Builder::new(Trickle(Vec::new()), Mode::Server);
+        builder.set_write_progress({
+            let pending = pending.clone();
+            move |written, total| pending.lock().unwrap().push(total - written)
+        });
+        let (mut sender, _) = builder.finish();
+        sender.send_binary(&[0xab; 20][..]).await.unwrap();
 
-    let header_bytes = codec.encode_header(&header);
-    let mut w = writer.lock().await;
-    w.write_all(&header_bytes).await.or(Err(Error::Closed))?;
+        // 2 header bytes + 20 payload bytes, written in chunks of 3 bytes.
+        let pending = pending.lock().unwrap();
+        assert_eq!(*pending, vec![20, 17, 14, 11, 8, 5, 2, 0])
+    }
 
-    if !header.is_masked() {
-        return w.write_all(data.as_ref()).await.or(Err(Error::Closed))
+    #[test]
+    fn close_code_from_error() {
+        let io = io::Error::from(io::ErrorKind::UnexpectedEof);
+        let invalid = vec![0xff];
+        let utf8 = std::str::from_utf8(&invalid).unwrap_err();
+        let too_large = Error::MessageTooLarge { limit: 1, actual: 2 };
+        let codec = Error::Protocol(crate::base::Error::PayloadTooLarge { actual: 2, maximum: 1 });
+        assert_eq!(CloseCode::ABNORMAL_CLOSURE, CloseCode::from(&Error::Io(io)));
+        assert_eq!(CloseCode::ABNORMAL_CLOSURE, CloseCode::from(&Error::Closed(CloseReason::abnormal())));
+        assert_eq!(CloseCode::INVALID_PAYLOAD, CloseCode::from(&Error::from(utf8)));
+        assert_eq!(CloseCode::MESSAGE_TOO_BIG, CloseCode::from(&too_large));
+        assert_eq!(CloseCode::MESSAGE_TOO_BIG, CloseCode::from(&codec));
+        let fragmented = crate::base::Error::FragmentedControl { opcode: OpCode::Close, first_byte: 0x08 };
+        assert_eq!(CloseCode::PROTOCOL_ERROR, CloseCode::from(&Error::Malformed { error: fragmented, raw_prefix: vec![0x08, 0x80] }));
+        assert_eq!(CloseCode::PROTOCOL_ERROR, CloseCode::from(&Error::Extension("bad".into())));
+        assert_eq!(CloseCode::PROTOCOL_ERROR, CloseCode::from(&Error::UnexpectedOpCode(crate::base::OpCode::Text)));
+        assert_eq!(CloseCode::PROTOCOL_ERROR, CloseCode::from(&Error::MaskedFrame));
+        assert_eq!(CloseCode::PROTOCOL_ERROR, CloseCode::from(&Error::UnmaskedFrame));
+        assert_eq!(CloseCode::PROTOCOL_ERROR, CloseCode::from(&Error::MaskingOptOutMismatch));
+        assert_eq!(CloseCode::PROTOCOL_ERROR, CloseCode::from(&Error::DataAfterClose));
+        assert_eq!(CloseCode::POLICY_VIOLATION, CloseCode::from(&Error::Timeout(TimeoutKind::ReadRate)));
+        assert_eq!(CloseCode::POLICY_VIOLATION, CloseCode::from(&Error::RateLimited));
+        assert_eq!(CloseCode::INVALID_PAYLOAD, CloseCode::from(&Error::InvalidText));
+        let invalid = crate::data::InvalidControlPayload::TooLong { len: 126 };
+        assert_eq!(CloseCode::INTERNAL_ERROR, CloseCode::from(&Error::InvalidControlPayload(invalid)))
     }
 
-    match data {
-        Storage::Shared(slice) => {
-            mask_buffer.clear();
-            mask_buffer.extend_from_slice(slice);
-            base::Codec::apply_mask(header, mask_buffer);
-            w.write_all(mask_buffer).await.or(Err(Error::Closed))
+    #[test]
+    fn error_variants() {
+        let eof = Error::from(io::Error::from(io::ErrorKind::UnexpectedEof));
+        assert!(matches!(&eof, Error::Closed(r) if r.code() == CloseCode::ABNORMAL_CLOSURE));
+        assert!(eof.is_fatal());
+        let would_block = Error::from(base::Error::Io(io::ErrorKind::WouldBlock.into()));
+        assert!(matches!(would_block, Error::Io(_)));
+        assert!(!would_block.is_fatal());
+        let reset = Error::Io(io::ErrorKind::ConnectionReset.into());
+        assert!(reset.is_fatal());
+        assert_eq!(None, reset.close_cause());
+        assert!(matches!(Error::from(base::Error::ReservedOpCode), Error::Protocol(base::Error::ReservedOpCode)));
+        assert_eq!(CloseCode::INVALID_PAYLOAD, Error::InvalidText.close_code());
+        let http = Error::HttpInjected { status_line: "HTTP/1.1 502 Bad Gateway".into() };
+        assert_eq!(CloseCode::ABNORMAL_CLOSURE, http.close_code());
+        let handshake = Error::from(crate::handshake::Error::InvalidSecWebSocketAccept);
+        assert!(handshake.is_fatal());
+        assert_eq!(CloseCode::ABNORMAL_CLOSURE, handshake.close_code());
+        assert_eq!(Some(CloseCause::ReadTooSlow), Error::Timeout(TimeoutKind::ReadRate).close_cause());
+        assert_eq!(None, Error::Timeout(TimeoutKind::Close).close_cause());
+        assert!(Error::MaskedFrame.is_fatal());
+        assert!(!Error::FrameTooLarge { size: 2, maximum: 1 }.is_fatal());
+        assert!(!Error::InvalidUtf8Outgoing.is_fatal());
+        assert!(Error::Closed(CloseReason::abnormal()).is_fatal())
+    }
+
+    #[tokio::test]
+    async fn close_on_error() {
+        let (a, b) = tokio::io::duplex(1024);
+        let mut server = Builder::new(b.compat(), Mode::Server);
+        server.set_max_message_size(4);
+        let (mut server_tx, mut server_rx) = server.finish();
+        let (mut client_tx, mut client_rx) = Builder::new(a.compat(), Mode::Client).finish();
+
+        client_tx.send_binary(&[1, 2, 3, 4, 5][..]).await.unwrap();
+        client_tx.flush().await.unwrap();
+
+        let mut data = Vec::new();
+        let e = server_rx.receive(&mut data).await.unwrap_err();
+        assert!(matches!(e, Error::MessageTooLarge {..}));
+        server_tx.close_on_error(&e).await.unwrap();
+
+        match client_rx.receive(&mut data).await {
+            Ok(Incoming::Closed(reason)) => assert_eq!(CloseCode::MESSAGE_TOO_BIG, reason.code()),
+            other => panic!("unexpected: {:?}", other)
         }
-        Storage::Unique(slice) => {
-            base::Codec::apply_mask(header, slice);
-            w.write_all(slice).await.or(Err(Error::Closed))
+    }
+
+    #[tokio::test]
+    async fn close_messages() {
+        let (a, b) = tokio::io::duplex(1024);
+        let mut server = Builder::new(b.compat(), Mode::Server);
+        server.set_max_message_size(4);
+        server.set_close_messages(|cause| match cause {
+            CloseCause::MessageTooBig => (CloseCode::POLICY_VIOLATION, format!("see https://example.com/{}", "é".repeat(100))),
+            other => (other.close_code(), String::new())
+        });
+        let (mut server_tx, mut server_rx) = server.finish();
+        let (mut client_tx, mut client_rx) = Builder::new(a.compat(), Mode::Client).finish();
+
+        client_tx.send_binary(&[1, 2, 3, 4, 5][..]).await.unwrap();
+        client_tx.flush().await.unwrap();
+
+        let mut data = Vec::new();
+        let e = server_rx.receive(&mut data).await.unwrap_err();
+        assert_eq!(Some(CloseCause::MessageTooBig), e.close_cause());
+        server_tx.close_on_error(&e).await.unwrap();
+
+        // The reason is cut off at a character boundary.
+        match client_rx.receive(&mut data).await {
+            Ok(Incoming::Closed(reason)) => {
+                assert_eq!(CloseCode::POLICY_VIOLATION, reason.code());
+                let text = reason.raw_reason().unwrap();
+                assert_eq!(122, text.len());
+                assert!(text.starts_with("see https://example.com/é"))
+            }
+            other => panic!("unexpected: {:?}", other)
         }
-        Storage::Owned(ref mut bytes) => {
-            base::Codec::apply_mask(header, bytes);
-            w.write_all(bytes).await.or(Err(Error::Closed))
+
+        // Codes which must not be sent are replaced with the default.
+        let (a, b) = tokio::io::duplex(1024);
+        let mut client = Builder::new(a.compat(), Mode::Client);
+        client.set_send_quota(Some(4));
+        client.set_close_messages(|_| (CloseCode::NO_STATUS_RECEIVED, "quota".into()));
+        let (mut client_tx, _) = client.finish();
+        let (_, mut server_rx) = Builder::new(b.compat(), Mode::Server).finish();
+        assert!(matches!(client_tx.send_binary(&[0; 8][..]).await, Err(Error::QuotaExceeded)));
+        match server_rx.receive(&mut data).await {
+            Ok(Incoming::Closed(reason)) => {
+                assert_eq!(CloseCode::POLICY_VIOLATION, reason.code());
+                assert_eq!(Some("quota"), reason.raw_reason())
+            }
+            other => panic!("unexpected: {:?}", other)
         }
     }
-}
 
-/// Create a close frame based on the given data.
-fn close_answer(data: &[u8]) -> Result<(Header, Option<u16>), Error> {
-    let answer = Header::new(OpCode::Close);
-    if data.len() < 2 {
-        return Ok((answer, None))
+    #[cfg(feature = "deflate")]
+    async fn receive_fragmented_deflate(second_rsv1: bool) -> Result<Data, Error> {
+        use crate::extension::{Extension, deflate::Deflate};
+
+        let (a, mut b) = tokio::io::duplex(1024);
+
+        let mut deflate = Deflate::new(Mode::Server);
+        deflate.configure(&[]).unwrap();
+
+        let mut builder = Builder::new(a.compat(), Mode::Server);
+        builder.add_extensions(Some(Box::new(deflate) as Box<dyn Extension + Send>));
+        let (_sender, mut receiver) = builder.finish();
+
+        // "Hello" compressed (RFC 7692, 7.2.3.1), split into two fragments
+        // which are masked with an all-zero key.
+        b.write_all(&[0x41, 0x83, 0, 0, 0, 0, 0xf2, 0x48, 0xcd]).await.unwrap();
+        let b0 = if second_rsv1 { 0xC0 } else { 0x80 };
+        b.write_all(&[b0, 0x84, 0, 0, 0, 0, 0xc9, 0xc9, 0x07, 0x00]).await.unwrap();
+
+        let mut data = Vec::new();
+        let d = receiver.receive_data(&mut data).await?;
+        assert_eq!(data, b"Hello");
+        Ok(d)
     }
-    std::str::from_utf8(&data[2 ..])?; // check reason is properly encoded
-    let code = u16::from_be_bytes([data[0], data[1]]);
-    match code {
-        | 1000 ..= 1003
-        | 1007 ..= 1011
-        | 1015
-        | 3000 ..= 4999 => Ok((answer, Some(code))), // acceptable codes
-        _               => Ok((answer, Some(1002))) // invalid code => protocol error (1002)
+
+    #[cfg(feature = "deflate")]
+    #[tokio::test]
+    async fn deflate_rejects_rsv1_on_continuation() {
+        assert!(matches!(receive_fragmented_deflate(false).await, Ok(Data::Text(5))));
+        match receive_fragmented_deflate(true).await {
+            Err(e @ Error::Extension(_)) => assert_eq!(CloseCode::PROTOCOL_ERROR, CloseCode::from(&e)),
+            other => panic!("unexpected: {:?}", other)
+        }
     }
-}
 
-/// Errors which may occur when sending or receiving messages.
-#[non_exhaustive]
-#[derive(Debug)]
-pub enum Error {
-    /// An I/O error was encountered.
-    Io(io::Error),
-    /// The base codec errored.
-    Codec(base::Error),
-    /// An extension produced an error while encoding or decoding.
-    Extension(crate::BoxedError),
-    /// An unexpected opcode was encountered.
-    UnexpectedOpCode(OpCode),
-    /// A close reason was not correctly UTF-8 encoded.
-    Utf8(str::Utf8Error),
-    /// The total message payload data size exceeds the configured maximum.
-    MessageTooLarge { current: usize, maximum: usize },
-    /// The connection is closed.
-    Closed
-}
+    #[cfg(feature = "deflate")]
+    #[tokio::test]
+    async fn empty_deflate_messages() {
+        use crate::extension::{Extension, deflate::Deflate};
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Error::Io(e) =>
-                write!(f, "i/o error: {}", e),
-            Error::Codec(e) =>
-                write!(f, "codec error: {}", e),
-            Error::Extension(e) =>
-                write!(f, "extension error: {}", e),
-            Error::UnexpectedOpCode(c) =>
-                write!(f, "unexpected opcode: {}", c),
-            Error::Utf8(e) =>
-                write!(f, "utf-8 error: {}", e),
-            Error::MessageTooLarge { current, maximum } =>
-                write!(f, "message too large: len >= {}, maximum = {}", current, maximum),
-            Error::Closed =>
-                f.write_str("connection closed")
-        }
+        let (a, mut b) = tokio::io::duplex(1024);
+
+        let mut deflate = Deflate::new(Mode::Server);
+        deflate.configure(&[]).unwrap();
+
+        let mut builder = Builder::new(a.compat(), Mode::Server);
+        builder.add_extensions(Some(Box::new(deflate) as Box<dyn Extension + Send>));
+        let (_sender, mut receiver) = builder.finish();
+
+        // An empty compressed message (RFC 7692, 7.2.3.6) followed by "Hello"
+        // with an empty first fragment, all masked with an all-zero key.
+        b.write_all(&[0xC1, 0x81, 0, 0, 0, 0, 0x00]).await.unwrap();
+        b.write_all(&[0x41, 0x80, 0, 0, 0, 0]).await.unwrap();
+        b.write_all(&[0x80, 0x87, 0, 0, 0, 0, 0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00]).await.unwrap();
+
+        let mut data = Vec::new();
+        assert!(matches!(receiver.receive_data(&mut data).await, Ok(Data::Text(0))));
+        assert!(data.is_empty());
+        assert!(matches!(receiver.receive_data(&mut data).await, Ok(Data::Text(5))));
+        assert_eq!(data, b"Hello")
     }
-}
 
-impl std::error::Error for Error {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        match self {
-            Error::Io(e) => Some(e),
-            Error::Codec(e) => Some(e),
-            Error::Extension(e) => Some(&**e),
-            Error::Utf8(e) => Some(e),
-            Error::UnexpectedOpCode(_)
-            | Error::MessageTooLarge {..}
-            | Error::Closed
-            => None
+    /// Receives a 100 MiB compressed message in pieces of at most 64 KiB.
+    #[cfg(feature = "deflate")]
+    #[tokio::test]
+    async fn deflate_message_parts() {
+        use crate::extension::{Extension, Param, deflate::Deflate};
+
+        const LEN: usize = 100 * 1024 * 1024;
+
+        fn deflate(mode: Mode) -> Box<dyn Extension + Send> {
+            let mut deflate = Deflate::new(mode);
+            deflate.configure(&[Param::new("server_no_context_takeover")]).unwrap();
+            assert!(deflate.is_enabled());
+            Box::new(deflate)
         }
+
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let mut client = Builder::new(a.compat(), Mode::Client);
+        client.add_extensions(Some(deflate(Mode::Client)));
+        let (mut sender, _) = client.finish();
+        let mut server = Builder::new(b.compat(), Mode::Server);
+        server.add_extensions(Some(deflate(Mode::Server)));
+        server.set_max_message_size(LEN);
+        let (_, mut receiver) = server.finish();
+
+        let send = async {
+            sender.send_binary(vec![0; LEN]).await.unwrap();
+            sender.send_text("hello").await.unwrap();
+            sender.flush().await.unwrap()
+        };
+        let receive = async {
+            let mut data = Vec::with_capacity(super::PART_SIZE);
+            let mut total = 0;
+            loop {
+                data.clear();
+                let part = receiver.receive_part(&mut data).await.unwrap();
+                assert!(matches!(part.data(), Data::Binary(n) if *n == data.len()));
+                assert!(data.iter().all(|b| *b == 0));
+                total += data.len();
+                if part.is_last() {
+                    break
+                }
+            }
+            assert_eq!(LEN, total);
+            assert!(data.capacity() <= super::PART_SIZE);
+
+            data.clear();
+            let part = receiver.receive_part(&mut data).await.unwrap();
+            assert_eq!((&Data::Text(5), true), (part.data(), part.is_last()));
+            assert_eq!(b"hello", &data[..]);
+
+            let stats = receiver.extension_stats().await;
+            assert_eq!(2, stats[0].decoded_frames())
+        };
+        tokio::join!(send, receive);
     }
-}
 
-impl From<io::Error> for Error {
-    fn from(e: io::Error) -> Self {
-        if e.kind() == io::ErrorKind::UnexpectedEof {
-            Error::Closed
-        } else {
-            Error::Io(e)
+    #[tokio::test]
+    async fn message_parts() {
+        let (a, b) = tokio::io::duplex(1024 * 1024);
+        let (mut sender, _) = Builder::new(a.compat(), Mode::Client).finish();
+        let (_, mut receiver) = Builder::new(b.compat(), Mode::Server).finish();
+
+        // A single frame is received in pieces which may split characters.
+        let text = "\u{20ac}".repeat(70_000);
+        sender.send_text(&text).await.unwrap();
+        // Pieces of a message may be interleaved with control frames.
+        sender.send_binary_part(b"abc", false).await.unwrap();
+        assert!(matches!(sender.send_binary(b"x").await, Err(Error::UnexpectedOpCode(OpCode::Binary))));
+        assert!(matches!(sender.send_text_part("x", true).await, Err(Error::UnexpectedOpCode(OpCode::Text))));
+        sender.send_ping(std::convert::TryFrom::try_from(&b"ping"[..]).unwrap()).await.unwrap();
+        sender.send_binary_part(b"defgh", true).await.unwrap();
+        sender.flush().await.unwrap();
+
+        let mut message = Vec::new();
+        let mut pieces = 0;
+        loop {
+            let part = receiver.receive_part(&mut message).await.unwrap();
+            assert!(matches!(part.data(), Data::Text(n) if *n <= super::PART_SIZE));
+            pieces += 1;
+            if part.is_last() {
+                break
+            }
         }
+        assert!(pieces >= 210_000 / super::PART_SIZE);
+        assert_eq!(text.as_bytes(), &message[..]);
+
+        message.clear();
+        let part = receiver.receive_part(&mut message).await.unwrap();
+        assert_eq!((&Data::Binary(3), false), (part.data(), part.is_last()));
+        let part = receiver.receive_part(&mut message).await.unwrap();
+        assert_eq!((&Data::Binary(5), true), (part.data(), part.is_last()));
+        assert_eq!(b"abcdefgh", &message[..]);
     }
-}
 
-impl From<str::Utf8Error> for Error {
-    fn from(e: str::Utf8Error) -> Self {
-        Error::Utf8(e)
+    #[tokio::test]
+    async fn message_parts_invalid_text() {
+        let (a, b) = tokio::io::duplex(1024);
+        let (mut sender, _) = Builder::new(a.compat(), Mode::Client).finish();
+        let (_, mut receiver) = Builder::new(b.compat(), Mode::Server).finish();
+
+        // The message ends with an incomplete character.
+        let mut header = Header::new(OpCode::Text);
+        header.set_fin(false);
+        sender.send_frame_with_mask(base::Frame::from_parts(header, b"ab\xe2\x82".to_vec()), 0x1234_5678).await.unwrap();
+        sender.send_frame_with_mask(base::Frame::new(OpCode::Continue), 0x1234_5678).await.unwrap();
+        sender.flush().await.unwrap();
+
+        let mut message = Vec::new();
+        let part = receiver.receive_part(&mut message).await.unwrap();
+        assert_eq!((&Data::Text(4), false), (part.data(), part.is_last()));
+        assert!(matches!(receiver.receive_part(&mut message).await, Err(Error::InvalidText)))
     }
-}
 
-impl From<base::Error> for Error {
-    fn from(e: base::Error) -> Self {
-        Error::Codec(e)
+    /// Streams a 5 GiB message in constant memory.
+    #[cfg(all(feature = "slow-tests", target_pointer_width = "64"))]
+    #[tokio::test]
+    #[ignore]
+    async fn message_beyond_4gib() {
+        const LEN: usize = 5 * 1024 * 1024 * 1024;
+        const CHUNK: usize = 1024 * 1024;
+
+        let (a, b) = tokio::io::duplex(1024 * 1024);
+        let mut server = Builder::new(a.compat(), Mode::Server);
+        server.set_max_message_size(LEN);
+        let (_, mut receiver) = server.finish();
+        let (mut sender, _) = Builder::new(b.compat(), Mode::Client).finish();
+
+        let send = async {
+            let chunk = vec![0; CHUNK];
+            for i in 1 ..= LEN / CHUNK {
+                sender.send_binary_part(&chunk, i == LEN / CHUNK).await.unwrap()
+            }
+            sender.flush().await.unwrap()
+        };
+        let receive = async {
+            let mut data = Vec::with_capacity(super::PART_SIZE);
+            let mut total = 0;
+            loop {
+                data.clear();
+                let part = receiver.receive_part(&mut data).await.unwrap();
+                assert!(matches!(part.data(), Data::Binary(n) if *n == data.len()));
+                assert!(data.iter().all(|b| *b == 0));
+                total += data.len();
+                assert!(total <= LEN);
+                if part.is_last() {
+                    break
+                }
+            }
+            assert_eq!(LEN, total)
+        };
+        tokio::join!(send, receive);
+    }
+
+    /// Streams a single 5 GiB frame in constant memory, once as is and once
+    /// compressed, which decompresses to 5 GiB.
+    #[cfg(all(feature = "slow-tests", feature = "deflate", target_pointer_width = "64"))]
+    #[tokio::test]
+    #[ignore]
+    async fn frame_beyond_4gib() {
+        use crate::extension::deflate::Deflate;
+        use flate2::{Compress, Compression, FlushCompress};
+        use tokio_util::compat::Compat;
+
+        const LEN: usize = 5 * 1024 * 1024 * 1024;
+
+        async fn receive(receiver: &mut super::Receiver<Compat<tokio::io::DuplexStream>>) -> usize {
+            let mut data = Vec::with_capacity(super::PART_SIZE);
+            let mut total = 0;
+            loop {
+                data.clear();
+                let part = receiver.receive_part(&mut data).await.unwrap();
+                assert!(matches!(part.data(), Data::Binary(n) if *n == data.len()));
+                assert!(data.len() <= super::PART_SIZE);
+                assert!(data.iter().all(|b| *b == 0));
+                total += data.len();
+                assert!(total <= LEN);
+                if part.is_last() {
+                    return total
+                }
+            }
+        }
+
+        // The zeroed allocation is not touched before it is read, so it maps
+        // no memory.
+        let zeros = vec![0; LEN];
+        let (a, b) = tokio::io::duplex(1024 * 1024);
+        let mut server = Builder::new(a.compat(), Mode::Server);
+        server.set_max_frame_size(LEN);
+        server.set_max_message_size(LEN);
+        let (_, mut receiver) = server.finish();
+        let (mut sender, _) = Builder::new(b.compat(), Mode::Client).finish();
+        let send = async {
+            sender.send_binary_part(&zeros, true).await.unwrap();
+            sender.flush().await.unwrap()
+        };
+        let ((), total) = tokio::join!(send, receive(&mut receiver));
+        assert_eq!(LEN, total);
+        drop(zeros);
+
+        let compressed = {
+            let chunk = vec![0; 1024 * 1024];
+            let mut compress = Compress::new(Compression::fast(), false);
+            let mut out = Vec::new();
+            while (compress.total_in() as usize) < LEN {
+                out.reserve(64 * 1024);
+                compress.compress_vec(&chunk, &mut out, FlushCompress::None).unwrap();
+            }
+            while !out.ends_with(&[0, 0, 0xFF, 0xFF]) {
+                out.reserve(64);
+                compress.compress_vec(&[], &mut out, FlushCompress::Sync).unwrap();
+            }
+            out.truncate(out.len() - 4);
+            out
+        };
+        let (a, b) = tokio::io::duplex(1024 * 1024);
+        let mut deflate = Deflate::new(Mode::Server);
+        deflate.configure(&[]).unwrap();
+        let mut server = Builder::new(a.compat(), Mode::Server);
+        server.add_extensions(Some(Box::new(deflate) as Box<dyn Extension + Send>));
+        server.set_max_message_size(LEN);
+        let (_, mut receiver) = server.finish();
+        // Parts bypass extensions, so the interceptor marks the payload as
+        // compressed.
+        let mut client = Builder::new(b.compat(), Mode::Client);
+        client.set_frame_interceptor(|f: &mut base::Frame| { f.header_mut().set_rsv1(true); });
+        let (mut sender, _) = client.finish();
+        let send = async {
+            sender.send_binary_part(&compressed, true).await.unwrap();
+            sender.flush().await.unwrap()
+        };
+        let ((), total) = tokio::join!(send, receive(&mut receiver));
+        assert_eq!(LEN, total)
     }
 }
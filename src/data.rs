@@ -8,26 +8,45 @@
 
 //! Types describing various forms of payload data.
 
+use crate::{base::{CloseCode, OpCode}, connection::CloseReason};
 use std::{convert::TryFrom, fmt};
 
+/// Max. size of a control frame payload.
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
 /// Data received from the remote end.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Incoming<'a> {
     /// Text or binary data.
     Data(Data),
     /// Data sent with a PONG control frame.
-    Pong(&'a [u8])
+    Pong(&'a [u8]),
+    /// The remote has closed the connection.
+    Closed(CloseReason),
+    /// The remote has shut down its writing side without sending a CLOSE
+    /// frame, but may still read data from us.
+    PeerClosedWrites
 }
 
 impl Incoming<'_> {
     /// Is this text or binary data?
     pub fn is_data(&self) -> bool {
-        if let Incoming::Data(_) = self { true } else { false }
+        matches!(self, Incoming::Data(_))
     }
 
     /// Is this a PONG?
     pub fn is_pong(&self) -> bool {
-        if let Incoming::Pong(_) = self { true } else { false }
+        matches!(self, Incoming::Pong(_))
+    }
+
+    /// Has the connection been closed by the remote?
+    pub fn is_closed(&self) -> bool {
+        matches!(self, Incoming::Closed(_))
+    }
+
+    /// Has the remote shut down its writing side?
+    pub fn is_peer_closed_writes(&self) -> bool {
+        matches!(self, Incoming::PeerClosedWrites)
     }
 
     /// Is this text data?
@@ -52,9 +71,15 @@ impl Incoming<'_> {
     pub fn len(&self) -> usize {
         match self {
             Incoming::Data(d) => d.len(),
-            Incoming::Pong(d) => d.len()
+            Incoming::Pong(d) => d.len(),
+            Incoming::Closed(_) | Incoming::PeerClosedWrites => 0
         }
     }
+
+    /// Is the data empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -68,12 +93,12 @@ pub enum Data {
 impl Data {
     /// Is this text data?
     pub fn is_text(&self) -> bool {
-        if let Data::Text(_) = self { true } else { false }
+        matches!(self, Data::Text(_))
     }
 
     /// Is this binary data?
     pub fn is_binary(&self) -> bool {
-        if let Data::Binary(_) = self { true } else { false }
+        matches!(self, Data::Binary(_))
     }
 
     /// The length of data (number of bytes).
@@ -83,6 +108,63 @@ impl Data {
             Data::Binary(n) => *n
         }
     }
+
+    /// Is the data empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The opcode of the frames carrying this data.
+    pub fn opcode(&self) -> OpCode {
+        match self {
+            Data::Text(_) => OpCode::Text,
+            Data::Binary(_) => OpCode::Binary
+        }
+    }
+}
+
+/// An owned message to send to the remote end.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Outgoing {
+    /// Textual data.
+    Text(String),
+    /// Binary data.
+    Binary(Vec<u8>)
+}
+
+impl Outgoing {
+    /// The length of data (number of bytes).
+    pub fn len(&self) -> usize {
+        match self {
+            Outgoing::Text(s) => s.len(),
+            Outgoing::Binary(b) => b.len()
+        }
+    }
+
+    /// Is the data empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The opcode of the frames carrying this data.
+    pub fn opcode(&self) -> OpCode {
+        match self {
+            Outgoing::Text(_) => OpCode::Text,
+            Outgoing::Binary(_) => OpCode::Binary
+        }
+    }
+}
+
+impl From<String> for Outgoing {
+    fn from(s: String) -> Self {
+        Outgoing::Text(s)
+    }
+}
+
+impl From<Vec<u8>> for Outgoing {
+    fn from(b: Vec<u8>) -> Self {
+        Outgoing::Binary(b)
+    }
 }
 
 /// Wrapper type which restricts the length of its byte slice to 125 bytes.
@@ -105,11 +187,8 @@ impl<'a> TryFrom<&'a [u8]> for ByteSlice125<'a> {
     type Error = SliceTooLarge;
 
     fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
-        if value.len() > 125 {
-            Err(SliceTooLarge(()))
-        } else {
-            Ok(ByteSlice125(value))
-        }
+        check_control_len(value.len()).or(Err(SliceTooLarge(())))?;
+        Ok(ByteSlice125(value))
     }
 }
 
@@ -119,3 +198,116 @@ impl AsRef<[u8]> for ByteSlice125<'_> {
     }
 }
 
+/// The payload of a PING, PONG or CLOSE frame which is known to be valid.
+///
+/// This allows validating control frames built from user input before
+/// sending them with [`Sender::send_control`](crate::connection::Sender::send_control).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlPayload {
+    opcode: OpCode,
+    data: Vec<u8>
+}
+
+impl ControlPayload {
+    /// The payload of a PING frame.
+    pub fn ping(data: &[u8]) -> Result<Self, InvalidControlPayload> {
+        check_control_len(data.len())?;
+        Ok(ControlPayload { opcode: OpCode::Ping, data: data.to_vec() })
+    }
+
+    /// The payload of a PONG frame.
+    pub fn pong(data: &[u8]) -> Result<Self, InvalidControlPayload> {
+        check_control_len(data.len())?;
+        Ok(ControlPayload { opcode: OpCode::Pong, data: data.to_vec() })
+    }
+
+    /// The payload of a CLOSE frame with status code and reason.
+    ///
+    /// The code must be allowed on the wire (cf. [`CloseCode::is_allowed_on_wire`])
+    /// and the reason must not be longer than 123 bytes.
+    pub fn close(code: CloseCode, reason: &str) -> Result<Self, InvalidControlPayload> {
+        if !code.is_allowed_on_wire() {
+            return Err(InvalidControlPayload::CloseCode(code))
+        }
+        let max = MAX_CONTROL_PAYLOAD - 2;
+        if reason.len() > max {
+            let truncate_at = (0 ..= max).rev().find(|i| reason.is_char_boundary(*i)).unwrap_or(0);
+            return Err(InvalidControlPayload::ReasonTooLong { len: reason.len(), truncate_at })
+        }
+        let mut data = Vec::with_capacity(2 + reason.len());
+        data.extend_from_slice(&u16::from(code).to_be_bytes());
+        data.extend_from_slice(reason.as_bytes());
+        Ok(ControlPayload { opcode: OpCode::Close, data })
+    }
+
+    /// The opcode of the control frame.
+    pub fn opcode(&self) -> OpCode {
+        self.opcode
+    }
+
+    /// Consume this payload and return the opcode and data.
+    pub(crate) fn into_parts(self) -> (OpCode, Vec<u8>) {
+        (self.opcode, self.data)
+    }
+}
+
+impl AsRef<[u8]> for ControlPayload {
+    fn as_ref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// Error, if creating a [`ControlPayload`] fails.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InvalidControlPayload {
+    /// The payload is longer than 125 bytes.
+    TooLong { len: usize },
+    /// The close code is not allowed to be sent.
+    CloseCode(CloseCode),
+    /// The close reason is longer than 123 bytes and needs to be truncated,
+    /// e.g. at the character boundary `truncate_at`.
+    ReasonTooLong { len: usize, truncate_at: usize }
+}
+
+impl fmt::Display for InvalidControlPayload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidControlPayload::TooLong { len } =>
+                write!(f, "control payload too long: {} > {}", len, MAX_CONTROL_PAYLOAD),
+            InvalidControlPayload::CloseCode(c) =>
+                write!(f, "close code {} can not be sent", c),
+            InvalidControlPayload::ReasonTooLong { len, truncate_at } =>
+                write!(f, "close reason too long: {} > {}, truncate at {}", len, MAX_CONTROL_PAYLOAD - 2, truncate_at)
+        }
+    }
+}
+
+impl std::error::Error for InvalidControlPayload {}
+
+/// Check the length of a control frame payload.
+pub(crate) fn check_control_len(len: usize) -> Result<(), InvalidControlPayload> {
+    if len > MAX_CONTROL_PAYLOAD {
+        Err(InvalidControlPayload::TooLong { len })
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::base::OpCode;
+    use super::{Data, Outgoing};
+
+    #[test]
+    fn accessors() {
+        assert_eq!(2, Outgoing::Text("hi".into()).len());
+        assert_eq!(OpCode::Text, Outgoing::Text("hi".into()).opcode());
+        assert!(Outgoing::Binary(vec![]).is_empty());
+        assert_eq!(OpCode::Binary, Outgoing::Binary(vec![]).opcode());
+
+        assert_eq!(2, Data::Text(2).len());
+        assert_eq!(OpCode::Text, Data::Text(2).opcode());
+        assert!(Data::Binary(0).is_empty());
+        assert_eq!(OpCode::Binary, Data::Binary(0).opcode())
+    }
+}
@@ -27,21 +27,21 @@ use std::{borrow::Cow, fmt};
 ///
 /// 1. All extensions should consider themselves as disabled but available.
 /// 2. When receiving a handshake request from a client, for each extension
-/// with a matching name, [`Extension::configure`] will be applied to the
-/// request parameters. The extension may internally enable itself.
+///    with a matching name, [`Extension::configure`] will be applied to the
+///    request parameters. The extension may internally enable itself.
 /// 3. When sending back the response, for each extension whose
-/// [`Extension::is_enabled`] returns true, the extension name and its
-/// parameters (as returned by [`Extension::params`]) will be included in the
-/// response.
+///    [`Extension::is_enabled`] returns true, the extension name and its
+///    parameters (as returned by [`Extension::params`]) will be included in the
+///    response.
 ///
 /// # Client
 ///
 /// 1. All extensions should consider themselves as disabled but available.
 /// 2. When creating the handshake request, all extensions and its parameters
-/// (as returned by [`Extension::params`]) will be included in the request.
+///    (as returned by [`Extension::params`]) will be included in the request.
 /// 3. When receiving the response from the server, for every extension with
-/// a matching name in the response, [`Extension::configure`] will be applied
-/// to the response parameters. The extension may internally enable itself.
+///    a matching name in the response, [`Extension::configure`] will be applied
+///    to the response parameters. The extension may internally enable itself.
 ///
 /// After this handshake phase, extensions have been configured and are
 /// potentially enabled. Enabled extensions can then be used for further base
@@ -54,7 +54,7 @@ pub trait Extension: std::fmt::Debug {
     fn name(&self) -> &str;
 
     /// The parameters this extension wants to send for negotiation.
-    fn params(&self) -> &[Param];
+    fn params(&self) -> &[Param<'_>];
 
     /// Configure this extension with the parameters received from negotiation.
     fn configure(&mut self, params: &[Param]) -> Result<(), BoxedError>;
@@ -65,15 +65,65 @@ pub trait Extension: std::fmt::Debug {
     /// Decode a frame.
     ///
     /// The frame header is given, as well as the accumulated payload data, i.e.
-    /// the concatenated payload data of all message fragments.
+    /// the concatenated payload data of all message fragments. This method is
+    /// invoked for every data frame, including intermediate fragments.
     fn decode(&mut self, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError>;
 
+    /// Decode part of a message incrementally.
+    ///
+    /// `input` is a piece of the payload data of the data frame with the
+    /// given header and `end_of_frame` tells if it is the last piece of this
+    /// frame. At most `max_output` bytes of decoded data are appended to
+    /// `output`, so the memory needed for a message does not depend on its
+    /// size. The returned [`Decoded`] tells how many input bytes have been
+    /// consumed, how many output bytes have been produced and how to go on.
+    ///
+    /// By default, extensions do not support incremental decoding and
+    /// return an error.
+    fn decode_chunk
+        ( &mut self
+        , header: &Header
+        , input: &[u8]
+        , end_of_frame: bool
+        , output: &mut Vec<u8>
+        , max_output: usize
+        ) -> Result<Decoded, BoxedError>
+    {
+        let _ = (header, input, end_of_frame, output, max_output);
+        let msg = format!("extension {} does not support incremental decoding", self.name());
+        Err(std::io::Error::other(msg).into())
+    }
+
     /// The reserved bits this extension uses.
     fn reserved_bits(&self) -> (bool, bool, bool) {
         (false, false, false)
     }
 }
 
+/// The outcome of [`Extension::decode_chunk`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decoded {
+    /// The number of input bytes consumed.
+    pub consumed: usize,
+    /// The number of bytes appended to the output.
+    pub produced: usize,
+    /// How to continue decoding.
+    pub state: DecodeState
+}
+
+/// How to continue after [`Extension::decode_chunk`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeState {
+    /// More output may be available. Call again with the input not yet
+    /// consumed, which may be empty.
+    MoreOutput,
+    /// All output for the input so far has been produced. Call again with
+    /// the next piece of input.
+    NeedInput,
+    /// The message has been decoded completely.
+    End
+}
+
 impl<E: Extension + ?Sized> Extension for Box<E> {
     fn is_enabled(&self) -> bool {
         (**self).is_enabled()
@@ -83,7 +133,7 @@ impl<E: Extension + ?Sized> Extension for Box<E> {
         (**self).name()
     }
 
-    fn params(&self) -> &[Param] {
+    fn params(&self) -> &[Param<'_>] {
         (**self).params()
     }
 
@@ -99,6 +149,18 @@ impl<E: Extension + ?Sized> Extension for Box<E> {
         (**self).decode(header, data)
     }
 
+    fn decode_chunk
+        ( &mut self
+        , header: &Header
+        , input: &[u8]
+        , end_of_frame: bool
+        , output: &mut Vec<u8>
+        , max_output: usize
+        ) -> Result<Decoded, BoxedError>
+    {
+        (**self).decode_chunk(header, input, end_of_frame, output, max_output)
+    }
+
     fn reserved_bits(&self) -> (bool, bool, bool) {
         (**self).reserved_bits()
     }
@@ -16,10 +16,10 @@ use crate::{
     Storage,
     base::{Header, OpCode},
     connection::Mode,
-    extension::{Extension, Param}
+    extension::{Decoded, DecodeState, Extension, Param}
 };
-use flate2::{Compress, Compression, FlushCompress, Status, write::DeflateDecoder};
-use std::{convert::TryInto, io::{self, Write}, mem};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use std::{convert::TryInto, fmt, io, mem};
 
 const SERVER_NO_CONTEXT_TAKEOVER: &str = "server_no_context_takeover";
 const SERVER_MAX_WINDOW_BITS: &str = "server_max_window_bits";
@@ -27,6 +27,13 @@ const SERVER_MAX_WINDOW_BITS: &str = "server_max_window_bits";
 const CLIENT_NO_CONTEXT_TAKEOVER: &str = "client_no_context_takeover";
 const CLIENT_MAX_WINDOW_BITS: &str = "client_max_window_bits";
 
+/// The empty deflate block removed from the end of a compressed message
+/// (cf. RFC 7692, 7.2.2).
+const TRAILER: [u8; 4] = [0, 0, 0xFF, 0xFF];
+
+/// The size of the chunks a whole message is decompressed in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
 /// The deflate extension type.
 ///
 /// The extension does currently not support max. window bits other than the
@@ -39,7 +46,101 @@ pub struct Deflate {
     params: Vec<Param<'static>>,
     our_max_window_bits: u8,
     their_max_window_bits: u8,
-    await_last_fragment: bool
+    await_last_fragment: bool,
+    max_decompressed_size: usize,
+    /// The state of a message decoded with [`Extension::decode_chunk`].
+    inflate: Option<Inflate>
+}
+
+/// The decompression state of a single message.
+struct Inflate {
+    decompress: Decompress,
+    /// The number of [`TRAILER`] bytes fed to the decompressor.
+    trailer: usize,
+    /// The number of bytes decompressed so far.
+    total: usize
+}
+
+impl fmt::Debug for Inflate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Inflate").field("trailer", &self.trailer).field("total", &self.total).finish()
+    }
+}
+
+impl Inflate {
+    /// Create a decompressor whose window does not exceed `2^window_bits`.
+    fn new(window_bits: u8) -> Self {
+        Inflate { decompress: Decompress::new_with_window_bits(false, window_bits), trailer: 0, total: 0 }
+    }
+
+    /// Decompress `input` into `output` (cf. [`Extension::decode_chunk`]).
+    ///
+    /// If `end` is set, this is the last input of the message and the
+    /// [`TRAILER`] is appended. Fails if more than `max_size` bytes would be
+    /// decompressed in total.
+    fn inflate
+        ( &mut self
+        , input: &[u8]
+        , end: bool
+        , output: &mut Vec<u8>
+        , max_output: usize
+        , max_size: usize
+        ) -> Result<Decoded, BoxedError>
+    {
+        let offset = output.len();
+        output.resize(offset + max_output, 0);
+        let mut consumed = 0;
+        let mut produced = 0;
+        let state = loop {
+            if produced == max_output {
+                break DecodeState::MoreOutput
+            }
+            let (src, flush) =
+                if consumed < input.len() {
+                    (&input[consumed ..], FlushDecompress::None)
+                } else if end {
+                    (&TRAILER[self.trailer ..], FlushDecompress::Sync)
+                } else {
+                    (&[][..], FlushDecompress::None)
+                };
+            let (total_in, total_out) = (self.decompress.total_in(), self.decompress.total_out());
+            let result = self.decompress.decompress(src, &mut output[offset + produced ..], flush);
+            let n_in: usize = (self.decompress.total_in() - total_in).try_into()?;
+            let n_out: usize = (self.decompress.total_out() - total_out).try_into()?;
+            let status = match result {
+                Ok(status) => status,
+                Err(e) => {
+                    output.truncate(offset + produced);
+                    return Err(e.into())
+                }
+            };
+            if consumed < input.len() {
+                consumed += n_in
+            } else if end {
+                self.trailer += n_in
+            }
+            produced += n_out;
+            self.total = self.total.saturating_add(n_out);
+            if self.total > max_size {
+                output.truncate(offset + produced);
+                log::debug!("deflate: decompressed message exceeds {} bytes", max_size);
+                return Err(io::Error::other("decompressed message too large").into())
+            }
+            if status == Status::StreamEnd {
+                // The peer terminated the deflate stream; there is nothing more to decode.
+                break DecodeState::End
+            }
+            if n_in == 0 && n_out == 0 {
+                if !src.is_empty() {
+                    output.truncate(offset + produced);
+                    return Err(io::Error::other("decompression made no progress").into())
+                }
+                break if end { DecodeState::End } else { DecodeState::NeedInput }
+            }
+        };
+        output.truncate(offset + produced);
+        Ok(Decoded { consumed, produced, state })
+    }
 }
 
 impl Deflate {
@@ -47,13 +148,11 @@ impl Deflate {
     pub fn new(mode: Mode) -> Self {
         let params = match mode {
             Mode::Server => Vec::new(),
-            Mode::Client => {
-                let mut params = Vec::new();
-                params.push(Param::new(SERVER_NO_CONTEXT_TAKEOVER));
-                params.push(Param::new(CLIENT_NO_CONTEXT_TAKEOVER));
-                params.push(Param::new(CLIENT_MAX_WINDOW_BITS));
-                params
-            }
+            Mode::Client => vec![
+                Param::new(SERVER_NO_CONTEXT_TAKEOVER),
+                Param::new(CLIENT_NO_CONTEXT_TAKEOVER),
+                Param::new(CLIENT_MAX_WINDOW_BITS)
+            ]
         };
         Deflate {
             mode,
@@ -62,10 +161,22 @@ impl Deflate {
             params,
             our_max_window_bits: 15,
             their_max_window_bits: 15,
-            await_last_fragment: false
+            await_last_fragment: false,
+            max_decompressed_size: usize::MAX,
+            inflate: None
         }
     }
 
+    /// Set the max. size of a decompressed message.
+    ///
+    /// Decoding a message which decompresses to more bytes fails as soon as
+    /// the limit is exceeded, which protects against messages that are small
+    /// when compressed but huge when decompressed. By default, there is no
+    /// limit besides the max. message size of the connection.
+    pub fn set_max_decompressed_size(&mut self, max: usize) {
+        self.max_decompressed_size = max
+    }
+
     /// Set the server's max. window bits.
     ///
     /// The value must be within 9 ..= 15.
@@ -111,7 +222,7 @@ impl Deflate {
 
     fn set_their_max_window_bits(&mut self, p: &Param, expected: Option<u8>) -> Result<(), ()> {
         if let Some(Ok(v)) = p.value().map(|s| s.parse::<u8>()) {
-            if v < 8 || v > 15 {
+            if !(8..=15).contains(&v) {
                 log::debug!("invalid {}: {} (expected range: 8 ..= 15)", p.name(), v);
                 return Err(())
             }
@@ -136,7 +247,7 @@ impl Extension for Deflate {
         self.enabled
     }
 
-    fn params(&self) -> &[Param] {
+    fn params(&self) -> &[Param<'_>] {
         &self.params
     }
 
@@ -148,7 +259,7 @@ impl Extension for Deflate {
                     log::trace!("configure server with: {}", p);
                     match p.name() {
                         CLIENT_MAX_WINDOW_BITS =>
-                            if self.set_their_max_window_bits(&p, None).is_err() {
+                            if self.set_their_max_window_bits(p, None).is_err() {
                                 // we just accept the client's offer as is => no need to reply
                                 return Ok(())
                             }
@@ -156,7 +267,7 @@ impl Extension for Deflate {
                             if let Some(Ok(v)) = p.value().map(|s| s.parse::<u8>()) {
                                 // The RFC allows 8 to 15 bits, but due to zlib limitations we
                                 // only support 9 to 15.
-                                if v < 9 || v > 15 {
+                                if !(9..=15).contains(&v) {
                                     log::debug!("unacceptable server_max_window_bits: {}", v);
                                     return Ok(())
                                 }
@@ -189,13 +300,13 @@ impl Extension for Deflate {
                         CLIENT_NO_CONTEXT_TAKEOVER => {} // must be supported
                         SERVER_MAX_WINDOW_BITS => {
                             let expected = Some(self.their_max_window_bits);
-                            if self.set_their_max_window_bits(&p, expected).is_err() {
+                            if self.set_their_max_window_bits(p, expected).is_err() {
                                 return Ok(())
                             }
                         }
                         CLIENT_MAX_WINDOW_BITS =>
                             if let Some(Ok(v)) = p.value().map(|s| s.parse::<u8>()) {
-                                if v < 8 || v > 15 {
+                                if !(8..=15).contains(&v) {
                                     log::debug!("unacceptable client_max_window_bits: {}", v);
                                     return Ok(())
                                 }
@@ -225,10 +336,15 @@ impl Extension for Deflate {
     }
 
     fn decode(&mut self, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError> {
-        if data.is_empty() {
-            return Ok(())
+        // Only the first frame of a compressed message has RSV1 set (RFC 7692, 6.1).
+        if header.opcode() == OpCode::Continue && header.is_rsv1() {
+            log::debug!("deflate: RSV1 set on continuation frame {}", header);
+            return Err(io::Error::other("RSV1 set on continuation frame").into())
         }
 
+        // An empty payload is decoded like any other: the first fragment of a
+        // compressed message may be empty and an empty compressed payload
+        // decompresses to an empty message.
         match header.opcode() {
             OpCode::Binary | OpCode::Text if header.is_rsv1() => {
                 if !header.is_fin() {
@@ -248,13 +364,16 @@ impl Extension for Deflate {
             }
         }
 
-        // Restore LEN and NLEN:
-        data.extend_from_slice(&[0, 0, 0xFF, 0xFF]); // cf. RFC 7692, 7.2.2
-
         self.buffer.clear();
-        let mut decoder = DeflateDecoder::new(&mut self.buffer);
-        decoder.write_all(&data)?;
-        decoder.finish()?;
+        let mut inflate = Inflate::new(self.their_max_window_bits);
+        let mut consumed = 0;
+        loop {
+            let decoded = inflate.inflate(&data[consumed ..], true, &mut self.buffer, CHUNK_SIZE, self.max_decompressed_size)?;
+            consumed += decoded.consumed;
+            if decoded.state == DecodeState::End {
+                break
+            }
+        }
         mem::swap(data, &mut self.buffer);
 
         header.set_rsv1(false);
@@ -263,11 +382,63 @@ impl Extension for Deflate {
         Ok(())
     }
 
+    fn decode_chunk
+        ( &mut self
+        , header: &Header
+        , input: &[u8]
+        , end_of_frame: bool
+        , output: &mut Vec<u8>
+        , max_output: usize
+        ) -> Result<Decoded, BoxedError>
+    {
+        if header.opcode() == OpCode::Continue && header.is_rsv1() {
+            log::debug!("deflate: RSV1 set on continuation frame {}", header);
+            return Err(io::Error::other("RSV1 set on continuation frame").into())
+        }
+
+        let end = end_of_frame && header.is_fin();
+
+        if self.inflate.is_none() {
+            match header.opcode() {
+                OpCode::Binary | OpCode::Text if header.is_rsv1() => {
+                    log::trace!("deflate: decoding {} incrementally", header);
+                    self.inflate = Some(Inflate::new(self.their_max_window_bits))
+                }
+                _ => {
+                    // Uncompressed data is passed on as is.
+                    let n = std::cmp::min(input.len(), max_output);
+                    output.extend_from_slice(&input[.. n]);
+                    let state =
+                        if n < input.len() {
+                            DecodeState::MoreOutput
+                        } else if end {
+                            DecodeState::End
+                        } else {
+                            DecodeState::NeedInput
+                        };
+                    return Ok(Decoded { consumed: n, produced: n, state })
+                }
+            }
+        }
+
+        let result = match &mut self.inflate {
+            Some(inflate) => inflate.inflate(input, end, output, max_output, self.max_decompressed_size),
+            None => return Err(io::Error::other("no message to decode").into())
+        };
+        match &result {
+            Ok(d) if d.state != DecodeState::End => {}
+            _ => self.inflate = None
+        }
+        result
+    }
+
     fn encode(&mut self, header: &mut Header, data: &mut Storage) -> Result<(), BoxedError> {
         if data.as_ref().is_empty() {
             return Ok(())
         }
 
+        // Only whole messages or their first fragment are compressed, so RSV1
+        // is never set on a continuation frame (RFC 7692, 6.1).
         if let OpCode::Binary | OpCode::Text = header.opcode() {
             log::trace!("deflate: encoding {}", header)
         } else {
@@ -304,7 +475,7 @@ impl Extension for Deflate {
         // If we still have not seen the empty deflate block appended, something is wrong.
         if !self.buffer.ends_with(&[0, 0, 0xFF, 0xFF]) {
             log::error!("missing 00 00 FF FF");
-            return Err(io::Error::new(io::ErrorKind::Other, "missing 00 00 FF FF").into())
+            return Err(io::Error::other("missing 00 00 FF FF").into())
         }
 
         self.buffer.truncate(self.buffer.len() - 4); // Remove 00 00 FF FF; cf. RFC 7692, 7.2.1
@@ -320,3 +491,86 @@ impl Extension for Deflate {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compress `len` zero bytes as a permessage-deflate payload.
+    fn deflate_zeros(len: usize) -> Vec<u8> {
+        let zeros = vec![0; 64 * 1024];
+        let mut compress = Compress::new(Compression::fast(), false);
+        let mut out = Vec::new();
+        while (compress.total_in() as usize) < len {
+            let n = std::cmp::min(zeros.len(), len - compress.total_in() as usize);
+            out.reserve(64 * 1024);
+            compress.compress_vec(&zeros[.. n], &mut out, FlushCompress::None).unwrap();
+        }
+        while !out.ends_with(&[0, 0, 0xFF, 0xFF]) {
+            out.reserve(64);
+            compress.compress_vec(&[], &mut out, FlushCompress::Sync).unwrap();
+        }
+        out.truncate(out.len() - 4);
+        out
+    }
+
+    #[test]
+    fn deflate_decode_chunk() {
+        const LEN: usize = 100 * 1024 * 1024;
+        const MAX_OUTPUT: usize = 16 * 1024;
+
+        let payload = deflate_zeros(LEN);
+        let mut deflate = Deflate::new(Mode::Server);
+        deflate.configure(&[]).unwrap();
+
+        // The message is split into three frames, each of which is fed in
+        // pieces of 4 KiB.
+        let frames = payload.chunks(payload.len() / 3 + 1).collect::<Vec<_>>();
+        let mut output = Vec::with_capacity(MAX_OUTPUT);
+        let mut total = 0;
+        let mut state = DecodeState::NeedInput;
+        for (i, frame) in frames.iter().enumerate() {
+            let mut header = Header::new(if i == 0 { OpCode::Binary } else { OpCode::Continue });
+            header.set_rsv1(i == 0);
+            header.set_fin(i == frames.len() - 1);
+            let pieces = frame.chunks(4096).collect::<Vec<_>>();
+            for (j, piece) in pieces.iter().enumerate() {
+                let end_of_frame = j == pieces.len() - 1;
+                let mut input = &piece[..];
+                loop {
+                    let d = deflate.decode_chunk(&header, input, end_of_frame, &mut output, MAX_OUTPUT).unwrap();
+                    assert!(output.iter().all(|b| *b == 0));
+                    total += d.produced;
+                    input = &input[d.consumed ..];
+                    output.clear();
+                    state = d.state;
+                    if state != DecodeState::MoreOutput {
+                        break
+                    }
+                }
+                assert!(input.is_empty());
+            }
+        }
+        assert_eq!(DecodeState::End, state);
+        assert_eq!(LEN, total);
+        assert!(output.capacity() <= MAX_OUTPUT);
+
+        // Decoding fails once the limit on the decompressed size is exceeded.
+        deflate.set_max_decompressed_size(1024 * 1024);
+        let mut header = Header::new(OpCode::Binary);
+        header.set_rsv1(true);
+        let mut output = Vec::new();
+        let mut input = &payload[..];
+        let e = loop {
+            match deflate.decode_chunk(&header, input, true, &mut output, MAX_OUTPUT) {
+                Ok(d) => {
+                    assert_eq!(DecodeState::MoreOutput, d.state);
+                    input = &input[d.consumed ..];
+                    output.clear()
+                }
+                Err(e) => break e
+            }
+        };
+        assert_eq!("decompressed message too large", e.to_string())
+    }
+}
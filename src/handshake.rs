@@ -11,25 +11,87 @@
 //! [handshake]: https://tools.ietf.org/html/rfc6455#section-4
 
 pub mod client;
+pub mod consts;
 pub mod server;
 
 use bytes::BytesMut;
 use crate::extension::{Param, Extension};
+use sha1::{Digest, Sha1};
 use std::{fmt, io, str};
 
 pub use client::{Client, ServerResponse};
 pub use server::{Server, ClientRequest};
 
-// Defined in RFC 6455 and used to generate the `Sec-WebSocket-Accept` header
-// in the server handshake response.
-const KEY: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
-
 // How many HTTP headers do we support during parsing?
 const MAX_NUM_HEADERS: usize = 32;
 
-// Some HTTP headers we need to check during parsing.
-const SEC_WEBSOCKET_EXTENSIONS: &str = "Sec-WebSocket-Extensions";
-const SEC_WEBSOCKET_PROTOCOL: &str = "Sec-WebSocket-Protocol";
+// The length of a base-64 encoded SHA-1 digest.
+const ACCEPT_LEN: usize = 28;
+
+/// Compute the `Sec-WebSocket-Accept` header value for the given
+/// `Sec-WebSocket-Key` header value (RFC 6455, section 4.2.2).
+pub fn compute_accept(key: &str) -> String {
+    let mut buf = [0; ACCEPT_LEN];
+    String::from_utf8_lossy(accept_value(key.as_bytes(), &mut buf)).into_owned()
+}
+
+/// Encode the `Sec-WebSocket-Accept` header value for `key` into `buf`.
+fn accept_value<'a>(key: &[u8], buf: &'a mut [u8; ACCEPT_LEN]) -> &'a [u8] {
+    let mut digest = Sha1::new();
+    digest.update(key);
+    digest.update(consts::GUID.as_bytes());
+    let n = base64::encode_config_slice(digest.finalize(), base64::STANDARD, buf);
+    &buf[.. n]
+}
+
+/// An HTTP header as received.
+///
+/// Header values are opaque octets (RFC 7230, section 3.2). They are kept
+/// as sent, whether or not they are ASCII or valid UTF-8.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    name: String,
+    value: Vec<u8>
+}
+
+impl Header {
+    fn from_httparse(h: &httparse::Header) -> Self {
+        Header { name: h.name.into(), value: Vec::from(h.value) }
+    }
+
+    /// The header name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The header value.
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    /// The header value if it is valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        str::from_utf8(&self.value).ok()
+    }
+}
+
+/// Can the header be sent as is?
+///
+/// The name must be an HTTP token and the value must not contain line
+/// breaks, which would allow for injecting headers.
+fn is_valid_header(name: &str, value: &[u8]) -> bool {
+    let is_tchar = |b: u8| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b);
+    !name.is_empty()
+        && name.bytes().all(is_tchar)
+        && !value.iter().any(|b| *b == b'\r' || *b == b'\n' || *b == 0)
+}
+
+/// Append a header line break and the given header name.
+fn append_header_name(bytes: &mut BytesMut, name: &str) {
+    bytes.extend_from_slice(b"\r\n");
+    bytes.extend_from_slice(name.as_bytes());
+    bytes.extend_from_slice(b": ")
+}
 
 /// Check a set of headers contains a specific one.
 fn expect_ascii_header(headers: &[httparse::Header], name: &str, ours: &str) -> Result<(), Error> {
@@ -41,9 +103,9 @@ fn expect_ascii_header(headers: &[httparse::Header], name: &str, ours: &str) ->
 
     headers.iter()
         .filter(|h| h.name.eq_ignore_ascii_case(name))
-        .fold(Ok(State::Init), |result, header| {
-            if let Ok(State::Match) = result {
-                return result
+        .try_fold(State::Init, |state, header| {
+            if let State::Match = state {
+                return Ok(state)
             }
             if str::from_utf8(header.value)?
                 .split(',')
@@ -62,6 +124,38 @@ fn expect_ascii_header(headers: &[httparse::Header], name: &str, ours: &str) ->
         })
 }
 
+/// Check the `Upgrade` header lists the `websocket` protocol.
+///
+/// The header is a list of protocols, each optionally followed by a version
+/// (cf. RFC 7230, 6.7), so e.g. `h2c, websocket/13` is accepted.
+fn expect_websocket_upgrade(headers: &[httparse::Header]) -> Result<(), Error> {
+    let mut found = false;
+    for h in headers.iter().filter(|h| h.name.eq_ignore_ascii_case(consts::UPGRADE)) {
+        found = true;
+        for token in str::from_utf8(h.value)?.split(',') {
+            let protocol = token.split_once('/').map_or(token, |(p, _)| p);
+            if protocol.trim().eq_ignore_ascii_case("websocket") {
+                return Ok(())
+            }
+        }
+    }
+    if found {
+        Err(Error::UnexpectedHeader(consts::UPGRADE.into()))
+    } else {
+        Err(Error::HeaderNotFound(consts::UPGRADE.into()))
+    }
+}
+
+/// Check that none of the given single-valued headers occurs more than once.
+fn expect_single_headers(headers: &[httparse::Header], names: &[&str]) -> Result<(), Error> {
+    for name in names {
+        if headers.iter().filter(|h| h.name.eq_ignore_ascii_case(name)).nth(1).is_some() {
+            return Err(Error::DuplicateHeader((*name).into()))
+        }
+    }
+    Ok(())
+}
+
 /// Pick the first header with the given name and apply the given closure to it.
 fn with_first_header<'a, F, R>(headers: &[httparse::Header<'a>], name: &str, f: F) -> Result<R, Error>
 where
@@ -74,30 +168,139 @@ where
     }
 }
 
-// Configure all extensions with parsed parameters.
-fn configure_extensions(extensions: &mut [Box<dyn Extension + Send>], line: &str) -> Result<(), Error> {
-    for e in line.split(',') {
-        let mut ext_parts = e.split(';');
-        if let Some(name) = ext_parts.next() {
-            let name = name.trim();
-            if let Some(ext) = extensions.iter_mut().find(|x| x.name().eq_ignore_ascii_case(name)) {
-                let mut params = Vec::new();
-                for p in ext_parts {
-                    let mut key_value = p.split('=');
-                    if let Some(key) = key_value.next().map(str::trim) {
-                        let val = key_value.next().map(|v| v.trim().trim_matches('"'));
-                        let mut p = Param::new(key);
-                        p.set_value(val);
-                        params.push(p)
-                    }
-                }
-                ext.configure(&params).map_err(Error::Extension)?
+/// Collect the protocols of all `Sec-WebSocket-Protocol` headers in order.
+///
+/// Multiple protocols may be given in one header as a comma-separated list,
+/// in multiple headers, or both. Empty protocol names are rejected.
+fn parse_protocols<'a>(headers: &[httparse::Header<'a>]) -> Result<Vec<&'a str>, Error> {
+    let mut protocols = Vec::new();
+    for h in headers.iter().filter(|h| h.name.eq_ignore_ascii_case(consts::SEC_WEBSOCKET_PROTOCOL)) {
+        for p in str::from_utf8(h.value)?.split(',') {
+            let p = p.trim();
+            if p.is_empty() {
+                return Err(Error::EmptyProtocol)
             }
+            protocols.push(p)
+        }
+    }
+    Ok(protocols)
+}
+
+/// An extension name and its parameters with optional values.
+pub type ExtensionParams = (String, Vec<(String, Option<String>)>);
+
+/// Parse the value of a `Sec-WebSocket-Extensions` header.
+///
+/// The value is a comma-separated list of extensions, each followed by
+/// semicolon-separated parameters. Parameter values may be tokens or
+/// quoted strings (RFC 6455, section 9.1). Quotes and escapes are removed
+/// from the returned values. Empty list elements are skipped.
+pub fn parse_extensions(line: &str) -> Result<Vec<ExtensionParams>, Error> {
+    let mut extensions = Vec::new();
+    for e in split_unquoted(line, ',')? {
+        let mut parts = split_unquoted(e, ';')?.into_iter();
+        let name = parts.next().map(str::trim).unwrap_or("");
+        if name.is_empty() {
+            continue
+        }
+        let mut params = Vec::new();
+        for p in parts {
+            let (key, value) = match p.find('=') {
+                Some(i) => (p[.. i].trim(), Some(unquote(p[i + 1 ..].trim())?)),
+                None => (p.trim(), None)
+            };
+            if key.is_empty() {
+                return Err(Error::InvalidExtensionParams(line.into()))
+            }
+            params.push((key.to_string(), value))
+        }
+        extensions.push((name.to_string(), params))
+    }
+    Ok(extensions)
+}
+
+/// Split a string at every separator which is not part of a quoted string.
+fn split_unquoted(s: &str, sep: char) -> Result<Vec<&str>, Error> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == sep && !quoted => {
+                parts.push(&s[start .. i]);
+                start = i + 1
+            }
+            _ => {}
+        }
+    }
+    if quoted {
+        return Err(Error::InvalidExtensionParams(s.into()))
+    }
+    parts.push(&s[start ..]);
+    Ok(parts)
+}
+
+/// Remove the quotes and escapes of a quoted string.
+///
+/// Values which are not quoted are returned as they are.
+fn unquote(value: &str) -> Result<String, Error> {
+    if !value.starts_with('"') {
+        return Ok(value.to_string())
+    }
+    if value.len() < 2 || !value.ends_with('"') {
+        return Err(Error::InvalidExtensionParams(value.into()))
+    }
+    let mut unquoted = String::with_capacity(value.len() - 2);
+    let mut chars = value[1 .. value.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c) => unquoted.push(c),
+                None => return Err(Error::InvalidExtensionParams(value.into()))
+            }
+            c => unquoted.push(c)
+        }
+    }
+    Ok(unquoted)
+}
+
+// Configure all extensions with parsed parameters.
+fn configure_extensions(extensions: &mut [Box<dyn Extension + Send>], parsed: &[ExtensionParams]) -> Result<(), Error> {
+    for (name, params) in parsed {
+        if let Some(ext) = extensions.iter_mut().find(|x| x.name().eq_ignore_ascii_case(name)) {
+            let params = params.iter()
+                .map(|(key, value)| {
+                    let mut p = Param::new(key.as_str());
+                    p.set_value(value.as_deref());
+                    p
+                })
+                .collect::<Vec<_>>();
+            ext.configure(&params).map_err(Error::Extension)?
         }
     }
     Ok(())
 }
 
+// Write the extension token which disables masking to the given buffer,
+// after the extensions written by `append_extensions`, if any.
+fn append_unmasked(bytes: &mut BytesMut, has_extensions: bool) {
+    if has_extensions {
+        bytes.extend_from_slice(b", ")
+    } else {
+        append_header_name(bytes, consts::SEC_WEBSOCKET_EXTENSIONS)
+    }
+    bytes.extend_from_slice(consts::UNMASKED_EXTENSION.as_bytes())
+}
+
+/// Is the extension token which disables masking among the extensions?
+fn has_unmasked(extensions: &[ExtensionParams]) -> bool {
+    extensions.iter().any(|(name, _)| name.eq_ignore_ascii_case(consts::UNMASKED_EXTENSION))
+}
+
 // Write all extensions to the given buffer.
 fn append_extensions<'a, I>(extensions: I, bytes: &mut BytesMut)
 where
@@ -106,7 +309,7 @@ where
     let mut iter = extensions.into_iter().peekable();
 
     if iter.peek().is_some() {
-        bytes.extend_from_slice(b"\r\nSec-WebSocket-Extensions: ")
+        append_header_name(bytes, consts::SEC_WEBSOCKET_EXTENSIONS)
     }
 
     while let Some(e) = iter.next() {
@@ -139,12 +342,28 @@ pub enum Error {
     HeaderNotFound(String),
     /// An HTTP header value was not expected.
     UnexpectedHeader(String),
+    /// A single-valued HTTP header occurred more than once.
+    DuplicateHeader(String),
     /// The Sec-WebSocket-Accept header value did not match.
     InvalidSecWebSocketAccept,
     /// The server returned an extension we did not ask for.
     UnsolicitedExtension,
     /// The server returned a protocol we did not ask for.
     UnsolicitedProtocol,
+    /// A `Sec-WebSocket-Protocol` header contained an empty protocol name.
+    EmptyProtocol,
+    /// The client offered only protocols the server does not support.
+    NoCommonProtocol,
+    /// A `Sec-WebSocket-Extensions` header could not be parsed.
+    InvalidExtensionParams(String),
+    /// The HTTP response body exceeded the configured maximum size.
+    BodyTooLarge { maximum: usize },
+    /// The server's response was followed by another HTTP response.
+    DuplicateResponse,
+    /// The handshake has been completed already.
+    HandshakeCompleted,
+    /// Disabling masking has been offered or accepted by one end only.
+    MaskingOptOutMismatch,
     /// An extension produced an error while encoding or decoding.
     Extension(crate::BoxedError),
     /// The HTTP entity could not be parsed successfully.
@@ -166,12 +385,28 @@ impl fmt::Display for Error {
                 write!(f, "header {} not found", name),
             Error::UnexpectedHeader(name) =>
                 write!(f, "header {} had an unexpected value", name),
+            Error::DuplicateHeader(name) =>
+                write!(f, "header {} occurred more than once", name),
             Error::InvalidSecWebSocketAccept =>
                 f.write_str("websocket key mismatch"),
             Error::UnsolicitedExtension =>
                 f.write_str("unsolicited extension returned"),
             Error::UnsolicitedProtocol =>
                 f.write_str("unsolicited protocol returned"),
+            Error::EmptyProtocol =>
+                f.write_str("empty protocol name in Sec-WebSocket-Protocol header"),
+            Error::NoCommonProtocol =>
+                f.write_str("no common protocol"),
+            Error::InvalidExtensionParams(v) =>
+                write!(f, "invalid Sec-WebSocket-Extensions header: {}", v),
+            Error::BodyTooLarge { maximum } =>
+                write!(f, "http body too large: maximum = {}", maximum),
+            Error::DuplicateResponse =>
+                f.write_str("more than one handshake response"),
+            Error::HandshakeCompleted =>
+                f.write_str("handshake has been completed already"),
+            Error::MaskingOptOutMismatch =>
+                f.write_str("masking has been disabled by one end only"),
             Error::Extension(e) =>
                 write!(f, "extension error: {}", e),
             Error::Http(e) =>
@@ -193,9 +428,17 @@ impl std::error::Error for Error {
             | Error::InvalidRequestMethod
             | Error::HeaderNotFound(_)
             | Error::UnexpectedHeader(_)
+            | Error::DuplicateHeader(_)
             | Error::InvalidSecWebSocketAccept
             | Error::UnsolicitedExtension
             | Error::UnsolicitedProtocol
+            | Error::EmptyProtocol
+            | Error::NoCommonProtocol
+            | Error::InvalidExtensionParams(_)
+            | Error::BodyTooLarge {..}
+            | Error::DuplicateResponse
+            | Error::HandshakeCompleted
+            | Error::MaskingOptOutMismatch
             => None
         }
     }
@@ -215,7 +458,22 @@ impl From<str::Utf8Error> for Error {
 
 #[cfg(test)]
 mod tests {
-    use super::expect_ascii_header;
+    use super::{Error, compute_accept, expect_ascii_header, expect_websocket_upgrade, parse_extensions, parse_protocols};
+
+    #[test]
+    fn websocket_upgrade() {
+        let upgrade = |value: &'static str| [httparse::Header { name: "upgrade", value: value.as_bytes() }];
+        for value in &["websocket", "WebSocket", "websocket/13", "h2c, websocket", "h2c,WEBSOCKET/13 , foo/1"] {
+            assert!(expect_websocket_upgrade(&upgrade(value)).is_ok(), "{}", value)
+        }
+        for value in &["h2c", "websocket2", "web socket", "13/websocket", ""] {
+            match expect_websocket_upgrade(&upgrade(value)) {
+                Err(Error::UnexpectedHeader(name)) => assert_eq!("Upgrade", name),
+                other => panic!("{}: unexpected result: {:?}", value, other)
+            }
+        }
+        assert!(matches!(expect_websocket_upgrade(&[]), Err(Error::HeaderNotFound(_))))
+    }
 
     #[test]
     fn header_match() {
@@ -242,4 +500,61 @@ mod tests {
         assert!(expect_ascii_header(headers, "baz", "???").is_err());
         assert!(expect_ascii_header(headers, "???", "x").is_err());
     }
+
+    #[test]
+    fn protocols() {
+        let one_line = &[httparse::Header { name: "Sec-WebSocket-Protocol", value: b"a, b ,c" }];
+        assert_eq!(vec!["a", "b", "c"], parse_protocols(one_line).unwrap());
+
+        let multi_line = &[
+            httparse::Header { name: "Sec-WebSocket-Protocol", value: b"a" },
+            httparse::Header { name: "foo", value: b"x" },
+            httparse::Header { name: "sec-websocket-protocol", value: b" b" },
+            httparse::Header { name: "Sec-WebSocket-Protocol", value: b"c" }
+        ];
+        assert_eq!(vec!["a", "b", "c"], parse_protocols(multi_line).unwrap());
+
+        let mixed = &[
+            httparse::Header { name: "Sec-WebSocket-Protocol", value: b"a,b" },
+            httparse::Header { name: "Sec-WebSocket-Protocol", value: b"c" }
+        ];
+        assert_eq!(vec!["a", "b", "c"], parse_protocols(mixed).unwrap());
+
+        assert!(parse_protocols(&[]).unwrap().is_empty());
+
+        for v in &[&b""[..], b"a,,b", b"a, ", b" , a"] {
+            let empty = &[httparse::Header { name: "Sec-WebSocket-Protocol", value: v }];
+            assert!(matches!(parse_protocols(empty), Err(Error::EmptyProtocol)))
+        }
+    }
+
+    #[test]
+    fn extensions() {
+        let parsed = parse_extensions("permessage-deflate; server_max_window_bits=10; client_no_context_takeover").unwrap();
+        let expected = vec![("permessage-deflate".to_string(), vec![
+            ("server_max_window_bits".to_string(), Some("10".to_string())),
+            ("client_no_context_takeover".to_string(), None)
+        ])];
+        assert_eq!(expected, parsed);
+
+        let parsed = parse_extensions(r#"foo; a="1;2, \"3\"" , bar,, baz;b = "" "#).unwrap();
+        assert_eq!(3, parsed.len());
+        assert_eq!(("foo".to_string(), vec![("a".to_string(), Some(r#"1;2, "3""#.to_string()))]), parsed[0]);
+        assert_eq!(("bar".to_string(), Vec::new()), parsed[1]);
+        assert_eq!(("baz".to_string(), vec![("b".to_string(), Some(String::new()))]), parsed[2]);
+
+        assert!(parse_extensions("").unwrap().is_empty());
+
+        for v in &["foo; a=\"1", "foo; =1", "foo; a=\"", "foo; a=\"x\\\""] {
+            assert!(matches!(parse_extensions(v), Err(Error::InvalidExtensionParams(_))), "{}", v)
+        }
+    }
+
+    #[test]
+    fn accept() {
+        // RFC 6455, section 1.3
+        assert_eq!("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", compute_accept("dGhlIHNhbXBsZSBub25jZQ=="));
+        // RFC 6455, section 1.2
+        assert_eq!("HSmrc0sMlYUkAGmm5OPpG2HaGWk=", compute_accept("x3JJHMbDL1EzLkh9GBhXDw=="))
+    }
 }
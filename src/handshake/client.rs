@@ -11,25 +11,39 @@
 //! [handshake]: https://tools.ietf.org/html/rfc6455#section-4
 
 use bytes::{Buf, BytesMut};
-use crate::{Parsing, extension::Extension};
+use crate::{Parsing, extension::Extension, rng::Rng};
 use crate::connection::{self, Mode};
 use futures::prelude::*;
-use sha1::{Digest, Sha1};
-use std::{mem, str};
+use std::{convert::TryFrom, mem, str, time::{Duration, SystemTime}};
 use super::{
+    ACCEPT_LEN,
     Error,
-    KEY,
+    ExtensionParams,
     MAX_NUM_HEADERS,
-    SEC_WEBSOCKET_EXTENSIONS,
-    SEC_WEBSOCKET_PROTOCOL,
+    accept_value,
     append_extensions,
+    append_header_name,
+    append_unmasked,
     configure_extensions,
+    consts,
     expect_ascii_header,
+    expect_single_headers,
+    expect_websocket_upgrade,
+    has_unmasked,
+    is_valid_header,
+    parse_extensions,
+    parse_protocols,
     with_first_header
 };
 
 const BLOCK_SIZE: usize = 8 * 1024;
 
+/// The beginning of an HTTP response.
+const HTTP_PREFIX: &[u8] = b"HTTP/";
+
+/// Default max. size of a response body we read when the server rejects our request.
+const MAX_RESPONSE_BODY_SIZE: usize = 64 * 1024;
+
 /// Websocket client handshake.
 #[derive(Debug)]
 pub struct Client<'a, T> {
@@ -47,10 +61,26 @@ pub struct Client<'a, T> {
     nonce_offset: usize,
     /// The protocols to include in the handshake.
     protocols: Vec<&'a str>,
+    /// Send each protocol in a separate header?
+    split_protocol_headers: bool,
+    /// Additional headers to include in the request.
+    headers: Vec<(&'a str, &'a [u8])>,
     /// The extensions the client wishes to include in the request.
     extensions: Vec<Box<dyn Extension + Send>>,
+    /// Offer to disable masking?
+    offer_unmasked: bool,
+    /// Has the server accepted to disable masking?
+    unmasked: bool,
+    /// The max. size of a response body we accept.
+    max_response_body_size: usize,
+    /// The extensions and parameters the server has accepted.
+    accepted_extensions: Vec<ExtensionParams>,
     /// Encoding/decoding buffer.
-    buffer: BytesMut
+    buffer: BytesMut,
+    /// Has the server accepted the handshake?
+    is_accepted: bool,
+    /// The source of request nonces, passed on to the connection.
+    rng: Rng
 }
 
 impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
@@ -64,11 +94,34 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
             nonce: [0; 32],
             nonce_offset: 0,
             protocols: Vec::new(),
+            split_protocol_headers: false,
+            headers: Vec::new(),
             extensions: Vec::new(),
-            buffer: BytesMut::new()
+            offer_unmasked: false,
+            unmasked: false,
+            max_response_body_size: MAX_RESPONSE_BODY_SIZE,
+            accepted_extensions: Vec::new(),
+            buffer: BytesMut::new(),
+            is_accepted: false,
+            rng: Rng::default()
         }
     }
 
+    /// Create a new client handshake whose request nonce, and the mask keys
+    /// of the connection it turns into, are generated from a random number
+    /// generator seeded with `seed`.
+    ///
+    /// See [`connection::Builder::new_deterministic`]. **Never use this in
+    /// production.**
+    ///
+    /// Requires the `test-util` feature.
+    #[cfg(feature = "test-util")]
+    pub fn new_deterministic(socket: T, host: &'a str, resource: &'a str, seed: u64) -> Self {
+        let mut client = Client::new(socket, host, resource);
+        client.rng = Rng::seeded(seed);
+        client
+    }
+
     /// Override the buffer to use for request/response handling.
     pub fn set_buffer(&mut self, b: BytesMut) -> &mut Self {
         self.buffer = b;
@@ -92,20 +145,87 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
         self
     }
 
+    /// Send each protocol in a separate `Sec-WebSocket-Protocol` header.
+    ///
+    /// By default all protocols are sent as a comma-separated list in a single
+    /// header. Some servers only understand one protocol per header line.
+    pub fn set_split_protocol_headers(&mut self, split: bool) -> &mut Self {
+        self.split_protocol_headers = split;
+        self
+    }
+
+    /// Add a header to be included in the handshake.
+    ///
+    /// The value is sent as is and need not be ASCII. If the name is not a
+    /// valid HTTP token or the value contains a line break,
+    /// [`Client::handshake`] fails with [`Error::UnexpectedHeader`].
+    pub fn add_header(&mut self, name: &'a str, value: &'a [u8]) -> &mut Self {
+        self.headers.push((name, value));
+        self
+    }
+
     /// Add an extension to be included in the handshake.
     pub fn add_extension(&mut self, e: Box<dyn Extension + Send>) -> &mut Self {
         self.extensions.push(e);
         self
     }
 
+    /// Disable masking of the frames the client sends.
+    ///
+    /// **Warning**: This violates [RFC 6455][rfc6455], see
+    /// [`Deviations::set_unmasked`](connection::Deviations::set_unmasked).
+    ///
+    /// The client offers the [`consts::UNMASKED_EXTENSION`] token, which the
+    /// server has to accept (see [`Server::dangerous_accept_unmasked_frames`](super::Server::dangerous_accept_unmasked_frames)),
+    /// otherwise [`Client::handshake`] fails with
+    /// [`Error::MaskingOptOutMismatch`]. Once accepted, [`Client::into_builder`]
+    /// creates a builder with masking disabled.
+    ///
+    /// [rfc6455]: https://tools.ietf.org/html/rfc6455#section-5.3
+    pub fn dangerous_disable_masking(&mut self, disable: bool) -> &mut Self {
+        self.offer_unmasked = disable;
+        self
+    }
+
+    /// Set the max. size of a response body to read if the server rejects our request.
+    ///
+    /// Bodies larger than this (64 KiB by default) cause the handshake to fail
+    /// with [`Error::BodyTooLarge`].
+    pub fn set_max_response_body_size(&mut self, max: usize) -> &mut Self {
+        self.max_response_body_size = max;
+        self
+    }
+
     /// Get back all extensions.
     pub fn drain_extensions(&mut self) -> impl Iterator<Item = Box<dyn Extension + Send>> + '_ {
         self.extensions.drain(..)
     }
 
+    /// The extensions and their parameters as accepted by the server.
+    ///
+    /// Available after a successful [`Client::handshake`]. Includes the
+    /// parameter values the server has chosen, e.g. the
+    /// `server_max_window_bits` of `permessage-deflate`.
+    pub fn accepted_extensions(&self) -> &[ExtensionParams] {
+        &self.accepted_extensions
+    }
+
     /// Initiate client handshake request to server and get back the response.
+    ///
+    /// Once the server has accepted the handshake, the connection can only be
+    /// used for websocket frames and calling this method again fails with
+    /// [`Error::HandshakeCompleted`]. If the response is followed by the
+    /// beginning of another HTTP response, e.g. a previous response replayed
+    /// by an intermediary, [`Error::DuplicateResponse`] is returned.
     pub async fn handshake(&mut self) -> Result<ServerResponse, Error> {
+        if self.is_accepted {
+            return Err(Error::HandshakeCompleted)
+        }
+        if let Some((name, _)) = self.headers.iter().find(|(n, v)| !is_valid_header(n, v)) {
+            return Err(Error::UnexpectedHeader((*name).into()))
+        }
         self.buffer.clear();
+        self.accepted_extensions.clear();
         self.encode_request();
         self.socket.write_all(&self.buffer).await?;
         self.socket.flush().await?;
@@ -115,6 +235,15 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
             crate::read(&mut self.socket, &mut self.buffer, BLOCK_SIZE).await?;
             if let Parsing::Done { value, offset } = self.decode_response()? {
                 self.buffer.advance(offset);
+                if let ServerResponse::Accepted {..} = value {
+                    self.is_accepted = true;
+                    // No websocket frame starts with `H`, which would be a
+                    // non-final CLOSE frame, so these bytes are HTTP.
+                    let n = std::cmp::min(self.buffer.len(), HTTP_PREFIX.len());
+                    if n > 0 && self.buffer[.. n] == HTTP_PREFIX[.. n] {
+                        return Err(Error::DuplicateResponse)
+                    }
+                }
                 return Ok(value)
             }
         }
@@ -125,6 +254,11 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
         let mut builder = connection::Builder::new(self.socket, Mode::Client);
         builder.set_buffer(self.buffer);
         builder.add_extensions(self.extensions.drain(..));
+        builder.set_rng(mem::take(&mut self.rng));
+        if self.unmasked {
+            let mut deviations = builder.deviations();
+            builder.set_deviations(*deviations.set_unmasked(true))
+        }
         builder
     }
 
@@ -135,22 +269,30 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
 
     /// Encode the client handshake as a request, ready to be sent to the server.
     fn encode_request(&mut self) {
-        let nonce: [u8; 16] = rand::random();
-        self.nonce_offset = base64::encode_config_slice(&nonce, base64::STANDARD, &mut self.nonce);
+        // Allocate once for the request and the response which is read
+        // into the same buffer afterwards.
+        self.buffer.reserve(std::cmp::max(self.request_len(), BLOCK_SIZE));
+        let nonce: [u8; 16] = self.rng.gen();
+        self.nonce_offset = base64::encode_config_slice(nonce, base64::STANDARD, &mut self.nonce);
         self.buffer.extend_from_slice(b"GET ");
         self.buffer.extend_from_slice(self.resource.as_bytes());
         self.buffer.extend_from_slice(b" HTTP/1.1");
         self.buffer.extend_from_slice(b"\r\nHost: ");
         self.buffer.extend_from_slice(self.host.as_bytes());
         self.buffer.extend_from_slice(b"\r\nUpgrade: websocket\r\nConnection: upgrade");
-        self.buffer.extend_from_slice(b"\r\nSec-WebSocket-Key: ");
+        append_header_name(&mut self.buffer, consts::SEC_WEBSOCKET_KEY);
         self.buffer.extend_from_slice(&self.nonce[.. self.nonce_offset]);
         if let Some(o) = &self.origin {
             self.buffer.extend_from_slice(b"\r\nOrigin: ");
             self.buffer.extend_from_slice(o.as_bytes())
         }
-        if let Some((last, prefix)) = self.protocols.split_last() {
-            self.buffer.extend_from_slice(b"\r\nSec-WebSocket-Protocol: ");
+        if self.split_protocol_headers {
+            for p in &self.protocols {
+                append_header_name(&mut self.buffer, consts::SEC_WEBSOCKET_PROTOCOL);
+                self.buffer.extend_from_slice(p.as_bytes())
+            }
+        } else if let Some((last, prefix)) = self.protocols.split_last() {
+            append_header_name(&mut self.buffer, consts::SEC_WEBSOCKET_PROTOCOL);
             for p in prefix {
                 self.buffer.extend_from_slice(p.as_bytes());
                 self.buffer.extend_from_slice(b",")
@@ -158,7 +300,33 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
             self.buffer.extend_from_slice(last.as_bytes())
         }
         append_extensions(&self.extensions, &mut self.buffer);
-        self.buffer.extend_from_slice(b"\r\nSec-WebSocket-Version: 13\r\n\r\n")
+        if self.offer_unmasked {
+            append_unmasked(&mut self.buffer, !self.extensions.is_empty())
+        }
+        for (name, value) in &self.headers {
+            append_header_name(&mut self.buffer, name);
+            self.buffer.extend_from_slice(value)
+        }
+        append_header_name(&mut self.buffer, consts::SEC_WEBSOCKET_VERSION);
+        self.buffer.extend_from_slice(consts::VERSION.as_bytes());
+        self.buffer.extend_from_slice(b"\r\n\r\n")
+    }
+
+    /// An upper bound of the length of the request.
+    fn request_len(&self) -> usize {
+        // Request line, fixed headers and nonce.
+        const FIXED: usize = 192;
+        // Header name and separators.
+        const HEADER: usize = 26;
+        FIXED + self.resource.len() + self.host.len()
+            + self.origin.map_or(0, |o| HEADER + o.len())
+            + self.protocols.iter().map(|p| HEADER + p.len()).sum::<usize>()
+            + self.headers.iter().map(|(n, v)| 4 + n.len() + v.len()).sum::<usize>()
+            + if self.offer_unmasked { HEADER + consts::UNMASKED_EXTENSION.len() } else { 0 }
+            + self.extensions.iter().map(|e| {
+                let params = e.params().iter().map(|p| 3 + p.name().len() + p.value().map_or(0, str::len));
+                HEADER + e.name().len() + params.sum::<usize>()
+            }).sum::<usize>()
     }
 
     /// Decode the server response to this client request.
@@ -186,21 +354,32 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
                 return Ok(Parsing::Done { value: response, offset })
             }
             other => {
-                let response = ServerResponse::Rejected { status_code: other.unwrap_or(0) };
-                return Ok(Parsing::Done { value: response, offset })
+                let bytes = &self.buffer[offset ..];
+                let (body, n) = match decode_body(response.headers, bytes, self.max_response_body_size)? {
+                    Parsing::Done { value, offset } => (value, offset),
+                    Parsing::NeedMore(()) => return Ok(Parsing::NeedMore(()))
+                };
+                let response =
+                    if other == Some(503) {
+                        let retry_after = response.headers.iter()
+                            .find(|h| h.name.eq_ignore_ascii_case("Retry-After"))
+                            .and_then(|h| retry_after(h.value, SystemTime::now()));
+                        ServerResponse::ServiceUnavailable { retry_after, body }
+                    } else {
+                        ServerResponse::Rejected { status_code: other.unwrap_or(0), body }
+                    };
+                return Ok(Parsing::Done { value: response, offset: offset + n })
             }
         }
 
-        expect_ascii_header(response.headers, "Upgrade", "websocket")?;
+        expect_single_headers(response.headers, &[consts::UPGRADE, consts::SEC_WEBSOCKET_ACCEPT])?;
+        expect_websocket_upgrade(response.headers)?;
         expect_ascii_header(response.headers, "Connection", "upgrade")?;
 
         let nonce = &self.nonce[.. self.nonce_offset];
-        with_first_header(&response.headers, "Sec-WebSocket-Accept", |theirs| {
-            let mut digest = Sha1::new();
-            digest.update(nonce);
-            digest.update(KEY);
-            let ours = base64::encode(&digest.finalize());
-            if ours.as_bytes() != theirs {
+        with_first_header(response.headers, consts::SEC_WEBSOCKET_ACCEPT, |theirs| {
+            let mut buf = [0; ACCEPT_LEN];
+            if accept_value(nonce, &mut buf) != theirs {
                 return Err(Error::InvalidSecWebSocketAccept)
             }
             Ok(())
@@ -208,23 +387,33 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
 
         // Parse `Sec-WebSocket-Extensions` headers.
 
+        let mut unmasked = false;
         for h in response.headers.iter()
-            .filter(|h| h.name.eq_ignore_ascii_case(SEC_WEBSOCKET_EXTENSIONS))
+            .filter(|h| h.name.eq_ignore_ascii_case(consts::SEC_WEBSOCKET_EXTENSIONS))
         {
-            configure_extensions(&mut self.extensions, std::str::from_utf8(h.value)?)?
+            let parsed = parse_extensions(std::str::from_utf8(h.value)?)?;
+            configure_extensions(&mut self.extensions, &parsed)?;
+            unmasked |= has_unmasked(&parsed);
+            self.accepted_extensions.extend(parsed)
+        }
+        match (self.offer_unmasked, unmasked) {
+            (false, true) => return Err(Error::UnsolicitedExtension),
+            (true, false) => return Err(Error::MaskingOptOutMismatch),
+            _ => self.unmasked = unmasked
         }
 
         // Match `Sec-WebSocket-Protocol` header.
 
         let mut selected_proto = None;
-        if let Some(tp) = response.headers.iter()
-            .find(|h| h.name.eq_ignore_ascii_case(SEC_WEBSOCKET_PROTOCOL))
-        {
-            if let Some(&p) = self.protocols.iter().find(|x| x.as_bytes() == tp.value) {
-                selected_proto = Some(String::from(p))
-            } else {
-                return Err(Error::UnsolicitedProtocol)
-            }
+        match parse_protocols(response.headers)?.as_slice() {
+            [] => {}
+            [tp] =>
+                if let Some(&p) = self.protocols.iter().find(|x| x == &tp) {
+                    selected_proto = Some(String::from(p))
+                } else {
+                    return Err(Error::UnsolicitedProtocol)
+                }
+            _ => return Err(Error::UnexpectedHeader(consts::SEC_WEBSOCKET_PROTOCOL.into()))
         }
 
         let response = ServerResponse::Accepted { protocol: selected_proto };
@@ -232,6 +421,105 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Client<'a, T> {
     }
 }
 
+/// Parse the value of a `Retry-After` header (cf. RFC 7231, 7.1.3).
+///
+/// The value is either a number of seconds or an HTTP date, which is
+/// converted to the duration from `now`. A date in the past yields a zero
+/// duration. Invalid values are ignored.
+fn retry_after(value: &[u8], now: SystemTime) -> Option<Duration> {
+    let value = str::from_utf8(trim(value)).ok()?;
+    if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) {
+        return Some(Duration::from_secs(value.parse().unwrap_or(u64::MAX)))
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+/// Decode the body of a response from the given bytes.
+///
+/// The body length is determined by chunked transfer encoding or a
+/// `Content-Length` header. Without either, the response has no body.
+fn decode_body(headers: &[httparse::Header], bytes: &[u8], max: usize) -> Result<Parsing<Vec<u8>>, Error> {
+    let is_chunked = headers.iter()
+        .filter(|h| h.name.eq_ignore_ascii_case("Transfer-Encoding"))
+        .any(|h| h.value.split(|b| *b == b',').any(|v| trim(v).eq_ignore_ascii_case(b"chunked")));
+
+    if is_chunked {
+        return decode_chunked(bytes, max)
+    }
+
+    let len = match headers.iter().find(|h| h.name.eq_ignore_ascii_case("Content-Length")) {
+        Some(h) => str::from_utf8(h.value)?.trim().parse::<usize>()
+            .map_err(|_| Error::UnexpectedHeader("Content-Length".into()))?,
+        None => 0
+    };
+
+    if len > max {
+        return Err(Error::BodyTooLarge { maximum: max })
+    }
+
+    if bytes.len() < len {
+        return Ok(Parsing::NeedMore(()))
+    }
+
+    Ok(Parsing::Done { value: Vec::from(&bytes[.. len]), offset: len })
+}
+
+/// Decode a body with chunked transfer encoding (cf. RFC 7230, section 4.1).
+fn decode_chunked(bytes: &[u8], max: usize) -> Result<Parsing<Vec<u8>>, Error> {
+    let mut body = Vec::new();
+    let mut offset = 0;
+    loop {
+        let (n, size) = match httparse::parse_chunk_size(&bytes[offset ..]) {
+            Ok(httparse::Status::Complete(x)) => x,
+            Ok(httparse::Status::Partial) => return Ok(Parsing::NeedMore(())),
+            Err(httparse::InvalidChunkSize) => return Err(Error::Http("invalid chunk size".into()))
+        };
+        offset += n;
+        if size == 0 {
+            break
+        }
+        let size = usize::try_from(size).map_err(|_| Error::BodyTooLarge { maximum: max })?;
+        if size > max - body.len() {
+            return Err(Error::BodyTooLarge { maximum: max })
+        }
+        if bytes.len() - offset < size + 2 {
+            return Ok(Parsing::NeedMore(()))
+        }
+        body.extend_from_slice(&bytes[offset .. offset + size]);
+        offset += size;
+        if &bytes[offset .. offset + 2] != b"\r\n" {
+            return Err(Error::Http("invalid chunk delimiter".into()))
+        }
+        offset += 2
+    }
+    // Skip over any trailer fields up to and including the final empty line.
+    loop {
+        match bytes[offset ..].windows(2).position(|w| w == b"\r\n") {
+            Some(0) => return Ok(Parsing::Done { value: body, offset: offset + 2 }),
+            Some(i) => offset += i + 2,
+            None => return Ok(Parsing::NeedMore(()))
+        }
+    }
+}
+
+/// Remove leading and trailing whitespace.
+fn trim(mut bytes: &[u8]) -> &[u8] {
+    while let [first, rest @ ..] = bytes {
+        if !first.is_ascii_whitespace() {
+            break
+        }
+        bytes = rest
+    }
+    while let [rest @ .., last] = bytes {
+        if !last.is_ascii_whitespace() {
+            break
+        }
+        bytes = rest
+    }
+    bytes
+}
+
 /// Handshake response received from the server.
 #[derive(Debug)]
 pub enum ServerResponse {
@@ -247,10 +535,358 @@ pub enum ServerResponse {
         /// The location URL we should go to.
         location: String
     },
+    /// The server is unavailable, e.g. overloaded (status code 503).
+    ServiceUnavailable {
+        /// How long to wait before trying again, if the server said so.
+        retry_after: Option<Duration>,
+        /// The response body (if any).
+        body: Vec<u8>
+    },
     /// The server rejected our request.
     Rejected {
         /// HTTP response status code.
-        status_code: u16
+        status_code: u16,
+        /// The response body (if any).
+        body: Vec<u8>
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::{Parsing, handshake::compute_accept};
+    use std::time::{Duration, SystemTime};
+    use super::{Client, Error, ServerResponse, retry_after};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    fn client() -> Client<'static, futures::io::Cursor<Vec<u8>>> {
+        Client::new(futures::io::Cursor::new(Vec::new()), "localhost", "/")
+    }
+
+    #[test]
+    fn rejected_with_chunked_body() {
+        let mut client = client();
+        let parts: &[&[u8]] = &[
+            b"HTTP/1.1 403 Forbidden\r\nTransfer-Encoding: chunked\r\n\r\n",
+            b"9\r\nforbid",
+            b"den\r\n5\r\n: n",
+            b"o!\r\n0\r\n",
+            b"\r\n"
+        ];
+        for p in &parts[.. parts.len() - 1] {
+            client.buffer.extend_from_slice(p);
+            assert!(matches!(client.decode_response(), Ok(Parsing::NeedMore(()))))
+        }
+        client.buffer.extend_from_slice(parts[parts.len() - 1]);
+        match client.decode_response() {
+            Ok(Parsing::Done { value: ServerResponse::Rejected { status_code, body }, offset }) => {
+                assert_eq!(status_code, 403);
+                assert_eq!(body, b"forbidden: no!");
+                assert_eq!(offset, client.buffer.len())
+            }
+            other => panic!("unexpected: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn rejected_with_content_length() {
+        let mut client = client();
+        client.buffer.extend_from_slice(b"HTTP/1.1 429 Too Many Requests\r\nContent-Length: 4\r\n\r\nsl");
+        assert!(matches!(client.decode_response(), Ok(Parsing::NeedMore(()))));
+        client.buffer.extend_from_slice(b"owHTTP");
+        match client.decode_response() {
+            Ok(Parsing::Done { value: ServerResponse::Rejected { status_code, body }, offset }) => {
+                assert_eq!(status_code, 429);
+                assert_eq!(body, b"slow");
+                assert_eq!(&client.buffer[offset ..], b"HTTP")
+            }
+            other => panic!("unexpected: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn service_unavailable() {
+        let mut client = client();
+        client.buffer.extend_from_slice(b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: 30\r\nContent-Length: 4\r\n\r\nbusy");
+        match client.decode_response() {
+            Ok(Parsing::Done { value: ServerResponse::ServiceUnavailable { retry_after, body }, .. }) => {
+                assert_eq!(retry_after, Some(Duration::from_secs(30)));
+                assert_eq!(body, b"busy")
+            }
+            other => panic!("unexpected: {:?}", other)
+        }
+
+        client.buffer.clear();
+        client.buffer.extend_from_slice(b"HTTP/1.1 503 Service Unavailable\r\n\r\n");
+        match client.decode_response() {
+            Ok(Parsing::Done { value: ServerResponse::ServiceUnavailable { retry_after, .. }, .. }) =>
+                assert_eq!(retry_after, None),
+            other => panic!("unexpected: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn retry_after_values() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(784111777); // Sun, 06 Nov 1994 08:49:37 GMT
+        assert_eq!(Some(Duration::from_secs(30)), retry_after(b" 30 ", now));
+        assert_eq!(Some(Duration::from_secs(0)), retry_after(b"0", now));
+        assert_eq!(Some(Duration::from_secs(120)), retry_after(b"Sun, 06 Nov 1994 08:51:37 GMT", now));
+        assert_eq!(Some(Duration::from_secs(120)), retry_after(b"Sunday, 06-Nov-94 08:51:37 GMT", now));
+        assert_eq!(Some(Duration::from_secs(120)), retry_after(b"Sun Nov  6 08:51:37 1994", now));
+        assert_eq!(Some(Duration::from_secs(0)), retry_after(b"Sun, 06 Nov 1994 08:00:00 GMT", now));
+        for v in &[&b""[..], b"-1", b"1.5", b"soon", b"\xFF"] {
+            assert_eq!(None, retry_after(v, now))
+        }
+    }
+
+    #[test]
+    fn rejected_with_too_large_body() {
+        let mut client = client();
+        client.set_max_response_body_size(8);
+        client.buffer.extend_from_slice(b"HTTP/1.1 403 Forbidden\r\nTransfer-Encoding: chunked\r\n\r\n");
+        client.buffer.extend_from_slice(b"5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n");
+        assert!(matches!(client.decode_response(), Err(Error::BodyTooLarge { maximum: 8 })));
+        client.buffer.clear();
+        client.buffer.extend_from_slice(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 9\r\n\r\n");
+        assert!(matches!(client.decode_response(), Err(Error::BodyTooLarge { maximum: 8 })))
+    }
+
+    #[test]
+    fn split_protocol_headers() {
+        let mut client = client();
+        client.add_protocol("a").add_protocol("b");
+        client.encode_request();
+        let request = String::from_utf8(client.take_buffer().to_vec()).unwrap();
+        assert!(request.contains("\r\nSec-WebSocket-Protocol: a,b\r\n"));
+
+        client.set_split_protocol_headers(true);
+        client.encode_request();
+        let request = String::from_utf8(client.take_buffer().to_vec()).unwrap();
+        assert!(request.contains("\r\nSec-WebSocket-Protocol: a\r\nSec-WebSocket-Protocol: b\r\n"))
+    }
+
+    #[test]
+    fn request_len() {
+        let mut client = client();
+        client.set_origin("https://example.com").add_protocol("a").add_protocol("graphql-transport-ws");
+        #[cfg(feature = "deflate")]
+        client.add_extension(Box::new(crate::extension::deflate::Deflate::new(crate::Mode::Client)));
+        for split in &[false, true] {
+            client.set_split_protocol_headers(*split);
+            client.encode_request();
+            let request = client.take_buffer();
+            assert!(request.len() <= client.request_len(), "{} > {}", request.len(), client.request_len())
+        }
+    }
+
+    #[test]
+    fn accepted_extensions() {
+        let mut client = client();
+        client.encode_request();
+        client.buffer.clear();
+        let key = std::str::from_utf8(&client.nonce[.. client.nonce_offset]).unwrap();
+        let response = format! {
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\
+             Sec-WebSocket-Extensions: permessage-deflate; server_max_window_bits=10; client_no_context_takeover\r\n\r\n",
+            compute_accept(key)
+        };
+        client.buffer.extend_from_slice(response.as_bytes());
+        assert!(matches!(client.decode_response(), Ok(Parsing::Done { value: ServerResponse::Accepted {..}, .. })));
+        let expected = [("permessage-deflate".to_string(), vec![
+            ("server_max_window_bits".to_string(), Some("10".to_string())),
+            ("client_no_context_takeover".to_string(), None)
+        ])];
+        assert_eq!(&expected[..], client.accepted_extensions())
+    }
+
+    #[test]
+    fn upgrade_tokens() {
+        for upgrade in &["h2c, websocket", "WebSocket/13"] {
+            let mut client = client();
+            client.encode_request();
+            client.buffer.clear();
+            let key = std::str::from_utf8(&client.nonce[.. client.nonce_offset]).unwrap();
+            let response = format! {
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: {}\r\nConnection: upgrade\r\n\
+                 Sec-WebSocket-Accept: {}\r\n\r\n",
+                upgrade, compute_accept(key)
+            };
+            client.buffer.extend_from_slice(response.as_bytes());
+            let result = client.decode_response();
+            assert!(matches!(result, Ok(Parsing::Done { value: ServerResponse::Accepted {..}, .. })), "{}", upgrade)
+        }
+    }
+
+    #[test]
+    fn duplicate_accept_header() {
+        let mut client = client();
+        client.encode_request();
+        client.buffer.clear();
+        let key = std::str::from_utf8(&client.nonce[.. client.nonce_offset]).unwrap();
+        let response = format! {
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: upgrade\r\n\
+             Sec-WebSocket-Accept: {0}\r\nSec-WebSocket-Accept: {0}\r\n\r\n",
+            compute_accept(key)
+        };
+        client.buffer.extend_from_slice(response.as_bytes());
+        match client.decode_response() {
+            Err(Error::DuplicateHeader(name)) => assert_eq!("Sec-WebSocket-Accept", name),
+            other => panic!("unexpected: {:?}", other.map(|_| ()))
+        }
+    }
+
+    /// Answer the request read from `socket` with the given number of
+    /// responses, followed by `trailer`.
+    async fn respond(socket: &mut tokio::io::DuplexStream, responses: usize, trailer: &[u8]) {
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            socket.read_exact(&mut byte).await.unwrap();
+            request.push(byte[0])
+        }
+        let request = String::from_utf8(request).unwrap();
+        let key = request.lines()
+            .find_map(|l| l.strip_prefix("Sec-WebSocket-Key: "))
+            .unwrap();
+        let response = format! {
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            compute_accept(key)
+        };
+        for _ in 0 .. responses {
+            socket.write_all(response.as_bytes()).await.unwrap()
+        }
+        socket.write_all(trailer).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn replayed_response() {
+        let (a, mut b) = tokio::io::duplex(4096);
+        let mut client = Client::new(a.compat(), "localhost", "/");
+        let (result, ()) = tokio::join!(client.handshake(), respond(&mut b, 2, b""));
+        assert!(matches!(result, Err(Error::DuplicateResponse)));
+        assert!(matches!(client.handshake().await, Err(Error::HandshakeCompleted)));
+
+        // Only the beginning of the replayed response has been received.
+        let (a, mut b) = tokio::io::duplex(4096);
+        let mut client = Client::new(a.compat(), "localhost", "/");
+        let (result, ()) = tokio::join!(client.handshake(), respond(&mut b, 1, b"HT"));
+        assert!(matches!(result, Err(Error::DuplicateResponse)))
+    }
+
+    #[tokio::test]
+    async fn frame_after_response() {
+        let (a, mut b) = tokio::io::duplex(4096);
+        let mut client = Client::new(a.compat(), "localhost", "/");
+        let (result, ()) = tokio::join!(client.handshake(), respond(&mut b, 1, &[0x81, 0x02, b'h', b'i']));
+        assert!(matches!(result, Ok(ServerResponse::Accepted { protocol: None })));
+        assert!(matches!(client.handshake().await, Err(Error::HandshakeCompleted)));
+
+        let (_, mut receiver) = client.into_builder().finish();
+        let mut data = Vec::new();
+        receiver.receive_data(&mut data).await.unwrap();
+        assert_eq!(b"hi", &data[..])
+    }
+
+    #[tokio::test]
+    async fn opaque_header_values() {
+        use crate::handshake::{Server, server::Response};
+
+        let utf8 = "Grüße, 世界";
+        let binary = [0xFF, 0xFE, b'\t', 0x80, b'a'];
+        let (a, b) = tokio::io::duplex(4096);
+        let mut sending = Client::new(a.compat(), "localhost", "/");
+        sending.add_header("X-Greeting", utf8.as_bytes()).add_header("X-Blob", &binary);
+        let mut server = Server::new(b.compat());
+        let serve = async {
+            let request = server.receive_request().await.unwrap();
+            assert_eq!(Some(utf8), request.header_str("x-greeting"));
+            let blob = request.header("X-Blob").unwrap();
+            assert_eq!(binary, blob.value());
+            assert_eq!(None, blob.as_str());
+            assert_eq!(None, request.header_str("X-Blob"));
+            server.send_response(&Response::Accept { key: request.key(), protocol: None }).await.unwrap()
+        };
+        let (result, ()) = tokio::join!(sending.handshake(), serve);
+        assert!(matches!(result, Ok(ServerResponse::Accepted { protocol: None })));
+
+        // Values which would end up on several lines are not sent.
+        let mut client = client();
+        client.add_header("X-Evil", b"a\r\nX-Injected: 1");
+        assert!(matches!(client.handshake().await, Err(Error::UnexpectedHeader(name)) if name == "X-Evil"));
+        assert!(client.into_inner().into_inner().is_empty())
+    }
+
+    #[tokio::test]
+    async fn masking_opt_out() {
+        use crate::{Data, connection::Deviations, handshake::{Server, server::Response}};
+
+        type Socket = tokio_util::compat::Compat<tokio::io::DuplexStream>;
+
+        async fn handshake(offer: bool, accept: bool) -> (Result<Client<'static, Socket>, Error>, Result<Server<'static, Socket>, Error>) {
+            let (a, b) = tokio::io::duplex(4096);
+            let mut client = Client::new(a.compat(), "localhost", "/");
+            client.dangerous_disable_masking(offer);
+            let mut server = Server::new(b.compat());
+            server.dangerous_accept_unmasked_frames(accept);
+            // A failing server is dropped, which lets the client fail too.
+            let serve = async move {
+                let request = server.receive_request().await?;
+                server.send_response(&Response::Accept { key: request.key(), protocol: None }).await?;
+                Ok(server)
+            };
+            let (c, s) = tokio::join!(client.handshake(), serve);
+            (c.map(|_| client), s)
+        }
+
+        // Both ends agree, so the client sends unmasked frames, which the
+        // server expects.
+        let (client, server) = handshake(true, true).await;
+        let (client, server) = (client.unwrap(), server.unwrap());
+        assert_eq!(crate::handshake::consts::UNMASKED_EXTENSION, client.accepted_extensions()[0].0);
+        let (client, server) = (client.into_builder(), server.into_builder());
+        assert_eq!(*Deviations::new().set_unmasked(true), client.deviations());
+        assert_eq!(*Deviations::new().set_unmasked(true), server.deviations());
+        let (mut sender, _) = client.finish();
+        let (_, mut receiver) = server.finish();
+        sender.send_text("unmasked").await.unwrap();
+        sender.flush().await.unwrap();
+        let mut data = Vec::new();
+        assert!(matches!(receiver.receive_data(&mut data).await, Ok(Data::Text(8))));
+
+        // A server which has not opted in ignores the offer, so the client fails.
+        let (offering, ignoring) = handshake(true, false).await;
+        assert!(matches!(offering.err(), Some(Error::MaskingOptOutMismatch)));
+        assert_eq!(Deviations::new(), ignoring.unwrap().into_builder().deviations());
+
+        // A server which has opted in fails if nothing is offered.
+        let (_, accepting) = handshake(false, true).await;
+        assert!(matches!(accepting.err(), Some(Error::MaskingOptOutMismatch)));
+
+        // Without an offer, the token is unsolicited.
+        let mut unoffered = self::client();
+        unoffered.encode_request();
+        unoffered.buffer.clear();
+        let key = std::str::from_utf8(&unoffered.nonce[.. unoffered.nonce_offset]).unwrap();
+        let response = format! {
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\nSec-WebSocket-Extensions: x-soketto-unmasked\r\n\r\n",
+            compute_accept(key)
+        };
+        unoffered.buffer.extend_from_slice(response.as_bytes());
+        assert!(matches!(unoffered.decode_response(), Err(Error::UnsolicitedExtension)))
+    }
+
+    #[cfg(feature = "test-util")]
+    #[test]
+    fn deterministic_nonce() {
+        let request = |seed| {
+            let mut client = Client::new_deterministic(futures::io::Cursor::new(Vec::new()), "localhost", "/", seed);
+            client.encode_request();
+            client.buffer.to_vec()
+        };
+        assert_eq!(request(1), request(1));
+        assert_ne!(request(1), request(2))
+    }
+}
@@ -0,0 +1,46 @@
+// Copyright (c) 2021 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Protocol constants of the websocket handshake (RFC 6455, section 4).
+//!
+//! These are the definitions the [`Client`](super::Client) and
+//! [`Server`](super::Server) handshakes use. They are meant for code which
+//! does its own HTTP routing. See also [`compute_accept`](super::compute_accept).
+
+/// The GUID appended to the client's key to compute the
+/// `Sec-WebSocket-Accept` header value.
+pub const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The websocket protocol version, i.e. the value of the
+/// `Sec-WebSocket-Version` header.
+pub const VERSION: &str = "13";
+
+/// The header carrying the protocols to upgrade to, which must include
+/// `websocket`.
+pub const UPGRADE: &str = "Upgrade";
+
+/// The header carrying the client's nonce.
+pub const SEC_WEBSOCKET_KEY: &str = "Sec-WebSocket-Key";
+
+/// The header carrying the server's answer to the client's nonce.
+pub const SEC_WEBSOCKET_ACCEPT: &str = "Sec-WebSocket-Accept";
+
+/// The header carrying the websocket protocol version.
+pub const SEC_WEBSOCKET_VERSION: &str = "Sec-WebSocket-Version";
+
+/// The header carrying the offered or selected subprotocols.
+pub const SEC_WEBSOCKET_PROTOCOL: &str = "Sec-WebSocket-Protocol";
+
+/// The header carrying the offered or accepted extensions.
+pub const SEC_WEBSOCKET_EXTENSIONS: &str = "Sec-WebSocket-Extensions";
+
+/// The extension token with which a client offers and a server accepts to
+/// disable masking (see [`Client::dangerous_disable_masking`](super::Client::dangerous_disable_masking)).
+///
+/// This is not part of RFC 6455 and only understood by this crate.
+pub const UNMASKED_EXTENSION: &str = "x-soketto-unmasked";
@@ -14,20 +14,30 @@ use bytes::{Buf, BytesMut};
 use crate::{Parsing, extension::Extension};
 use crate::connection::{self, Mode};
 use futures::prelude::*;
-use sha1::{Digest, Sha1};
 use std::{mem, str};
 use super::{
+    ACCEPT_LEN,
     Error,
-    KEY,
+    Header,
     MAX_NUM_HEADERS,
-    SEC_WEBSOCKET_EXTENSIONS,
-    SEC_WEBSOCKET_PROTOCOL,
+    accept_value,
     append_extensions,
+    append_header_name,
+    append_unmasked,
     configure_extensions,
+    consts,
     expect_ascii_header,
+    expect_single_headers,
+    expect_websocket_upgrade,
+    has_unmasked,
+    is_valid_header,
+    parse_extensions,
+    parse_protocols,
     with_first_header
 };
 
+pub use crate::connection::{Shutdown, ShutdownReport};
+
 const BLOCK_SIZE: usize = 8 * 1024;
 const SOKETTO_VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -39,6 +49,14 @@ pub struct Server<'a, T> {
     protocols: Vec<&'a str>,
     /// Extensions the server supports.
     extensions: Vec<Box<dyn Extension + Send>>,
+    /// Accept clients which disable masking?
+    accept_unmasked: bool,
+    /// Has the client disabled masking?
+    unmasked: bool,
+    /// Fail if the client offers protocols of which we support none.
+    require_protocol_match: bool,
+    /// Reject the request unless a protocol has been negotiated.
+    require_protocol: bool,
     /// Encoding/decoding buffer.
     buffer: BytesMut
 }
@@ -50,6 +68,10 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
             socket,
             protocols: Vec::new(),
             extensions: Vec::new(),
+            accept_unmasked: false,
+            unmasked: false,
+            require_protocol_match: false,
+            require_protocol: false,
             buffer: BytesMut::new()
         }
     }
@@ -71,12 +93,57 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
         self
     }
 
+    /// Require a common protocol if the client offers any.
+    ///
+    /// If the client offers protocols of which the server supports none,
+    /// the server may either proceed without selecting one or fail the
+    /// handshake (cf. RFC 6455, 4.2.2). By default, the server proceeds and
+    /// [`ClientRequest::protocols`] is empty. If required,
+    /// [`Server::receive_request`] fails with [`Error::NoCommonProtocol`]
+    /// instead, after which the request should be rejected, e.g. with
+    /// status code 400.
+    pub fn set_require_protocol_match(&mut self, require: bool) -> &mut Self {
+        self.require_protocol_match = require;
+        self
+    }
+
+    /// Require a common protocol, even if the client offers none.
+    ///
+    /// This is for services which only speak specific protocols. If none
+    /// of the client's offers is supported, or the client offers nothing,
+    /// [`Server::receive_request`] answers the request with status code
+    /// 400 and a body listing the supported protocols, and fails with
+    /// [`Error::NoCommonProtocol`]. For softer policies, see
+    /// [`ClientRequest::has_common_protocol`].
+    pub fn set_require_protocol(&mut self, require: bool) -> &mut Self {
+        self.require_protocol = require;
+        self
+    }
+
     /// Add an extension the server supports.
     pub fn add_extension(&mut self, e: Box<dyn Extension + Send>) -> &mut Self {
         self.extensions.push(e);
         self
     }
 
+    /// Accept only clients which disable masking.
+    ///
+    /// **Warning**: This violates [RFC 6455][rfc6455]. It is the server's
+    /// side of [`Client::dangerous_disable_masking`](super::Client::dangerous_disable_masking),
+    /// see there. If the client offers the [`consts::UNMASKED_EXTENSION`]
+    /// token, the server accepts it and [`Server::into_builder`] creates a
+    /// builder which expects unmasked frames. If not,
+    /// [`Server::receive_request`] fails with
+    /// [`Error::MaskingOptOutMismatch`], after which the request should be
+    /// rejected. Servers which do not accept it ignore the token, so that the
+    /// client's handshake fails.
+    ///
+    /// [rfc6455]: https://tools.ietf.org/html/rfc6455#section-5.1
+    pub fn dangerous_accept_unmasked_frames(&mut self, accept: bool) -> &mut Self {
+        self.accept_unmasked = accept;
+        self
+    }
+
     /// Get back all extensions.
     pub fn drain_extensions(&mut self) -> impl Iterator<Item = Box<dyn Extension + Send>> + '_ {
         self.extensions.drain(..)
@@ -87,20 +154,48 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
         self.buffer.clear();
         loop {
             crate::read(&mut self.socket, &mut self.buffer, BLOCK_SIZE).await?;
-            if let Parsing::Done { value, offset } = self.decode_request()? {
-                self.buffer.advance(offset);
-                return Ok(value)
+            match self.decode_request() {
+                Ok(Parsing::Done { value, offset }) => {
+                    self.buffer.advance(offset);
+                    return Ok(value)
+                }
+                Ok(Parsing::NeedMore(())) => {}
+                Err(Error::NoCommonProtocol) if self.require_protocol => {
+                    let body = format!("Supported protocols: {}\n", self.protocols.join(", "));
+                    let headers = b"Connection: close\r\nContent-Type: text/plain";
+                    self.send_error_response(b"400 Bad Request", headers, body.as_bytes()).await?;
+                    return Err(Error::NoCommonProtocol)
+                }
+                Err(e@Error::HeaderNotFound(_)) | Err(e@Error::UnexpectedHeader(_)) if is_upgrade_error(&e) => {
+                    let headers = b"Upgrade: websocket\r\nConnection: upgrade\r\nSec-WebSocket-Version: 13";
+                    self.send_error_response(b"426 Upgrade Required", headers, b"").await?;
+                    return Err(e)
+                }
+                Err(e) => return Err(e)
             }
         }
     }
 
     /// Respond to the client.
+    ///
+    /// Bytes the client has sent after its request, e.g. a first frame, are
+    /// kept and passed on by [`Server::into_builder`].
+    ///
+    /// The headers of a [`Response::Rejection`] are checked first. If a name
+    /// is not a valid HTTP token or a value contains a line break, nothing is
+    /// sent and [`Error::UnexpectedHeader`] is returned.
     pub async fn send_response(&mut self, r: &Response<'_>) -> Result<(), Error> {
-        self.buffer.clear();
+        if let Response::Rejection { headers, .. } = r {
+            if let Some((name, _)) = headers.iter().find(|(n, v)| !is_valid_header(n, v)) {
+                return Err(Error::UnexpectedHeader((*name).into()))
+            }
+        }
+        let pending = self.buffer.split();
         self.encode_response(r);
         self.socket.write_all(&self.buffer).await?;
         self.socket.flush().await?;
         self.buffer.clear();
+        self.buffer.unsplit(pending);
         Ok(())
     }
 
@@ -109,6 +204,10 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
         let mut builder = connection::Builder::new(self.socket, Mode::Server);
         builder.set_buffer(self.buffer);
         builder.add_extensions(self.extensions.drain(..));
+        if self.unmasked {
+            let mut deviations = builder.deviations();
+            builder.set_deviations(*deviations.set_unmasked(true))
+        }
         builder
     }
 
@@ -135,39 +234,63 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
             return Err(Error::UnsupportedHttpVersion)
         }
 
+        expect_single_headers(request.headers, &[
+            "Host",
+            consts::UPGRADE,
+            consts::SEC_WEBSOCKET_VERSION,
+            consts::SEC_WEBSOCKET_KEY
+        ])?;
+
         // TODO: Host Validation
-        with_first_header(&request.headers, "Host", |_h| Ok(()))?;
+        with_first_header(request.headers, "Host", |_h| Ok(()))?;
 
-        expect_ascii_header(request.headers, "Upgrade", "websocket")?;
+        expect_websocket_upgrade(request.headers)?;
         expect_ascii_header(request.headers, "Connection", "upgrade")?;
-        expect_ascii_header(request.headers, "Sec-WebSocket-Version", "13")?;
+        expect_ascii_header(request.headers, consts::SEC_WEBSOCKET_VERSION, consts::VERSION)?;
 
-        let ws_key = with_first_header(&request.headers, "Sec-WebSocket-Key", |k| {
+        let ws_key = with_first_header(request.headers, consts::SEC_WEBSOCKET_KEY, |k| {
             Ok(Vec::from(k))
         })?;
 
+        let mut unmasked = false;
         for h in request.headers.iter()
-            .filter(|h| h.name.eq_ignore_ascii_case(SEC_WEBSOCKET_EXTENSIONS))
+            .filter(|h| h.name.eq_ignore_ascii_case(consts::SEC_WEBSOCKET_EXTENSIONS))
         {
-            configure_extensions(&mut self.extensions, std::str::from_utf8(h.value)?)?
+            let parsed = parse_extensions(std::str::from_utf8(h.value)?)?;
+            configure_extensions(&mut self.extensions, &parsed)?;
+            unmasked |= has_unmasked(&parsed)
         }
+        if self.accept_unmasked && !unmasked {
+            return Err(Error::MaskingOptOutMismatch)
+        }
+        self.unmasked = self.accept_unmasked;
 
         let mut protocols = Vec::new();
-        for p in request.headers.iter()
-            .filter(|h| h.name.eq_ignore_ascii_case(SEC_WEBSOCKET_PROTOCOL))
-        {
-            if let Some(&p) = self.protocols.iter().find(|x| x.as_bytes() == p.value) {
+        let mut offered = false;
+        for p in parse_protocols(request.headers)? {
+            offered = true;
+            if let Some(&p) = self.protocols.iter().find(|x| **x == p) {
                 protocols.push(p)
             }
         }
+        if protocols.is_empty() && (self.require_protocol || offered && self.require_protocol_match) {
+            return Err(Error::NoCommonProtocol)
+        }
 
         let mut path = String::new();
         if let Some(val) = request.path {
             path.push_str(val)
         }
 
+        let query = match path.split_once('?') {
+            Some((_, q)) => parse_query(q),
+            None => Vec::new()
+        };
+
+        let headers = request.headers.iter().map(Header::from_httparse).collect();
+
         Ok(Parsing::Done {
-            value: ClientRequest { ws_key, protocols, path }, offset,
+            value: ClientRequest { ws_key, protocols, offered, path, query, headers }, offset,
         })
     }
 
@@ -175,43 +298,60 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
     fn encode_response(&mut self, response: &Response<'_>) {
         match response {
             Response::Accept { key, protocol } => {
-                let mut key_buf = [0; 32];
-                let accept_value = {
-                    let mut digest = Sha1::new();
-                    digest.update(key);
-                    digest.update(KEY);
-                    let d = digest.finalize();
-                    let n = base64::encode_config_slice(&d, base64::STANDARD, &mut key_buf);
-                    &key_buf[.. n]
-                };
+                let mut key_buf = [0; ACCEPT_LEN];
+                let accept_value = accept_value(key, &mut key_buf);
                 self.buffer.extend_from_slice(b"HTTP/1.1 101 Switching Protocols");
                 self.buffer.extend_from_slice(b"\r\nServer: soketto-");
                 self.buffer.extend_from_slice(SOKETTO_VERSION.as_bytes());
                 self.buffer.extend_from_slice(b"\r\nUpgrade: websocket\r\nConnection: upgrade");
-                self.buffer.extend_from_slice(b"\r\nSec-WebSocket-Accept: ");
+                append_header_name(&mut self.buffer, consts::SEC_WEBSOCKET_ACCEPT);
                 self.buffer.extend_from_slice(accept_value);
                 if let Some(p) = protocol {
-                    self.buffer.extend_from_slice(b"\r\nSec-WebSocket-Protocol: ");
+                    append_header_name(&mut self.buffer, consts::SEC_WEBSOCKET_PROTOCOL);
                     self.buffer.extend_from_slice(p.as_bytes())
                 }
                 append_extensions(self.extensions.iter().filter(|e| e.is_enabled()), &mut self.buffer);
+                if self.unmasked {
+                    append_unmasked(&mut self.buffer, self.extensions.iter().any(|e| e.is_enabled()))
+                }
                 self.buffer.extend_from_slice(b"\r\n\r\n")
             }
             Response::Reject { status_code } => {
-                self.buffer.extend_from_slice(b"HTTP/1.1 ");
-                let (_, s, reason) =
-                    if let Ok(i) = STATUSCODES.binary_search_by_key(status_code, |(n, _, _)| *n) {
-                        STATUSCODES[i]
-                    } else {
-                        (500, "500", "Internal Server Error")
-                    };
-                self.buffer.extend_from_slice(s.as_bytes());
-                self.buffer.extend_from_slice(b" ");
-                self.buffer.extend_from_slice(reason.as_bytes());
+                append_status_line(&mut self.buffer, *status_code);
                 self.buffer.extend_from_slice(b"\r\n\r\n")
             }
+            Response::Rejection { status_code, headers, body } => {
+                append_status_line(&mut self.buffer, *status_code);
+                self.buffer.extend_from_slice(b"\r\nServer: soketto-");
+                self.buffer.extend_from_slice(SOKETTO_VERSION.as_bytes());
+                for (name, value) in headers.iter() {
+                    append_header_name(&mut self.buffer, name);
+                    self.buffer.extend_from_slice(value)
+                }
+                append_header_name(&mut self.buffer, "Content-Length");
+                self.buffer.extend_from_slice(body.len().to_string().as_bytes());
+                self.buffer.extend_from_slice(b"\r\n\r\n");
+                self.buffer.extend_from_slice(body)
+            }
         }
     }
+
+    // Send the response to a request which can not be accepted.
+    async fn send_error_response(&mut self, status: &[u8], headers: &[u8], body: &[u8]) -> Result<(), Error> {
+        self.buffer.clear();
+        self.buffer.extend_from_slice(b"HTTP/1.1 ");
+        self.buffer.extend_from_slice(status);
+        self.buffer.extend_from_slice(b"\r\nServer: soketto-");
+        self.buffer.extend_from_slice(SOKETTO_VERSION.as_bytes());
+        self.buffer.extend_from_slice(b"\r\n");
+        self.buffer.extend_from_slice(headers);
+        self.buffer.extend_from_slice(format!("\r\nContent-Length: {}\r\n\r\n", body.len()).as_bytes());
+        self.buffer.extend_from_slice(body);
+        self.socket.write_all(&self.buffer).await?;
+        self.socket.flush().await?;
+        self.buffer.clear();
+        Ok(())
+    }
 }
 
 /// Handshake request received from the client.
@@ -219,7 +359,13 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
 pub struct ClientRequest<'a> {
     ws_key: Vec<u8>,
     protocols: Vec<&'a str>,
+    /// Did the client offer any protocols?
+    offered: bool,
     path: String,
+    /// The percent-decoded name/value pairs of the query string.
+    query: Vec<(String, String)>,
+    /// All request headers in order.
+    headers: Vec<Header>
 }
 
 impl<'a> ClientRequest<'a> {
@@ -237,10 +383,142 @@ impl<'a> ClientRequest<'a> {
         self.protocols.iter().cloned()
     }
 
+    /// Did the client offer any protocols?
+    pub fn offers_protocols(&self) -> bool {
+        self.offered
+    }
+
+    /// Did the client offer a protocol the server supports?
+    ///
+    /// If not, the server may still accept the request without a protocol
+    /// or reject it, depending on the application's policy.
+    pub fn has_common_protocol(&self) -> bool {
+        !self.protocols.is_empty()
+    }
+
     /// The path the client is requesting.
     pub fn path(&self) -> &str {
         &self.path
     }
+
+    /// The name/value pairs of the query string in order.
+    ///
+    /// Names and values are percent-decoded and `+` is decoded as a space
+    /// (cf. `application/x-www-form-urlencoded`).
+    pub fn query_pairs(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.query.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    /// The value of the first query parameter with the given name.
+    ///
+    /// Names are compared case-sensitively after decoding. If a parameter
+    /// occurs more than once, the first occurrence wins.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query_pairs().find(|(n, _)| *n == name).map(|(_, v)| v)
+    }
+
+    /// All request headers in the order the client sent them.
+    ///
+    /// Values are the bytes as received, see [`Header`].
+    pub fn headers(&self) -> &[Header] {
+        &self.headers
+    }
+
+    /// The first header with the given name.
+    ///
+    /// Names are compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&Header> {
+        self.headers.iter().find(|h| h.name().eq_ignore_ascii_case(name))
+    }
+
+    /// The value of the first header with the given name, if it is valid
+    /// UTF-8.
+    pub fn header_str(&self, name: &str) -> Option<&str> {
+        self.header(name).and_then(Header::as_str)
+    }
+
+    /// The bearer token the client authenticates with.
+    ///
+    /// The token of an `Authorization: Bearer` header takes precedence. As
+    /// browsers can not set headers on websocket connections, the query
+    /// parameter `param`, e.g. `access_token`, is used otherwise.
+    pub fn bearer_token(&self, param: &str) -> Option<&str> {
+        let header = self.header_str("Authorization").and_then(|a| {
+            let (scheme, token) = a.trim().split_once(' ')?;
+            if !scheme.eq_ignore_ascii_case("Bearer") {
+                return None
+            }
+            Some(token.trim())
+        });
+        header.or_else(|| self.query_param(param))
+    }
+}
+
+/// Append the status line for the given code.
+///
+/// Unknown codes are replaced with 500.
+fn append_status_line(bytes: &mut BytesMut, status_code: u16) {
+    let (_, s, reason) =
+        if let Ok(i) = STATUSCODES.binary_search_by_key(&status_code, |(n, _, _)| *n) {
+            STATUSCODES[i]
+        } else {
+            (500, "500", "Internal Server Error")
+        };
+    bytes.extend_from_slice(b"HTTP/1.1 ");
+    bytes.extend_from_slice(s.as_bytes());
+    bytes.extend_from_slice(b" ");
+    bytes.extend_from_slice(reason.as_bytes())
+}
+
+/// Is the `Upgrade` header missing or without `websocket`?
+fn is_upgrade_error(e: &Error) -> bool {
+    match e {
+        Error::HeaderNotFound(name) | Error::UnexpectedHeader(name) => name == consts::UPGRADE,
+        _ => false
+    }
+}
+
+/// Split a query string into percent-decoded name/value pairs.
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query.split('&')
+        .filter(|p| !p.is_empty())
+        .map(|p| {
+            let (name, value) = p.split_once('=').unwrap_or((p, ""));
+            (percent_decode(name), percent_decode(value))
+        })
+        .collect()
+}
+
+/// Decode `%XX` escapes and `+`.
+///
+/// Invalid escapes are kept as they are and invalid UTF-8 is replaced.
+fn percent_decode(s: &str) -> String {
+    fn hex(b: u8) -> Option<u8> {
+        match b {
+            b'0' ..= b'9' => Some(b - b'0'),
+            b'a' ..= b'f' => Some(b - b'a' + 10),
+            b'A' ..= b'F' => Some(b - b'A' + 10),
+            _ => None
+        }
+    }
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => match (bytes.get(i + 1).copied().and_then(hex), bytes.get(i + 2).copied().and_then(hex)) {
+                (Some(h), Some(l)) => {
+                    decoded.push(h << 4 | l);
+                    i += 2
+                }
+                _ => decoded.push(b'%')
+            }
+            b => decoded.push(b)
+        }
+        i += 1
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
 }
 
 /// Handshake response the server sends back to the client.
@@ -254,6 +532,25 @@ pub enum Response<'a> {
     /// The server rejects the handshake request.
     Reject {
         status_code: u16
+    },
+    /// The server rejects the handshake request with additional headers and
+    /// a body, e.g. to tell the client why (see [`Response::reject`]).
+    Rejection {
+        status_code: u16,
+        headers: &'a [(&'a str, &'a [u8])],
+        body: &'a [u8]
+    }
+}
+
+impl<'a> Response<'a> {
+    /// Reject the handshake request for an application-specific reason.
+    ///
+    /// Unlike [`Response::Reject`], the response carries the given headers,
+    /// e.g. `Retry-After` or `WWW-Authenticate`, and a body with a message
+    /// for the client. A `Content-Length` header is added. The connection
+    /// should be closed after sending the response.
+    pub fn reject(status_code: u16, headers: &'a [(&'a str, &'a [u8])], body: &'a [u8]) -> Self {
+        Response::Rejection { status_code, headers, body }
     }
 }
 
@@ -321,3 +618,249 @@ const STATUSCODES: &[(u16, &str, &str)] = &[
     (511, "511", "Network Authentication Required")
 ];
 
+#[cfg(test)]
+mod tests {
+    use crate::Parsing;
+    use super::{Error, Server};
+
+    fn server() -> Server<'static, futures::io::Cursor<Vec<u8>>> {
+        Server::new(futures::io::Cursor::new(Vec::new()))
+    }
+
+    fn request(protocols: Option<&str>) -> Vec<u8> {
+        let headers = protocols.map(|p| format!("Sec-WebSocket-Protocol: {}\r\n", p)).unwrap_or_default();
+        request_with("/", &headers)
+    }
+
+    fn request_with(path: &str, headers: &str) -> Vec<u8> {
+        format! {
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: upgrade\r\n\
+             Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n{}\r\n",
+            path, headers
+        }.into_bytes()
+    }
+
+    #[tokio::test]
+    async fn frame_after_request() {
+        use super::Response;
+
+        // A masked binary frame sent right after the request.
+        let frame = [0x82, 0x83, 0, 0, 0, 0, 1, 2, 3];
+        let mut bytes = request(None);
+        bytes.extend_from_slice(&frame);
+        let mut server = Server::new(futures::io::Cursor::new(bytes));
+        let key = server.receive_request().await.unwrap().into_key();
+        server.send_response(&Response::Accept { key: &key, protocol: None }).await.unwrap();
+
+        let (_, mut receiver) = server.into_builder().finish();
+        let mut data = Vec::new();
+        assert!(matches!(receiver.receive_data(&mut data).await, Ok(crate::Data::Binary(3))));
+        assert_eq!(data, [1, 2, 3])
+    }
+
+    #[test]
+    fn require_protocol_match() {
+        for require in &[false, true] {
+            let mut server = server();
+            server.add_protocol("other").set_require_protocol_match(*require);
+            server.buffer.extend_from_slice(&request(Some("chat, superchat")));
+            match server.decode_request() {
+                Ok(Parsing::Done { value, .. }) if !require => assert_eq!(0, value.protocols().count()),
+                Err(Error::NoCommonProtocol) if *require => {}
+                other => panic!("unexpected: {:?}", other)
+            }
+        }
+
+        // Requests without protocols or with a common one are accepted.
+        for (protocols, expected) in &[(None, None), (Some("chat, other"), Some("other"))] {
+            let mut server = server();
+            server.add_protocol("other").set_require_protocol_match(true);
+            server.buffer.extend_from_slice(&request(*protocols));
+            match server.decode_request() {
+                Ok(Parsing::Done { value, .. }) => assert_eq!(*expected, value.protocols().next()),
+                other => panic!("unexpected: {:?}", other)
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn require_protocol() {
+        use futures::io::AsyncReadExt;
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+
+        for protocols in &[None, Some("chat")] {
+            let (a, b) = tokio::io::duplex(4096);
+            let mut client = b.compat();
+            futures::io::AsyncWriteExt::write_all(&mut client, &request(*protocols)).await.unwrap();
+            let mut rejecting = Server::new(a.compat());
+            rejecting.add_protocol("graphql-transport-ws").add_protocol("graphql-ws").set_require_protocol(true);
+            assert!(matches!(rejecting.receive_request().await, Err(Error::NoCommonProtocol)));
+            drop(rejecting);
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+            assert!(response.ends_with("\r\n\r\nSupported protocols: graphql-transport-ws, graphql-ws\n"), "{}", response)
+        }
+
+        let mut required = server();
+        required.add_protocol("chat").set_require_protocol(true);
+        required.buffer.extend_from_slice(&request(Some("other, chat")));
+        match required.decode_request() {
+            Ok(Parsing::Done { value, .. }) => {
+                assert!(value.offers_protocols());
+                assert!(value.has_common_protocol())
+            }
+            other => panic!("unexpected: {:?}", other)
+        }
+
+        // Without the requirement, applications decide themselves.
+        let mut optional = server();
+        optional.add_protocol("chat");
+        optional.buffer.extend_from_slice(&request(Some("other")));
+        match optional.decode_request() {
+            Ok(Parsing::Done { value, .. }) => {
+                assert!(value.offers_protocols());
+                assert!(!value.has_common_protocol())
+            }
+            other => panic!("unexpected: {:?}", other)
+        }
+    }
+
+    #[tokio::test]
+    async fn upgrade_tokens() {
+        use futures::io::AsyncReadExt;
+        use tokio_util::compat::TokioAsyncReadCompatExt;
+
+        let request = |upgrade: &str| String::from_utf8(request(None)).unwrap().replace("Upgrade: websocket", upgrade);
+        for upgrade in &["Upgrade: websocket/13", "Upgrade: h2c, WebSocket"] {
+            let mut server = server();
+            server.buffer.extend_from_slice(request(upgrade).as_bytes());
+            assert!(matches!(server.decode_request(), Ok(Parsing::Done {..})), "{}", upgrade)
+        }
+
+        for upgrade in &["Upgrade: h2c", "X-Upgrade: websocket"] {
+            let (a, b) = tokio::io::duplex(4096);
+            let mut client = b.compat();
+            futures::io::AsyncWriteExt::write_all(&mut client, request(upgrade).as_bytes()).await.unwrap();
+            let mut rejecting = Server::new(a.compat());
+            match rejecting.receive_request().await {
+                Err(Error::UnexpectedHeader(name)) | Err(Error::HeaderNotFound(name)) => assert_eq!("Upgrade", name),
+                other => panic!("unexpected: {:?}", other)
+            }
+            drop(rejecting);
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"), "{}", response);
+            assert!(response.contains("\r\nUpgrade: websocket\r\n"), "{}", response)
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_with_reason() {
+        use super::{ClientRequest, Response};
+
+        let mut limited = Server::new(futures::io::Cursor::new(request(None)));
+        let request = limited.receive_request().await.unwrap();
+
+        // An application callback deciding whether to accept a request.
+        let quota_exceeded = |_: &ClientRequest| true;
+        let retry_after = 30.to_string();
+        let headers = [("Retry-After", retry_after.as_bytes()), ("Content-Type", &b"text/plain"[..])];
+        let response = if quota_exceeded(&request) {
+            Response::reject(429, &headers, b"too many connections, try again later")
+        } else {
+            Response::Accept { key: request.key(), protocol: None }
+        };
+        limited.send_response(&response).await.unwrap();
+
+        let written = String::from_utf8(limited.into_inner().into_inner()).unwrap();
+        let response = &written[written.find("\r\n\r\n").unwrap() + 4 ..];
+        assert!(response.starts_with("HTTP/1.1 429 Too Many Requests\r\nServer: soketto-"), "{}", response);
+        assert!(response.contains("\r\nRetry-After: 30\r\nContent-Type: text/plain\r\n"), "{}", response);
+        assert!(response.ends_with("\r\nContent-Length: 37\r\n\r\ntoo many connections, try again later"), "{}", response);
+
+        // Headers which would end up on several lines are not sent.
+        let mut server = server();
+        for headers in &[[("X-Reason", &b"a\r\nSet-Cookie: x"[..])], [("X Reason", &b"a"[..])]] {
+            match server.send_response(&Response::reject(403, headers, b"")).await {
+                Err(Error::UnexpectedHeader(name)) => assert_eq!(headers[0].0, name),
+                other => panic!("unexpected: {:?}", other)
+            }
+        }
+        assert!(server.into_inner().into_inner().is_empty())
+    }
+
+    #[test]
+    fn duplicate_headers() {
+        let duplicates = [
+            "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n",
+            "sec-websocket-key: AQIDBAUGBwgJCgsMDQ4PEA==\r\n",
+            "Upgrade: websocket\r\n",
+            "Sec-WebSocket-Version: 13\r\n",
+            "Host: example.com\r\n"
+        ];
+        for duplicate in &duplicates {
+            let mut server = server();
+            server.buffer.extend_from_slice(&request_with("/", duplicate));
+            match server.decode_request() {
+                Err(Error::DuplicateHeader(name)) =>
+                    assert!(duplicate.to_lowercase().starts_with(&name.to_lowercase()), "{}", duplicate),
+                other => panic!("unexpected: {:?}", other)
+            }
+        }
+
+        // Multi-valued headers may be repeated.
+        let mut server = server();
+        server.add_protocol("b");
+        let headers = "Sec-WebSocket-Protocol: a\r\nSec-WebSocket-Protocol: b\r\nConnection: keep-alive\r\n";
+        server.buffer.extend_from_slice(&request_with("/", headers));
+        match server.decode_request() {
+            Ok(Parsing::Done { value, .. }) => assert_eq!(Some("b"), value.protocols().next()),
+            other => panic!("unexpected: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn query_params() {
+        let mut server = server();
+        server.buffer.extend_from_slice(&request_with("/chat?room=a%20b&access_token=x%2By&room=c&flag&n=1+2", ""));
+        let request = match server.decode_request() {
+            Ok(Parsing::Done { value, .. }) => value,
+            other => panic!("unexpected: {:?}", other)
+        };
+        let pairs = request.query_pairs().collect::<Vec<_>>();
+        assert_eq!(vec![("room", "a b"), ("access_token", "x+y"), ("room", "c"), ("flag", ""), ("n", "1 2")], pairs);
+        assert_eq!(Some("a b"), request.query_param("room"));
+        assert_eq!(None, request.query_param("Room"));
+        assert_eq!(Some(""), request.query_param("flag"));
+        assert_eq!(Some("x+y"), request.bearer_token("access_token"));
+        assert_eq!(None, request.bearer_token("token"));
+
+        assert_eq!("%zz%4", super::percent_decode("%zz%4"));
+        assert_eq!("\u{fffd}", super::percent_decode("%FF"))
+    }
+
+    #[test]
+    fn bearer_token() {
+        let cases = [
+            // The header takes precedence over the query parameter.
+            ("/?access_token=query", "Authorization: Bearer header\r\n", Some("header")),
+            ("/?access_token=query", "authorization: bearer  header \r\n", Some("header")),
+            ("/?access_token=query", "Authorization: Basic dXNlcjpwYXNz\r\n", Some("query")),
+            ("/?access_token=query", "", Some("query")),
+            ("/", "Authorization: Bearer header\r\n", Some("header")),
+            ("/", "Authorization: Basic dXNlcjpwYXNz\r\n", None),
+            ("/", "", None)
+        ];
+        for (path, headers, expected) in &cases {
+            let mut server = server();
+            server.buffer.extend_from_slice(&request_with(path, headers));
+            match server.decode_request() {
+                Ok(Parsing::Done { value, .. }) => assert_eq!(*expected, value.bearer_token("access_token"), "{} {}", path, headers),
+                other => panic!("unexpected: {:?}", other)
+            }
+        }
+    }
+}
@@ -41,7 +41,8 @@
 //! let (mut sender, mut receiver) = match client.handshake().await? {
 //!     ServerResponse::Accepted { .. } => client.into_builder().finish(),
 //!     ServerResponse::Redirect { status_code, location } => unimplemented!("follow location URL"),
-//!     ServerResponse::Rejected { status_code } => unimplemented!("handle failure")
+//!     ServerResponse::ServiceUnavailable { retry_after, .. } => unimplemented!("try again later"),
+//!     ServerResponse::Rejected { status_code, .. } => unimplemented!("handle failure")
 //! };
 //!
 //! // Over the established websocket connection we can send
@@ -116,13 +117,19 @@ pub mod data;
 pub mod extension;
 pub mod handshake;
 pub mod connection;
+pub mod registry;
+pub mod utf8;
+
+mod rng;
+mod split;
+mod tap;
 
 use bytes::BytesMut;
 use futures::io::{AsyncRead, AsyncReadExt};
 use std::io;
 
 pub use connection::{Mode, Receiver, Sender};
-pub use data::{Data, Incoming};
+pub use data::{Data, Incoming, Outgoing};
 
 pub type BoxedError = Box<dyn std::error::Error + Send + Sync>;
 
@@ -161,6 +168,38 @@ impl AsRef<[u8]> for Storage<'_> {
     }
 }
 
+/// The optional features this crate has been compiled with.
+///
+/// See [`capabilities`].
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The permessage-deflate extension is available (feature `deflate`).
+    pub permessage_deflate: bool,
+    /// The SIMD UTF-8 validator `utf8::Simd` is available (feature `simd-utf8`).
+    pub simd_utf8: bool,
+    /// Large payloads are masked in parallel (feature `parallel`).
+    pub parallel_mask: bool,
+    /// Wire taps can be set (feature `tap`).
+    pub wire_tap: bool,
+    /// Deterministic connections can be created (feature `test-util`).
+    pub test_util: bool
+}
+
+/// Get the optional features this crate has been compiled with.
+///
+/// Allows a deployed binary to log what it supports, e.g. when debugging
+/// interoperability problems.
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        permessage_deflate: cfg!(feature = "deflate"),
+        simd_utf8: cfg!(feature = "simd-utf8"),
+        parallel_mask: cfg!(feature = "parallel"),
+        wire_tap: cfg!(feature = "tap"),
+        test_util: cfg!(feature = "test-util")
+    }
+}
+
 /// Helper function to allow casts from `usize` to `u64` only on platforms
 /// where the sizes are guaranteed to fit.
 #[cfg(any(target_pointer_width = "32", target_pointer_width = "64"))]
@@ -184,3 +223,18 @@ where
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn capabilities() {
+        let c = super::capabilities();
+        #[cfg(feature = "deflate")]
+        assert!(c.permessage_deflate);
+        #[cfg(not(feature = "deflate"))]
+        assert!(!c.permessage_deflate);
+        assert_eq!(cfg!(feature = "simd-utf8"), c.simd_utf8);
+        assert_eq!(cfg!(feature = "parallel"), c.parallel_mask);
+        assert_eq!(cfg!(feature = "tap"), c.wire_tap);
+        assert_eq!(cfg!(feature = "test-util"), c.test_util)
+    }
+}
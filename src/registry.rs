@@ -0,0 +1,341 @@
+// Copyright (c) 2019 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! A registry of connections keyed by application-defined IDs.
+//!
+//! Servers often need to send messages to particular connections, or to all
+//! of them. A [`Registry`] maps keys to the [`SharedSender`]s of connections
+//! (see [`Sender::into_shared`](crate::connection::Sender::into_shared)).
+//!
+//! An entry is removed as soon as its connection closes (see
+//! [`SharedSender::on_close`]). [`Registry::insert`] also returns a
+//! [`Registration`] which removes the entry when dropped, e.g. when the task
+//! handling a connection ends while the connection stays open.
+//!
+//! Sends do not hold the registry's lock, so waiting for a slow connection
+//! does not block other users of the registry.
+
+use crate::{Outgoing, connection::{CloseReason, Error, SharedSender}};
+use futures::future;
+use std::{collections::HashMap, fmt, hash::Hash};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+/// A cloneable map of keys to [`SharedSender`]s.
+pub struct Registry<K> {
+    inner: Arc<Mutex<Inner<K>>>
+}
+
+struct Inner<K> {
+    entries: HashMap<K, Entry>,
+    /// Identifies entries, so that a [`Registration`] does not remove the
+    /// entry which replaced its own.
+    next_id: u64
+}
+
+struct Entry {
+    id: u64,
+    sender: SharedSender
+}
+
+impl<K> Clone for Registry<K> {
+    fn clone(&self) -> Self {
+        Registry { inner: self.inner.clone() }
+    }
+}
+
+impl<K> fmt::Debug for Registry<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Registry").finish()
+    }
+}
+
+impl<K: Eq + Hash + Clone> Default for Registry<K> {
+    fn default() -> Self {
+        Registry::new()
+    }
+}
+
+impl<K: Eq + Hash + Clone> Registry<K> {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        let inner = Inner { entries: HashMap::new(), next_id: 0 };
+        Registry { inner: Arc::new(Mutex::new(inner)) }
+    }
+
+    /// Register the sender of a connection under the given key.
+    ///
+    /// A sender previously registered under the same key is replaced. The
+    /// entry is removed when the connection closes or the returned
+    /// [`Registration`] is dropped, whichever happens first.
+    pub fn insert(&self, key: K, sender: SharedSender) -> Registration<K>
+    where
+        K: Send + 'static
+    {
+        let id = {
+            let mut inner = self.lock();
+            let id = inner.next_id;
+            inner.next_id += 1;
+            inner.entries.insert(key.clone(), Entry { id, sender: sender.clone() });
+            id
+        };
+        // Not called with the lock held, as it runs right away if the
+        // connection is closed already.
+        let registry = Arc::downgrade(&self.inner);
+        let k = key.clone();
+        sender.on_close(move || {
+            if let Some(inner) = registry.upgrade() {
+                inner.lock().unwrap_or_else(|e| e.into_inner()).remove(&k, id)
+            }
+        });
+        Registration { inner: Arc::downgrade(&self.inner), key, id }
+    }
+
+    /// Remove the sender registered under the given key.
+    pub fn remove(&self, key: &K) -> Option<SharedSender> {
+        self.lock().entries.remove(key).map(|e| e.sender)
+    }
+
+    /// Is an open sender registered under the given key?
+    pub fn contains(&self, key: &K) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// The number of open senders.
+    pub fn len(&self) -> usize {
+        let mut inner = self.lock();
+        inner.prune();
+        inner.entries.len()
+    }
+
+    /// Are no open senders registered?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// A snapshot of the keys of all open senders.
+    pub fn keys(&self) -> Vec<K> {
+        self.snapshot().into_iter().map(|(k, ..)| k).collect()
+    }
+
+    /// Queue a message for the connection registered under the given key.
+    ///
+    /// Fails with [`Error::Closed`] if no open sender is registered under
+    /// the key. If queueing fails, the entry is removed.
+    pub async fn send_to(&self, key: &K, message: Outgoing) -> Result<(), Error> {
+        let (id, mut sender) = self.get(key).ok_or_else(|| Error::Closed(CloseReason::abnormal()))?;
+        let result = sender.send(message).await;
+        if result.is_err() {
+            self.lock().remove(key, id)
+        }
+        result
+    }
+
+    /// Queue a message for all registered connections.
+    ///
+    /// The payload is shared, not copied, between the connections. The
+    /// message is queued for all connections concurrently, so one with a
+    /// full channel does not delay the others. Entries for which queueing
+    /// fails are removed.
+    ///
+    /// Returns the number of connections the message has been queued for.
+    pub async fn broadcast(&self, message: Outgoing) -> usize {
+        let message = Arc::new(message);
+        let snapshot = self.snapshot();
+        let sends = snapshot.into_iter().map(|(key, id, mut sender)| {
+            let message = message.clone();
+            async move {
+                let result = sender.send_shared(message).await;
+                (key, id, result)
+            }
+        });
+        let mut queued = 0;
+        for (key, id, result) in future::join_all(sends).await {
+            if result.is_ok() {
+                queued += 1
+            } else {
+                self.lock().remove(&key, id)
+            }
+        }
+        queued
+    }
+
+    /// The ID and a clone of the open sender registered under `key`.
+    fn get(&self, key: &K) -> Option<(u64, SharedSender)> {
+        let mut inner = self.lock();
+        let (id, sender) = inner.entries.get(key).map(|e| (e.id, e.sender.clone()))?;
+        if sender.is_closed() {
+            inner.remove(key, id);
+            return None
+        }
+        Some((id, sender))
+    }
+
+    /// Clones of all open senders, so that the lock is not held while
+    /// sending.
+    fn snapshot(&self) -> Vec<(K, u64, SharedSender)> {
+        let mut inner = self.lock();
+        inner.prune();
+        inner.entries.iter().map(|(k, e)| (k.clone(), e.id, e.sender.clone())).collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner<K>> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<K: Eq + Hash> Inner<K> {
+    /// Remove the entry under `key` if it is still the one with the given ID.
+    fn remove(&mut self, key: &K, id: u64) {
+        if self.entries.get(key).map(|e| e.id) == Some(id) {
+            self.entries.remove(key);
+        }
+    }
+
+    /// Remove the entries whose messages are no longer being sent.
+    ///
+    /// Closed connections remove their entries themselves. This catches
+    /// senders which stopped for other reasons, e.g. because the future of
+    /// [`Sender::into_shared`](crate::connection::Sender::into_shared)
+    /// completed after all other producers were gone.
+    fn prune(&mut self) {
+        self.entries.retain(|_, e| !e.sender.is_closed())
+    }
+}
+
+/// Removes the entry created by [`Registry::insert`] when dropped.
+///
+/// If another sender has since been registered under the same key, that
+/// entry is left in place.
+pub struct Registration<K: Eq + Hash> {
+    inner: Weak<Mutex<Inner<K>>>,
+    key: K,
+    id: u64
+}
+
+impl<K: Eq + Hash> Registration<K> {
+    /// The key of the entry.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+}
+
+impl<K: Eq + Hash + fmt::Debug> fmt::Debug for Registration<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Registration").field("key", &self.key).finish()
+    }
+}
+
+impl<K: Eq + Hash> Drop for Registration<K> {
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.upgrade() {
+            inner.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.key, self.id)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Outgoing, connection::{Builder, Mode}};
+    use super::Registry;
+    use tokio_util::compat::TokioAsyncReadCompatExt;
+
+    #[tokio::test]
+    async fn send_and_broadcast() {
+        let registry = Registry::new();
+        let mut receivers = Vec::new();
+        let mut registrations = Vec::new();
+        for i in 0 .. 3 {
+            let (a, b) = tokio::io::duplex(1024);
+            let (sender, _) = Builder::new(a.compat(), Mode::Server).finish();
+            let (_, receiver) = Builder::new(b.compat(), Mode::Client).finish();
+            let (shared, task) = sender.into_shared(4);
+            tokio::spawn(task);
+            registrations.push(registry.insert(i, shared));
+            receivers.push(receiver)
+        }
+        assert_eq!(3, registry.len());
+
+        registry.send_to(&1, Outgoing::Text("one".into())).await.unwrap();
+        assert_eq!(3, registry.broadcast(Outgoing::Binary(vec![1, 2, 3])).await);
+
+        for (i, receiver) in receivers.iter_mut().enumerate() {
+            let mut data = Vec::new();
+            if i == 1 {
+                receiver.receive_data(&mut data).await.unwrap();
+                assert_eq!(b"one", &data[..]);
+                data.clear()
+            }
+            receiver.receive_data(&mut data).await.unwrap();
+            assert_eq!([1, 2, 3], data[..])
+        }
+
+        // Dropping a registration removes its entry.
+        drop(registrations.remove(1));
+        assert!(!registry.contains(&1));
+        assert!(registry.send_to(&1, Outgoing::Text("one".into())).await.is_err());
+        let mut keys = registry.keys();
+        keys.sort_unstable();
+        assert_eq!(vec![0, 2], keys)
+    }
+
+    #[tokio::test]
+    async fn closed_connections_are_removed() {
+        let registry = Registry::new();
+        let (a, b) = tokio::io::duplex(1024);
+        drop(b);
+        let (sender, _) = Builder::new(a.compat(), Mode::Server).finish();
+        let (shared, task) = sender.into_shared(4);
+        let task = tokio::spawn(task);
+        let registration = registry.insert("a", shared);
+
+        // The message is queued, but sending it fails, which ends the task
+        // and removes the entry.
+        assert_eq!(1, registry.broadcast(Outgoing::Text("x".into())).await);
+        assert!(task.await.unwrap().is_err());
+        assert_eq!(0, registry.lock().entries.len());
+        assert_eq!(0, registry.broadcast(Outgoing::Text("x".into())).await);
+
+        // A replacing entry is not removed by the old registration.
+        let (c, _d) = tokio::io::duplex(1024);
+        let (sender, _) = Builder::new(c.compat(), Mode::Server).finish();
+        let (shared, task) = sender.into_shared(4);
+        tokio::spawn(task);
+        let _replacement = registry.insert("a", shared);
+        drop(registration);
+        assert!(registry.contains(&"a"))
+    }
+
+    #[tokio::test]
+    async fn entries_are_removed_on_close() {
+        let registry = Registry::new();
+        let (a, b) = tokio::io::duplex(1024);
+        let (sender, mut receiver) = Builder::new(a.compat(), Mode::Server).finish();
+        let (mut peer, _) = Builder::new(b.compat(), Mode::Client).finish();
+        let (shared, task) = sender.into_shared(4);
+        tokio::spawn(task);
+        let _registration = registry.insert(1, shared);
+        assert_eq!(1, registry.lock().entries.len());
+
+        // Receiving the remote's CLOSE removes the entry without the
+        // registry being used in between.
+        peer.close().await.unwrap();
+        assert!(receiver.receive_data(&mut Vec::new()).await.is_err());
+        assert_eq!(0, registry.lock().entries.len());
+
+        // A sender of a closed connection is removed right away.
+        let (shared, task) = {
+            let (c, _d) = tokio::io::duplex(1024);
+            let (mut sender, _) = Builder::new(c.compat(), Mode::Server).finish();
+            sender.close().await.unwrap();
+            sender.into_shared(4)
+        };
+        tokio::spawn(task);
+        let _registration = registry.insert(2, shared);
+        assert_eq!(0, registry.lock().entries.len());
+    }
+}
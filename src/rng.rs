@@ -0,0 +1,45 @@
+// Copyright (c) 2021 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! The source of random numbers, e.g. for mask keys and handshake nonces.
+//!
+//! With the `test-util` feature, a seeded generator can be used instead of
+//! the thread-local one, which makes the bytes a connection writes
+//! reproducible.
+
+use rand::distributions::{Distribution, Standard};
+
+#[derive(Debug, Default)]
+pub(crate) enum Rng {
+    /// The thread-local generator of the `rand` crate.
+    #[default]
+    Thread,
+    /// A generator seeded for reproducible output.
+    #[cfg(feature = "test-util")]
+    Seeded(Box<rand::rngs::StdRng>)
+}
+
+impl Rng {
+    /// Create a generator which always produces the same values for a seed.
+    #[cfg(feature = "test-util")]
+    pub(crate) fn seeded(seed: u64) -> Self {
+        Rng::Seeded(Box::new(rand::SeedableRng::seed_from_u64(seed)))
+    }
+
+    /// Generate a random value.
+    pub(crate) fn gen<T>(&mut self) -> T
+    where
+        Standard: Distribution<T>
+    {
+        match self {
+            Rng::Thread => rand::random(),
+            #[cfg(feature = "test-util")]
+            Rng::Seeded(r) => rand::Rng::gen(&mut **r)
+        }
+    }
+}
@@ -0,0 +1,76 @@
+// Copyright (c) 2021 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Splitting a socket into a read and a write half.
+//!
+//! Like `futures::io::split`, but the socket can be borrowed through a half
+//! while no I/O is in progress, and the halves can be put back together.
+
+use futures::{io::{AsyncRead, AsyncWrite}, lock::{BiLock, BiLockGuard}};
+use std::{io, pin::Pin, task::{Context, Poll}};
+
+/// Split a socket into a read and a write half.
+pub(crate) fn split<T>(socket: T) -> (ReadHalf<T>, WriteHalf<T>) {
+    let (a, b) = BiLock::new(socket);
+    (ReadHalf(a), WriteHalf(b))
+}
+
+/// The read half of a socket.
+#[derive(Debug)]
+pub(crate) struct ReadHalf<T>(BiLock<T>);
+
+/// The write half of a socket.
+#[derive(Debug)]
+pub(crate) struct WriteHalf<T>(BiLock<T>);
+
+impl<T> ReadHalf<T> {
+    /// Borrow the socket.
+    ///
+    /// The socket is only locked while one of the halves is being polled,
+    /// so this fails only if called from within such a poll.
+    pub(crate) fn get(&self) -> Option<BiLockGuard<'_, T>> {
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        match self.0.poll_lock(&mut cx) {
+            Poll::Ready(guard) => Some(guard),
+            Poll::Pending => None
+        }
+    }
+}
+
+impl<T: Unpin> ReadHalf<T> {
+    /// Put the socket back together.
+    ///
+    /// Returns `None` if the halves do not belong to the same socket.
+    pub(crate) fn reunite(self, other: WriteHalf<T>) -> Option<T> {
+        self.0.reunite(other.0).ok()
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for ReadHalf<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let mut guard = futures::ready!(self.0.poll_lock(cx));
+        guard.as_pin_mut().poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for WriteHalf<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut guard = futures::ready!(self.0.poll_lock(cx));
+        guard.as_pin_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let mut guard = futures::ready!(self.0.poll_lock(cx));
+        guard.as_pin_mut().poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let mut guard = futures::ready!(self.0.poll_lock(cx));
+        guard.as_pin_mut().poll_close(cx)
+    }
+}
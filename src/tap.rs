@@ -0,0 +1,111 @@
+// Copyright (c) 2021 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Recording of the raw bytes read from and written to a socket.
+//!
+//! Without the `tap` feature, [`Tapped`] is the socket half itself.
+
+#[cfg(feature = "tap")]
+pub(crate) use imp::{Tap, Tapped};
+
+#[cfg(not(feature = "tap"))]
+pub(crate) type Tapped<S> = S;
+
+/// The socket half of a [`Tapped`].
+#[cfg(not(feature = "tap"))]
+pub(crate) fn inner<S>(tapped: &Tapped<S>) -> &S {
+    tapped
+}
+
+/// Get back the socket half, removing the tap.
+#[cfg(not(feature = "tap"))]
+pub(crate) fn into_inner<S>(tapped: Tapped<S>) -> S {
+    tapped
+}
+
+/// The socket half of a [`Tapped`].
+#[cfg(feature = "tap")]
+pub(crate) fn inner<S>(tapped: &Tapped<S>) -> &S {
+    &tapped.inner
+}
+
+/// Get back the socket half, removing the tap.
+#[cfg(feature = "tap")]
+pub(crate) fn into_inner<S>(tapped: Tapped<S>) -> S {
+    tapped.inner
+}
+
+#[cfg(feature = "tap")]
+mod imp {
+    use futures::io::{AsyncRead, AsyncWrite};
+    use std::{fmt, io::{self, Write}, pin::Pin, task::{Context, Poll}};
+
+    /// The destination of recorded bytes.
+    pub(crate) struct Tap(pub(crate) Box<dyn Write + Send>);
+
+    impl fmt::Debug for Tap {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("Tap")
+        }
+    }
+
+    /// A socket half which copies all bytes passing through it to a [`Tap`].
+    #[derive(Debug)]
+    pub(crate) struct Tapped<S> {
+        pub(super) inner: S,
+        tap: Option<Tap>
+    }
+
+    impl<S> Tapped<S> {
+        pub(crate) fn new(inner: S, tap: Option<Tap>) -> Self {
+            Tapped { inner, tap }
+        }
+
+        /// Record the given bytes.
+        ///
+        /// A tap which fails is removed, the connection is not affected.
+        fn record(&mut self, bytes: &[u8]) {
+            if let Some(Tap(w)) = &mut self.tap {
+                if let Err(e) = w.write_all(bytes) {
+                    log::debug!("removing wire tap: {}", e);
+                    self.tap = None
+                }
+            }
+        }
+    }
+
+    impl<S: AsyncRead + Unpin> AsyncRead for Tapped<S> {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+            let n = futures::ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+            self.record(&buf[.. n]);
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    impl<S: AsyncWrite + Unpin> AsyncWrite for Tapped<S> {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+            let n = futures::ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+            self.record(&buf[.. n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+            if let Some(Tap(w)) = &mut self.tap {
+                if let Err(e) = w.flush() {
+                    log::debug!("removing wire tap: {}", e);
+                    self.tap = None
+                }
+            }
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_close(cx)
+        }
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright (c) 2020 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! UTF-8 validation of text messages.
+//!
+//! The [`Validator`] used by a connection can be set with
+//! [`Builder::set_utf8_validator`](crate::connection::Builder::set_utf8_validator).
+//! By default [`Std`] is used. With feature `simd-utf8` enabled, [`Simd`] is
+//! available as well.
+
+use std::fmt;
+
+/// The result of validating a byte sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Validation {
+    /// All bytes are valid UTF-8.
+    Valid,
+    /// The bytes end with an incomplete UTF-8 sequence which may be
+    /// completed by more bytes. All bytes before `valid_up_to` are valid.
+    Incomplete { valid_up_to: usize },
+    /// The bytes contain an invalid UTF-8 sequence at `valid_up_to`.
+    Invalid { valid_up_to: usize }
+}
+
+impl Validation {
+    /// Are all bytes valid UTF-8?
+    pub fn is_valid(&self) -> bool {
+        matches!(self, Validation::Valid)
+    }
+}
+
+/// A UTF-8 validator.
+pub trait Validator: fmt::Debug {
+    /// Validate the given bytes.
+    fn validate(&self, bytes: &[u8]) -> Validation;
+}
+
+impl<V: Validator + ?Sized> Validator for Box<V> {
+    fn validate(&self, bytes: &[u8]) -> Validation {
+        (**self).validate(bytes)
+    }
+}
+
+/// Validator using `std::str::from_utf8`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Std;
+
+impl Validator for Std {
+    fn validate(&self, bytes: &[u8]) -> Validation {
+        match std::str::from_utf8(bytes) {
+            Ok(_) => Validation::Valid,
+            Err(e) if e.error_len().is_none() => Validation::Incomplete { valid_up_to: e.valid_up_to() },
+            Err(e) => Validation::Invalid { valid_up_to: e.valid_up_to() }
+        }
+    }
+}
+
+/// SIMD-accelerated validator using the `simdutf8` crate.
+#[cfg(feature = "simd-utf8")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Simd;
+
+#[cfg(feature = "simd-utf8")]
+impl Validator for Simd {
+    fn validate(&self, bytes: &[u8]) -> Validation {
+        // Checking for validity first is faster than computing error details.
+        if simdutf8::basic::from_utf8(bytes).is_ok() {
+            return Validation::Valid
+        }
+        match simdutf8::compat::from_utf8(bytes) {
+            Ok(_) => Validation::Valid,
+            Err(e) if e.error_len().is_none() => Validation::Incomplete { valid_up_to: e.valid_up_to() },
+            Err(e) => Validation::Invalid { valid_up_to: e.valid_up_to() }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{Std, Validation, Validator};
+
+    const INPUTS: &[(&[u8], Validation)] = &[
+        (b"", Validation::Valid),
+        (b"hello", Validation::Valid),
+        ("größer €".as_bytes(), Validation::Valid),
+        (b"a\xF0\x9F\x98\x80", Validation::Valid),
+        (b"a\xF0\x9F\x98", Validation::Incomplete { valid_up_to: 1 }),
+        (b"a\xF0", Validation::Incomplete { valid_up_to: 1 }),
+        (b"ab\xC3", Validation::Incomplete { valid_up_to: 2 }),
+        (b"a\xFF", Validation::Invalid { valid_up_to: 1 }),
+        (b"a\xC3\x28", Validation::Invalid { valid_up_to: 1 }),
+        (b"\xED\xA0\x80", Validation::Invalid { valid_up_to: 0 }),
+        (b"ab\xF0\x9F\x98x", Validation::Invalid { valid_up_to: 2 })
+    ];
+
+    /// Invalid sequences and the number of bytes after which they are
+    /// known to be invalid.
+    pub(crate) const INVALID: &[(&[u8], usize)] = &[
+        // Overlong encodings.
+        (b"\xC0\xAF", 1),
+        (b"\xC1\xBF", 1),
+        (b"\xE0\x80\xAF", 2),
+        (b"\xE0\x9F\xBF", 2),
+        (b"\xF0\x80\x80\xAF", 2),
+        (b"\xF0\x8F\xBF\xBF", 2),
+        // Surrogates.
+        (b"\xED\xA0\x80", 2),
+        (b"\xED\xBF\xBF", 2),
+        // Code points above U+10FFFF.
+        (b"\xF4\x90\x80\x80", 2),
+        (b"\xF5\x80\x80\x80", 1),
+        (b"\xFF", 1)
+    ];
+
+    fn check(v: &dyn Validator) {
+        for (bytes, expected) in INPUTS {
+            assert_eq!(*expected, v.validate(bytes), "{:?}", bytes)
+        }
+        // Every prefix is reported as incomplete until it is known to be invalid.
+        for (sequence, invalid_after) in INVALID {
+            let mut bytes = b"a".to_vec();
+            bytes.extend_from_slice(sequence);
+            for n in 2 ..= bytes.len() {
+                let expected =
+                    if n - 1 < *invalid_after {
+                        Validation::Incomplete { valid_up_to: 1 }
+                    } else {
+                        Validation::Invalid { valid_up_to: 1 }
+                    };
+                assert_eq!(expected, v.validate(&bytes[.. n]), "{:?}", &bytes[.. n])
+            }
+        }
+    }
+
+    #[test]
+    fn std_validator() {
+        check(&Std)
+    }
+
+    #[cfg(feature = "simd-utf8")]
+    #[test]
+    fn simd_validator() {
+        check(&super::Simd);
+
+        // Longer inputs take the SIMD code paths.
+        let mut long = "größer €".repeat(100).into_bytes();
+        assert_eq!(Std.validate(&long), super::Simd.validate(&long));
+        long.truncate(long.len() - 1);
+        assert_eq!(Std.validate(&long), super::Simd.validate(&long));
+        long.push(0xFF);
+        assert_eq!(Std.validate(&long), super::Simd.validate(&long))
+    }
+}
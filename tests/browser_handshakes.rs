@@ -0,0 +1,91 @@
+// Copyright (c) 2021 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Server handshakes with requests as sent by web browsers.
+//!
+//! The requests in `tests/fixtures` reproduce the handshake requests of
+//! Chrome, Firefox and Safari byte by byte, including their header order,
+//! casing and extra headers. Each must be accepted and the response must
+//! pass the checks the browsers apply (RFC 6455, 4.1).
+
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use soketto::handshake::{Server, compute_accept, server::Response};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+
+const BROWSERS: &[(&str, &[u8])] = &[
+    ("chrome", include_bytes!("fixtures/chrome.http")),
+    ("firefox", include_bytes!("fixtures/firefox.http")),
+    ("safari", include_bytes!("fixtures/safari.http"))
+];
+
+/// The header values of a parsed request or response.
+fn values<'a>(headers: &'a [httparse::Header], name: &str) -> Vec<&'a str> {
+    headers.iter()
+        .filter(|h| h.name.eq_ignore_ascii_case(name))
+        .flat_map(|h| std::str::from_utf8(h.value).unwrap().split(','))
+        .map(str::trim)
+        .collect()
+}
+
+#[tokio::test]
+async fn browser_requests_are_accepted() {
+    for (browser, request) in BROWSERS {
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut parsed = httparse::Request::new(&mut headers);
+        assert!(parsed.parse(request).unwrap().is_complete(), "{}", browser);
+        let offered_protocols = values(parsed.headers, "Sec-WebSocket-Protocol");
+        let offered_extensions = values(parsed.headers, "Sec-WebSocket-Extensions");
+        let key = values(parsed.headers, "Sec-WebSocket-Key")[0];
+
+        let (a, b) = tokio::io::duplex(4096);
+        let mut browser_end = b.compat();
+        browser_end.write_all(request).await.unwrap();
+
+        let mut server = Server::new(a.compat());
+        server.add_protocol("chat");
+        #[cfg(feature = "deflate")]
+        server.add_extension(Box::new(soketto::extension::deflate::Deflate::new(soketto::Mode::Server)));
+
+        let client_request = server.receive_request().await.unwrap_or_else(|e| panic!("{}: {}", browser, e));
+        assert_eq!("/chat?room=1", client_request.path(), "{}", browser);
+        assert_eq!(key.as_bytes(), client_request.key(), "{}", browser);
+        let protocol = client_request.protocols().next();
+        assert_eq!(Some("chat"), protocol, "{}", browser);
+        let response = Response::Accept { key: client_request.key(), protocol };
+        server.send_response(&response).await.unwrap();
+        drop(server);
+
+        let mut bytes = Vec::new();
+        browser_end.read_to_end(&mut bytes).await.unwrap();
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut parsed = httparse::Response::new(&mut headers);
+        let n = match parsed.parse(&bytes).unwrap() {
+            httparse::Status::Complete(n) => n,
+            httparse::Status::Partial => panic!("{}: incomplete response", browser)
+        };
+        assert_eq!(n, bytes.len(), "{}", browser);
+        assert_eq!(Some(101), parsed.code, "{}", browser);
+
+        assert_eq!(vec!["websocket"], values(parsed.headers, "Upgrade"), "{}", browser);
+        assert!(values(parsed.headers, "Connection").iter().any(|v| v.eq_ignore_ascii_case("upgrade")), "{}", browser);
+
+        let accept = compute_accept(key);
+        assert_eq!(vec![accept.as_str()], values(parsed.headers, "Sec-WebSocket-Accept"), "{}", browser);
+
+        assert_eq!(vec!["chat"], values(parsed.headers, "Sec-WebSocket-Protocol"), "{}", browser);
+        assert!(offered_protocols.contains(&"chat"), "{}", browser);
+
+        // Only offered extensions may be accepted.
+        for e in values(parsed.headers, "Sec-WebSocket-Extensions") {
+            let name = e.split(';').next().unwrap().trim();
+            assert!(offered_extensions.iter().any(|o| o.starts_with(name)), "{}: {}", browser, e)
+        }
+        #[cfg(feature = "deflate")]
+        assert_eq!(1, values(parsed.headers, "Sec-WebSocket-Extensions").len(), "{}", browser)
+    }
+}
@@ -0,0 +1,51 @@
+// Copyright (c) 2021 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Encoding control frames does not allocate.
+//!
+//! This is a test binary of its own because it replaces the global
+//! allocator with one counting the allocations of the current thread.
+
+use soketto::base::{Codec, Header, MAX_CONTROL_FRAME_SIZE, OpCode};
+use std::{alloc::{GlobalAlloc, Layout, System}, cell::Cell};
+
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+#[test]
+fn encode_control_frame_does_not_allocate() {
+    let mut codec = Codec::new();
+    let mut ping = Header::new(OpCode::Ping);
+    ping.set_masked(true);
+    ping.set_mask(0xDEAD_BEEF);
+    let mut out = [0; MAX_CONTROL_FRAME_SIZE];
+
+    let before = ALLOCATIONS.with(Cell::get);
+    for len in 0 ..= 125 {
+        let n = codec.encode_control_frame(&ping, &[7; 125][.. len], &mut out).unwrap();
+        assert_eq!(6 + len, n)
+    }
+    assert_eq!(before, ALLOCATIONS.with(Cell::get))
+}